@@ -12,17 +12,16 @@ else
     BUILD_FLAGS=""
 fi
 
-ARCH=${ARCH:-i686} # or x86_64
+ARCH=${ARCH:-i686} # or x86_64 or aarch64
 if [ $ARCH = "i686" ]
 then
-    EFIARCH="ia32"
-    QEMUARCH="i386"
     OVMF_PATH="OVMF.fd"
 elif [ $ARCH = "x86_64" ]
 then
-    EFIARCH="x64"
-    QEMUARCH=$ARCH
     OVMF_PATH="/usr/share/ovmf/OVMF.fd"
+elif [ $ARCH = "aarch64" ]
+then
+    OVMF_PATH="/usr/share/qemu-efi-aarch64/QEMU_EFI.fd"
 else
     echo "unknown arch $ARCH"
     return 1
@@ -30,10 +29,10 @@ fi
 KVM=${KVM:-no}
 if [ $KVM = "yes" ]
 then
-    QEMUMACHINE="-machine pc,accel=kvm,kernel-irqchip=off"
+    QEMUFLAGS="--kvm"
 elif [ $KVM = "no" ]
 then
-    QEMUMACHINE=""
+    QEMUFLAGS=""
 else
     echo "KVM has to be either yes or no, but is $KVM"
     return 1
@@ -41,10 +40,10 @@ fi
 GDB=${GDB:-no}
 if [ $GDB = "yes" ]
 then
-    QEMUDEBUG="-S -s"
+    QEMUFLAGS="$QEMUFLAGS --gdb"
 elif [ $GDB = "no" ]
 then
-    QEMUDEBUG=""
+    true
 else
     echo "GDB has to be either yes or no, but is $GDB"
     return 1
@@ -52,33 +51,13 @@ fi
 echo "building $BUILD for $ARCH, set BUILD or ARCH to override…"
 cargo build --target $ARCH-unknown-uefi $BUILD_FLAGS
 
-echo "checking whether mkgpt exists and building it if not…"
-if [ ! -d mkgpt ]
-then
-    git clone https://github.com/jncronin/mkgpt.git
-fi
-if [ ! -f mkgpt/mkgpt ]
-then
-    cd mkgpt
-    aclocal
-    automake --add-missing
-    ./configure
-    make
-    cd ..
-fi
-
 echo "building image…"
 KERNEL=${KERNEL:-../../kernels/multiboot1.elf}
 echo "Using $KERNEL, set KERNEL to override."
-mformat -i part.img -C -F -T $(echo "100 * 1024" | bc) -h 1 -s 1024 :: # 50 MiB
-mmd -i part.img efi
-mmd -i part.img efi/boot
-mcopy -i part.img target/$ARCH-unknown-uefi/$BUILD/towboot.efi ::efi/boot/boot$EFIARCH.efi
-mcopy -i part.img towboot.toml ::
-mcopy -i part.img "$KERNEL" ::multiboot1.elf
-
-mkgpt/mkgpt -o image.img --part part.img --type system
-rm part.img
+(cd towbootctl && cargo run --quiet -- image --target "$OLDPWD/image.img" \
+    --config "$OLDPWD/towboot.toml" \
+    --efi "$OLDPWD/target/$ARCH-unknown-uefi/$BUILD/towboot.efi" \
+    --file "$(realpath "$KERNEL"):\\multiboot1.elf")
 
 echo "checking whether OVMF exists, else trying to download…"
 if [ ! -f $OVMF_PATH ]
@@ -109,10 +88,6 @@ then
 fi
 
 echo "launching qemu with KVM=$KVM…"
-if [ $GDB = "yes" ]
-then
-    echo "The machine starts paused, waiting for GDB to attach to localhost:1234."
-fi
-qemu-system-$QEMUARCH $QEMUMACHINE $QEMUDEBUG -bios $OVMF_PATH \
--serial stdio \
--drive driver=raw,node-name=disk,file.driver=file,file.filename=image.img -m 256
+(cd towbootctl && cargo run --quiet -- boot-image "$OLDPWD/image.img" --arch $ARCH \
+    --firmware "$(realpath "$OVMF_PATH")" \
+    --serial-log "$OLDPWD/serial.log" --debugcon-log "$OLDPWD/debugcon.log" $QEMUFLAGS)
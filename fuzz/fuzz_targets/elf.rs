@@ -0,0 +1,30 @@
+//! ELF kernels
+//!
+//! This checks the segments that would be loaded, the entry point and the
+//! symbols that would be passed to the kernel.
+
+#![no_main]
+
+extern crate alloc;
+
+use goblin::elf::Elf;
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/boot/parse.rs"]
+mod parse;
+
+fuzz_target!(|data: &[u8]| {
+    let mut binary = match Elf::parse(data) {
+        Ok(binary) => binary,
+        Err(_) => return,
+    };
+    if let Ok(segments) = parse::elf_segments(&binary, data) {
+        for segment in &segments {
+            // This is what towboot loads.
+            assert!(data[segment.file_range.clone()].len() <= segment.memory_size);
+        }
+        let _ = parse::elf_entry_point(&segments, binary.entry);
+    }
+    let _ = parse::symbols(&mut binary, data);
+});
@@ -0,0 +1,27 @@
+//! Kernels with a Multiboot header that has the addresses in it
+//!
+//! This checks the header itself and the layout that's calculated from it.
+
+#![no_main]
+
+extern crate alloc;
+
+use libfuzzer_sys::fuzz_target;
+use multiboot::header::Header;
+
+#[allow(dead_code)]
+#[path = "../../src/boot/parse.rs"]
+mod parse;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(header) = Header::from_slice(data) {
+        if let Some(addresses) = header.get_addresses() {
+            let layout = parse::multiboot_layout(&addresses, header.header_start, data.len());
+            if let Ok(layout) = layout {
+                // This is what towboot reads.
+                let _ = &data[layout.load_offset..layout.load_offset + layout.file_length];
+                assert!(layout.kernel_length >= layout.file_length);
+            }
+        }
+    }
+});
@@ -0,0 +1,109 @@
+//! Variables in command lines
+//!
+//! The command lines of kernels and modules may contain variables (`$NAME` or
+//! `${NAME}`), which are replaced when preparing an entry. This way, a config
+//! doesn't have to hardcode eg. the filesystem it lives on.
+//! `$$` is a literal dollar sign, unknown variables are kept as they are.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+
+use uefi::prelude::*;
+use uefi::proto::media::block::BlockIO;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+
+use log::{debug, warn};
+
+/// the serial number of the filesystem we've been loaded from (`u64::MAX` if unknown)
+static VOLUME_SERIAL: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Read the serial number of the FAT filesystem we've been loaded from.
+///
+/// This is what Linux calls the UUID of the filesystem.
+/// If it can't be read, `$FSUUID` is kept as it is.
+pub(crate) fn read_volume_serial(device: Handle, image: Handle, systab: &SystemTable<Boot>) {
+    let block_io = match systab.boot_services().open_protocol::<BlockIO>(
+        OpenProtocolParams {
+            handle: device,
+            agent: image,
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    ) {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("failed to open our partition: {e:?}");
+            return
+        },
+    };
+    let block_io = unsafe { &*block_io.interface.get() };
+    let media = block_io.media();
+    let mut sector = vec![0; media.block_size().try_into().unwrap()];
+    if let Err(e) = block_io.read_blocks(media.media_id(), 0, &mut sector) {
+        warn!("failed to read the boot sector: {e:?}");
+        return
+    }
+    // FAT32 has no sectors per FAT here, but a longer BIOS parameter block
+    let offset = if sector[0x16..0x18] == [0, 0] { 0x43 } else { 0x27 };
+    // the extended boot signature, which says that there is a serial number
+    if sector[offset - 1] != 0x29 {
+        debug!("our partition has no FAT filesystem with a serial number");
+        return
+    }
+    let serial = u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+    debug!("our filesystem has the serial number {serial:08x}");
+    VOLUME_SERIAL.store(serial.into(), Ordering::Relaxed);
+}
+
+/// Get the value of a variable.
+fn lookup(name: &str) -> Option<String> {
+    match name {
+        "FSUUID" => match VOLUME_SERIAL.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            serial => Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff)),
+        },
+        "TOWBOOT_VERSION" => Some(env!("CARGO_PKG_VERSION").to_string()),
+        _ => None,
+    }
+}
+
+/// Replace the variables in a command line.
+pub(crate) fn expand(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(position) = rest.find('$') {
+        result.push_str(&rest[..position]);
+        rest = &rest[position + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+            continue
+        }
+        // the name and how much of the text it takes up
+        let (name, length) = match rest.strip_prefix('{')
+            .and_then(|r| r.find('}').map(|end| (&r[..end], end + 2))) {
+            Some(braced) => braced,
+            None => {
+                let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            },
+        };
+        match lookup(name) {
+            Some(value) => result.push_str(&value),
+            None => {
+                if !name.is_empty() {
+                    warn!("'{name}' is not a known variable, keeping it");
+                }
+                result.push('$');
+                result.push_str(&rest[..length]);
+            },
+        }
+        rest = &rest[length..];
+    }
+    result.push_str(rest);
+    result
+}
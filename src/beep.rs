@@ -0,0 +1,113 @@
+//! Audible feedback
+//!
+//! If enabled, towboot beeps when the menu is opened, when the selection changes
+//! and when an entry is booted, so that blind users know what's going on.
+//! This uses the firmware's speaker protocol where present and the configured
+//! fallback otherwise.
+
+use core::fmt::Write;
+
+use uefi::prelude::*;
+use uefi::proto::Protocol;
+use uefi::unsafe_guid;
+
+use log::debug;
+
+use crate::config::{BeepFallback, Config};
+use crate::firmware::system_table;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::port::{inb, outb};
+
+/// Something to tell the user about.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Sound {
+    /// The menu has been opened.
+    MenuOpened,
+    /// Another line of the menu has been selected.
+    SelectionChanged,
+    /// An entry is going to be booted.
+    Booting,
+}
+
+impl Sound {
+    /// Get the frequency (in Hz), the length of a beep (in microseconds)
+    /// and the number of beeps.
+    fn tone(self) -> (u16, usize, usize) {
+        match self {
+            Sound::MenuOpened => (880, 150_000, 1),
+            Sound::SelectionChanged => (1320, 30_000, 1),
+            Sound::Booting => (440, 100_000, 2),
+        }
+    }
+}
+
+/// `EFI_SPEAKER_IF_PROTOCOL`
+///
+/// (This is not part of the UEFI specification, but many firmwares based on EDK II have it.)
+#[repr(C)]
+#[unsafe_guid("400b4476-3081-11d6-87ed-00062945c3b9")]
+#[derive(Protocol)]
+struct Speaker {
+    set_speaker_tone_frequency: extern "efiapi" fn(this: &mut Speaker, frequency: u16) -> Status,
+    generate_beep: extern "efiapi" fn(
+        this: &mut Speaker, number_of_beeps: usize, beep_duration: usize, time_interval: usize,
+    ) -> Status,
+}
+
+/// Beep, if this is enabled in the configuration.
+///
+/// Errors are not fatal, so they're only logged.
+pub(crate) fn play(config: &Config, sound: Sound) {
+    if !config.beep.unwrap_or(false) {
+        return
+    }
+    let (frequency, duration, count) = sound.tone();
+    let systab = unsafe { system_table().as_mut() };
+    if let Ok(speaker) = systab.boot_services().locate_protocol::<Speaker>() {
+        let speaker = unsafe { &mut *speaker.get() };
+        let status = (speaker.set_speaker_tone_frequency)(speaker, frequency);
+        let status = if status.is_success() {
+            (speaker.generate_beep)(speaker, count, duration, duration)
+        } else {
+            status
+        };
+        if status.is_success() {
+            return
+        }
+        debug!("the firmware failed to beep: {status:?}");
+    }
+    match config.beep_fallback.unwrap_or(BeepFallback::PcSpeaker) {
+        BeepFallback::PcSpeaker => for index in 0..count {
+            if index > 0 {
+                systab.boot_services().stall(duration);
+            }
+            pc_speaker(frequency, duration);
+        },
+        // this only beeps if there's a terminal on the other side
+        BeepFallback::Console => for _ in 0..count {
+            let _ = systab.stdout().write_char('\x07');
+        },
+        BeepFallback::None => (),
+    }
+}
+
+/// Beep using the PC speaker (which is driven by the second channel of the PIT).
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn pc_speaker(frequency: u16, duration: usize) {
+    let divisor = (1_193_182 / u32::from(frequency)) as u16;
+    unsafe {
+        // channel 2, square wave
+        outb(0x43, 0xb6);
+        outb(0x42, divisor as u8);
+        outb(0x42, (divisor >> 8) as u8);
+        // connect the speaker
+        let state = inb(0x61);
+        outb(0x61, state | 0b11);
+        system_table().as_ref().boot_services().stall(duration);
+        outb(0x61, state & !0b11);
+    }
+}
+
+/// There's no PC speaker on this architecture.
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn pc_speaker(_frequency: u16, _duration: usize) {}
@@ -0,0 +1,184 @@
+//! AArch64
+//!
+//! There is no Multiboot specification for this architecture, so we do what the
+//! Linux boot protocol (see `Documentation/arm64/booting.rst`) asks for and pass
+//! the arguments in the registers that correspond to the ones on x86:
+//! * Multiboot kernels get the magic value in `x0` and the address of the
+//!   Multiboot information in `x1`.
+//! * `Image` kernels get the address of the device tree in `x0`.
+//!
+//! In both cases, the kernel runs at the exception level the firmware ran us at,
+//! with all exceptions masked, the MMU off and the data caches off and cleaned
+//! to the point of coherency (so that it sees what we've loaded).
+//! `x2` and `x3` are zero. The stack pointer is undefined.
+
+use core::arch::asm;
+
+use uefi::prelude::*;
+
+use log::debug;
+
+use multiboot::information::{MultibootInfo, SIGNATURE_EAX};
+
+/// the bits to clear in `SCTLR_ELx`: the MMU (M), the data cache (C)
+/// and the instruction cache (I)
+const SCTLR_DISABLE: u64 = (1 << 0) | (1 << 2) | (1 << 12);
+
+/// Hands over to the kernel.
+///
+/// There's nothing to prepare here, the code doesn't need to be copied anywhere.
+pub(crate) struct Handoff;
+
+impl Handoff {
+    pub(crate) fn new() -> Result<Self, Status> {
+        Ok(Self)
+    }
+
+    /// Bring the machine into the state the kernel expects and jump to it.
+    ///
+    /// This has to be called after exiting boot services.
+    pub(crate) unsafe fn jump(
+        self, entry_address: usize, multiboot_information: &MultibootInfo,
+    ) -> ! {
+        enter(
+            entry_address, SIGNATURE_EAX.into(),
+            multiboot_information as *const MultibootInfo as u64,
+        )
+    }
+
+    /// Jump to a kernel in the `Image` format.
+    ///
+    /// This has to be called after exiting boot services.
+    pub(crate) unsafe fn jump_to_image(self, entry_address: usize, device_tree: usize) -> ! {
+        enter(entry_address, device_tree as u64, 0)
+    }
+}
+
+/// Clean the caches, turn off the MMU and jump.
+///
+/// UEFI identity-maps all memory, so we can continue running after disabling the MMU.
+/// Once the data cache is off, nothing may be written to memory anymore,
+/// that's why this is a single block of assembly.
+unsafe fn enter(entry_address: usize, x0: u64, x1: u64) -> ! {
+    asm!(
+        "msr daifset, #0xf",
+        // clean and invalidate all data caches by set/way, up to the level of coherency
+        // (this is the example from the Architecture Reference Manual)
+        "mrs x0, clidr_el1",
+        "and w3, w0, #0x07000000",
+        // the level of coherency, times two
+        "lsr w3, w3, #23",
+        "cbz w3, 5f",
+        // the current cache level, times two
+        "mov w10, #0",
+        "1:",
+        "add w2, w10, w10, lsr #1",
+        "lsr w1, w0, w2",
+        // the type of the cache at this level, skip it if there's no data cache
+        "and w1, w1, #0x7",
+        "cmp w1, #2",
+        "b.lt 4f",
+        "msr csselr_el1, x10",
+        "isb",
+        "mrs x1, ccsidr_el1",
+        // log2 of the line size
+        "and w2, w1, #7",
+        "add w2, w2, #4",
+        // the maximum way number and where it goes in the operand
+        "ubfx w4, w1, #3, #10",
+        "clz w5, w4",
+        // the maximum set number
+        "ubfx w7, w1, #13, #15",
+        "2:",
+        "mov w9, w4",
+        "3:",
+        "lsl w6, w9, w5",
+        "orr w11, w10, w6",
+        "lsl w6, w7, w2",
+        "orr w11, w11, w6",
+        "dc cisw, x11",
+        "subs w9, w9, #1",
+        "b.ge 3b",
+        "subs w7, w7, #1",
+        "b.ge 2b",
+        "4:",
+        "add w10, w10, #2",
+        "cmp w3, w10",
+        "b.gt 1b",
+        "5:",
+        "dsb sy",
+        "isb",
+        // disable the MMU and the caches at the current exception level (EL1 or EL2)
+        "mov x10, #{sctlr_disable}",
+        "mrs x9, CurrentEL",
+        "cmp x9, #(2 << 2)",
+        "b.eq 6f",
+        "mrs x9, sctlr_el1",
+        "bic x9, x9, x10",
+        "msr sctlr_el1, x9",
+        "b 7f",
+        "6:",
+        "mrs x9, sctlr_el2",
+        "bic x9, x9, x10",
+        "msr sctlr_el2, x9",
+        "7:",
+        "isb",
+        "ic iallu",
+        "dsb sy",
+        "isb",
+        "mov x0, x20",
+        "mov x1, x21",
+        "mov x2, xzr",
+        "mov x3, xzr",
+        "br x22",
+        sctlr_disable = const SCTLR_DISABLE,
+        in("x20") x0,
+        in("x21") x1,
+        in("x22") entry_address,
+        options(noreturn),
+    )
+}
+
+/// Mask all interrupts.
+///
+/// The interrupt controller is left alone, masking them at the CPU has to be enough.
+/// (This also happens during the handoff, anyway.)
+pub(crate) unsafe fn quiesce() {
+    asm!("msr daifset, #0xf", options(nomem, nostack));
+}
+
+/// There's nothing to do here.
+///
+/// On this architecture, the other processors are held by the firmware
+/// (usually via PSCI) until the kernel asks for them to be started.
+pub(crate) fn park_application_processors(_systab: &SystemTable<Boot>) {
+    debug!("not parking the other processors, they are held by the firmware");
+}
+
+/// Get a random number from RNDR, if the processor has it.
+pub(crate) fn hardware_random() -> Option<u32> {
+    let features: u64;
+    unsafe { asm!("mrs {}, id_aa64isar0_el1", out(reg) features, options(nomem, nostack)) };
+    // the RNDR field
+    if features >> 60 == 0 {
+        return None
+    }
+    let value: u64;
+    let success: u64;
+    // RNDR (written as the register number, so that the assembler doesn't need to know it)
+    // sets the zero flag if it fails.
+    unsafe { asm!(
+        "mrs {value}, s3_3_c2_c4_0",
+        "cset {success}, ne",
+        value = out(reg) value, success = out(reg) success,
+        options(nomem, nostack),
+    ) };
+    (success != 0).then(|| value as u32)
+}
+
+/// Read the virtual counter.
+pub(crate) fn timestamp() -> u64 {
+    let value: u64;
+    unsafe { asm!("mrs {}, cntvct_el0", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
@@ -0,0 +1,26 @@
+//! Architecture-specific parts of the boot process
+//!
+//! Everything that depends on the CPU lives below here: bringing the machine into
+//! the state the kernel expects (including caches and the MMU), which registers the
+//! kernel gets its arguments in, silencing interrupts and parking the other processors.
+//!
+//! Each architecture provides the same interface:
+//! * `Handoff`: created while preparing an entry, `Handoff::jump` hands over to the kernel
+//! * `quiesce`: masks interrupts (for the `MaskInterrupts` quirk)
+//! * `park_application_processors`: is called right before exiting boot services
+//! * `hardware_random`: gets a random number from the processor (if it can)
+//! * `timestamp`: reads a fast counter (for jitter, if there's nothing better)
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(super) use x86::{
+    hardware_random, park_application_processors, quiesce, timestamp, Handoff,
+};
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "aarch64")]
+pub(super) use aarch64::{
+    hardware_random, park_application_processors, quiesce, timestamp, Handoff,
+};
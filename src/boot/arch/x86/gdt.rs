@@ -0,0 +1,56 @@
+//! The Global Descriptor Table
+//!
+//! Multiboot kernels expect flat 32-bit segments, but we can't know what the firmware's
+//! GDT looks like. (On x86_64, it doesn't even need to contain 32-bit segments.)
+//! So, we install our own before jumping to the kernel.
+
+/// the selectors of the segments
+pub(super) const CODE_SELECTOR: u16 = 0x08;
+pub(super) const DATA_SELECTOR: u16 = 0x10;
+
+/// present, ring 0, code segment, executable and readable
+const CODE_ACCESS: u8 = 0x9a;
+/// present, ring 0, data segment, writable
+const DATA_ACCESS: u8 = 0x92;
+/// the limit is in pages, 32-bit segment
+const FLAGS: u8 = 0xc;
+
+/// the null descriptor and 4 GB flat code and data segments
+pub(super) const ENTRIES: [u64; 3] = [
+    0,
+    descriptor(0, 0xfffff, CODE_ACCESS, FLAGS),
+    descriptor(0, 0xfffff, DATA_ACCESS, FLAGS),
+];
+
+/// The value to load into GDTR.
+#[repr(C, packed)]
+pub(super) struct Pointer {
+    pub(super) limit: u16,
+    pub(super) base: usize,
+}
+
+impl Pointer {
+    /// Point to the entries at the given address.
+    pub(super) fn new(base: usize) -> Self {
+        Self { limit: (ENTRIES.len() * 8 - 1) as u16, base }
+    }
+}
+
+/// Encode a segment descriptor.
+///
+/// The limit has 20 bits, the flags and the access byte are put in as they are.
+const fn descriptor(base: u32, limit: u32, access: u8, flags: u8) -> u64 {
+    (limit as u64 & 0xffff)
+    | (base as u64 & 0xff_ffff) << 16
+    | (access as u64) << 40
+    | ((limit as u64 >> 16) & 0xf) << 48
+    | (flags as u64 & 0xf) << 52
+    | (base as u64 >> 24) << 56
+}
+
+/// Write the entries to memory.
+pub(super) fn write(memory: &mut [u8]) {
+    for (index, descriptor) in ENTRIES.iter().enumerate() {
+        memory[index * 8..(index + 1) * 8].copy_from_slice(&descriptor.to_le_bytes());
+    }
+}
@@ -0,0 +1,202 @@
+//! Handing over to the kernel
+//!
+//! Multiboot kernels expect the machine to be in a specific state
+//! (see section 3.2 "Machine state" of the specification): 32-bit protected mode
+//! without paging, flat segments, interrupts disabled and the magic value and the
+//! address of the Multiboot information in EAX and EBX.
+//!
+//! On x86_64, the firmware runs us in long mode, and that can only be left from
+//! compatibility mode (32-bit code in a 64-bit environment) by disabling paging.
+//! So, we first load a GDT with flat 32-bit segments, switch to its code segment
+//! and then execute the stub below, which does the rest. (On i686, the same stub
+//! is used, it just doesn't have to leave long mode.)
+//! The stub needs to be identity-mapped and under 4 GB. Because towboot itself
+//! may have been loaded anywhere, it is copied to a page of its own beforehand.
+//! That page (which also contains the GDT) and the stack are reserved memory,
+//! so that the kernel doesn't overwrite them while it's still using them.
+
+use core::arch::{asm, global_asm};
+
+use uefi::Status;
+
+use multiboot::information::{MultibootInfo, SIGNATURE_EAX};
+
+use crate::mem::{Allocation, PAGE_SIZE};
+use super::registers::ControlRegisters;
+use super::gdt::{self, CODE_SELECTOR, DATA_SELECTOR};
+
+/// where the parts are inside of the page
+const GDT_OFFSET: usize = 0;
+const PARAMETERS_OFFSET: usize = 64;
+const CODE_OFFSET: usize = 128;
+
+/// how large the stack for the kernel is
+const STACK_SIZE: usize = 16 * 1024;
+
+/// What the stub needs to know.
+///
+/// Its address is passed in ESI.
+#[repr(C)]
+struct Parameters {
+    entry_address: u32,
+    multiboot_information: u32,
+    /// the top of the stack
+    stack: u32,
+    /// CR4 while paging is still enabled (see `ControlRegisters`)
+    cr4_with_paging: u32,
+    cr0: u32,
+    cr4: u32,
+    efer: u32,
+    /// whether to write the EFER (only on x86_64)
+    write_efer: u32,
+}
+
+/// Assemble the stub.
+///
+/// The argument switches back to the mode the rest of the binary is in.
+/// (This is a macro because parts of `global_asm!` can't be configured.)
+macro_rules! handoff_stub {
+    ($($mode:literal)?) => {
+        // This has to be position-independent, as it's copied.
+        global_asm!(
+            ".global towboot_handoff_start",
+            ".global towboot_handoff_end",
+            ".code32",
+            "towboot_handoff_start:",
+            // ESI points to the parameters.
+            // The values of the control registers are calculated in the `registers` module.
+            "mov eax, [esi + 12]",
+            "mov cr4, eax",
+            // disable paging (on x86_64, this deactivates long mode)
+            "mov eax, [esi + 16]",
+            "mov cr0, eax",
+            // i686 CPUs may not even have the EFER
+            "cmp dword ptr [esi + 28], 0",
+            "je 2f",
+            "mov ecx, 0xC0000080",
+            "mov eax, [esi + 24]",
+            "xor edx, edx",
+            "wrmsr",
+            "2:",
+            "mov eax, [esi + 20]",
+            "mov cr4, eax",
+            // > ‘DS’, ‘ES’, ‘FS’, ‘GS’, ‘SS’: Must be a 32-bit read/write data segment with an
+            // > offset of ‘0’ and a limit of ‘0xFFFFFFFF’. The exact values are all undefined.
+            "mov ax, {data}",
+            "mov ds, ax",
+            "mov es, ax",
+            "mov fs, ax",
+            "mov gs, ax",
+            "mov ss, ax",
+            // > ‘ESP’: The OS image must create its own stack as soon as it needs one.
+            // But it doesn't hurt to give it a usable one.
+            "mov esp, [esi + 8]",
+            // > ‘EFLAGS’: Bit 17 (VM) must be cleared. Bit 9 (IF) must be cleared.
+            // > Other bits are all undefined.
+            // Clear all of them (including DF), only bit 1 is reserved and always set.
+            "push 2",
+            "popfd",
+            "mov edi, [esi]",
+            // > ‘EBX’: Must contain the 32-bit physical address of the Multiboot information
+            // > structure provided by the boot loader.
+            "mov ebx, [esi + 4]",
+            // > ‘EAX’: Must contain the magic value ‘0x2BADB002’.
+            "mov eax, {signature}",
+            "jmp edi",
+            "towboot_handoff_end:",
+            $($mode,)?
+            data = const DATA_SELECTOR,
+            signature = const SIGNATURE_EAX,
+        );
+    };
+}
+
+// the rest of the binary is 64-bit code again
+#[cfg(target_arch = "x86_64")]
+handoff_stub!(".code64");
+#[cfg(target_arch = "x86")]
+handoff_stub!();
+
+extern "C" {
+    static towboot_handoff_start: u8;
+    static towboot_handoff_end: u8;
+}
+
+/// A copy of the stub, ready to be jumped to.
+pub(crate) struct Handoff {
+    page: Allocation,
+    stack: Allocation,
+}
+
+impl Handoff {
+    /// Copy the stub (and the GDT it needs) to a page under 4 GB and allocate a stack.
+    pub(crate) fn new() -> Result<Self, Status> {
+        let mut page = Allocation::new_reserved_under_4gb(PAGE_SIZE)?;
+        let stack = Allocation::new_reserved_under_4gb(STACK_SIZE)?;
+        let memory = page.as_mut_slice();
+        gdt::write(&mut memory[GDT_OFFSET..]);
+        let code = unsafe {
+            let start = &towboot_handoff_start as *const u8;
+            let end = &towboot_handoff_end as *const u8;
+            core::slice::from_raw_parts(start, end as usize - start as usize)
+        };
+        memory[CODE_OFFSET..CODE_OFFSET + code.len()].copy_from_slice(code);
+        Ok(Self { page, stack })
+    }
+
+    /// Bring the machine into the state the kernel expects and jump to it.
+    ///
+    /// This has to be called after exiting boot services.
+    pub(crate) unsafe fn jump(
+        self, entry_address: usize, multiboot_information: &MultibootInfo,
+    ) -> ! {
+        let page = self.page.as_ptr() as usize;
+        let stack_top = self.stack.as_ptr() as usize + STACK_SIZE;
+        // the kernel may use them until it sets up its own
+        core::mem::forget(self.page);
+        core::mem::forget(self.stack);
+        let current = ControlRegisters::read();
+        let wanted = current.for_multiboot();
+        let parameters = (page + PARAMETERS_OFFSET) as *mut Parameters;
+        parameters.write(Parameters {
+            entry_address: entry_address.try_into().unwrap(),
+            multiboot_information: (multiboot_information as *const MultibootInfo as usize)
+                .try_into().unwrap(),
+            stack: stack_top.try_into().unwrap(),
+            cr4_with_paging: current.cr4_before_disabling_paging(),
+            cr0: wanted.cr0,
+            cr4: wanted.cr4,
+            efer: wanted.efer.unwrap_or(0),
+            write_efer: wanted.efer.is_some().into(),
+        });
+        let gdt_pointer = gdt::Pointer::new(page + GDT_OFFSET);
+        // A far return is the easiest way to load CS.
+        // (On x86_64, this also switches to compatibility mode.)
+        #[cfg(target_arch = "x86_64")]
+        asm!(
+            "cli",
+            "lgdt [{gdt_pointer}]",
+            "push {selector}",
+            "push {code}",
+            "retfq",
+            gdt_pointer = in(reg) &gdt_pointer,
+            selector = const CODE_SELECTOR,
+            code = in(reg) page + CODE_OFFSET,
+            in("rsi") parameters,
+            options(noreturn),
+        );
+        #[cfg(target_arch = "x86")]
+        asm!(
+            "cli",
+            "lgdt [{gdt_pointer}]",
+            "push {selector}",
+            "push {code}",
+            "retf",
+            gdt_pointer = in(reg) &gdt_pointer,
+            selector = const CODE_SELECTOR,
+            code = in(reg) page + CODE_OFFSET,
+            in("esi") parameters,
+            options(noreturn),
+        );
+    }
+}
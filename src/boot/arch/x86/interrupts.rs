@@ -0,0 +1,68 @@
+//! Silencing interrupts
+//!
+//! The firmware may have configured interrupt sources that keep firing after we've
+//! exited boot services. The kernel starts with interrupts disabled, but some kernels
+//! enable them before their IDT is complete and triple-fault on the first stray one.
+//! With the `MaskInterrupts` quirk, the legacy PICs and the local APIC timer are masked.
+
+use core::arch::asm;
+
+use crate::port::outb;
+
+/// the data ports of the master and the slave PIC
+const PIC_MASTER_DATA: u16 = 0x21;
+const PIC_SLAVE_DATA: u16 = 0xa1;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLED: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDRESS: u64 = 0xf_ffff_f000;
+/// the offsets of the timer registers (in xAPIC mode)
+const APIC_LVT_TIMER: usize = 0x320;
+const APIC_TIMER_INITIAL_COUNT: usize = 0x380;
+/// the MSRs of the timer registers (in x2APIC mode)
+const X2APIC_LVT_TIMER: u32 = 0x832;
+const X2APIC_TIMER_INITIAL_COUNT: u32 = 0x838;
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Mask all interrupts of the legacy PICs and stop the local APIC timer.
+///
+/// This has to be called after exiting boot services.
+pub(crate) unsafe fn quiesce() {
+    asm!("cli", options(nomem, nostack));
+    outb(PIC_MASTER_DATA, 0xff);
+    outb(PIC_SLAVE_DATA, 0xff);
+    let apic_base = read_msr(IA32_APIC_BASE);
+    if apic_base & APIC_BASE_ENABLED == 0 {
+        return
+    }
+    if apic_base & APIC_BASE_X2APIC != 0 {
+        let timer = read_msr(X2APIC_LVT_TIMER);
+        write_msr(X2APIC_LVT_TIMER, timer | u64::from(LVT_MASKED));
+        write_msr(X2APIC_TIMER_INITIAL_COUNT, 0);
+    } else {
+        // the registers are memory-mapped (and the memory is identity-mapped)
+        // (On i686, we can't reach them if they've been moved above 4 GB.)
+        let base: usize = match (apic_base & APIC_BASE_ADDRESS).try_into() {
+            Ok(base) => base,
+            Err(_) => return,
+        };
+        let timer = (base + APIC_LVT_TIMER) as *mut u32;
+        timer.write_volatile(timer.read_volatile() | LVT_MASKED);
+        ((base + APIC_TIMER_INITIAL_COUNT) as *mut u32).write_volatile(0);
+    }
+}
+
+unsafe fn read_msr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    u64::from(high) << 32 | u64::from(low)
+}
+
+unsafe fn write_msr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32,
+        options(nostack),
+    );
+}
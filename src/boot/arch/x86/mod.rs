@@ -0,0 +1,16 @@
+//! x86 (both i686 and x86_64)
+//!
+//! Multiboot has been designed for this architecture, so this is where the
+//! "Machine state" section of the specification applies.
+
+mod gdt;
+mod handoff;
+mod interrupts;
+mod processors;
+mod random;
+mod registers;
+
+pub(crate) use handoff::Handoff;
+pub(crate) use interrupts::quiesce;
+pub(crate) use processors::park_application_processors;
+pub(crate) use random::{hardware_random, timestamp};
@@ -0,0 +1,150 @@
+//! Parking the application processors
+//!
+//! The firmware may have started the other processors (APs) and keeps them waiting
+//! in a loop somewhere in its own memory. After exiting boot services, that memory
+//! belongs to the kernel, so an AP might start executing whatever gets loaded there.
+//! To prevent this, we send them to a tiny loop (with interrupts disabled) in reserved
+//! memory before exiting boot services. They stay there until the kernel starts them
+//! (with the usual INIT-SIPI-SIPI sequence).
+
+use core::ffi::c_void;
+use core::arch::asm;
+
+use uefi::prelude::*;
+use uefi::proto::Protocol;
+use uefi::table::boot::{EventType, Tpl};
+use uefi::{unsafe_guid, Event};
+
+use log::{debug, warn};
+
+use crate::mem::{Allocation, PAGE_SIZE};
+
+/// where the parts are inside of the page
+const COUNTER_OFFSET: usize = 0;
+const CODE_OFFSET: usize = 16;
+
+/// The loop the APs are parked in.
+///
+/// This is the same in 32-bit and in 64-bit mode:
+/// ```asm
+/// lock inc dword ptr [eax] ; tell the BSP that we're here
+/// cli
+/// 1: hlt
+/// jmp 1b
+/// ```
+const PARKING_CODE: [u8; 7] = [0xf0, 0xff, 0x00, 0xfa, 0xf4, 0xeb, 0xfd];
+
+/// How long to wait for the APs to arrive (in microseconds).
+const TIMEOUT: usize = 100_000;
+/// How often to check whether they've arrived (in microseconds).
+const POLL_INTERVAL: usize = 1000;
+
+/// The MP Services Protocol
+///
+/// See section 13.4 of the Platform Initialization Specification, Volume 2.
+/// (The `uefi` crate only has a blocking wrapper for `StartupAllAPs`.)
+#[repr(C)]
+#[unsafe_guid("3fdda605-a76e-4f46-ad29-12f4531b3d08")]
+#[derive(Protocol)]
+struct MpServices {
+    get_number_of_processors: extern "efiapi" fn(
+        this: &MpServices, number_of_processors: &mut usize,
+        number_of_enabled_processors: &mut usize,
+    ) -> Status,
+    // we don't need this
+    _get_processor_info: usize,
+    startup_all_aps: extern "efiapi" fn(
+        this: &MpServices, procedure: extern "efiapi" fn(*mut c_void), single_thread: bool,
+        wait_event: Event, timeout_in_micro_seconds: usize,
+        procedure_argument: *mut c_void, failed_cpu_list: *mut *mut usize,
+    ) -> Status,
+    // we don't need these
+    _startup_this_ap: usize,
+    _switch_bsp: usize,
+    _enable_disable_ap: usize,
+    _who_am_i: usize,
+}
+
+/// This is run by each AP. It just jumps to the copy of the parking code.
+extern "efiapi" fn park(page: *mut c_void) {
+    unsafe { asm!(
+        "jmp {code}",
+        code = in(reg) page as usize + CODE_OFFSET,
+        // the page is under 4 GB, so this works in both modes
+        in("eax") page as u32,
+        options(noreturn),
+    ) }
+}
+
+/// Send all APs to the parking loop.
+///
+/// Failing to do so is not fatal, most kernels will cope.
+pub(crate) fn park_application_processors(systab: &SystemTable<Boot>) {
+    let boot_services = systab.boot_services();
+    let mp = match boot_services.locate_protocol::<MpServices>() {
+        Ok(mp) => unsafe { &*mp.get() },
+        Err(e) => {
+            debug!("not parking APs, there are no MP services: {e:?}");
+            return
+        },
+    };
+    let (mut total, mut enabled) = (0, 0);
+    let status = (mp.get_number_of_processors)(mp, &mut total, &mut enabled);
+    if status.is_error() {
+        warn!("failed to get the number of processors: {status:?}");
+        return
+    }
+    debug!("found {total} processors, {enabled} of them enabled");
+    // the BSP (that's us) is always enabled
+    let aps = enabled.saturating_sub(1);
+    if aps == 0 {
+        return
+    }
+    let mut page = match Allocation::new_reserved_under_4gb(PAGE_SIZE) {
+        Ok(page) => page,
+        Err(e) => {
+            warn!("failed to allocate memory for parking the APs: {e:?}");
+            return
+        },
+    };
+    let memory = page.as_mut_slice();
+    memory[COUNTER_OFFSET..COUNTER_OFFSET + 4].fill(0);
+    memory[CODE_OFFSET..CODE_OFFSET + PARKING_CODE.len()].copy_from_slice(&PARKING_CODE);
+    let counter = (page.as_ptr() as usize + COUNTER_OFFSET) as *const u32;
+    // Passing an event makes the call non-blocking, which we need, as the APs never return.
+    // It's never going to be signalled, so we don't need to keep it.
+    // This is safe because there is no callback.
+    let event = match unsafe { boot_services.create_event(
+        EventType::empty(), Tpl::APPLICATION, None, None
+    ) } {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("failed to create an event for parking the APs: {e:?}");
+            return
+        },
+    };
+    let status = (mp.startup_all_aps)(
+        mp, park, false, event, 0, page.as_ptr() as *mut c_void, core::ptr::null_mut(),
+    );
+    if status.is_error() {
+        warn!("failed to start the APs: {status:?}");
+        return
+    }
+    // The APs are going to stay there, so the page has to, too.
+    core::mem::forget(page);
+    // Wait until they have left our code.
+    let mut waited = 0;
+    loop {
+        let arrived = unsafe { counter.read_volatile() } as usize;
+        if arrived >= aps {
+            debug!("parked {arrived} APs");
+            break
+        }
+        if waited >= TIMEOUT {
+            warn!("only {arrived} of {aps} APs have been parked");
+            break
+        }
+        boot_services.stall(POLL_INTERVAL);
+        waited += POLL_INTERVAL;
+    }
+}
@@ -0,0 +1,44 @@
+//! Randomness from the processor
+//!
+//! RDRAND is only there on newer processors (which is checked via CPUID),
+//! the timestamp counter is always there.
+
+use core::arch::asm;
+#[cfg(target_arch = "x86")]
+use core::arch::x86::__cpuid;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+
+/// RDRAND may fail if it's out of entropy, so it's retried a few times.
+const RDRAND_ATTEMPTS: usize = 10;
+
+/// Get a random number from RDRAND, if the processor has it.
+pub(crate) fn hardware_random() -> Option<u32> {
+    // This is safe because every processor that runs UEFI has CPUID.
+    if unsafe { __cpuid(1) }.ecx & (1 << 30) == 0 {
+        return None
+    }
+    for _ in 0..RDRAND_ATTEMPTS {
+        let value: u32;
+        let success: u8;
+        unsafe { asm!(
+            "rdrand {value:e}",
+            "setc {success}",
+            value = out(reg) value, success = out(reg_byte) success,
+            options(nomem, nostack),
+        ) };
+        if success != 0 {
+            return Some(value)
+        }
+    }
+    None
+}
+
+/// Read the timestamp counter.
+pub(crate) fn timestamp() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!(
+        "rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags),
+    ) };
+    u64::from(high) << 32 | u64::from(low)
+}
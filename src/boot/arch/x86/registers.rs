@@ -0,0 +1,138 @@
+//! The control registers
+//!
+//! Multiboot kernels expect protected mode without paging (see 3.2 "Machine state"):
+//! > ‘CR0’ Bit 31 (PG) must be cleared. Bit 0 (PE) must be set.
+//! > Other bits are all undefined.
+//!
+//! The spec doesn't say anything about CR4 and the EFER, but long mode and PAE
+//! have to be disabled (or most kernels would be surprised once they enable paging).
+//! The new values are calculated here from the current ones, so that the stub
+//! in the `handoff` module just has to write them.
+
+use core::arch::asm;
+
+const CR0_PE: u32 = 1 << 0;
+const CR0_PG: u32 = 1 << 31;
+const CR4_PAE: u32 = 1 << 5;
+const CR4_LA57: u32 = 1 << 12;
+const CR4_PCIDE: u32 = 1 << 17;
+const EFER_LME: u32 = 1 << 8;
+
+/// The relevant control registers.
+///
+/// (The upper halves are reserved on x86_64, so only the lower ones are stored.)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct ControlRegisters {
+    pub(super) cr0: u32,
+    pub(super) cr4: u32,
+    /// the EFER, if we have to care about it (only on x86_64)
+    pub(super) efer: Option<u32>,
+}
+
+impl ControlRegisters {
+    /// Read the current values.
+    #[cfg(target_arch = "x86_64")]
+    pub(super) fn read() -> Self {
+        let (cr0, cr4): (u64, u64);
+        let efer: u32;
+        unsafe {
+            asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+            asm!(
+                "rdmsr",
+                in("ecx") 0xC000_0080u32, out("eax") efer, out("edx") _,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        Self { cr0: cr0 as u32, cr4: cr4 as u32, efer: Some(efer) }
+    }
+    
+    /// Read the current values.
+    ///
+    /// i686 CPUs may not even have the EFER, and it can't enable long mode here anyway.
+    #[cfg(target_arch = "x86")]
+    pub(super) fn read() -> Self {
+        let (cr0, cr4): (u32, u32);
+        unsafe {
+            asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        }
+        Self { cr0, cr4, efer: None }
+    }
+    
+    /// Calculate the values for a Multiboot kernel.
+    ///
+    /// Protected mode is enabled, paging, PAE and long mode are disabled.
+    /// Everything else stays as it is.
+    pub(super) fn for_multiboot(self) -> Self {
+        Self {
+            cr0: (self.cr0 | CR0_PE) & !CR0_PG,
+            // 5-level paging can only be enabled together with PAE
+            cr4: self.cr4 & !(CR4_PAE | CR4_LA57 | CR4_PCIDE),
+            // clear LME, so that enabling paging doesn't activate long mode again
+            efer: self.efer.map(|efer| efer & !EFER_LME),
+        }
+    }
+    
+    /// Calculate the value of CR4 to write while paging is still enabled.
+    ///
+    /// Paging can't be disabled while PCIDE is set, but PAE can't be disabled in long mode,
+    /// so this has to happen in two steps.
+    pub(super) fn cr4_before_disabling_paging(self) -> u32 {
+        self.cr4 & !CR4_PCIDE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the registers in long mode (with 4-level paging and PCIDs)
+    const LONG_MODE: ControlRegisters = ControlRegisters {
+        // PE, MP, ET, NE, WP, AM, PG
+        cr0: 0x8005_0033,
+        // PAE, PGE, OSFXSR, OSXMMEXCPT, PCIDE
+        cr4: 0x0002_06a0,
+        // SCE, LME, LMA, NXE
+        efer: Some(0x0000_0d01),
+    };
+
+    #[test]
+    fn protected_mode_without_paging() {
+        let registers = LONG_MODE.for_multiboot();
+        assert_eq!(registers.cr0 & CR0_PE, CR0_PE);
+        assert_eq!(registers.cr0 & CR0_PG, 0);
+        assert_eq!(registers.cr4 & (CR4_PAE | CR4_LA57 | CR4_PCIDE), 0);
+        assert_eq!(registers.efer.unwrap() & EFER_LME, 0);
+    }
+
+    #[test]
+    fn other_bits_are_kept() {
+        assert_eq!(LONG_MODE.for_multiboot(), ControlRegisters {
+            cr0: 0x0005_0033,
+            cr4: 0x0000_0680,
+            // LMA is read-only, it's cleared by the CPU once paging is disabled
+            efer: Some(0x0000_0c01),
+        });
+    }
+
+    #[test]
+    fn protected_mode_is_enabled() {
+        let registers = ControlRegisters { cr0: 0x10, cr4: 0, efer: None }.for_multiboot();
+        assert_eq!(registers, ControlRegisters { cr0: 0x11, cr4: 0, efer: None });
+    }
+
+    #[test]
+    fn five_level_paging() {
+        let registers = ControlRegisters { cr0: 0x8000_0011, cr4: 0x1020, efer: Some(0x500) };
+        let registers = registers.for_multiboot();
+        assert_eq!(registers.cr4 & CR4_LA57, 0);
+        assert_eq!(registers.efer, Some(0x400));
+    }
+
+    #[test]
+    fn pcide_before_paging() {
+        // paging can't be disabled with PCIDE set, but PAE has to stay for now
+        assert_eq!(LONG_MODE.cr4_before_disabling_paging(), 0x0000_06a0);
+    }
+}
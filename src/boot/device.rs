@@ -0,0 +1,230 @@
+//! Finding the disks (and the one we've been loaded from)
+//!
+//! Multiboot can tell the kernel which BIOS drive (and partition) it has been
+//! loaded from and which drives there are. There are no BIOS drives on UEFI,
+//! so this is a best guess: The disks are numbered in the order the firmware
+//! lists them, starting with 0x80 (the first hard disk), and the partition is
+//! taken from our device path. Nothing is passed if we haven't been loaded
+//! from a partition of a disk (eg. from a CD or over the network).
+//!
+//! The geometry of the drives is made up like a BIOS would for large disks
+//! (255 heads, 63 sectors per track and at most 1024 cylinders), they're
+//! always accessed via LBA anyway.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::media::block::BlockIO;
+use uefi::table::boot::{BootServices, OpenProtocolAttributes, OpenProtocolParams};
+
+use log::{debug, warn};
+
+use multiboot::information::{BootDevice, MemoryManagement, MultibootInfo};
+
+use super::super::mem::MultibootAllocator;
+
+/// the type of media device path nodes
+const MEDIA: u8 = 0x04;
+/// the subtype of device path nodes for partitions (MBR or GPT)
+const HARD_DRIVE: u8 = 0x01;
+/// the type of the nodes that end a device path
+const END: u8 = 0x7f;
+/// the signature type of GPT partitions (the signature is their unique GUID)
+const GPT_SIGNATURE: u8 = 0x02;
+
+/// the BIOS drive number of the first hard disk
+const FIRST_HARD_DISK: u8 = 0x80;
+/// the partition number for "none"
+const NO_PARTITION: u8 = 0xff;
+
+/// how many drives are passed to the kernel at most
+const MAX_DRIVES: usize = 16;
+const HEADS: u8 = 255;
+const SECTORS_PER_TRACK: u8 = 63;
+const MAX_CYLINDERS: u64 = 1024;
+/// the drive is accessed via LBA (and not CHS)
+const LBA_MODE: u8 = 1;
+/// the size of a drive structure (with an empty list of ports)
+const DRIVE_SIZE: usize = 12;
+
+/// where the drive fields are in the Multiboot information
+const DRIVES_LENGTH_OFFSET: usize = 52;
+const DRIVES_ADDRESS_OFFSET: usize = 56;
+/// the flag saying that the drive fields are valid
+const DRIVES_FLAG: u32 = 1 << 7;
+
+/// the drive and partitions we've been loaded from (`u32::MAX` if unknown)
+static BOOT_DEVICE: AtomicU32 = AtomicU32::new(u32::MAX);
+
+// This is only used to initialize the array.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_DRIVE: AtomicU64 = AtomicU64::new(0);
+/// the geometry of each drive (cylinders, heads and sectors, 0 if there's none)
+static DRIVES: [AtomicU64; MAX_DRIVES] = [NO_DRIVE; MAX_DRIVES];
+
+/// Find all disks and the drive and partition we've been loaded from.
+///
+/// `device` is the partition our image has been loaded from.
+/// If this fails, the kernel doesn't get a boot device (or drives).
+pub(crate) fn find_disks(device: Handle, image: Handle, systab: &SystemTable<Boot>) {
+    let boot_services = systab.boot_services();
+    let disks: Vec<(Vec<Vec<u8>>, u64)> = boot_services.find_handles::<BlockIO>()
+        .unwrap_or_default().into_iter()
+        .filter_map(|handle| whole_disk_size(handle, image, boot_services)
+            .zip(device_path(handle, image, boot_services))
+            .map(|(size, path)| (path, size))
+        )
+        .collect();
+    for ((_, size), drive) in disks.iter().zip(&DRIVES) {
+        // the sectors are always 512 bytes for the BIOS
+        let cylinders = (size / 512 / u64::from(HEADS) / u64::from(SECTORS_PER_TRACK))
+            .clamp(1, MAX_CYLINDERS);
+        drive.store(
+            cylinders << 16 | u64::from(HEADS) << 8 | u64::from(SECTORS_PER_TRACK),
+            Ordering::Relaxed,
+        );
+    }
+    if disks.len() > MAX_DRIVES {
+        warn!("there are {} disks, only passing the first {MAX_DRIVES}", disks.len());
+    }
+    let path = match device_path(device, image, boot_services) {
+        Some(path) => path,
+        None => {
+            warn!("failed to get the device path of our partition");
+            return
+        },
+    };
+    let partition_node = match path.iter().position(|n| n[..2] == [MEDIA, HARD_DRIVE]) {
+        Some(position) => position,
+        None => {
+            debug!("we haven't been loaded from a partition, so there's no boot device");
+            return
+        },
+    };
+    // partitions are counted from 1, BIOS partitions from 0
+    let partition = u32::from_le_bytes(path[partition_node][4..8].try_into().unwrap())
+        .checked_sub(1).and_then(|p| u8::try_from(p).ok()).unwrap_or(NO_PARTITION);
+    // the device path of a disk is the one of its partitions without the last node
+    let disk = &path[..partition_node];
+    let drive = match disks.iter().position(|(path, _)| path == disk) {
+        Some(index) => FIRST_HARD_DISK.saturating_add(index.try_into().unwrap_or(u8::MAX)),
+        None => {
+            warn!("failed to find the disk we've been loaded from, assuming the first one");
+            FIRST_HARD_DISK
+        },
+    };
+    debug!("we've been loaded from drive {drive:#x}, partition {partition}");
+    BOOT_DEVICE.store(
+        u32::from_le_bytes([drive, partition, NO_PARTITION, NO_PARTITION]), Ordering::Relaxed,
+    );
+}
+
+/// Get the boot device for the Multiboot information (see `find_disks`).
+pub(super) fn boot_device() -> Option<BootDevice> {
+    match BOOT_DEVICE.load(Ordering::Relaxed) {
+        u32::MAX => None,
+        value => {
+            let [drive, partition1, partition2, partition3] = value.to_le_bytes();
+            Some(BootDevice { drive, partition1, partition2, partition3 })
+        },
+    }
+}
+
+/// Get the unique GUID of a partition (in the byte order of the GPT).
+///
+/// This is `None` for anything that isn't a GPT partition.
+pub(crate) fn partition_guid(
+    handle: Handle, image: Handle, boot_services: &BootServices,
+) -> Option<[u8; 16]> {
+    let path = device_path(handle, image, boot_services)?;
+    // the signature is at 24, followed by the MBR type and the signature type
+    path.iter().rev().find(|n| n[..2] == [MEDIA, HARD_DRIVE])
+        .filter(|node| node.get(41) == Some(&GPT_SIGNATURE))
+        .map(|node| node[24..40].try_into().unwrap())
+}
+
+/// Pass the drives to the kernel.
+///
+/// The multiboot crate doesn't know about them, so the fields are written directly.
+pub(super) fn prepare_information(info: &mut MultibootInfo, allocator: &mut MultibootAllocator) {
+    let drives: Vec<u64> = DRIVES.iter()
+        .map(|d| d.load(Ordering::Relaxed))
+        .take_while(|d| *d != 0)
+        .collect();
+    if drives.is_empty() {
+        return
+    }
+    let length = drives.len() * DRIVE_SIZE;
+    let (address, buffer) = match unsafe { allocator.allocate(length) } {
+        Some(allocation) => allocation,
+        None => {
+            warn!("failed to allocate memory for the drives, not passing them");
+            return
+        },
+    };
+    for ((drive, geometry), entry) in (FIRST_HARD_DISK..).zip(drives)
+        .zip(buffer.chunks_exact_mut(DRIVE_SIZE)) {
+        entry[0..4].copy_from_slice(&(DRIVE_SIZE as u32).to_le_bytes());
+        entry[4] = drive;
+        entry[5] = LBA_MODE;
+        entry[6..8].copy_from_slice(&((geometry >> 16) as u16).to_le_bytes());
+        entry[8] = (geometry >> 8) as u8;
+        entry[9] = geometry as u8;
+        // there are no I/O ports, so the list only has the terminating 0
+        entry[10..12].fill(0);
+    }
+    let info = info as *mut MultibootInfo as *mut u8;
+    // This is safe because the Multiboot information has the layout of the specification.
+    unsafe {
+        let flags = info.cast::<u32>();
+        flags.write_unaligned(flags.read_unaligned() | DRIVES_FLAG);
+        info.add(DRIVES_LENGTH_OFFSET).cast::<u32>().write_unaligned(length as u32);
+        info.add(DRIVES_ADDRESS_OFFSET).cast::<u32>().write_unaligned(address as u32);
+    }
+}
+
+/// Get the nodes of the device path of a handle (without the end node).
+///
+/// Each node is returned as its raw bytes, including the header.
+pub(crate) fn device_path(
+    handle: Handle, image: Handle, boot_services: &BootServices,
+) -> Option<Vec<Vec<u8>>> {
+    let path = boot_services.open_protocol::<DevicePath>(
+        OpenProtocolParams {
+            handle,
+            agent: image,
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    ).ok()?;
+    let path = unsafe { &*path.interface.get() };
+    Some(path.node_iter()
+        // This is safe because the length of a node includes its header.
+        .map(|node| unsafe { core::slice::from_raw_parts(
+            node.as_ffi_ptr().cast::<u8>(), node.length().into(),
+        ) }.to_vec())
+        .take_while(|node| node[0] != END)
+        .collect())
+}
+
+/// Get the size of a whole disk in bytes.
+///
+/// This returns `None` for partitions (and drives without media).
+fn whole_disk_size(handle: Handle, image: Handle, boot_services: &BootServices) -> Option<u64> {
+    boot_services.open_protocol::<BlockIO>(
+        OpenProtocolParams {
+            handle,
+            agent: image,
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    ).ok().and_then(|block_io| {
+        let media = unsafe { &*block_io.interface.get() }.media();
+        (media.is_media_present() && !media.is_logical_partition()).then(|| {
+            (media.last_block() + 1) * u64::from(media.block_size())
+        })
+    })
+}
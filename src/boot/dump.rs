@@ -0,0 +1,157 @@
+//! Logging what the kernel gets (for the `DumpMultibootInformation` quirk)
+//!
+//! The Multiboot information is read back at the offsets from the
+//! specification, so this shows what has actually been written (and not what
+//! we've meant to write). The memory map is only added after exiting boot
+//! services, so a preview of it is logged instead (see `mem::preview_information`).
+
+use alloc::string::String;
+
+use log::{info, warn};
+
+use multiboot::information::{MemoryEntry, MultibootInfo};
+
+/// the bits of the flags, saying which fields are valid
+const MEMORY: u32 = 1 << 0;
+const BOOT_DEVICE: u32 = 1 << 1;
+const COMMAND_LINE: u32 = 1 << 2;
+const MODULES: u32 = 1 << 3;
+const AOUT_SYMBOLS: u32 = 1 << 4;
+const ELF_SYMBOLS: u32 = 1 << 5;
+const MEMORY_MAP: u32 = 1 << 6;
+const DRIVES: u32 = 1 << 7;
+const CONFIG_TABLE: u32 = 1 << 8;
+const BOOT_LOADER_NAME: u32 = 1 << 9;
+const APM_TABLE: u32 = 1 << 10;
+const VBE: u32 = 1 << 11;
+const FRAMEBUFFER: u32 = 1 << 12;
+
+/// how large the Multiboot information is (up to the color info of the framebuffer)
+const INFORMATION_SIZE: usize = 116;
+/// how long a string may be (anything longer is cut off)
+const MAX_STRING: usize = 4096;
+
+/// Log every field of the Multiboot information that has been set.
+pub(super) fn multiboot_information(info: &MultibootInfo) {
+    let mut bytes = [0; INFORMATION_SIZE];
+    let size = core::mem::size_of::<MultibootInfo>().min(INFORMATION_SIZE);
+    // This is safe because we only read the structure itself.
+    bytes[..size].copy_from_slice(unsafe { core::slice::from_raw_parts(
+        (info as *const MultibootInfo).cast::<u8>(), size,
+    ) });
+    let word = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let half = |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+    let flags = word(0);
+    info!("Multiboot information at {info:p}, flags {flags:#x}:");
+    if flags & MEMORY != 0 {
+        info!("  lower memory: {} KiB, upper memory: {} KiB", word(4), word(8));
+    }
+    if flags & BOOT_DEVICE != 0 {
+        let [part3, part2, part1, drive] = word(12).to_le_bytes();
+        info!("  boot device: drive {drive:#x}, partitions {part1:#x} {part2:#x} {part3:#x}");
+    }
+    if flags & COMMAND_LINE != 0 {
+        info!("  command line at {:#x}: '{}'", word(16), read_string(word(16)));
+    }
+    if flags & MODULES != 0 {
+        info!("  {} modules at {:#x}:", word(20), word(24));
+        for index in 0..word(20) as usize {
+            let module = (word(24) as usize + index * 16) as *const u32;
+            // This is safe because we've written the list ourselves.
+            let [start, end, string] = [0, 1, 2].map(|field| unsafe {
+                module.add(field).read_unaligned()
+            });
+            info!("    {index}: {start:#x}-{end:#x} '{}'", read_string(string));
+        }
+    }
+    if flags & AOUT_SYMBOLS != 0 {
+        info!(
+            "  a.out symbols: table size {:#x}, string size {:#x}, at {:#x}",
+            word(28), word(32), word(36),
+        );
+    }
+    if flags & ELF_SYMBOLS != 0 {
+        info!(
+            "  ELF section headers: {} of {} bytes at {:#x}, string table {}",
+            word(28), word(32), word(36), word(40),
+        );
+    }
+    if flags & MEMORY_MAP != 0 {
+        info!("  memory map: {} bytes at {:#x}", word(44), word(48));
+    }
+    if flags & DRIVES != 0 {
+        info!("  drives: {} bytes at {:#x}:", word(52), word(56));
+        let mut position = 0;
+        while position < word(52) as usize {
+            let drive = (word(56) as usize + position) as *const u8;
+            // This is safe because we've written the drives ourselves.
+            let (size, [number, mode, cylinders_low, cylinders_high, heads, sectors]) = unsafe { (
+                drive.cast::<u32>().read_unaligned(),
+                [0, 1, 2, 3, 4, 5].map(|offset| drive.add(4 + offset).read()),
+            ) };
+            info!(
+                "    {number:#x}: mode {mode}, {} cylinders, {heads} heads, {sectors} sectors",
+                u16::from_le_bytes([cylinders_low, cylinders_high]),
+            );
+            if size == 0 {
+                warn!("    this drive has a size of 0, stopping here");
+                break
+            }
+            position += size as usize;
+        }
+    }
+    if flags & CONFIG_TABLE != 0 {
+        info!("  config table at {:#x}", word(60));
+    }
+    if flags & BOOT_LOADER_NAME != 0 {
+        info!("  boot loader name at {:#x}: '{}'", word(64), read_string(word(64)));
+    }
+    if flags & APM_TABLE != 0 {
+        info!("  APM table at {:#x}", word(68));
+    }
+    if flags & VBE != 0 {
+        info!(
+            "  VBE: control info at {:#x}, mode info at {:#x}, mode {:#x}, \
+            interface {:#x}:{:#x} ({} bytes)",
+            word(72), word(76), half(80), half(82), half(84), half(86),
+        );
+    }
+    if flags & FRAMEBUFFER != 0 {
+        info!(
+            "  framebuffer at {:#x}: {}x{}, {} bits per pixel, pitch {}, type {}",
+            u64::from(word(88)) | u64::from(word(92)) << 32, word(100), word(104),
+            bytes[108], word(96), bytes[109],
+        );
+        match bytes[109] {
+            0 => info!("    palette: {} colors at {:#x}", half(114), word(110)),
+            1 => info!(
+                "    red: {} bits at {}, green: {} bits at {}, blue: {} bits at {}",
+                bytes[111], bytes[110], bytes[113], bytes[112], bytes[115], bytes[114],
+            ),
+            _ => (),
+        }
+    }
+}
+
+/// Log the memory map the kernel is going to get.
+pub(super) fn memory_map(entries: &[MemoryEntry]) {
+    info!("  memory map (before exiting boot services), {} entries:", entries.len());
+    for entry in entries {
+        info!(
+            "    {:#014x}-{:#014x} {:?}", entry.base_address(),
+            (entry.base_address() + entry.length()).saturating_sub(1), entry.memory_type(),
+        );
+    }
+}
+
+/// Read a null-terminated string we've written.
+fn read_string(address: u32) -> String {
+    if address == 0 {
+        return String::new()
+    }
+    let start = address as usize as *const u8;
+    // This is safe because we've written the string ourselves (terminating it).
+    let length = (0..MAX_STRING).find(|i| unsafe { start.add(*i).read() } == 0)
+        .unwrap_or(MAX_STRING);
+    String::from_utf8_lossy(unsafe { core::slice::from_raw_parts(start, length) }).into_owned()
+}
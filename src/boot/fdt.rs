@@ -0,0 +1,262 @@
+//! Setting properties of `/chosen` in a flattened device tree
+//!
+//! Kernels in the arm64 `Image` format get their command line (`bootargs`)
+//! and the place of their initrd (`linux,initrd-start` and `linux,initrd-end`)
+//! from the `/chosen` node of the device tree.
+//! Instead of patching the firmware's tree in place (which would need room in it),
+//! it is written anew, see the Devicetree Specification for the format.
+//!
+//! Like the `parse` module, this only looks at bytes, so it's tested on the host.
+
+use alloc::vec::Vec;
+
+const MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+/// the version we write (and the oldest one we can read)
+const VERSION: u32 = 17;
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+const END: u32 = 9;
+
+const TRUNCATED: &str = "the device tree is truncated";
+
+/// Get the big-endian value at this offset.
+fn read(tree: &[u8], offset: usize) -> Result<u32, &'static str> {
+    tree.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or(TRUNCATED)
+}
+
+/// Get the nul-terminated string at this offset.
+fn string(bytes: &[u8], offset: usize) -> Result<&[u8], &'static str> {
+    bytes.get(offset..).and_then(|rest| rest.split(|b| *b == 0).next()).ok_or(TRUNCATED)
+}
+
+/// Tokens in the structure block are aligned to 4 bytes.
+const fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Get the offset of a name in the strings block, adding it if it isn't there yet.
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut start = 0;
+    for string in strings.split(|b| *b == 0) {
+        if string == name.as_bytes() {
+            return start as u32
+        }
+        start += string.len() + 1;
+    }
+    let start = strings.len();
+    strings.extend_from_slice(name.as_bytes());
+    strings.push(0);
+    start as u32
+}
+
+fn push_begin_node(structure: &mut Vec<u8>, name: &str) {
+    structure.extend_from_slice(&BEGIN_NODE.to_be_bytes());
+    structure.extend_from_slice(name.as_bytes());
+    structure.push(0);
+    structure.resize(align(structure.len()), 0);
+}
+
+fn push_property(structure: &mut Vec<u8>, name_offset: u32, value: &[u8]) {
+    for word in [PROP, value.len() as u32, name_offset] {
+        structure.extend_from_slice(&word.to_be_bytes());
+    }
+    structure.extend_from_slice(value);
+    structure.resize(align(structure.len()), 0);
+}
+
+/// Put the blocks together (behind a new header).
+fn assemble(reservations: &[u8], structure: &[u8], strings: &[u8], boot_cpu: u32) -> Vec<u8> {
+    let structure_offset = HEADER_SIZE + reservations.len();
+    let strings_offset = structure_offset + structure.len();
+    let total_size = strings_offset + strings.len();
+    let mut tree = Vec::with_capacity(total_size);
+    for field in [
+        MAGIC, total_size as u32, structure_offset as u32, strings_offset as u32,
+        HEADER_SIZE as u32, VERSION, LAST_COMPATIBLE_VERSION, boot_cpu,
+        strings.len() as u32, structure.len() as u32,
+    ] {
+        tree.extend_from_slice(&field.to_be_bytes());
+    }
+    tree.extend_from_slice(reservations);
+    tree.extend_from_slice(structure);
+    tree.extend_from_slice(strings);
+    tree
+}
+
+/// Copy the device tree with these properties set in `/chosen`.
+///
+/// Properties that are already there are replaced, the node is created if it's missing.
+pub(super) fn set_chosen(
+    tree: &[u8], properties: &[(&str, Vec<u8>)],
+) -> Result<Vec<u8>, &'static str> {
+    let field = |index: usize| read(tree, index * 4).map(|value| value as usize);
+    if field(0)? != MAGIC as usize {
+        return Err("this is not a device tree")
+    }
+    if field(5)? < LAST_COMPATIBLE_VERSION as usize || field(6)? > VERSION as usize {
+        return Err("the version of the device tree is not supported")
+    }
+    let tree = tree.get(..field(1)?).ok_or(TRUNCATED)?;
+    let (structure_offset, strings_offset) = (field(2)?, field(3)?);
+    let strings = tree.get(strings_offset..strings_offset + field(8)?).ok_or(TRUNCATED)?;
+    // the reservations end with an empty one
+    let reservations_offset = field(4)?;
+    let mut reservations_end = reservations_offset;
+    loop {
+        let reservation = tree.get(reservations_end..reservations_end + 16).ok_or(TRUNCATED)?;
+        reservations_end += 16;
+        if reservation.iter().all(|b| *b == 0) {
+            break
+        }
+    }
+
+    let mut new_strings = strings.to_vec();
+    let name_offsets: Vec<u32> = properties.iter()
+        .map(|(name, _)| string_offset(&mut new_strings, name))
+        .collect();
+    let push_properties = |structure: &mut Vec<u8>| {
+        for ((_, value), name_offset) in properties.iter().zip(&name_offsets) {
+            push_property(structure, *name_offset, value);
+        }
+    };
+    let mut structure = Vec::new();
+    let mut offset = structure_offset;
+    let mut depth = 0;
+    let mut in_chosen = false;
+    let mut found_chosen = false;
+    loop {
+        let start = offset;
+        offset += 4;
+        match read(tree, start)? {
+            BEGIN_NODE => {
+                let name = string(tree, offset)?;
+                offset = align(offset + name.len() + 1);
+                depth += 1;
+                // only the direct child of the root
+                if depth == 2 && name == b"chosen" {
+                    in_chosen = true;
+                    found_chosen = true;
+                }
+            },
+            END_NODE => {
+                if depth == 0 {
+                    return Err("the device tree has too many ends of nodes")
+                }
+                if in_chosen && depth == 2 {
+                    push_properties(&mut structure);
+                    in_chosen = false;
+                } else if depth == 1 && !found_chosen {
+                    push_begin_node(&mut structure, "chosen");
+                    push_properties(&mut structure);
+                    structure.extend_from_slice(&END_NODE.to_be_bytes());
+                }
+                depth -= 1;
+            },
+            PROP => {
+                let length = read(tree, offset)? as usize;
+                let name = string(strings, read(tree, offset + 4)? as usize)?;
+                offset = align(offset + 8 + length);
+                // drop the ones we're going to replace
+                if in_chosen && depth == 2
+                && properties.iter().any(|(n, _)| n.as_bytes() == name) {
+                    continue
+                }
+            },
+            NOP => (),
+            END => {
+                structure.extend_from_slice(&END.to_be_bytes());
+                break
+            },
+            _ => return Err("the device tree contains an invalid token"),
+        }
+        structure.extend_from_slice(tree.get(start..offset).ok_or(TRUNCATED)?);
+    }
+    if depth != 0 {
+        return Err("the device tree has too few ends of nodes")
+    }
+    Ok(assemble(
+        &tree[reservations_offset..reservations_end], &structure, &new_strings, field(7)? as u32,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{
+        assemble, push_begin_node, push_property, set_chosen, string_offset, END, END_NODE,
+    };
+
+    /// Build a device tree with these strings and (if given) a `/chosen` node.
+    fn tree(names: &[&str], chosen: Option<&[(&str, &[u8])]>) -> Vec<u8> {
+        let mut strings = Vec::new();
+        for name in names {
+            string_offset(&mut strings, name);
+        }
+        let mut structure = Vec::new();
+        push_begin_node(&mut structure, "");
+        push_property(&mut structure, string_offset(&mut strings, "model"), b"test\0");
+        push_begin_node(&mut structure, "cpus");
+        push_begin_node(&mut structure, "chosen");
+        structure.extend_from_slice(&END_NODE.to_be_bytes());
+        structure.extend_from_slice(&END_NODE.to_be_bytes());
+        if let Some(properties) = chosen {
+            push_begin_node(&mut structure, "chosen");
+            for (name, value) in properties {
+                push_property(&mut structure, string_offset(&mut strings, name), value);
+            }
+            structure.extend_from_slice(&END_NODE.to_be_bytes());
+        }
+        structure.extend_from_slice(&END_NODE.to_be_bytes());
+        structure.extend_from_slice(&END.to_be_bytes());
+        let mut reservations = Vec::new();
+        for value in [0x8000_0000u64, 0x1000, 0, 0] {
+            reservations.extend_from_slice(&value.to_be_bytes());
+        }
+        assemble(&reservations, &structure, &strings, 0)
+    }
+
+    #[test]
+    fn add_chosen() {
+        let initrd = 0x1_2345_6000u64.to_be_bytes();
+        assert_eq!(
+            set_chosen(
+                &tree(&["model"], None),
+                &[("bootargs", b"quiet\0".to_vec()), ("linux,initrd-start", initrd.to_vec())],
+            ),
+            Ok(tree(
+                &["model", "bootargs", "linux,initrd-start"],
+                Some(&[("bootargs", b"quiet\0"), ("linux,initrd-start", &initrd)]),
+            )),
+        );
+    }
+
+    #[test]
+    fn replace_in_chosen() {
+        assert_eq!(
+            set_chosen(
+                &tree(&[], Some(&[("bootargs", b"old\0"), ("stdout-path", b"serial0\0")])),
+                &[("bootargs", b"new\0".to_vec())],
+            ),
+            Ok(tree(
+                &["model", "bootargs", "stdout-path"],
+                Some(&[("stdout-path", b"serial0\0"), ("bootargs", b"new\0")]),
+            )),
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(set_chosen(b"not a device tree", &[]).is_err());
+        let mut truncated = tree(&[], None);
+        truncated.truncate(truncated.len() - 8);
+        assert!(set_chosen(&truncated, &[]).is_err());
+    }
+}
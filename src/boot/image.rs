@@ -0,0 +1,122 @@
+//! Handling of kernels in the arm64 `Image` format
+//!
+//! This is what Linux (and a few other kernels) use on AArch64,
+//! see `Documentation/arm64/booting.rst` in the Linux source tree.
+//! The kernel has to be loaded at a 2 MiB aligned address plus `text_offset`
+//! and gets the address of the device tree in `x0`.
+//! The command line and the initrd are passed inside the device tree
+//! (see the `fdt` module), so the kernel gets a modified copy of it.
+//! These kernels can handle 64-bit addresses, so everything may be above 4 GB.
+
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::Guid;
+
+use log::{debug, error};
+
+use super::fdt;
+use super::super::file::File;
+use super::super::mem::Allocation;
+use super::super::progress::Style;
+
+/// "ARM\x64"
+const MAGIC: u32 = 0x644d_5241;
+const MAGIC_OFFSET: usize = 0x38;
+const ALIGNMENT: usize = 2 * 1024 * 1024;
+
+/// the GUID of the device tree in the configuration table
+const DEVICE_TREE_GUID: Guid = Guid::from_values(
+    0xb1b6_21d5, 0xf19c, 0x41a5, 0x830b, 0xd915_2c69_aae0,
+);
+
+/// The relevant parts of the header.
+#[derive(Debug)]
+pub(super) struct Header {
+    text_offset: usize,
+    image_size: usize,
+}
+
+impl Header {
+    /// Check whether this is an `Image` and parse its header.
+    pub(super) fn parse(kernel: &[u8]) -> Option<Self> {
+        let field = |offset: usize| kernel.get(offset..offset + 8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+        let magic = kernel.get(MAGIC_OFFSET..MAGIC_OFFSET + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))?;
+        if magic != MAGIC {
+            return None
+        }
+        let text_offset = field(8)?.try_into().ok()?;
+        let image_size: usize = field(16)?.try_into().ok()?;
+        // Kernels older than Linux 3.17 don't tell us their size.
+        if image_size == 0 {
+            error!("this Image is too old, its size is unknown");
+            return None
+        }
+        Some(Self { text_offset, image_size })
+    }
+
+    /// Load the kernel and return the allocation and the entry address.
+    ///
+    /// (The kernel may also run at the start of the allocation,
+    /// but this would waste up to 2 MiB of memory.)
+    pub(super) fn load(
+        &self, kernel: &mut File, style: Style,
+    ) -> Result<(Allocation, usize), Status> {
+        let mut allocation = Allocation::new_anywhere(
+            ALIGNMENT + self.text_offset + self.image_size,
+        )?;
+        let start = allocation.as_ptr() as usize;
+        let entry_address = (start + ALIGNMENT - 1) / ALIGNMENT * ALIGNMENT + self.text_offset;
+        let offset = entry_address - start;
+        let buffer = &mut allocation.as_mut_slice()[offset..offset + self.image_size];
+        // read the kernel and zero the rest
+        let file_length = kernel.file_size().min(self.image_size);
+        kernel.read_at(0, &mut buffer[..file_length], style)?;
+        buffer[file_length..].fill(0);
+        debug!("loaded the Image to {entry_address:x}");
+        Ok((allocation, entry_address))
+    }
+}
+
+/// Find the device tree the firmware provides and copy it with the command line
+/// and the initrd set.
+///
+/// (The firmware may have placed it in boot services memory.)
+pub(super) fn device_tree(
+    systab: &SystemTable<Boot>, argv: Option<&str>, initrd: Option<&Allocation>,
+) -> Result<(Allocation, usize), Status> {
+    let address = systab.config_table().iter()
+        .find(|entry| entry.guid == DEVICE_TREE_GUID)
+        .ok_or_else(|| {
+            error!("the firmware doesn't provide a device tree");
+            Status::NOT_FOUND
+        })?
+        .address as *const u8;
+    // the size is the second field of the header (big-endian)
+    let size = u32::from_be(unsafe { (address as *const u32).add(1).read_unaligned() });
+    let tree = unsafe { core::slice::from_raw_parts(address, size.try_into().unwrap()) };
+    let mut properties = Vec::new();
+    if let Some(argv) = argv {
+        let mut value = argv.as_bytes().to_vec();
+        value.push(0);
+        properties.push(("bootargs", value));
+    }
+    if let Some(initrd) = initrd {
+        let start = initrd.final_address();
+        let end = start + initrd.len as u64;
+        properties.push(("linux,initrd-start", start.to_be_bytes().to_vec()));
+        properties.push(("linux,initrd-end", end.to_be_bytes().to_vec()));
+    }
+    let tree = fdt::set_chosen(tree, &properties).map_err(|msg| {
+        error!("failed to modify the device tree: {msg}");
+        Status::LOAD_ERROR
+    })?;
+    let size = tree.len();
+    let mut allocation = Allocation::new_anywhere(size)?;
+    allocation.as_mut_slice()[..size].copy_from_slice(&tree);
+    let address = allocation.as_ptr() as usize;
+    debug!("copied the device tree ({size} bytes) to {address:x}");
+    Ok((allocation, address))
+}
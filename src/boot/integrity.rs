@@ -0,0 +1,125 @@
+//! Last checks before jumping to the kernel
+//!
+//! After exiting boot services, the kernel has been moved to its final place,
+//! the memory map has been written and nothing is going to change anymore.
+//! If anything we pass to the kernel overlaps with something else at this point,
+//! the kernel would start with corrupted data, so we'd rather stop here.
+//!
+//! There's no logging and no allocating anymore, so the error is drawn
+//! directly to the framebuffer (and written to the first serial port on x86).
+
+use core::fmt::{Arguments, Display, Formatter, Write};
+
+use alloc::vec::Vec;
+
+use uefi::proto::console::gop::BltPixel;
+
+use super::super::font::Font;
+use super::super::mem::Range;
+use super::video::Screen;
+
+/// A region of memory the kernel gets.
+struct Region {
+    /// what it is (eg. "module")
+    kind: &'static str,
+    /// the index of the part or module (if there can be multiple ones)
+    index: Option<usize>,
+    range: Range,
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "{} {index} at {:x?}", self.kind, self.range),
+            None => write!(f, "{} at {:x?}", self.kind, self.range),
+        }
+    }
+}
+
+/// Everything the kernel gets, to be checked right before the jump.
+pub(super) struct Regions(Vec<Region>);
+
+impl Regions {
+    /// Make room for the regions.
+    ///
+    /// This has to be called before exiting boot services, as adding doesn't allocate.
+    pub(super) fn with_capacity(capacity: usize) -> Self {
+        Self(Vec::with_capacity(capacity))
+    }
+
+    /// Add a region. Empty ones are ignored.
+    pub(super) fn add(
+        &mut self, kind: &'static str, index: Option<usize>, start: u64, length: u64,
+    ) {
+        assert!(self.0.len() < self.0.capacity(), "there's no room for another region");
+        if length > 0 {
+            self.0.push(Region { kind, index, range: Range::new(start, length) });
+        }
+    }
+
+    /// Make sure that no two regions overlap.
+    ///
+    /// If they do, this shows an error and halts.
+    pub(super) fn check(&self, screen: Option<&Screen>) {
+        for (position, first) in self.0.iter().enumerate() {
+            if let Some(second) = self.0[position + 1..].iter()
+                .find(|r| r.range.overlaps(&first.range)) {
+                fail(screen, format_args!("{first} overlaps with {second}"));
+            }
+        }
+    }
+}
+
+/// A message that is formatted without allocating. (Anything too long is cut off.)
+struct Message {
+    buffer: [u8; 256],
+    length: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for chr in s.chars() {
+            if self.length + chr.len_utf8() > self.buffer.len() {
+                break
+            }
+            chr.encode_utf8(&mut self.buffer[self.length..]);
+            self.length += chr.len_utf8();
+        }
+        Ok(())
+    }
+}
+
+impl Message {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.length]).unwrap_or("")
+    }
+}
+
+/// Show an error after exiting boot services and halt.
+fn fail(screen: Option<&Screen>, args: Arguments) -> ! {
+    let mut message = Message { buffer: [0; 256], length: 0 };
+    let _ = write!(message, "towboot: refusing to boot, {args}");
+    if let Some(screen) = screen {
+        // This is safe because nothing else uses the framebuffer anymore.
+        let pixels = unsafe { core::slice::from_raw_parts_mut(
+            screen.address as *mut BltPixel, screen.stride * screen.height,
+        ) };
+        // red and blue may be swapped, so better stick with gray
+        pixels.fill(BltPixel::new(0x40, 0x40, 0x40));
+        Font::builtin().draw_text(
+            pixels, screen.stride, 16, 16, message.as_str(), BltPixel::new(0xff, 0xff, 0xff), 1,
+        );
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    write_to_serial(message.as_str());
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Write a line to the first serial port, assuming the firmware has set it up.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn write_to_serial(text: &str) {
+    super::super::port::write_serial(text.as_bytes());
+    super::super::port::write_serial(b"\r\n");
+}
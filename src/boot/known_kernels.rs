@@ -0,0 +1,61 @@
+//! A small database of kernels that are known to need certain quirks.
+//!
+//! Kernels are matched either by the checksum field of their Multiboot header
+//! or by their file name. The quirks of all matching kernels are applied
+//! automatically, unless this is disabled by setting `known_quirks = false`
+//! in the configuration.
+
+use alloc::collections::btree_set::BTreeSet;
+
+use log::info;
+
+use multiboot::header::Header;
+
+use super::super::config::Quirk;
+use super::super::file::matches_pattern;
+
+/// A kernel that needs some quirks.
+struct KnownKernel {
+    /// What this is, for the log.
+    description: &'static str,
+    /// The checksum field of the Multiboot header.
+    checksum: Option<u32>,
+    /// A pattern for the file name (without directories).
+    file_name: Option<&'static str>,
+    /// The quirks to apply.
+    quirks: &'static [Quirk],
+}
+
+impl KnownKernel {
+    /// Check whether this is the kernel at hand.
+    fn matches(&self, image: &str, checksum: Option<u32>) -> bool {
+        let file_name = image.rsplit('\\').next().unwrap_or(image);
+        self.checksum.map_or(false, |c| Some(c) == checksum)
+        || self.file_name.map_or(false, |p| matches_pattern(p, file_name))
+    }
+}
+
+const KNOWN_KERNELS: &[KnownKernel] = &[
+    KnownKernel {
+        description: "old Haiku loaders",
+        checksum: None,
+        file_name: Some("haiku_loader*"),
+        quirks: &[Quirk::ModulesBelow200Mb],
+    },
+];
+
+/// Get the quirks that should be applied to the given kernel.
+///
+/// `image` is the path of the kernel, `kernel` its content.
+pub(super) fn quirks_for(image: &str, kernel: &[u8], header: &Header) -> BTreeSet<Quirk> {
+    // The checksum is the third field of the header.
+    let checksum_start: usize = header.header_start as usize + 8;
+    let checksum = kernel.get(checksum_start..checksum_start + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+    let mut quirks = BTreeSet::new();
+    for known in KNOWN_KERNELS.iter().filter(|k| k.matches(image, checksum)) {
+        info!("this looks like {}, applying {:?}", known.description, known.quirks);
+        quirks.extend(known.quirks.iter().copied());
+    }
+    quirks
+}
@@ -5,37 +5,79 @@
 use alloc::{
     collections::btree_set::BTreeSet,
     format,
+    string::String,
     vec,
     vec::Vec,
 };
 
-use core::arch::asm;
 use uefi::prelude::*;
 use uefi::proto::console::gop::GraphicsOutput;
 use uefi::proto::media::file::Directory;
 
-use log::{debug, info, error};
+use log::{debug, info, error, warn};
 
 use multiboot::header::{Header, MultibootAddresses};
 use multiboot::information::{
-    MemoryEntry, Module, Multiboot, MultibootInfo, SIGNATURE_EAX, SymbolType
+    MemoryEntry, Module, Multiboot, MultibootInfo, SymbolType
 };
 
 use goblin::elf::Elf;
 
-use super::config::{Entry, Quirk};
-use super::file::File;
-use super::mem::{Allocation, MultibootAllocator};
+use super::args;
+use super::compression;
+use super::config::{Config, Entry, Quirk};
+use super::cpio;
+use super::file::{self, File};
+use super::logger;
+use super::panic;
+use super::mem::{self, Allocation, MultibootAllocator, Range};
+use super::progress;
+use super::vars;
 
+mod arch;
+pub(crate) mod device;
+mod dump;
 mod elf;
-mod video;
+#[cfg(any(target_arch = "aarch64", test))]
+mod fdt;
+#[cfg(target_arch = "aarch64")]
+mod image;
+mod integrity;
+mod known_kernels;
+mod parse;
+mod placement;
+mod random;
+pub(crate) mod timing;
+pub(crate) mod video;
 
+use arch::Handoff;
+pub(crate) use device::find_disks;
 use elf::OurElfLoader;
+use integrity::Regions;
+use placement::Placement;
+use timing::Step;
+use video::Screen;
+
+/// The Multiboot header has to be in the first 8 KiB of the kernel.
+const MULTIBOOT_SEARCH: usize = 8192;
+
+/// Bit 0 of the Multiboot header's flags: modules have to be page-aligned.
+const MULTIBOOT_PAGE_ALIGN: u32 = 1 << 0;
+
+/// How many entries the memory map may grow by before we've exited boot services.
+const MMAP_SPARE_ENTRIES: usize = 8;
+/// How often to try to exit boot services (with a larger buffer each time).
+const EXIT_BOOT_SERVICES_ATTEMPTS: usize = 4;
+/// The command line of the module containing our log.
+const LOG_MODULE: &str = "towboot.log";
 
 enum Addresses {
     Multiboot(MultibootAddresses),
     /// the entry address
     Elf(usize),
+    /// the entry address and the address of the device tree
+    #[cfg(target_arch = "aarch64")]
+    Image { entry_address: usize, device_tree: usize },
 }
 
 /// A kernel loaded into memory
@@ -46,47 +88,45 @@ struct LoadedKernel {
 }
 
 impl LoadedKernel {
-    /// Load a kernel from a vector.
+    /// Load a kernel from a file.
     /// This requires that the Multiboot header has already been parsed.
     fn new(
-        kernel_vec: Vec<u8>, header: &Header, quirks: &BTreeSet<Quirk>,
+        mut kernel_file: File, header: &Header, quirks: &BTreeSet<Quirk>, style: progress::Style,
     ) -> Result<Self, Status> {
         match (header.get_addresses(), quirks.contains(&Quirk::ForceElf)) {
-            (Some(addr), false) => LoadedKernel::new_multiboot(kernel_vec, addr, header.header_start),
-            _ => LoadedKernel::new_elf(kernel_vec),
+            (Some(addr), false) => LoadedKernel::new_multiboot(
+                &mut kernel_file, addr, header.header_start, quirks, style,
+            ),
+            // ELF kernels are parsed as a whole, so they have to be read completely first.
+            _ => LoadedKernel::new_elf(kernel_file.try_into_vec(style)?, quirks),
         }
     }
     
     /// Load a kernel which has its addresses specified inside the Multiboot header.
+    ///
+    /// The file is read directly into the kernel's destination (if it's free).
     fn new_multiboot(
-        kernel_vec: Vec<u8>, addresses: MultibootAddresses, header_start: u32
+        kernel_file: &mut File, addresses: MultibootAddresses, header_start: u32,
+        quirks: &BTreeSet<Quirk>, style: progress::Style,
     ) -> Result<Self, Status> {
         // TODO: Add support for AOut symbols? Do we really know this binary is AOut at this point?
         
-        // Try to allocate the memory where to load the kernel and move the kernel there.
-        // In the worst case we might have blocked the destination by loading the file there,
-        // but `move_to_where_it_should_be` should fix this later.
-        info!("moving the kernel to its desired location...");
-        let load_offset = addresses.compute_load_offset(header_start);
-        // allocate
-        let kernel_length: usize = {
-            if addresses.bss_end_address == 0 {addresses.load_end_address - addresses.load_address}
-            else {addresses.bss_end_address - addresses.load_address}
-        }.try_into().unwrap();
+        let parse::MultibootLayout { load_offset, file_length, kernel_length } =
+            parse::multiboot_layout(&addresses, header_start, kernel_file.file_size())
+            .map_err(|msg| {
+                error!("{msg}");
+                Status::LOAD_ERROR
+            })?;
+        // Try to allocate the memory where to load the kernel.
+        // If it's in use, the kernel is loaded somewhere else
+        // and `move_to_where_it_should_be` moves it later.
         let mut allocation = Allocation::new_at(
-            addresses.load_address.try_into().unwrap(), kernel_length
+            addresses.load_address.try_into().unwrap(), kernel_length, quirks,
         )?;
         let kernel_buf = allocation.as_mut_slice();
-        // copy from beginning of text to end of data segment and fill the rest with zeroes
-        kernel_buf.iter_mut().zip(
-            kernel_vec.iter()
-            .skip(load_offset.try_into().unwrap())
-            .take((addresses.load_end_address - addresses.load_address).try_into().unwrap())
-            .chain(core::iter::repeat(&0))
-        )
-        .for_each(|(dst,src)| *dst = *src);
-        // drop the old vector
-        core::mem::drop(kernel_vec);
+        // read from beginning of text to end of data segment and zero the BSS
+        kernel_file.read_at(load_offset, &mut kernel_buf[..file_length], style)?;
+        kernel_buf[file_length..kernel_length].fill(0);
         
         Ok(Self {
             allocations: vec![allocation],
@@ -96,17 +136,17 @@ impl LoadedKernel {
     }
     
     /// Load a kernel which uses ELF semantics.
-    fn new_elf(kernel_vec: Vec<u8>) -> Result<Self, Status> {
+    fn new_elf(kernel_vec: Vec<u8>, quirks: &BTreeSet<Quirk>) -> Result<Self, Status> {
         let mut binary = Elf::parse(kernel_vec.as_slice()).map_err(|msg| {
             error!("failed to parse ELF structure of kernel: {msg}");
             Status::LOAD_ERROR
         })?;
-        let mut loader = OurElfLoader::new(binary.entry);
+        let mut loader = OurElfLoader::new(binary.entry, quirks);
         loader.load_elf(&binary, kernel_vec.as_slice()).map_err(|msg| {
             error!("failed to load kernel: {msg}");
             Status::LOAD_ERROR
         })?;
-        let symbols = Some(elf::symbols(&mut binary, kernel_vec.as_slice()));
+        let symbols = parse::symbols(&mut binary, kernel_vec.as_slice());
         let entry_point = loader.entry_point();
         Ok(Self{
             allocations: loader.into(),
@@ -115,6 +155,24 @@ impl LoadedKernel {
         })
     }
     
+    /// Load a kernel in the arm64 `Image` format.
+    ///
+    /// It also needs the device tree (with the command line and the initrd),
+    /// so this is loaded, too.
+    #[cfg(target_arch = "aarch64")]
+    fn new_image(
+        kernel_file: &mut File, header: &image::Header, systab: &SystemTable<Boot>,
+        argv: Option<&str>, initrd: Option<&Allocation>, style: progress::Style,
+    ) -> Result<Self, Status> {
+        let (kernel, entry_address) = header.load(kernel_file, style)?;
+        let (device_tree_allocation, device_tree) = image::device_tree(systab, argv, initrd)?;
+        Ok(Self {
+            allocations: vec![kernel, device_tree_allocation],
+            addresses: Addresses::Image { entry_address, device_tree },
+            symbols: None,
+        })
+    }
+    
     /// Get the symbols struct.
     /// This is needed for the Multiboot Information struct.
     fn symbols_struct(&self) -> Option<&SymbolType> {
@@ -125,21 +183,31 @@ impl LoadedKernel {
 /// Prepare information for the kernel.
 fn prepare_multiboot_information(
     entry: &Entry, modules: &[Allocation], symbols: Option<SymbolType>,
-    graphics_output: &mut GraphicsOutput
+    graphics_output: Option<&mut GraphicsOutput>
 ) -> (MultibootInfo, MultibootAllocator) {
+    // The strings are copied into memory from the allocator, which is kept until the jump.
+    let argv = entry.argv.as_deref().map(args::expand);
+    // The log is the last module (if it's passed at all).
+    let module_argvs: Vec<Option<String>> = entry.modules.iter()
+        .map(|m| m.argv.as_deref().map(args::expand))
+        .chain(core::iter::once(Some(String::from(LOG_MODULE))))
+        .collect();
     let mut info = MultibootInfo::default();
     let mut allocator = MultibootAllocator::new();
     let mut multiboot = Multiboot::from_ref(&mut info, &mut allocator);
     
     // We don't have much information about the partition we loaded the kernel from.
-    // There's the UEFI Handle, but the kernel probably won't understand that.
+    // There's the UEFI Handle, but the kernel probably won't understand that,
+    // so this is only a guess at what the BIOS would have said.
+    multiboot.set_boot_device(device::boot_device());
     
-    multiboot.set_command_line(entry.argv.as_deref());
-    let mb_modules: Vec<Module> = modules.iter().zip(entry.modules.iter()).map(|(module, module_entry)| {
+    multiboot.set_command_line(argv.as_deref());
+    let mb_modules: Vec<Module> = modules.iter().zip(module_argvs.iter()).map(|(module, argv)| {
+        // modules with `load_at` may still be moved there
         Module::new(
-            module.as_ptr() as u64,
-            unsafe { module.as_ptr().offset(module.len.try_into().unwrap()) as u64 },
-            module_entry.argv.as_deref()
+            module.final_address(),
+            module.final_address() + module.len as u64,
+            argv.as_deref()
         )
     }).collect();
     multiboot.set_modules(Some(&mb_modules));
@@ -150,10 +218,11 @@ fn prepare_multiboot_information(
     // TODO: Do we really need to do this? Our allocations don't matter to the kernel.
     // TODO: But do they affect the firmware's allocations?
     
-    // We can't ask the BIOS for information about the drives.
-    // (We could ask the firmware and convert it to the legacy BIOS format, however.)
+    // We can't ask the BIOS for information about the drives,
+    // so the disks the firmware knows are converted to the legacy BIOS format.
+    // (This happens below, as the multiboot crate doesn't support it.)
     
-    // There is no BIOS config table.
+    // There is no BIOS config table. (UEFI doesn't have an equivalent.)
     
     multiboot.set_boot_loader_name(Some(&format!(
         "{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")
@@ -163,90 +232,462 @@ fn prepare_multiboot_information(
     
     // There is no VBE information.
     
-    video::prepare_information(&mut multiboot, graphics_output);
+    if let Some(graphics_output) = graphics_output {
+        video::prepare_information(&mut multiboot, graphics_output);
+    } else {
+        // make sure the kernel doesn't think there's a framebuffer
+        multiboot.set_framebuffer_table(None);
+    }
+    
+    device::prepare_information(&mut info, &mut allocator);
     
     (info, allocator)
 }
 
+/// Get the flags of the Multiboot header.
+///
+/// `kernel_start` has to contain the header.
+fn header_flags(kernel_start: &[u8], header: &Header) -> u32 {
+    // The flags are the second field of the header.
+    let flags_start = header.header_start as usize + 4;
+    kernel_start.get(flags_start..flags_start + 4)
+        .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// Make sure that all modules start at a page boundary if the kernel wants that.
+///
+/// Modules are always allocated as whole pages, so this should never fail.
+/// But the kernel relies on it if it has set `MULTIBOOT_PAGE_ALIGN` in the flags
+/// of its header, so check it anyway.
+/// (Multiboot has no way to tell the kernel about the alignment, it just expects it.)
+fn check_page_alignment(header_flags: u32, modules: &[Allocation]) -> Result<(), Status> {
+    if header_flags & MULTIBOOT_PAGE_ALIGN == 0 {
+        return Ok(())
+    }
+    for (index, module) in modules.iter().enumerate() {
+        if module.final_address() % mem::PAGE_SIZE as u64 != 0 {
+            error!(
+                "the kernel wants page-aligned modules, but module {index} is at {:#x}",
+                module.final_address(),
+            );
+            return Err(Status::LOAD_ERROR)
+        }
+    }
+    debug!("all modules are page-aligned as requested");
+    Ok(())
+}
+
+/// Allocate the buffers for the memory map (ours and the one for the kernel).
+///
+/// Leave some room at the end, the memory map may grow until we've exited
+/// boot services. (Allocating the buffers alone may add entries.)
+/// The kernel's memory map gets one entry per descriptor (at most),
+/// plus the reserved regions, which may each split another entry.
+fn allocate_memory_map_buffers(
+    systab: &SystemTable<Boot>, reserved: &[Range],
+) -> (Vec<u8>, Vec<MemoryEntry>) {
+    let mut mmap_vec = Vec::<u8>::new();
+    let mut mb_mmap_vec = Vec::<MemoryEntry>::new();
+    let mmap_size = systab.boot_services().memory_map_size();
+    let entries = mmap_size.map_size / mmap_size.entry_size + MMAP_SPARE_ENTRIES;
+    debug!("allocating room for {entries} memory map entries");
+    mmap_vec.resize(entries * mmap_size.entry_size, 0);
+    mb_mmap_vec.resize(entries + 2 * reserved.len(), MemoryEntry::default());
+    // They grow if exiting boot services has to be retried (see `PreparedEntry::boot`).
+    // They may not move then (the placement has been planned already), so make room now.
+    let retries = EXIT_BOOT_SERVICES_ATTEMPTS - 1;
+    mmap_vec.reserve_exact(retries * MMAP_SPARE_ENTRIES * mmap_size.entry_size);
+    mb_mmap_vec.reserve_exact(retries * MMAP_SPARE_ENTRIES);
+    (mmap_vec, mb_mmap_vec)
+}
+
+/// Check whether an entry looks bootable without actually loading it.
+///
+/// This makes sure that the kernel and all modules exist and that the kernel
+/// has a valid Multiboot header. Problems are logged as warnings.
+pub(crate) fn check(key: &str, entry: &Entry, volume: &mut Directory) -> bool {
+    let entry = match resolve_patterns(entry, volume) {
+        Ok(entry) => entry,
+        Err(_) => {
+            warn!("{key}: a pattern in a path doesn't match any file");
+            return false
+        },
+    };
+    let kernel_start = match File::read_start(&entry.image, volume, MULTIBOOT_SEARCH) {
+        Some(start) => start,
+        None => {
+            warn!("{key}: the kernel '{}' is missing", entry.image);
+            return false
+        },
+    };
+    #[cfg(target_arch = "aarch64")]
+    let is_image = image::Header::parse(kernel_start.as_slice()).is_some();
+    #[cfg(not(target_arch = "aarch64"))]
+    let is_image = false;
+    if Header::from_slice(kernel_start.as_slice()).is_none() && !is_image {
+        warn!("{key}: the kernel '{}' has no valid Multiboot header", entry.image);
+        return false
+    }
+    if is_image && entry.modules.len() > 1 {
+        warn!("{key}: Image kernels take only one module (the initrd)");
+        return false
+    }
+    for image in entry.modules.iter().flat_map(|m| &m.image) {
+        if !File::exists(image, volume) {
+            warn!("{key}: the module '{image}' is missing");
+            return false
+        }
+    }
+    true
+}
+
+/// Find out whether the video mode an entry wants is unavailable (without loading it).
+///
+/// This returns the mode that would be used instead.
+pub(crate) fn video_substitution(
+    entry: &Entry, config: &Config, volume: &mut Directory,
+) -> Option<video::Substitution> {
+    let entry = resolve_patterns(entry, volume).ok()?;
+    let kernel_start = File::read_start(&entry.image, volume, MULTIBOOT_SEARCH)?;
+    let header = Header::from_slice(kernel_start.as_slice())?;
+    let mut quirks = entry.quirks.clone();
+    if config.known_quirks.unwrap_or(true) {
+        quirks.extend(known_kernels::quirks_for(&entry.image, &kernel_start, &header));
+    }
+    if quirks.contains(&Quirk::NoFramebuffer) {
+        return None
+    }
+    video::substitution(&header, entry.resolution, &quirks)
+}
+
+/// Replace the patterns in the paths of an entry with the matching files.
+///
+/// For the kernel, the file with the highest version is used.
+/// A module whose image is a pattern becomes one module per matching file
+/// (each with the same command line); in a list of images (which are concatenated),
+/// a pattern is replaced by all matching files.
+fn resolve_patterns(entry: &Entry, volume: &mut Directory) -> Result<Entry, Status> {
+    let mut resolved = entry.clone();
+    resolved.image = file::resolve_pattern(&entry.image, volume)?.pop().unwrap();
+    if resolved.image != entry.image {
+        info!("using '{}' for '{}'", resolved.image, entry.image);
+    }
+    resolved.modules.clear();
+    for module in &entry.modules {
+        match module.image.as_slice() {
+            [image] if file::is_pattern(image) => {
+                for image in file::resolve_pattern(image, volume)? {
+                    let mut resolved_module = module.clone();
+                    resolved_module.image = vec![image];
+                    resolved.modules.push(resolved_module);
+                }
+            },
+            images => {
+                let mut resolved_module = module.clone();
+                resolved_module.image.clear();
+                for image in images {
+                    resolved_module.image.extend(file::resolve_pattern(image, volume)?);
+                }
+                resolved.modules.push(resolved_module);
+            },
+        }
+    }
+    Ok(resolved)
+}
+
+/// Load all modules of an entry, fail completely if one fails to load.
+fn load_modules(
+    entry: &Entry, volume: &mut Directory, systab: &SystemTable<Boot>,
+    quirks: &BTreeSet<Quirk>, style: progress::Style,
+) -> Result<Vec<Allocation>, Status> {
+    let start = timing::now();
+    // Open them all first, so that a missing one is noticed before reading the others.
+    // A module may consist of several files, which are concatenated.
+    let mut module_files: Vec<Vec<File>> = entry.modules.iter()
+        .map(|module| module.image.iter()
+            .map(|image| File::open(image, volume))
+            .collect::<Result<Vec<_>, _>>()
+        )
+        .collect::<Result<Vec<_>, _>>()?;
+    // The format helps when a kernel can't read them (or towboot can't decompress them).
+    for (file, image) in module_files.iter_mut().flatten()
+        .zip(entry.modules.iter().flat_map(|m| &m.image)) {
+        info!("'{image}': {}", compression::Info::of(file)?);
+    }
+    let module_parts = module_files.into_iter().zip(&entry.modules)
+        .map(|(files, module)| {
+            if files.is_empty() && module.directory.is_none() && module.random_seed.is_none() {
+                error!("a module needs an image, a directory or a random seed");
+                return Err(Status::LOAD_ERROR)
+            }
+            // Directories are packed into an archive after the files,
+            // random bytes come last.
+            let mut appended = match &module.directory {
+                Some(directory) => cpio::archive(directory, volume)?,
+                None => Vec::new(),
+            };
+            if let Some(length) = module.random_seed {
+                appended.extend(random::seed(length, systab));
+            }
+            Ok(file::ModuleParts {
+                files, appended, load_at: module.load_at, decompress: module.decompress,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // just always use whole pages, that's easier for us
+    // (The files are read at the same time, see `file::read_concurrently`.)
+    let modules_vec = file::read_into_allocations(module_parts, quirks, style)?;
+    timing::add(Step::Modules, start);
+    info!("loaded {} modules", modules_vec.len());
+    for (index, module) in modules_vec.iter().enumerate() {
+        match module.should_be_at() {
+            Some(address) => debug!(
+                "loaded module {index} to {:?}, moving it to {address:#x} later",
+                module.as_ptr(),
+            ),
+            None => debug!("loaded module {} to {:?}", index, module.as_ptr()),
+        }
+    }
+    Ok(modules_vec)
+}
+
 pub(crate) struct PreparedEntry<'a> {
     entry: &'a Entry,
+    /// the quirks of the entry and the ones of known kernels
+    quirks: BTreeSet<Quirk>,
     loaded_kernel: LoadedKernel,
     multiboot_information: MultibootInfo,
     multiboot_allocator: MultibootAllocator,
     modules_vec: Vec<Allocation>,
+    /// the code that brings the machine into the state the kernel expects
+    handoff: Handoff,
+    /// the buffer for the memory map
+    mmap_vec: Vec<u8>,
+    /// the buffer for the memory map that is passed to the kernel
+    mb_mmap_vec: Vec<MemoryEntry>,
+    /// the order to move the kernel in
+    placement: Placement,
+    /// what to check for overlaps before the jump (allocated before planning)
+    regions: Regions,
+    /// the memory the user has reserved for the kernel
+    reserved_memory: Vec<Range>,
+    /// where to show errors after exiting boot services
+    screen: Option<Screen>,
 }
 
 impl<'a> PreparedEntry<'a> {
     /// Prepare an entry for boot.
     ///
     /// What this means:
-    /// 1. load the kernel into memory
-    /// 2. try to parse the Multiboot information
-    /// 3. move the kernel to where it wants to be
+    /// 1. read the beginning of the kernel
+    /// 2. try to parse the Multiboot information (and look for known quirks)
+    /// 3. load the kernel to where it wants to be
     /// 4. load the modules
-    /// 5. make the framebuffer ready
+    /// 5. make the framebuffer ready (unless the `NoFramebuffer` quirk is set)
     /// 6. create the Multiboot information for the kernel
+    /// 7. plan how to move the kernel (if needed)
+    /// 8. set the UEFI variables of the entry
     ///
     /// Return a `PreparedEntry` which can be used to actually boot.
     /// This is non-destructive and will always return.
     pub(crate) fn new(
-        entry: &'a Entry, volume: &mut Directory, systab: &SystemTable<Boot>
+        entry: &'a Entry, config: &Config, volume: &mut Directory, systab: &SystemTable<Boot>
     ) -> Result<PreparedEntry<'a>, Status> {
-        let kernel_vec: Vec<u8> = File::open(&entry.image, volume)?.try_into()?;
-        let header = Header::from_slice(kernel_vec.as_slice()).ok_or_else(|| {
-            error!("invalid Multiboot header");
-            Status::LOAD_ERROR
-        })?;
-        debug!("loaded kernel {:?} to {:?}", header, kernel_vec.as_ptr());
-        let loaded_kernel = LoadedKernel::new(kernel_vec, &header, &entry.quirks)?;
+        let style = progress::Style::from_config(config);
+        // forget about the kernel of the previous attempt
+        panic::set_kernel(&[]);
+        // Patterns in the paths are resolved now, the menu shows them as they are.
+        let original = entry;
+        let resolved = resolve_patterns(entry, volume)?;
+        let entry = &resolved;
+        // Only read the header for now, the rest is read to where it's needed.
+        let mut kernel_file = File::open(&entry.image, volume)?;
+        let kernel_start = kernel_file.read_beginning(MULTIBOOT_SEARCH)?;
+        let header = match Header::from_slice(kernel_start.as_slice()) {
+            Some(header) => header,
+            #[cfg(target_arch = "aarch64")]
+            None => match image::Header::parse(kernel_start.as_slice()) {
+                Some(header) => return Self::new_image(
+                    original, entry, &mut kernel_file, &header, volume, systab, style,
+                ),
+                None => {
+                    error!("neither a Multiboot header nor an Image");
+                    return Err(Status::LOAD_ERROR)
+                },
+            },
+            #[cfg(not(target_arch = "aarch64"))]
+            None => {
+                error!("invalid Multiboot header");
+                return Err(Status::LOAD_ERROR)
+            },
+        };
+        debug!("kernel header: {header:?}");
+        let mut quirks = entry.quirks.clone();
+        if config.known_quirks.unwrap_or(true) {
+            quirks.extend(known_kernels::quirks_for(&entry.image, &kernel_start, &header));
+        }
+        // The Multiboot information only has room for 32-bit addresses of modules.
+        if quirks.contains(&Quirk::ModulesAbove4Gb) {
+            error!("Multiboot kernels can't use modules above 4 GB, remove ModulesAbove4Gb");
+            return Err(Status::LOAD_ERROR)
+        }
+        let start = timing::now();
+        let loaded_kernel = LoadedKernel::new(kernel_file, &header, &quirks, style)?;
+        timing::add(Step::Kernel, start);
+        panic::set_kernel(&loaded_kernel.allocations);
         info!("kernel is loaded and bootable");
         
-        // Load all modules, fail completely if one fails to load.
-        // just always use whole pages, that's easier for us
-        let modules_vec: Vec<Allocation> = entry.modules.iter().map(|module|
-            File::open(&module.image, volume)
-            .and_then(|f| f.try_into_allocation(&entry.quirks))
-        ).collect::<Result<Vec<_>, _>>()?;
-        info!("loaded {} modules", modules_vec.len());
-        for (index, module) in modules_vec.iter().enumerate() {
-            debug!("loaded module {} to {:?}", index, module.as_ptr());
+        let mut modules_vec = load_modules(entry, volume, systab, &quirks, style)?;
+        // The log is taken as late as possible, so that it contains the loading, too.
+        if let Some(log) = logger::for_kernel() {
+            let mut allocation = Allocation::new_under_4gb(log.len(), &quirks)?;
+            allocation.as_mut_slice().copy_from_slice(log.as_bytes());
+            debug!("passing {} bytes of the log as module {}", log.len(), modules_vec.len());
+            modules_vec.push(allocation);
         }
+        check_page_alignment(header_flags(&kernel_start, &header), &modules_vec)?;
         
-        let graphics_output = video::setup_video(&header, systab, &entry.quirks)?;
+        let mut graphics_output = if quirks.contains(&Quirk::NoFramebuffer) {
+            info!("not touching the video as requested");
+            None
+        } else {
+            let start = timing::now();
+            let video = video::setup_video(&header, entry.resolution, systab, &quirks)?;
+            timing::add(Step::Video, start);
+            Some(video)
+        };
+        let screen = graphics_output.as_deref_mut().and_then(video::screen);
         
         let (multiboot_information, multiboot_allocator) = prepare_multiboot_information(
             entry, &modules_vec, loaded_kernel.symbols_struct().copied(),
             graphics_output,
         );
         
+        let handoff = Handoff::new()?;
+        
+        // Everything is allocated now, so we can check whether the kernel can be moved.
+        let reserved_memory = mem::reserved_ranges(config);
+        let (mmap_vec, mb_mmap_vec) = allocate_memory_map_buffers(systab, &reserved_memory);
+        // the kernel's parts, the modules, the Multiboot information (and what it points to)
+        // and the memory map
+        let regions = Regions::with_capacity(
+            loaded_kernel.allocations.len() + modules_vec.len()
+            + multiboot_allocator.ranges().count() + 2,
+        );
+        let placement = Placement::plan(&loaded_kernel.allocations, &modules_vec)?;
+        // This is the last step, so that nothing else can fail afterwards.
+        vars::apply(&entry.set_vars)?;
+        
         Ok(PreparedEntry {
-            entry, loaded_kernel, multiboot_information,
-            multiboot_allocator, modules_vec,
+            entry: original, quirks, loaded_kernel, multiboot_information,
+            multiboot_allocator, modules_vec, handoff, mmap_vec, mb_mmap_vec, placement,
+            regions, reserved_memory, screen,
+        })
+    }
+    
+    /// Prepare an entry with a kernel in the arm64 `Image` format.
+    ///
+    /// These kernels get their command line and at most one module (the initrd)
+    /// through the device tree (see the `image` module)
+    /// and don't care about the Multiboot information.
+    #[cfg(target_arch = "aarch64")]
+    fn new_image(
+        original: &'a Entry, entry: &Entry, kernel_file: &mut File, header: &image::Header,
+        volume: &mut Directory, systab: &SystemTable<Boot>, style: progress::Style,
+    ) -> Result<PreparedEntry<'a>, Status> {
+        debug!("Image header: {header:?}");
+        if entry.modules.len() > 1 {
+            error!("Image kernels take only one module (the initrd)");
+            return Err(Status::LOAD_ERROR)
+        }
+        if entry.modules.iter().any(|m| m.argv.is_some()) {
+            warn!("the initrd of an Image kernel doesn't get a command line, ignoring it");
+        }
+        let quirks = entry.quirks.clone();
+        // The device tree points to the initrd, so that is loaded first.
+        let modules_vec = load_modules(entry, volume, systab, &quirks, style)?;
+        let argv = entry.argv.as_deref().map(args::expand);
+        let start = timing::now();
+        let loaded_kernel = LoadedKernel::new_image(
+            kernel_file, header, systab, argv.as_deref(), modules_vec.first(), style,
+        )?;
+        timing::add(Step::Kernel, start);
+        panic::set_kernel(&loaded_kernel.allocations);
+        info!("kernel is loaded and bootable");
+        let handoff = Handoff::new()?;
+        // the kernel doesn't get a memory map from us
+        let reserved_memory = Vec::new();
+        let (mmap_vec, mb_mmap_vec) = allocate_memory_map_buffers(systab, &reserved_memory);
+        // the kernel's parts, the initrd and the memory map (the rest is empty)
+        let regions = Regions::with_capacity(
+            loaded_kernel.allocations.len() + modules_vec.len() + 2,
+        );
+        let placement = Placement::plan(&loaded_kernel.allocations, &modules_vec)?;
+        vars::apply(&entry.set_vars)?;
+        Ok(PreparedEntry {
+            entry: original, quirks, loaded_kernel,
+            multiboot_information: MultibootInfo::default(),
+            multiboot_allocator: MultibootAllocator::new(),
+            modules_vec, handoff, mmap_vec, mb_mmap_vec, placement, regions,
+            reserved_memory, screen: None,
         })
     }
     
     /// Actually boot an entry.
     ///
     /// What this means:
-    /// 1. exit `BootServices`
-    /// 2. pass the memory map to the kernel
-    /// 3. copy the kernel to its desired location (if needed)
-    /// 4. bring the machine in the correct state
-    /// 5. jump!
+    /// 1. park the other processors
+    /// 2. exit `BootServices`
+    /// 3. pass the memory map to the kernel
+    /// 4. copy the kernel to its desired location (if needed)
+    /// 5. check that nothing the kernel gets overlaps (see the `integrity` module)
+    /// 6. bring the machine in the correct state
+    /// 7. jump!
     ///
     /// This function won't return.
-    pub(crate) fn boot(mut self, image: Handle, systab: SystemTable<Boot>) {
-        // allocate memory for the memory map
-        // also, keep a bit of room
+    pub(crate) fn boot(mut self, image: Handle, mut systab: SystemTable<Boot>) {
+        // This allocates memory, but the buffers for the memory map have some room left.
+        panic::set_multiboot_information(&self.multiboot_information as *const _ as u64);
+        if self.quirks.contains(&Quirk::DumpMultibootInformation) {
+            dump::multiboot_information(&self.multiboot_information);
+            // errors have already been logged
+            if let Ok(entries) = mem::preview_information(&self.reserved_memory, &self.quirks) {
+                dump::memory_map(&entries);
+            }
+        }
+        arch::park_application_processors(&systab);
         info!("exiting boot services...");
-        let mut mmap_vec = Vec::<u8>::new();
-        let mut mb_mmap_vec = Vec::<MemoryEntry>::new();
-        // Leave a bit of room at the end, we only have one chance.
-        let estimated_size = systab.boot_services().memory_map_size().map_size + 100;
-        mmap_vec.resize(estimated_size, 0);
-        mb_mmap_vec.resize(estimated_size, MemoryEntry::default());
-        let (_systab, mmap_iter) = systab.exit_boot_services(image, mmap_vec.as_mut_slice())
-        .expect("failed to exit boot services");
+        // Logging doesn't work without boot services.
+        logger::disable();
+        // the buffers have been allocated while preparing
+        let mut mmap_vec = core::mem::take(&mut self.mmap_vec);
+        let mut mb_mmap_vec = core::mem::take(&mut self.mb_mmap_vec);
+        let spare_size = MMAP_SPARE_ENTRIES * systab.boot_services().memory_map_size().entry_size;
+        let mut attempts = 1;
+        let (_systab, mmap_iter) = loop {
+            // If exiting fails, we may still allocate memory (but nothing else).
+            // This needs a system table, though.
+            let systab_for_retry = unsafe { systab.unsafe_clone() };
+            // This works around a limitation of the borrow checker (see Rust bug 51526).
+            let mmap_buf = unsafe { &mut *(mmap_vec.as_mut_slice() as *mut [u8]) };
+            match systab.exit_boot_services(image, mmap_buf) {
+                Ok(result) => break result,
+                // `exit_boot_services` already retries if the memory map changed in between,
+                // but it may have grown too large for our buffer.
+                Err(e) if e.status() == Status::BUFFER_TOO_SMALL
+                && attempts < EXIT_BOOT_SERVICES_ATTEMPTS => {
+                    systab = systab_for_retry;
+                    // This doesn't allocate, the room has been reserved while preparing.
+                    mmap_vec.resize(mmap_vec.len() + spare_size, 0);
+                    mb_mmap_vec.resize(
+                        mb_mmap_vec.len() + MMAP_SPARE_ENTRIES, MemoryEntry::default(),
+                    );
+                    attempts += 1;
+                },
+                // Logging might not work anymore, so there's not much we can do.
+                Err(e) => panic!("failed to exit boot services: {e:?}"),
+            }
+        };
         // now, write! won't work anymore. Also, we can't allocate any memory.
         
         // Passing the memory map has to happen here,
@@ -254,16 +695,37 @@ impl<'a> PreparedEntry<'a> {
         let mut multiboot = Multiboot::from_ref(
             &mut self.multiboot_information, &mut self.multiboot_allocator
         );
-        let mb_mmap = super::mem::prepare_information(
-            &mut multiboot, mmap_iter, mb_mmap_vec.leak()
+        let mb_mmap = mem::prepare_information(
+            &mut multiboot, mmap_iter, mb_mmap_vec.leak(), &self.reserved_memory, &self.quirks,
         );
         
-        for allocation in &mut self.loaded_kernel.allocations {
-            // It could be possible that we failed to allocate memory for the kernel in the correct
-            // place before. Just copy it now to where is belongs.
-            // This is *really* unsafe, please see the documentation comment for details.
-            unsafe { allocation.move_to_where_it_should_be(mb_mmap) };
+        // It could be possible that we failed to allocate memory for the kernel (or modules)
+        // in the correct place before. Just copy it now to where is belongs
+        // (in the order we've planned).
+        // This is *really* unsafe, please see the documentation comment for details.
+        unsafe { self.placement.execute(
+            &mut self.loaded_kernel.allocations, &mut self.modules_vec, mb_mmap,
+        ) };
+        
+        // Everything is where the kernel is going to find it now, so make sure it's intact.
+        for (index, part) in self.loaded_kernel.allocations.iter().enumerate() {
+            self.regions.add("kernel part", Some(index), part.as_ptr() as u64, part.len as u64);
+        }
+        for (index, module) in self.modules_vec.iter().enumerate() {
+            self.regions.add("module", Some(index), module.as_ptr() as u64, module.len as u64);
         }
+        self.regions.add(
+            "Multiboot information", None, &self.multiboot_information as *const _ as u64,
+            core::mem::size_of::<MultibootInfo>() as u64,
+        );
+        // eg. the command lines
+        for (index, range) in self.multiboot_allocator.ranges().enumerate() {
+            self.regions.add("Multiboot data", Some(index), range.start, range.end - range.start);
+        }
+        self.regions.add(
+            "memory map", None, mb_mmap.as_ptr() as u64, core::mem::size_of_val(mb_mmap) as u64,
+        );
+        self.regions.check(self.screen.as_ref());
         // The kernel will need its code and data, so make sure it stays around indefinitely.
         core::mem::forget(self.loaded_kernel.allocations);
         // The kernel is going to need the modules, so make sure they stay.
@@ -271,75 +733,86 @@ impl<'a> PreparedEntry<'a> {
         // The kernel is going to need the section headers and symbols.
         core::mem::forget(self.loaded_kernel.symbols);
         
+        if self.quirks.contains(&Quirk::MaskInterrupts) {
+            unsafe { arch::quiesce() };
+        }
+        
         let entry_address = match &self.loaded_kernel.addresses {
             Addresses::Multiboot(addr) => addr.entry_address as usize,
             Addresses::Elf(e) => *e,
+            #[cfg(target_arch = "aarch64")]
+            Addresses::Image { entry_address, device_tree } => unsafe {
+                self.handoff.jump_to_image(*entry_address, *device_tree)
+            },
         };
         
-        unsafe {
-            asm!(
-                // The jump to the kernel has to happen in protected mode.
-                // If we're built for i686, we already are in protected mode,
-                // so this has no effect.
-                // If we're built for x86_64, this brings us to compatibility mode.
-                ".code32",
-                
-                // 3.2 Machine state says:
-                
-                // > ‘CS’: Must be a 32-bit read/execute code segment with an offset of ‘0’
-                // > and a limit of ‘0xFFFFFFFF’. The exact value is undefined.
-                // TODO: Maybe set this?
-                // > 'DS’, 'ES’, ‘FS’, ‘GS’, ‘SS’: Must be a 32-bit read/write data segment with an
-                // > offset of ‘0’ and a limit of ‘0xFFFFFFFF’. The exact values are all undefined.
-                // TODO: Maybe set this?
-                
-                // > ‘EFLAGS’: Bit 17 (VM) must be cleared. Bit 9 (IF) must be cleared.
-                // > Other bits are all undefined. 
-                // disable interrupts (should have been enabled)
-                "cli",
-                // virtual 8086 mode can't be set, as we're 32 or 64 bit code
-                // (and changing that flag is rather difficult)
-
-                // Writing to RBX (and thus EBX) using in("ebx") is forbidden,
-                // since this register is used internally by LLVM.
-                // Thus, we need to write the mulitboot information address to EAX
-                // and copy it into EBX here.
-                "mov ebx, eax",
-
-                // > ‘CR0’ Bit 31 (PG) must be cleared. Bit 0 (PE) must be set.
-                // > Other bits are all undefined.
-                "mov ecx, cr0",
-                // disable paging (it should have been enabled)
-                "and ecx, ~(1<<31)",
-                // enable protected mode (it should have already been enabled)
-                "or ecx, 1",
-                "mov cr0, ecx",
-                
-                // The spec doesn't say anything about cr4, but let's do it anyway.
-                "mov ecx, cr4",
-                // disable PAE
-                "and ecx, ~(1<<5)",
-                "mov cr4, ecx",
-                
-                // TODO: Only do this on x86_64?
-                // x86_64: switch from compatibility mode to protected mode
-                // get the EFER
-                "mov ecx, 0xC0000080",
-                "rdmsr",
-                // disable long mode
-                "and eax, ~(1<<8)",
-                "wrmsr",
-                
-                // write the signature to EAX
-                "mov eax, {}",
-                // finally jump to the kernel
-                "jmp edi",
-                
-                const SIGNATURE_EAX,
-                in("eax") &self.multiboot_information,
-                in("edi") entry_address,
-                options(noreturn),
-            );
+        // bring the machine into the correct state and jump
+        unsafe { self.handoff.jump(entry_address, &self.multiboot_information) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use uefi::prelude::*;
+    use uefi::table::boot::MemoryType;
+
+    use multiboot::header::Header;
+
+    use super::{check_page_alignment, header_flags, Allocation, MULTIBOOT_PAGE_ALIGN};
+    use super::super::firmware::mock::Mock;
+
+    /// Get a kernel that only consists of a Multiboot header with these flags.
+    fn kernel(flags: u32) -> Vec<u8> {
+        let magic: u32 = 0x1bad_b002;
+        let checksum = 0u32.wrapping_sub(magic).wrapping_sub(flags);
+        let mut kernel = Vec::from([0; 16]);
+        for value in [magic, flags, checksum] {
+            kernel.extend_from_slice(&value.to_le_bytes());
+        }
+        // room for the rest of the header (which isn't used)
+        kernel.resize(256, 0);
+        kernel
+    }
+
+    #[test]
+    fn flags() {
+        for flags in [0, MULTIBOOT_PAGE_ALIGN, MULTIBOOT_PAGE_ALIGN | 1 << 1] {
+            let kernel = kernel(flags);
+            let header = Header::from_slice(&kernel).unwrap();
+            assert_eq!(header_flags(&kernel, &header), flags);
         }
     }
+
+    #[test]
+    fn aligned_modules() {
+        Mock::install(&[(0x10_0000, 0x100_0000, MemoryType::CONVENTIONAL)]);
+        let modules = [
+            Allocation::new_under_4gb(0x1234, &Default::default()).unwrap(),
+            Allocation::new_moving(0x20_0000, 0x1000, 0x40_0000),
+        ];
+        assert_eq!(check_page_alignment(MULTIBOOT_PAGE_ALIGN, &modules), Ok(()));
+    }
+
+    #[test]
+    fn unaligned_modules() {
+        Mock::install(&[(0x10_0000, 0x100_0000, MemoryType::CONVENTIONAL)]);
+        let modules = [
+            Allocation::new_under_4gb(0x1000, &Default::default()).unwrap(),
+            Allocation::new_moving(0x20_0000, 0x1000, 0x40_0800),
+        ];
+        assert_eq!(
+            check_page_alignment(MULTIBOOT_PAGE_ALIGN, &modules), Err(Status::LOAD_ERROR),
+        );
+        // the kernel doesn't care
+        assert_eq!(check_page_alignment(0, &modules), Ok(()));
+        assert_eq!(check_page_alignment(!MULTIBOOT_PAGE_ALIGN, &modules), Ok(()));
+    }
+
+    #[test]
+    fn no_modules() {
+        assert_eq!(check_page_alignment(MULTIBOOT_PAGE_ALIGN, &[]), Ok(()));
+        assert_eq!(check_page_alignment(0, &[]), Ok(()));
+    }
 }
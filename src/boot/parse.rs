@@ -0,0 +1,286 @@
+//! Making sense of the kernel's headers
+//!
+//! Everything here only looks at the bytes of the kernel and at what the
+//! Multiboot and ELF headers say; nothing is allocated from or written to the
+//! firmware. All sizes and offsets come from the kernel, so they're checked
+//! before they're used as indices or added up: A malformed kernel leads to an
+//! error, not to a panic or to towboot writing somewhere it shouldn't.
+//!
+//! This file doesn't depend on anything else in towboot, so it can be fuzzed on
+//! the host (see the `fuzz` folder).
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use log::{trace, warn};
+
+use goblin::container;
+use goblin::elf::Elf;
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::section_header::{SectionHeader, SHT_NOBITS};
+use scroll::ctx::IntoCtx;
+
+use multiboot::header::MultibootAddresses;
+use multiboot::information::{ElfSymbols, SymbolType};
+
+/// Where the parts of a kernel with addresses in its Multiboot header go.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct MultibootLayout {
+    /// where the loaded part starts in the file
+    pub(crate) load_offset: usize,
+    /// how long the loaded part is
+    pub(crate) file_length: usize,
+    /// how much memory the kernel needs (including the BSS)
+    pub(crate) kernel_length: usize,
+}
+
+/// Work out which part of the file is loaded and how large the kernel is in memory.
+///
+/// `header_start` is the offset of the Multiboot header in the file.
+pub(crate) fn multiboot_layout(
+    addresses: &MultibootAddresses, header_start: u32, file_size: usize,
+) -> Result<MultibootLayout, &'static str> {
+    // The header is at the same offset in the file and in the loaded part.
+    let load_offset: usize = addresses.header_address.checked_sub(addresses.load_address)
+        .and_then(|header_offset| header_start.checked_sub(header_offset))
+        .ok_or("the kernel's header address doesn't match where the header is")?
+        .try_into().unwrap();
+    // the part of the file that is loaded (0 means until the end of the file)
+    let file_length: usize = if addresses.load_end_address == 0 {
+        file_size.saturating_sub(load_offset)
+    } else {
+        addresses.load_end_address.checked_sub(addresses.load_address)
+            .ok_or("the kernel's load end address is before its load address")?
+            .try_into().unwrap()
+    };
+    if load_offset.checked_add(file_length).map_or(true, |end| end > file_size) {
+        return Err("the kernel is shorter than its Multiboot header says")
+    }
+    // the part that is zeroed afterwards (0 means there is none)
+    let bss_end: usize = (
+        addresses.bss_end_address.saturating_sub(addresses.load_address)
+    ).try_into().unwrap();
+    if addresses.bss_end_address != 0 && bss_end < file_length {
+        warn!("the kernel's BSS ends before its data does, ignoring it");
+    }
+    let kernel_length = file_length.max(bss_end);
+    // Multiboot only has 32-bit addresses.
+    if u64::from(addresses.load_address) + kernel_length as u64 > 1 << 32 {
+        return Err("the kernel doesn't fit below 4 GB")
+    }
+    Ok(MultibootLayout { load_offset, file_length, kernel_length })
+}
+
+/// A loadable segment of an ELF file.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Segment {
+    pub(crate) virtual_address: u64,
+    pub(crate) physical_address: usize,
+    /// the size in memory (the rest after the part from the file is zeroed)
+    pub(crate) memory_size: usize,
+    /// where the contents are in the file
+    pub(crate) file_range: Range<usize>,
+    pub(crate) flags: u32,
+}
+
+/// Get the segments of an ELF file that have to be loaded.
+///
+/// Their contents are inside the file and fit into their memory.
+pub(crate) fn elf_segments(binary: &Elf, data: &[u8]) -> Result<Vec<Segment>, &'static str> {
+    binary.program_headers.iter().filter(|h| h.p_type == PT_LOAD).map(|header| {
+        trace!("header: {header:?}");
+        // On i686, we can't load anything above 4 GB.
+        let physical_address: usize = header.p_paddr.try_into()
+            .map_err(|_e| "segment is out of reach")?;
+        let memory_size: usize = header.p_memsz.try_into()
+            .map_err(|_e| "segment is too large")?;
+        physical_address.checked_add(memory_size).ok_or("segment is out of reach")?;
+        if header.p_filesz > header.p_memsz {
+            return Err("segment is larger in the file than in memory")
+        }
+        let start: usize = header.p_offset.try_into().map_err(|_e| "segment is outside the file")?;
+        let end = start.checked_add(header.p_filesz as usize)
+            .filter(|end| *end <= data.len())
+            .ok_or("segment is outside the file")?;
+        Ok(Segment {
+            virtual_address: header.p_vaddr,
+            physical_address,
+            memory_size,
+            file_range: start..end,
+            flags: header.p_flags,
+        })
+    }).collect()
+}
+
+/// Find the physical address of the entry point.
+///
+/// This is `None` if no segment contains it.
+pub(crate) fn elf_entry_point(
+    segments: &[Segment], entry_point: u64,
+) -> Result<Option<usize>, &'static str> {
+    // if segments overlap, the last one wins (as it's loaded last)
+    segments.iter().rev()
+        .find(|s| s.virtual_address <= entry_point
+            && entry_point - s.virtual_address <= s.memory_size as u64)
+        .map(|s| (s.physical_address as u64).checked_add(entry_point - s.virtual_address)
+            .and_then(|address| address.try_into().ok())
+            .ok_or("entry point is out of reach"))
+        .transpose()
+}
+
+/// Get where a section is in the file (if it is there and inside the file).
+fn section_range(section: &SectionHeader, length: usize) -> Option<Range<usize>> {
+    if section.sh_type == SHT_NOBITS {
+        return None
+    }
+    let start: usize = section.sh_offset.try_into().ok()?;
+    let end = start.checked_add(section.sh_size.try_into().ok()?)?;
+    (end <= length).then(|| start..end)
+}
+
+/// Bring the binary's symbols in a format for Multiboot.
+///
+/// Returns a tuple of informations struct and vector containing the symbols.
+/// If the section headers don't make sense, there are no symbols.
+pub(crate) fn symbols(binary: &mut Elf, data: &[u8]) -> Option<(SymbolType, Vec<u8>)> {
+    let ctx = container::Ctx::new(
+        if binary.is_64 { container::Container::Big } else { container::Container::Little },
+        if binary.little_endian { container::Endian::Little } else { container::Endian::Big },
+    );
+    let size: u32 = binary.header.e_shentsize.into();
+    if size as usize != SectionHeader::size(ctx) {
+        warn!("the kernel's section headers have an unexpected size, not passing symbols");
+        return None
+    }
+    let num: u32 = binary.section_headers.len().try_into().ok()?;
+    // only copy sections that are not already loaded
+    let to_copy = binary.section_headers.iter()
+        .filter(|s| s.sh_addr == 0)
+        .filter_map(|s| section_range(s, data.len()))
+        .map(|r| r.len())
+        .try_fold(0usize, usize::checked_add)?;
+    // Sections don't overlap, so they can't be larger than the file.
+    if to_copy > data.len() {
+        warn!("the kernel's sections overlap, not passing symbols");
+        return None
+    }
+    let headers_size = size as usize * num as usize;
+
+    // allocate memory to place the section headers and sections
+    let mut memory = Vec::new();
+    // reserve memory so that we don't have to re-allocate
+    memory.reserve(to_copy + headers_size);
+    let ptr = memory.as_ptr();
+
+    // copy the symbols
+    for section in binary.section_headers.iter_mut().filter(|s| s.sh_addr == 0) {
+        if let Some(range) = section_range(section, data.len()) {
+            let index = memory.len();
+            memory.extend_from_slice(&data[range]);
+            section.sh_addr = (index + ptr as usize).try_into().unwrap();
+            trace!("Loaded section {:?} to {:#x}", section, section.sh_addr);
+        }
+    }
+
+    // copy the section headers
+    let shdr_begin = memory.len();
+    // make sure that resizing won't reallocate
+    assert!(memory.capacity() >= shdr_begin + headers_size);
+    memory.resize(shdr_begin + headers_size, 0);
+    // we can't copy from data as it still just contains null pointers
+    let mut begin_idx = shdr_begin;
+    for section in &binary.section_headers {
+        section.clone().into_ctx(&mut memory[begin_idx..begin_idx + size as usize], ctx);
+        begin_idx += size as usize;
+    }
+    let shndx = binary.header.e_shstrndx.into();
+    Some((
+        SymbolType::Elf(ElfSymbols::from_addr(
+            num, size, (ptr as usize + shdr_begin) as multiboot::information::PAddr, shndx
+        )),
+        memory
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use multiboot::header::MultibootAddresses;
+
+    use super::{multiboot_layout, MultibootLayout};
+
+    /// the header is at 0x40 in the file, so the file is loaded from its start
+    const HEADER_START: u32 = 0x40;
+    const FILE_SIZE: usize = 0x3000;
+
+    /// Get the addresses of a kernel loaded to 1 MiB.
+    fn addresses(load_end_address: u32, bss_end_address: u32) -> MultibootAddresses {
+        MultibootAddresses {
+            header_address: 0x10_0040,
+            load_address: 0x10_0000,
+            load_end_address,
+            bss_end_address,
+            entry_address: 0x10_0100,
+        }
+    }
+
+    /// Get the layout of the file and the length of the kernel in memory.
+    fn layout(load_end_address: u32, bss_end_address: u32) -> (usize, usize, usize) {
+        let MultibootLayout { load_offset, file_length, kernel_length } = multiboot_layout(
+            &addresses(load_end_address, bss_end_address), HEADER_START, FILE_SIZE,
+        ).unwrap();
+        (load_offset, file_length, kernel_length)
+    }
+
+    #[test]
+    fn without_bss() {
+        assert_eq!(layout(0x10_2000, 0), (0, 0x2000, 0x2000));
+        // the whole file
+        assert_eq!(layout(0, 0), (0, 0x3000, 0x3000));
+    }
+
+    #[test]
+    fn bss_before_the_end_of_the_file() {
+        // this doesn't make sense, so the BSS is ignored
+        assert_eq!(layout(0x10_2000, 0x10_1000), (0, 0x2000, 0x2000));
+    }
+
+    #[test]
+    fn bss_at_the_end_of_the_file() {
+        assert_eq!(layout(0x10_2000, 0x10_2000), (0, 0x2000, 0x2000));
+    }
+
+    #[test]
+    fn bss_after_the_file() {
+        assert_eq!(layout(0x10_2000, 0x10_5000), (0, 0x2000, 0x5000));
+        assert_eq!(layout(0, 0x10_5000), (0, 0x3000, 0x5000));
+    }
+
+    #[test]
+    fn bss_before_the_kernel() {
+        assert_eq!(layout(0x10_2000, 0x8_0000), (0, 0x2000, 0x2000));
+    }
+
+    #[test]
+    fn loaded_from_the_middle_of_the_file() {
+        // the header is 0x40 bytes into the loaded part, but 0x1040 bytes into the file
+        let layout = multiboot_layout(&addresses(0x10_1000, 0), 0x1040, FILE_SIZE).unwrap();
+        assert_eq!(layout, MultibootLayout {
+            load_offset: 0x1000, file_length: 0x1000, kernel_length: 0x1000,
+        });
+    }
+
+    #[test]
+    fn invalid() {
+        // longer than the file
+        assert!(multiboot_layout(&addresses(0x10_4000, 0), HEADER_START, FILE_SIZE).is_err());
+        // ending before it starts
+        assert!(multiboot_layout(&addresses(0xf_0000, 0), HEADER_START, FILE_SIZE).is_err());
+        // the header would have to be before the start of the file
+        assert!(multiboot_layout(&addresses(0, 0), 0x20, FILE_SIZE).is_err());
+        // above 4 GB
+        let mut high = addresses(0, 0);
+        high.load_address = 0xffff_f000;
+        high.header_address = 0xffff_f040;
+        assert!(multiboot_layout(&high, HEADER_START, FILE_SIZE).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Planning where everything ends up
+//!
+//! If the memory a kernel (or a module with `load_at`) wants to be loaded to is in use
+//! while we're preparing it, it is loaded somewhere else and moved after exiting boot services
+//! (see `Allocation::move_to_where_it_should_be`). At that point, it's too late to
+//! find out that the destination contains something we still need.
+//!
+//! So, the moves are planned while we can still go back to the menu:
+//! Every destination has to be in memory that's free after exiting boot services.
+//! It may not overlap with what we still need until the jump (our own image,
+//! the heap containing the modules, the Multiboot information and the memory map
+//! buffers, the stack) or with another destination. Destinations may overlap with
+//! parts that are moved themselves; these are moved away first.
+//! If parts block each other, one of them is moved to a bounce buffer in between.
+
+use alloc::collections::{btree_map::BTreeMap, btree_set::BTreeSet};
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::table::boot::MemoryType;
+
+use log::{debug, error};
+
+use super::super::mem::{self, Allocation, Range};
+
+/// Something that may have to be moved (identified by its index).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Part {
+    Kernel(usize),
+    Module(usize),
+}
+
+impl Part {
+    /// Get the allocation this refers to.
+    fn of<'a>(
+        &self, kernel: &'a mut [Allocation], modules: &'a mut [Allocation],
+    ) -> &'a mut Allocation {
+        match self {
+            Part::Kernel(index) => &mut kernel[*index],
+            Part::Module(index) => &mut modules[*index],
+        }
+    }
+}
+
+/// What to do with a part.
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    /// move it to where it should be
+    Move(Part),
+    /// move it to its bounce buffer, so that its memory becomes free
+    Bounce(Part),
+}
+
+/// How to move the parts of the kernel and the modules.
+pub(super) struct Placement {
+    steps: Vec<Step>,
+    bounce_buffers: BTreeMap<Part, Allocation>,
+    /// what may not be overwritten
+    in_use: Vec<Range>,
+}
+
+impl Placement {
+    /// Check that all parts of the kernel and all modules can be moved safely
+    /// and find an order to do so.
+    ///
+    /// This has to be called after everything else has been allocated.
+    pub(super) fn plan(kernel: &[Allocation], modules: &[Allocation]) -> Result<Self, Status> {
+        let parts: Vec<(Part, &Allocation)> = kernel.iter().enumerate()
+            .map(|(i, a)| (Part::Kernel(i), a))
+            .chain(modules.iter().enumerate().map(|(i, a)| (Part::Module(i), a)))
+            .collect();
+        // where everything is now and where it should be
+        let sources: Vec<(Part, Range)> = parts.iter()
+            .filter(|(_, a)| a.should_be_at().is_some())
+            .map(|(p, a)| (*p, Range::new(a.as_ptr() as u64, a.allocated_size() as u64)))
+            .collect();
+        let destinations: Vec<(Part, Range)> = parts.iter()
+            .filter_map(|(p, a)| a.should_be_at().map(|d| (*p, Range::new(d, a.len as u64))))
+            .collect();
+        for (part, allocation) in &parts {
+            let start = allocation.as_ptr() as u64;
+            match allocation.should_be_at() {
+                Some(destination) => debug!(
+                    "{part:?}: {start:#x} (+{:#x}), moving to {destination:#x}", allocation.len,
+                ),
+                None => debug!("{part:?}: {start:#x} (+{:#x})", allocation.len),
+            }
+        }
+        if destinations.is_empty() {
+            return Ok(Self {
+                steps: Vec::new(), bounce_buffers: BTreeMap::new(), in_use: Vec::new(),
+            })
+        }
+
+        // A part may only be moved once nothing else is where it should go.
+        // (Overlapping with itself is fine, the copy can handle that.)
+        let mut remaining = destinations.clone();
+        let mut steps = Vec::with_capacity(2 * remaining.len());
+        // the parts that are not at their original place anymore
+        let mut gone = BTreeSet::new();
+        while !remaining.is_empty() {
+            match remaining.iter().position(|(part, destination)|
+                !sources.iter().any(|(other, source)|
+                    other != part && !gone.contains(other) && source.overlaps(destination)
+                )
+            ) {
+                Some(next) => {
+                    let (part, _) = remaining.remove(next);
+                    steps.push(Step::Move(part));
+                    gone.insert(part);
+                },
+                // Everything that's left blocks something else, so move one out of the way.
+                // (There always is one, as the parts that have been moved out
+                // of the way don't block anything.)
+                None => {
+                    let (part, _) = remaining.iter().find(|(p, _)| !gone.contains(p)).unwrap();
+                    steps.push(Step::Bounce(*part));
+                    gone.insert(*part);
+                },
+            }
+        }
+        let mut bounce_buffers = BTreeMap::new();
+        for step in &steps {
+            if let Step::Bounce(part) = step {
+                let len = parts.iter().find(|(p, _)| p == part).unwrap().1.len;
+                bounce_buffers.insert(*part, Allocation::new_under_4gb(len, &BTreeSet::new())?);
+            }
+        }
+        debug!("going to move the parts like this: {steps:?}");
+
+        // This has to be done after the bounce buffers have been allocated.
+        let memory_map = mem::memory_map()?;
+        // This is all the memory the kernel may get.
+        let free: Vec<Range> = memory_map.iter().filter(|d| matches!(
+            d.ty,
+            MemoryType::LOADER_CODE | MemoryType::LOADER_DATA
+            | MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA
+            | MemoryType::CONVENTIONAL | MemoryType::PERSISTENT_MEMORY
+        )).map(Range::from).collect();
+        // This is what we still need (except for the parts that are moved).
+        // Our image is in loader memory, too, but the stack is in boot services memory.
+        let moving: Vec<Range> = sources.iter().map(|(_, r)| *r).collect();
+        let mut in_use: Vec<Range> = memory_map.iter().filter(|d|
+            matches!(d.ty, MemoryType::LOADER_CODE | MemoryType::LOADER_DATA)
+        ).flat_map(|d| Range::from(d).subtract(&moving)).collect();
+        in_use.extend(mem::own_ranges(&memory_map));
+
+        for (part, destination) in &destinations {
+            if !destination.subtract(&free).is_empty() {
+                error!("{part:?} can't be moved to {destination:x?}: not free memory");
+                return Err(Status::LOAD_ERROR)
+            }
+            if let Some(used) = in_use.iter().find(|u| u.overlaps(destination)) {
+                error!("{part:?} can't be moved to {destination:x?}: {used:x?} is in use");
+                return Err(Status::LOAD_ERROR)
+            }
+            if let Some((other, _)) = destinations.iter()
+                .find(|(other, d)| other != part && d.overlaps(destination)) {
+                error!("{part:?} and {other:?} want to be at the same place");
+                return Err(Status::LOAD_ERROR)
+            }
+        }
+        Ok(Self { steps, bounce_buffers, in_use })
+    }
+
+    /// Move the parts of the kernel and the modules where they belong.
+    ///
+    /// This is really unsafe, see `Allocation::move_to_where_it_should_be`.
+    pub(super) unsafe fn execute(
+        &self, kernel: &mut [Allocation], modules: &mut [Allocation],
+        memory_map: &[multiboot::information::MemoryEntry],
+    ) {
+        for step in &self.steps {
+            match step {
+                Step::Move(part) => part.of(kernel, modules).move_to_where_it_should_be(
+                    memory_map, &self.in_use,
+                ),
+                Step::Bounce(part) => part.of(kernel, modules)
+                    .move_into(&self.bounce_buffers[part]),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use uefi::prelude::*;
+    use uefi::table::boot::MemoryType;
+
+    use super::{Allocation, Part, Placement, Step};
+    use super::super::super::firmware::mock::Mock;
+
+    /// 16 MiB of free memory (from 1 MiB) with a bit of reserved memory at 8 MiB
+    fn firmware() {
+        Mock::install(&[
+            (0x10_0000, 0x70_0000, MemoryType::CONVENTIONAL),
+            (0x80_0000, 0x1_0000, MemoryType::RUNTIME_SERVICES_DATA),
+            (0x81_0000, 0x7f_0000, MemoryType::CONVENTIONAL),
+        ]);
+    }
+
+    /// Get which parts are moved (or bounced) in which order.
+    fn steps(placement: &Placement) -> Vec<(bool, Part)> {
+        placement.steps.iter().map(|step| match step {
+            Step::Move(part) => (true, *part),
+            Step::Bounce(part) => (false, *part),
+        }).collect()
+    }
+
+    #[test]
+    fn nothing_to_move() {
+        firmware();
+        let kernel = [Allocation::new_at(0x10_0000, 0x3000, &Default::default()).unwrap()];
+        let modules = [Allocation::new_at(0x20_0000, 0x1000, &Default::default()).unwrap()];
+        let placement = Placement::plan(&kernel, &modules).unwrap();
+        assert!(steps(&placement).is_empty());
+    }
+
+    #[test]
+    fn move_to_free_memory() {
+        firmware();
+        let kernel = [
+            Allocation::new_at(0x10_0000, 0x3000, &Default::default()).unwrap(),
+            Allocation::new_moving(0x20_0000, 0x2000, 0x30_0000),
+        ];
+        let modules = [Allocation::new_moving(0x40_0000, 0x1000, 0x50_0000)];
+        let placement = Placement::plan(&kernel, &modules).unwrap();
+        assert_eq!(steps(&placement), [(true, Part::Kernel(1)), (true, Part::Module(0))]);
+        // the parts that are moved aren't in use, but the one that stays is
+        assert!(placement.in_use.iter().any(|r| r.start == 0x10_0000));
+        assert!(!placement.in_use.iter().any(|r| r.start == 0x20_0000 || r.start == 0x40_0000));
+    }
+
+    #[test]
+    fn move_parts_out_of_the_way_first() {
+        firmware();
+        // the kernel goes where the module is
+        let kernel = [Allocation::new_moving(0x20_0000, 0x2000, 0x40_0000)];
+        let modules = [Allocation::new_moving(0x40_0000, 0x1000, 0x50_0000)];
+        let placement = Placement::plan(&kernel, &modules).unwrap();
+        assert_eq!(steps(&placement), [(true, Part::Module(0)), (true, Part::Kernel(0))]);
+    }
+
+    #[test]
+    fn bounce_parts_that_block_each_other() {
+        firmware();
+        let kernel = [Allocation::new_moving(0x20_0000, 0x2000, 0x40_0000)];
+        let modules = [Allocation::new_moving(0x40_0000, 0x1000, 0x20_0000)];
+        let placement = Placement::plan(&kernel, &modules).unwrap();
+        assert_eq!(steps(&placement), [
+            (false, Part::Kernel(0)), (true, Part::Module(0)), (true, Part::Kernel(0)),
+        ]);
+        let buffer = &placement.bounce_buffers[&Part::Kernel(0)];
+        assert!(buffer.allocated_size() >= 0x2000);
+        // the bounce buffer has to stay where it is
+        assert!(placement.in_use.iter().any(|r| r.start == buffer.as_ptr() as u64));
+    }
+
+    #[test]
+    fn destination_not_free() {
+        firmware();
+        let kernel = [Allocation::new_moving(0x20_0000, 0x2000, 0x80_0000)];
+        assert_eq!(Placement::plan(&kernel, &[]).err(), Some(Status::LOAD_ERROR));
+    }
+
+    #[test]
+    fn destination_in_use() {
+        firmware();
+        let kernel = [Allocation::new_moving(0x20_0000, 0x2000, 0x30_1000)];
+        let modules = [Allocation::new_at(0x30_0000, 0x2000, &Default::default()).unwrap()];
+        assert_eq!(Placement::plan(&kernel, &modules).err(), Some(Status::LOAD_ERROR));
+    }
+
+    #[test]
+    fn same_destination() {
+        firmware();
+        let kernel = [Allocation::new_moving(0x20_0000, 0x2000, 0x30_0000)];
+        let modules = [Allocation::new_moving(0x40_0000, 0x1000, 0x30_1000)];
+        assert_eq!(Placement::plan(&kernel, &modules).err(), Some(Status::LOAD_ERROR));
+    }
+}
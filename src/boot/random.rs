@@ -0,0 +1,72 @@
+//! Random seeds for the kernel
+//!
+//! A module can consist of random bytes (`random_seed`), so that the kernel has
+//! some entropy early on (eg. for KASLR), before its own sources are up.
+//! They come from the firmware's RNG protocol if there is one. Otherwise, the
+//! processor's random number generator (RDRAND or RNDR) is used and, as a last
+//! resort, the jitter of its timestamp counter, which is better than nothing,
+//! but not good enough for cryptography.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::proto::rng::Rng;
+
+use log::{debug, warn};
+
+use super::arch;
+
+/// how often the counter is sampled for each byte of jitter
+const JITTER_SAMPLES: usize = 64;
+
+/// Get `length` random bytes.
+pub(super) fn seed(length: usize, systab: &SystemTable<Boot>) -> Vec<u8> {
+    let mut seed = vec![0; length];
+    if let Ok(rng) = systab.boot_services().locate_protocol::<Rng>() {
+        let rng = unsafe { &mut *rng.get() };
+        match rng.get_rng(None, &mut seed) {
+            Ok(()) => {
+                debug!("got {length} random bytes from the firmware");
+                return seed
+            },
+            Err(e) => warn!("the firmware failed to generate random bytes: {e:?}"),
+        }
+    }
+    let mut chunks = seed.chunks_mut(4);
+    if let Some(first) = arch::hardware_random() {
+        debug!("getting {length} random bytes from the processor");
+        let mut value = Some(first);
+        for chunk in chunks.by_ref() {
+            match value {
+                Some(v) => chunk.copy_from_slice(&v.to_le_bytes()[..chunk.len()]),
+                // if it runs out of entropy, the rest is filled with jitter
+                None => {
+                    fill_with_jitter(chunk);
+                    break
+                },
+            }
+            value = arch::hardware_random();
+        }
+    } else {
+        warn!("there's no source of randomness, using the jitter of the timestamp counter");
+    }
+    for chunk in chunks {
+        fill_with_jitter(chunk);
+    }
+    seed
+}
+
+/// Fill the buffer with the jitter of the timestamp counter.
+fn fill_with_jitter(buffer: &mut [u8]) {
+    let mut state = arch::timestamp();
+    for byte in buffer {
+        for _ in 0..JITTER_SAMPLES {
+            let before = arch::timestamp();
+            core::hint::spin_loop();
+            let delta = arch::timestamp().wrapping_sub(before);
+            state = (state ^ delta).rotate_left(7).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+        *byte = (state >> 56) as u8;
+    }
+}
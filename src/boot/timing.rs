@@ -0,0 +1,141 @@
+//! Measuring how long the steps of booting take
+//!
+//! The processor's counter (see `arch::timestamp`) is calibrated against the
+//! firmware's `Stall` at the start. Each step adds up how long it has taken,
+//! so a step may run several times (eg. when an entry fails to load).
+//!
+//! Right before booting, the durations are logged, written to the volatile
+//! `TowbootTiming` variable (in microseconds) and optionally shown on the
+//! console. Moving the kernel and exiting boot services happen after that,
+//! so they can't be measured.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+
+use log::{info, warn};
+
+use super::super::config::Config;
+use super::super::vars;
+use super::arch;
+
+/// how long to calibrate the counter (in microseconds)
+const CALIBRATION: usize = 1000;
+/// how long the summary stays on the screen (in microseconds)
+const SUMMARY_DELAY: usize = 3_000_000;
+
+/// the counter when we've been started
+static START: AtomicU64 = AtomicU64::new(0);
+/// how much the counter increases per millisecond (0 if it's unknown)
+static TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+// This is only used to initialize the array.
+#[allow(clippy::declare_interior_mutable_const)]
+const NOTHING: AtomicU64 = AtomicU64::new(0);
+/// how many ticks each step has taken so far
+static DURATIONS: [AtomicU64; Step::ALL.len()] = [NOTHING; Step::ALL.len()];
+
+/// The steps that are measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// reading and parsing the configuration file
+    Config,
+    /// waiting for the user
+    Menu,
+    /// loading the kernel
+    Kernel,
+    /// loading the modules
+    Modules,
+    /// reading from files (this is part of the other steps)
+    FileReads,
+    /// decompressing modules (this is part of loading them)
+    Decompression,
+    /// setting the video mode
+    Video,
+}
+
+impl Step {
+    const ALL: [Self; 7] = [
+        Self::Config, Self::Menu, Self::Kernel, Self::Modules, Self::FileReads,
+        Self::Decompression, Self::Video,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Config => "config",
+            Self::Menu => "menu",
+            Self::Kernel => "kernel",
+            Self::Modules => "modules",
+            Self::FileReads => "file-reads",
+            Self::Decompression => "decompression",
+            Self::Video => "video",
+        }
+    }
+}
+
+/// Start measuring.
+///
+/// This has to be called at the start. It takes a millisecond.
+pub(crate) fn init(systab: &SystemTable<Boot>) {
+    let start = arch::timestamp();
+    START.store(start, Ordering::Relaxed);
+    systab.boot_services().stall(CALIBRATION);
+    let ticks = arch::timestamp().wrapping_sub(start) * 1000 / CALIBRATION as u64;
+    TICKS_PER_MS.store(ticks, Ordering::Relaxed);
+}
+
+/// Get the current value of the counter (to pass to `add` later).
+pub(crate) fn now() -> u64 {
+    arch::timestamp()
+}
+
+/// Add the time since `start` to a step.
+pub(crate) fn add(step: Step, start: u64) {
+    DURATIONS[step as usize].fetch_add(now().wrapping_sub(start), Ordering::Relaxed);
+}
+
+/// Convert ticks of the counter to microseconds.
+fn to_microseconds(ticks: u64, ticks_per_ms: u64) -> u64 {
+    (u128::from(ticks) * 1000 / u128::from(ticks_per_ms)).try_into().unwrap_or(u64::MAX)
+}
+
+/// Tell how long each step has taken.
+///
+/// This logs the durations, writes them to a variable and shows them on the
+/// console if `show_timing` is set.
+pub(crate) fn report(config: &Config, systab: &mut SystemTable<Boot>) {
+    let ticks_per_ms = TICKS_PER_MS.load(Ordering::Relaxed);
+    if ticks_per_ms == 0 {
+        warn!("the counter doesn't seem to work, not reporting how long booting took");
+        return
+    }
+    let total = now().wrapping_sub(START.load(Ordering::Relaxed));
+    let durations: Vec<(&str, u64)> = Step::ALL.iter()
+        .map(|step| (step.name(), DURATIONS[*step as usize].load(Ordering::Relaxed)))
+        .chain(core::iter::once(("total", total)))
+        .map(|(name, ticks)| (name, to_microseconds(ticks, ticks_per_ms)))
+        .collect();
+    let summary = durations.iter()
+        .map(|(name, us)| format!("{name}: {}.{:03} ms", us / 1000, us % 1000))
+        .collect::<Vec<_>>().join(", ");
+    info!("timing: {summary}");
+    let variable = durations.iter()
+        .map(|(name, us)| format!("{name}={us}"))
+        .collect::<Vec<_>>().join(" ");
+    // This has already been logged.
+    let _ = vars::set_volatile_string(vars::TIMING, &variable);
+    if config.show_timing.unwrap_or(false) {
+        let mut text = String::new();
+        for (name, us) in &durations {
+            let _ = writeln!(text, "{name:>10}: {:>6}.{:03} ms", us / 1000, us % 1000);
+        }
+        // There's nowhere to report this to.
+        let _ = systab.stdout().write_str(&text);
+        systab.boot_services().stall(SUMMARY_DELAY);
+    }
+}
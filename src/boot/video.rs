@@ -1,6 +1,7 @@
 //! Management of the video mode.
 
 use alloc::collections::btree_set::BTreeSet;
+use alloc::format;
 use alloc::vec::Vec;
 
 use uefi::prelude::*;
@@ -12,19 +13,42 @@ use multiboot::header::{Header, VideoModeType};
 use multiboot::information::{ColorInfoType, ColorInfoRgb, FramebufferTable, Multiboot};
 
 use super::super::config::Quirk;
+use super::super::firmware::system_table;
+
+/// Where the framebuffer is (if it has 32-bit pixels).
+///
+/// This is used to show errors after exiting boot services (see the `integrity` module).
+pub(super) struct Screen {
+    pub(super) address: usize,
+    /// the width of a line in pixels
+    pub(super) stride: usize,
+    pub(super) height: usize,
+}
+
+/// A video mode that is used because the wanted one isn't available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Substitution {
+    /// the resolution that has been asked for first (by the kernel or the entry)
+    pub(crate) wanted: (usize, usize),
+    /// the closest one that is available
+    pub(crate) used: (usize, usize),
+}
 
 /// Try to get the video in a mode the kernel wants.
 ///
 /// If there are multiple GPUs available, simply choose the first one.
-/// If there is no available mode that matches, just use the one we're already in.
+/// If the kernel's preferred resolution isn't available, the one of the entry
+/// is tried next. If neither is, the closest mode is used (and a warning logged).
+/// If nothing is wanted, just use the mode we're already in.
 pub(super) fn setup_video<'a>(
-    header: &Header, systab: &'a SystemTable<Boot>, quirks: &BTreeSet<Quirk>
+    header: &Header, resolution: Option<(usize, usize)>, systab: &'a SystemTable<Boot>,
+    quirks: &BTreeSet<Quirk>,
 ) -> Result<&'a mut GraphicsOutput<'a>, Status> {
     info!("setting up the video...");
-    let wanted_resolution = match (
+    if let (Some(mode), false) = (
         header.get_preferred_video_mode(), quirks.contains(&Quirk::KeepResolution)
     ) {
-        (Some(mode), false) => match mode.mode_type() {
+        match mode.mode_type() {
             Some(VideoModeType::LinearGraphics) => {
                 // lets just hope that the firmware supports 24-bit RGB
                 // the other modes are way too obscure
@@ -35,7 +59,6 @@ pub(super) fn setup_video<'a>(
                         mode.depth().unwrap()
                     );
                 }
-                Some((mode.width, mode.height))
             },
             Some(VideoModeType::TextMode) => {
                 // We could set the console to this resolution,
@@ -43,15 +66,11 @@ pub(super) fn setup_video<'a>(
                 // So, just chose a video mode and hope that the kernel supports video.
                 // TODO: Perhaps support EFI text mode later on.
                 warn!("text mode is not implemented");
-                None
-            },
-            None => {
-                warn!("kernel wants unknown video mode");
-                None
             },
-        },
-        _ => None,
-    };
+            None => warn!("kernel wants unknown video mode"),
+        }
+    }
+    let wanted = wanted_resolutions(header, resolution, quirks);
     // just get the first one
     let output = systab.boot_services().locate_protocol::<GraphicsOutput>().map_err(|e| {
         error!(
@@ -66,19 +85,16 @@ pub(super) fn setup_video<'a>(
         modes.iter().map(Mode::info).map(|i| (i.resolution(), i.pixel_format()))
         .collect::<Vec<((usize, usize), PixelFormat)>>()
     );
+    let available: Vec<(usize, usize)> = modes.iter().map(|m| m.info().resolution()).collect();
     // try to see, if we find a matching mode
-    if let Some(mode) = match wanted_resolution {
-        Some((w, h)) => {
-            modes.iter().find(|m|
-                m.info().resolution() == (w as usize, h as usize)
-            ).or_else(|| {
-                warn!("failed to find a matching video mode (kernel wants {w}x{h})");
-                None
-            })
-        },
-        None => None,
-    // in that case: set it
-    } {
+    if let Some((index, substitution)) = choose(&wanted, &available) {
+        if let Some(Substitution { used: (width, height), .. }) = substitution {
+            warn!(
+                "video mode unavailable: wanted {}, using {width}x{height} (the closest one)",
+                wanted.iter().map(|(w, h)| format!("{w}x{h}")).collect::<Vec<_>>().join(" or "),
+            );
+        }
+        let mode = &modes[index];
         debug!("chose {:?} as the video mode", mode.info().resolution());
         output.set_mode(mode).map_err(|e| {
             error!("failed to set video mode: {e:?}");
@@ -89,6 +105,54 @@ pub(super) fn setup_video<'a>(
     Ok(output)
 }
 
+/// Find out whether the wanted video mode is unavailable (without setting anything).
+///
+/// This returns the mode `setup_video` would use instead.
+pub(super) fn substitution(
+    header: &Header, resolution: Option<(usize, usize)>, quirks: &BTreeSet<Quirk>,
+) -> Option<Substitution> {
+    let output = unsafe { system_table().as_ref() }.boot_services()
+        .locate_protocol::<GraphicsOutput>().ok()?;
+    let output = unsafe { &mut *output.get() };
+    let available: Vec<(usize, usize)> = output.modes().map(|m| m.info().resolution()).collect();
+    choose(&wanted_resolutions(header, resolution, quirks), &available)?.1
+}
+
+/// Get the resolutions to try, in this order: the kernel's preferred one, the entry's one.
+fn wanted_resolutions(
+    header: &Header, resolution: Option<(usize, usize)>, quirks: &BTreeSet<Quirk>,
+) -> Vec<(usize, usize)> {
+    let mut wanted = Vec::new();
+    if !quirks.contains(&Quirk::KeepResolution) {
+        if let Some(mode) = header.get_preferred_video_mode() {
+            // 0 means "no preference"
+            if matches!(mode.mode_type(), Some(VideoModeType::LinearGraphics))
+            && mode.width != 0 && mode.height != 0 {
+                wanted.push((mode.width as usize, mode.height as usize));
+            }
+        }
+    }
+    wanted.extend(resolution);
+    wanted
+}
+
+/// Choose one of the available modes.
+///
+/// The first of the wanted resolutions that is available is used. If none is,
+/// the mode closest to the first one is used and returned as a substitution.
+/// This returns `None` if nothing is wanted (or nothing is available).
+fn choose(
+    wanted: &[(usize, usize)], available: &[(usize, usize)],
+) -> Option<(usize, Option<Substitution>)> {
+    if let Some(index) = wanted.iter().find_map(|w| available.iter().position(|a| a == w)) {
+        return Some((index, None))
+    }
+    let first = *wanted.first()?;
+    let (index, used) = available.iter().copied().enumerate()
+        .min_by_key(|(_, (w, h))| w.abs_diff(first.0) + h.abs_diff(first.1))?;
+    Some((index, Some(Substitution { wanted: first, used })))
+}
+
 /// Pass the framebuffer information to the kernel.
 pub(super) fn prepare_information(
     multiboot: &mut Multiboot, graphics_output: &mut GraphicsOutput
@@ -137,6 +201,19 @@ pub(super) fn prepare_information(
     multiboot.set_framebuffer_table(Some(framebuffer_table));
 }
 
+/// Remember where the framebuffer is.
+pub(super) fn screen(graphics_output: &mut GraphicsOutput) -> Option<Screen> {
+    let mode = graphics_output.current_mode_info();
+    match mode.pixel_format() {
+        PixelFormat::Rgb | PixelFormat::Bgr => Some(Screen {
+            address: graphics_output.frame_buffer().as_mut_ptr() as usize,
+            stride: mode.stride(),
+            height: mode.resolution().1,
+        }),
+        _ => None,
+    }
+}
+
 /// Converts UEFI's `PixelBitmask` to Multiboot's `ColorInfoRGB`.
 fn bitmask_to_color_info(pixel_bitmask: PixelBitmask) -> ColorInfoRgb {
     let (red_field_position, red_mask_size) = parse_color_bitmap(pixel_bitmask.red);
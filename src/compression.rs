@@ -0,0 +1,864 @@
+//! Recognizing and decompressing compressed modules
+//!
+//! Modules are usually passed to the kernel as they are, but it's useful to know
+//! what's in them (eg. a Linux initramfs may be compressed with gzip, xz or zstd).
+//! The format is detected by the magic bytes at the beginning; the unpacked size
+//! is taken from the trailer or header, where the format has it.
+//!
+//! gzip files can also be decompressed while loading them (see `decompress`).
+//! The compressed data is read a chunk at a time and unpacked directly into the
+//! memory of the module, so there's never a second copy of the whole file.
+
+use core::fmt::{Display, Formatter};
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use uefi::Status;
+
+use log::error;
+
+use super::boot::timing::{self, Step};
+use super::file::{File, ReadAhead};
+use super::progress::{Progress, Style};
+
+/// how much of the beginning of a file is needed (the longest zstd frame header)
+const HEADER_LENGTH: usize = 18;
+/// how much of the end of a file is needed (enough for the index of most xz files)
+const TRAILER_LENGTH: usize = 4096;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// the only compression method of gzip
+const DEFLATE: u8 = 8;
+/// the flags in the gzip header saying which optional fields follow
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+/// how far back deflate may refer
+const WINDOW_SIZE: usize = 32 * 1024;
+/// the longest Huffman code
+const MAX_BITS: usize = 15;
+/// how many bits are looked up at once when decoding (longer codes take the slow path)
+const FAST_BITS: u32 = 10;
+
+/// the base lengths of the length codes (257 to 285) and how many extra bits they have
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// the base distances of the distance codes and how many extra bits they have
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+/// the order in which the lengths of the code length code are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// the table for calculating CRC32 a byte at a time
+static CRC_TABLE: [u32; 256] = crc_table();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Uncompressed,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::Uncompressed => "uncompressed",
+            Self::Gzip => "gzip",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+/// What we know about a (possibly compressed) file.
+#[derive(Debug)]
+pub(crate) struct Info {
+    pub(crate) format: Format,
+    pub(crate) size: usize,
+    /// The size after decompressing, if the format stores it.
+    /// (gzip only stores it modulo 4 GB.)
+    pub(crate) unpacked_size: Option<u64>,
+}
+
+impl Info {
+    /// Look at the beginning and the end of an opened file.
+    pub(crate) fn of(file: &mut File) -> Result<Self, Status> {
+        let start = file.read_beginning(HEADER_LENGTH)?;
+        let end = file.read_end(TRAILER_LENGTH)?;
+        Ok(Self::parse(&start, &end, file.file_size()))
+    }
+
+    fn parse(start: &[u8], end: &[u8], size: usize) -> Self {
+        let (format, unpacked_size) = if start.starts_with(GZIP_MAGIC) {
+            // the trailer ends with the size
+            let size = end.len().checked_sub(4).map(|position| u32::from_le_bytes(
+                end[position..].try_into().unwrap()
+            ).into());
+            (Format::Gzip, size)
+        } else if start.starts_with(XZ_MAGIC) {
+            (Format::Xz, xz_size(end))
+        } else if start.starts_with(ZSTD_MAGIC) {
+            (Format::Zstd, zstd_size(start))
+        } else {
+            (Format::Uncompressed, None)
+        };
+        Self { format, size, unpacked_size }
+    }
+}
+
+impl Display for Info {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}, {} bytes", self.format, self.size)?;
+        if let Some(unpacked_size) = self.unpacked_size {
+            write!(f, " ({unpacked_size} bytes unpacked)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sum up the uncompressed sizes in the index of the last xz stream.
+fn xz_size(end: &[u8]) -> Option<u64> {
+    // the stream footer: CRC32, the size of the index, flags and the magic
+    let footer_start = end.len().checked_sub(12)?;
+    let footer = &end[footer_start..];
+    if &footer[10..] != b"YZ" {
+        return None
+    }
+    let index_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as usize + 1) * 4;
+    let index = &end[footer_start.checked_sub(index_size)?..footer_start];
+    // the indicator, the number of records and the unpadded and uncompressed size of each
+    let mut bytes = index.iter().copied();
+    if bytes.next()? != 0 {
+        return None
+    }
+    let mut size: u64 = 0;
+    for _ in 0..read_multibyte(&mut bytes)? {
+        read_multibyte(&mut bytes)?;
+        size = size.checked_add(read_multibyte(&mut bytes)?)?;
+    }
+    Some(size)
+}
+
+/// Read an integer as xz encodes them (7 bits per byte, least significant first).
+fn read_multibyte(bytes: &mut impl Iterator<Item = u8>) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..63).step_by(7) {
+        let byte = bytes.next()?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value)
+        }
+    }
+    None
+}
+
+/// Get the content size from the header of the first zstd frame.
+fn zstd_size(start: &[u8]) -> Option<u64> {
+    let descriptor = *start.get(ZSTD_MAGIC.len())?;
+    let single_segment = descriptor & 0x20 != 0;
+    let dictionary_id_length = [0, 1, 2, 4][usize::from(descriptor & 0x3)];
+    let content_size_length = match descriptor >> 6 {
+        0 if single_segment => 1,
+        0 => return None,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    // the window descriptor is only there if it's not a single segment
+    let offset = ZSTD_MAGIC.len() + 1 + usize::from(!single_segment) + dictionary_id_length;
+    let field = start.get(offset..offset + content_size_length)?;
+    let mut bytes = [0; 8];
+    bytes[..field.len()].copy_from_slice(field);
+    let size = u64::from_le_bytes(bytes);
+    // two bytes have an offset, so that they cover more
+    Some(if content_size_length == 2 { size + 256 } else { size })
+}
+
+/// Find out how large a file is going to be in memory.
+///
+/// gzip files are going to be decompressed (see `decompress`), uncompressed
+/// ones are loaded as they are. Other formats can't be decompressed.
+pub(crate) fn unpacked_size(file: &mut File) -> Result<(Format, usize), Status> {
+    let info = Info::of(file)?;
+    match (info.format, info.unpacked_size) {
+        (Format::Uncompressed, _) => Ok((Format::Uncompressed, info.size)),
+        (Format::Gzip, Some(size)) => Ok((Format::Gzip, size.try_into().unwrap())),
+        (Format::Gzip, None) => Ok((Format::Gzip, count(file, Style::None)?)),
+        (format, _) => {
+            error!("'{}' is compressed with {format}, but only gzip is supported", file.name());
+            Err(Status::UNSUPPORTED)
+        },
+    }
+}
+
+/// Decompress a gzip file into `output`, which has to be as large as the unpacked content.
+///
+/// The size in the trailer is only the one of the last member (and modulo 4 GB).
+/// If the content turns out to be larger than `output`, this fails with
+/// `Status::BUFFER_TOO_SMALL` (without logging an error), see `count`.
+pub(crate) fn decompress(file: &mut File, output: &mut [u8], style: Style) -> Result<(), Status> {
+    let name = file.name();
+    let start = timing::now();
+    let mut output = Flat { memory: output, position: 0, checked: 0 };
+    let result = gzip(&mut Bits::new(file, style), &mut output);
+    timing::add(Step::Decompression, start);
+    match result {
+        Ok(()) if output.position == output.memory.len() => Ok(()),
+        Ok(()) => {
+            error!(
+                "'{name}' is only {} bytes unpacked, but it should be {}",
+                output.position, output.memory.len(),
+            );
+            Err(Status::LOAD_ERROR)
+        },
+        Err(Failure::Full) => Err(Status::BUFFER_TOO_SMALL),
+        Err(failure) => Err(failure.report(name)),
+    }
+}
+
+/// Count how large a gzip file is unpacked, by decompressing it without keeping the result.
+pub(crate) fn count(file: &mut File, style: Style) -> Result<usize, Status> {
+    let name = file.name();
+    let start = timing::now();
+    let mut output = Window { data: vec![0; WINDOW_SIZE], position: 0, crc: 0 };
+    let result = gzip(&mut Bits::new(file, style), &mut output);
+    timing::add(Step::Decompression, start);
+    result.map_err(|failure| failure.report(name))?;
+    Ok(output.position)
+}
+
+/// Why decompressing has failed.
+#[derive(Debug)]
+enum Failure {
+    /// the data is invalid (for this reason)
+    Invalid(&'static str),
+    /// the unpacked content doesn't fit into the output
+    Full,
+    /// reading has failed (this has already been logged)
+    Read(Status),
+}
+
+impl From<Status> for Failure {
+    fn from(status: Status) -> Self {
+        Self::Read(status)
+    }
+}
+
+impl Failure {
+    /// Log what has happened and turn it into a `Status`.
+    fn report(self, name: &str) -> Status {
+        match self {
+            Self::Invalid(reason) => {
+                error!("'{name}' can't be decompressed: {reason}");
+                Status::LOAD_ERROR
+            },
+            Self::Full => {
+                error!("'{name}' is larger unpacked than expected");
+                Status::BUFFER_TOO_SMALL
+            },
+            Self::Read(status) => status,
+        }
+    }
+}
+
+/// Reads a file a chunk at a time and hands it out bit by bit.
+///
+/// The next chunk is read while this one is being decompressed (see `file::ReadAhead`).
+struct Bits<'f, 'a> {
+    chunks: ReadAhead<'f, 'a>,
+    chunk: Vec<u8>,
+    /// how much of the file has been read
+    read: usize,
+    /// the next byte in the chunk
+    position: usize,
+    /// bits that have been read, but not used yet (the next one is the lowest)
+    bits: u64,
+    count: u32,
+    progress: Progress<'a>,
+}
+
+impl<'f, 'a> Bits<'f, 'a> {
+    fn new(file: &'f mut File<'a>, style: Style) -> Self {
+        let progress = Progress::new(file.name(), file.file_size(), style);
+        Self {
+            chunks: ReadAhead::new(file), chunk: Vec::new(), read: 0, position: 0,
+            bits: 0, count: 0, progress,
+        }
+    }
+
+    /// Get the next byte of the file (`None` at its end).
+    fn byte(&mut self) -> Result<Option<u8>, Status> {
+        if self.position == self.chunk.len() {
+            self.chunks.next(&mut self.chunk)?;
+            self.position = 0;
+            if self.chunk.is_empty() {
+                return Ok(None)
+            }
+            self.read += self.chunk.len();
+            self.progress.update(self.read);
+        }
+        self.position += 1;
+        Ok(Some(self.chunk[self.position - 1]))
+    }
+
+    /// Read up to `n` bits ahead (fewer at the end of the file).
+    fn fill(&mut self, n: u32) -> Result<(), Status> {
+        while self.count < n {
+            match self.byte()? {
+                Some(byte) => {
+                    self.bits |= u64::from(byte) << self.count;
+                    self.count += 8;
+                },
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop `n` bits that have been read ahead.
+    fn consume(&mut self, n: u32) {
+        self.bits >>= n;
+        self.count -= n;
+    }
+
+    /// Take the next `n` bits (at most 32).
+    fn take(&mut self, n: u32) -> Result<u32, Failure> {
+        self.fill(n)?;
+        if self.count < n {
+            return Err(Failure::Invalid("it ends too early"))
+        }
+        let value = (self.bits & ((1 << n) - 1)) as u32;
+        self.consume(n);
+        Ok(value)
+    }
+
+    /// Take the next byte (this has to be at a byte boundary).
+    fn take_byte(&mut self) -> Result<u8, Failure> {
+        Ok(self.take(8)? as u8)
+    }
+
+    /// Look at the next byte without taking it (this has to be at a byte boundary).
+    fn peek_byte(&mut self) -> Result<Option<u8>, Failure> {
+        self.fill(8)?;
+        Ok((self.count >= 8).then_some(self.bits as u8))
+    }
+
+    /// Skip to the next byte boundary.
+    fn align(&mut self) {
+        self.consume(self.count % 8);
+    }
+}
+
+/// Where the unpacked data goes.
+trait Output {
+    /// Append a byte.
+    fn push(&mut self, byte: u8) -> Result<(), Failure>;
+
+    /// Append `length` bytes, copying them from `distance` bytes back.
+    fn repeat(&mut self, distance: usize, length: usize) -> Result<(), Failure>;
+
+    /// How many bytes have been written so far.
+    fn position(&self) -> usize;
+
+    /// Get the CRC32 of what has been written since the last call.
+    fn checksum(&mut self) -> u32;
+}
+
+/// Unpacking directly into the final memory.
+struct Flat<'o> {
+    memory: &'o mut [u8],
+    position: usize,
+    /// up to where the checksum has been calculated
+    checked: usize,
+}
+
+impl Output for Flat<'_> {
+    fn push(&mut self, byte: u8) -> Result<(), Failure> {
+        *self.memory.get_mut(self.position).ok_or(Failure::Full)? = byte;
+        self.position += 1;
+        Ok(())
+    }
+
+    fn repeat(&mut self, distance: usize, length: usize) -> Result<(), Failure> {
+        if distance > self.position - self.checked {
+            return Err(Failure::Invalid("it refers to data before its start"))
+        }
+        let end = self.position + length;
+        if end > self.memory.len() {
+            return Err(Failure::Full)
+        }
+        if distance >= length {
+            self.memory.copy_within(self.position - distance..end - distance, self.position);
+        } else {
+            // the copy overlaps with itself, so this has to go byte by byte
+            for index in self.position..end {
+                self.memory[index] = self.memory[index - distance];
+            }
+        }
+        self.position = end;
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn checksum(&mut self) -> u32 {
+        let checksum = crc32(0, &self.memory[self.checked..self.position]);
+        self.checked = self.position;
+        checksum
+    }
+}
+
+/// Unpacking just to count the bytes (keeping only what may be referred to).
+struct Window {
+    data: Vec<u8>,
+    position: usize,
+    /// the CRC32 since the last call to `checksum`
+    crc: u32,
+}
+
+impl Output for Window {
+    fn push(&mut self, byte: u8) -> Result<(), Failure> {
+        self.data[self.position % WINDOW_SIZE] = byte;
+        self.position += 1;
+        self.crc = crc32(self.crc, &[byte]);
+        Ok(())
+    }
+
+    fn repeat(&mut self, distance: usize, length: usize) -> Result<(), Failure> {
+        if distance > self.position {
+            return Err(Failure::Invalid("it refers to data before its start"))
+        }
+        for _ in 0..length {
+            self.push(self.data[(self.position - distance) % WINDOW_SIZE])?;
+        }
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn checksum(&mut self) -> u32 {
+        core::mem::take(&mut self.crc)
+    }
+}
+
+/// Unpack all members of a gzip file.
+///
+/// Several members are concatenated. Zeroes after a member are ignored (some
+/// tools pad files this way).
+fn gzip(bits: &mut Bits, output: &mut impl Output) -> Result<(), Failure> {
+    member(bits, output)?;
+    loop {
+        match bits.peek_byte()? {
+            None => return Ok(()),
+            Some(0) => { bits.take_byte()?; },
+            Some(_) => member(bits, output)?,
+        }
+    }
+}
+
+/// Unpack one member of a gzip file.
+fn member(bits: &mut Bits, output: &mut impl Output) -> Result<(), Failure> {
+    // the magic, the method, the flags, the time, extra flags and the operating system
+    let mut header = [0; 10];
+    for byte in &mut header {
+        *byte = bits.take_byte()?;
+    }
+    if !header.starts_with(GZIP_MAGIC) {
+        return Err(Failure::Invalid("there's something else than gzip data in it"))
+    }
+    if header[2] != DEFLATE {
+        return Err(Failure::Invalid("it uses an unknown compression method"))
+    }
+    let flags = header[3];
+    if flags & FEXTRA != 0 {
+        for _ in 0..bits.take(16)? {
+            bits.take_byte()?;
+        }
+    }
+    // the file name and the comment are null-terminated
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            while bits.take_byte()? != 0 {}
+        }
+    }
+    if flags & FHCRC != 0 {
+        bits.take(16)?;
+    }
+    let start = output.position();
+    inflate(bits, output)?;
+    bits.align();
+    let checksum = bits.take(32)?;
+    let size = bits.take(32)?;
+    if output.checksum() != checksum {
+        return Err(Failure::Invalid("its checksum is wrong"))
+    }
+    // the size is stored modulo 4 GB
+    if (output.position() - start) as u32 != size {
+        return Err(Failure::Invalid("its size is wrong"))
+    }
+    Ok(())
+}
+
+/// Unpack deflate data (RFC 1951).
+fn inflate(bits: &mut Bits, output: &mut impl Output) -> Result<(), Failure> {
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(bits, output)?,
+            1 => {
+                let mut lengths = [0; 288 + 30];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..288].fill(8);
+                lengths[288..].fill(5);
+                let literals = Huffman::new(&lengths[..288])?;
+                let distances = Huffman::new(&lengths[288..])?;
+                codes(bits, output, &literals, &distances)?;
+            },
+            2 => dynamic(bits, output)?,
+            _ => return Err(Failure::Invalid("it contains an invalid block")),
+        }
+        if last {
+            return Ok(())
+        }
+    }
+}
+
+/// Copy an uncompressed block.
+fn stored(bits: &mut Bits, output: &mut impl Output) -> Result<(), Failure> {
+    bits.align();
+    let length = bits.take(16)?;
+    if bits.take(16)? != !length & 0xffff {
+        return Err(Failure::Invalid("the length of an uncompressed block is wrong"))
+    }
+    for _ in 0..length {
+        output.push(bits.take_byte()?)?;
+    }
+    Ok(())
+}
+
+/// Unpack a block with its own Huffman codes.
+fn dynamic(bits: &mut Bits, output: &mut impl Output) -> Result<(), Failure> {
+    let literal_count = bits.take(5)? as usize + 257;
+    let distance_count = bits.take(5)? as usize + 1;
+    let code_length_count = bits.take(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(Failure::Invalid("a block has too many codes"))
+    }
+    // The lengths of the codes are compressed with another Huffman code.
+    let mut lengths = [0; 19];
+    for index in &CODE_LENGTH_ORDER[..code_length_count] {
+        lengths[*index] = bits.take(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&lengths)?;
+    let mut lengths = [0; 286 + 30];
+    let mut index = 0;
+    while index < literal_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 if index > 0 => (lengths[index - 1], 3 + bits.take(2)?),
+            17 => (0, 3 + bits.take(3)?),
+            18 => (0, 11 + bits.take(7)?),
+            _ => return Err(Failure::Invalid("a block has invalid code lengths")),
+        };
+        let end = index + repeat as usize;
+        if end > literal_count + distance_count {
+            return Err(Failure::Invalid("a block has too many code lengths"))
+        }
+        lengths[index..end].fill(length);
+        index = end;
+    }
+    if lengths[256] == 0 {
+        return Err(Failure::Invalid("a block can't end"))
+    }
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..literal_count + distance_count])?;
+    codes(bits, output, &literals, &distances)
+}
+
+/// Unpack the literals and references of a block until it ends.
+fn codes(
+    bits: &mut Bits, output: &mut impl Output, literals: &Huffman, distances: &Huffman,
+) -> Result<(), Failure> {
+    loop {
+        match literals.decode(bits)? {
+            literal @ 0..=255 => output.push(literal as u8)?,
+            256 => return Ok(()),
+            symbol @ 257..=285 => {
+                let index = usize::from(symbol - 257);
+                let length = usize::from(LENGTH_BASE[index])
+                    + bits.take(LENGTH_EXTRA[index].into())? as usize;
+                let index = usize::from(distances.decode(bits)?);
+                if index >= DISTANCE_BASE.len() {
+                    return Err(Failure::Invalid("it contains an invalid distance"))
+                }
+                let distance = usize::from(DISTANCE_BASE[index])
+                    + bits.take(DISTANCE_EXTRA[index].into())? as usize;
+                output.repeat(distance, length)?;
+            },
+            _ => return Err(Failure::Invalid("it contains an invalid length")),
+        }
+    }
+}
+
+/// A canonical Huffman code.
+struct Huffman {
+    /// how many codes there are of each length
+    counts: [u16; MAX_BITS + 1],
+    /// the symbols, ordered by their codes
+    symbols: [u16; 288],
+    /// `symbol << 4 | length` for all combinations of the next `FAST_BITS` bits
+    /// (or 0, if the code is longer)
+    fast: [u16; 1 << FAST_BITS],
+}
+
+impl Huffman {
+    /// Build the code from the lengths of the symbols' codes.
+    fn new(lengths: &[u8]) -> Result<Self, Failure> {
+        let mut counts = [0; MAX_BITS + 1];
+        for length in lengths {
+            counts[usize::from(*length)] += 1;
+        }
+        counts[0] = 0;
+        // there may not be more codes than fit (but fewer are fine)
+        let mut left: i32 = 1;
+        for count in &counts[1..] {
+            left = (left << 1) - i32::from(*count);
+            if left < 0 {
+                return Err(Failure::Invalid("it contains an invalid Huffman code"))
+            }
+        }
+        // where the codes of each length start, both in `symbols` and as a code
+        let mut offsets = [0; MAX_BITS + 1];
+        let mut next_code = [0; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+            next_code[length + 1] = (next_code[length] + counts[length]) << 1;
+        }
+        let mut huffman = Self { counts, symbols: [0; 288], fast: [0; 1 << FAST_BITS] };
+        for (symbol, length) in lengths.iter().enumerate().filter(|(_, l)| **l != 0) {
+            let length = usize::from(*length);
+            huffman.symbols[usize::from(offsets[length])] = symbol as u16;
+            offsets[length] += 1;
+            let code = next_code[length];
+            next_code[length] += 1;
+            if length as u32 <= FAST_BITS {
+                // the codes are stored starting with their highest bit
+                let reversed = (code.reverse_bits() >> (16 - length)) as usize;
+                for index in (reversed..1 << FAST_BITS).step_by(1 << length) {
+                    huffman.fast[index] = (symbol as u16) << 4 | length as u16;
+                }
+            }
+        }
+        Ok(huffman)
+    }
+
+    /// Decode the next symbol.
+    fn decode(&self, bits: &mut Bits) -> Result<u16, Failure> {
+        bits.fill(MAX_BITS as u32)?;
+        let entry = self.fast[(bits.bits & ((1 << FAST_BITS) - 1)) as usize];
+        if entry != 0 {
+            let length = u32::from(entry & 0xf);
+            if length > bits.count {
+                return Err(Failure::Invalid("it ends too early"))
+            }
+            bits.consume(length);
+            return Ok(entry >> 4)
+        }
+        // This is longer (or invalid), so go through the lengths one by one.
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for count in self.counts[1..].iter().copied().map(u32::from) {
+            code |= bits.take(1)?;
+            if code < first + count {
+                return Ok(self.symbols[(index + code - first) as usize])
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Failure::Invalid("it contains an invalid Huffman code"))
+    }
+}
+
+/// Build the table for `crc32`.
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+/// Continue calculating a CRC32 (as used by gzip) with more data (starting at 0).
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, byte| {
+        CRC_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use uefi::Status;
+
+    use super::super::file::{self, File};
+    use super::super::progress::Style;
+    use super::{count, crc32, decompress, unpacked_size, Format};
+
+    /// "hello hello hello hello\n" (with a fixed Huffman code)
+    const FIXED: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48,
+        0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x00, 0x88, 0x59,
+        0x0b, 0x18, 0x00, 0x00, 0x00,
+    ];
+
+    /// `dynamic_content` (with its own Huffman code)
+    const DYNAMIC: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xed, 0x8f,
+        0xc9, 0x11, 0xc3, 0x00, 0x0c, 0x02, 0x6b, 0x45, 0x1c, 0xea, 0xbf, 0x83,
+        0x60, 0xe7, 0x70, 0x1e, 0xe9, 0x20, 0x7e, 0x69, 0x46, 0x02, 0xb4, 0x60,
+        0x4c, 0x7a, 0xf0, 0x1e, 0x91, 0xc2, 0xe1, 0xda, 0x2b, 0x19, 0xd9, 0x89,
+        0x97, 0x18, 0xa1, 0x92, 0xde, 0x44, 0x1d, 0xcb, 0xcd, 0x6a, 0x68, 0x8e,
+        0x36, 0x99, 0x04, 0x22, 0x7b, 0x33, 0x16, 0xac, 0x32, 0xae, 0xb5, 0x09,
+        0xc7, 0x52, 0x39, 0xd3, 0x9b, 0xb0, 0xd0, 0x80, 0xb5, 0x56, 0xf2, 0x6b,
+        0xbc, 0x24, 0x17, 0x07, 0xb5, 0x0e, 0xec, 0x26, 0x34, 0x76, 0x61, 0x9e,
+        0x4b, 0x9c, 0x2f, 0x2f, 0x80, 0x03, 0xa7, 0x00, 0x85, 0x53, 0xad, 0xcf,
+        0x27, 0xf5, 0x74, 0x59, 0xe2, 0xa3, 0x49, 0x0b, 0x7d, 0x38, 0xbe, 0xcb,
+        0xde, 0xd5, 0xef, 0xea, 0x7f, 0x52, 0xfd, 0x01, 0xaa, 0xb2, 0xa4, 0x61,
+        0xe8, 0x03, 0x00, 0x00,
+    ];
+
+    fn dynamic_content() -> Vec<u8> {
+        (0..1000u32).map(|i| (i * i % 251 % 7) as u8 + b'a').collect()
+    }
+
+    /// Pack the content into a gzip member with a single uncompressed block.
+    fn stored(content: &[u8]) -> Vec<u8> {
+        let length = content.len() as u16;
+        let mut member = vec![0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0, 0x03, 0x01];
+        member.extend(length.to_le_bytes());
+        member.extend((!length).to_le_bytes());
+        member.extend(content);
+        member.extend(crc32(0, content).to_le_bytes());
+        member.extend((content.len() as u32).to_le_bytes());
+        member
+    }
+
+    /// Decompress the data the way `file::read_into_allocations` does.
+    fn unpack(data: Vec<u8>) -> Result<Vec<u8>, Status> {
+        let mut file = File::in_memory("test.gz", data);
+        let (format, size) = unpacked_size(&mut file)?;
+        assert_eq!(format, Format::Gzip);
+        let mut output = vec![0; size];
+        match decompress(&mut file, &mut output, Style::None) {
+            Err(Status::BUFFER_TOO_SMALL) => {
+                output.resize(count(&mut file, Style::None)?, 0);
+                decompress(&mut file, &mut output, Style::None)?;
+            },
+            result => result?,
+        }
+        Ok(output)
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn blocks() {
+        assert_eq!(unpack(FIXED.to_vec()).unwrap(), b"hello hello hello hello\n");
+        assert_eq!(unpack(DYNAMIC.to_vec()).unwrap(), dynamic_content());
+        assert_eq!(unpack(stored(b"towboot")).unwrap(), b"towboot");
+        assert_eq!(unpack(stored(b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn members() {
+        // the trailer only has the size of the last member, so this needs counting
+        let mut data = stored(b"towboot ");
+        data.extend(DYNAMIC);
+        data.extend(FIXED);
+        let mut expected = b"towboot ".to_vec();
+        expected.extend(dynamic_content());
+        expected.extend(b"hello hello hello hello\n");
+        assert_eq!(unpack(data.clone()).unwrap(), expected);
+        // zeroes after a member are ignored
+        data.extend([0; 9]);
+        assert_eq!(unpack(data).unwrap(), expected);
+    }
+
+    #[test]
+    fn small_chunks() {
+        file::set_chunk_size(3);
+        let result = unpack(DYNAMIC.to_vec());
+        file::set_chunk_size(1024 * 1024);
+        assert_eq!(result.unwrap(), dynamic_content());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut checksum = DYNAMIC.to_vec();
+        checksum[DYNAMIC.len() - 8] ^= 1;
+        let mut content = DYNAMIC.to_vec();
+        content[40] ^= 0xff;
+        let mut method = FIXED.to_vec();
+        method[2] = 7;
+        let mut garbage = FIXED.to_vec();
+        garbage.extend(b"junk");
+        let truncated = DYNAMIC[..80].to_vec();
+        for data in [checksum, content, method, garbage, truncated] {
+            let mut file = File::in_memory("test.gz", data);
+            assert_eq!(count(&mut file, Style::None), Err(Status::LOAD_ERROR));
+        }
+        // the trailer says 24 bytes
+        let mut file = File::in_memory("test.gz", FIXED.to_vec());
+        let mut output = vec![0; 25];
+        assert_eq!(decompress(&mut file, &mut output, Style::None), Err(Status::LOAD_ERROR));
+    }
+
+    #[test]
+    fn formats() {
+        let mut file = File::in_memory("test", b"not compressed".to_vec());
+        assert_eq!(unpacked_size(&mut file), Ok((Format::Uncompressed, 14)));
+        let mut file = File::in_memory("test.xz", b"\xfd7zXZ\0 and more".to_vec());
+        assert_eq!(unpacked_size(&mut file), Err(Status::UNSUPPORTED));
+    }
+}
@@ -0,0 +1,119 @@
+//! Translation of older configuration schemas.
+//!
+//! Configuration files declare the version of the schema they're written for
+//! in the `config_version` key. (If it's missing, version 1 is assumed.)
+//! Before deserializing, keys that have been renamed since that version are
+//! moved to their new name and keys that have been removed are dropped,
+//! each with a warning telling the user what to change.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use log::{info, warn};
+
+use toml::Value;
+use toml::value::Table;
+
+/// The version of the configuration schema this build understands.
+pub(super) const CURRENT_VERSION: i64 = 2;
+
+/// A change to the configuration schema.
+enum Change {
+    /// The key has been renamed. The value is kept.
+    Renamed(&'static str),
+    /// The key has been removed. The string explains what to do instead.
+    #[allow(dead_code)] // nothing has been removed yet
+    Removed(&'static str),
+}
+
+/// All changes to the schema.
+///
+/// Each change has the version that introduced it and the path of the key.
+/// `*` in a path matches every key on this level.
+const CHANGES: &[(i64, &str, Change)] = &[
+    // This was documented as `quirk`, but the key has always been `quirks`.
+    (2, "entries.*.quirk", Change::Renamed("quirks")),
+];
+
+/// Translate a parsed configuration to the current schema.
+///
+/// This modifies the value in place and logs a warning for every change.
+pub(super) fn migrate(config: &mut Value) {
+    let table = match config.as_table_mut() {
+        Some(t) => t,
+        None => return, // deserializing will fail later
+    };
+    let version = table.get("config_version").and_then(Value::as_integer).unwrap_or(1);
+    if version > CURRENT_VERSION {
+        warn!(
+            "the configuration is for version {version}, but this build only knows version {}",
+            CURRENT_VERSION,
+        );
+        warn!("unknown keys will be ignored");
+        return
+    }
+    if version < CURRENT_VERSION {
+        info!("translating configuration from version {version} to {CURRENT_VERSION}");
+    }
+    for (changed_in, path, change) in CHANGES {
+        let path: Vec<&str> = path.split('.').collect();
+        apply(table, &path, String::new(), *changed_in, version, change);
+    }
+}
+
+/// Apply a change to all keys matching the path.
+fn apply(
+    table: &mut Table, path: &[&str], prefix: String,
+    changed_in: i64, version: i64, change: &Change,
+) {
+    match path {
+        [] => (),
+        [key] => {
+            let full_key = format!("{prefix}{key}");
+            if let Some(value) = table.remove(*key) {
+                if version >= changed_in {
+                    // The file claims to be new enough, so don't translate.
+                    match change {
+                        Change::Renamed(new) => warn!(
+                            "ignoring unknown key '{full_key}' (it has been renamed to '{new}' in version {changed_in})"
+                        ),
+                        Change::Removed(reason) => warn!(
+                            "ignoring unknown key '{full_key}' (it has been removed in version {changed_in}: {reason})"
+                        ),
+                    }
+                    return
+                }
+                match change {
+                    Change::Renamed(new) => {
+                        if table.contains_key(*new) {
+                            warn!(
+                                "ignoring '{full_key}' as '{prefix}{new}' is also set (it has been renamed in version {changed_in})"
+                            );
+                        } else {
+                            warn!(
+                                "'{full_key}' has been renamed to '{prefix}{new}' in version {changed_in}, please update your configuration"
+                            );
+                            table.insert(String::from(*new), value);
+                        }
+                    },
+                    Change::Removed(reason) => warn!(
+                        "'{full_key}' has been removed in version {changed_in}: {reason}"
+                    ),
+                }
+            }
+        },
+        ["*", rest @ ..] => {
+            for (key, value) in table.iter_mut() {
+                if let Some(inner) = value.as_table_mut() {
+                    apply(inner, rest, format!("{prefix}{key}."), changed_in, version, change);
+                }
+            }
+        },
+        [key, rest @ ..] => {
+            if let Some(inner) = table.get_mut(*key).and_then(Value::as_table_mut) {
+                apply(inner, rest, format!("{prefix}{key}."), changed_in, version, change);
+            }
+        },
+    }
+}
@@ -0,0 +1,782 @@
+//! This module contains structs and functions to load the configuration.
+//!
+//! The configuration can come from a file or from the command line.
+//! The command line options take precedence if they are specified.
+
+use core::fmt::Write;
+
+use alloc::collections::{btree_map::BTreeMap, btree_set::BTreeSet};
+use alloc::fmt;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use log::{trace, debug, error};
+
+use uefi::prelude::*;
+use uefi::CStr16;
+use uefi::proto::media::file::Directory;
+use uefi::table::runtime::VariableVendor;
+
+use miniarg::{ArgumentIterator, Key};
+
+use serde::{Deserialize, Deserializer, de::{self, IntoDeserializer, Unexpected, Visitor, value}};
+
+use super::file::File;
+use super::firmware::system_table;
+
+mod migration;
+
+#[allow(dead_code)]
+mod built_info {
+    include!(concat!(env!("OUT_DIR"), "/built.rs"));
+}
+
+const CONFIG_FILE: &str = "\\towboot.toml";
+const CONFIG_FILE_JSON: &str = "\\towboot.json";
+
+/// Get the config.
+/// If we were called with command line options, try them first.
+/// Otherwise, read and parse a configuration file.
+///
+/// Returns None if just a help text has been displayed.
+pub fn get(
+    volume: &mut Directory, load_options: Option<&str>,
+) -> Result<Option<Config>, Status> {
+    let config_source: ConfigSource = match load_options {
+        Some(lo) => match parse_load_options(lo)? {
+            Some(cs) => cs,
+            None => return Ok(None),
+        },
+        // fall back to the hardcoded config file
+        None => ConfigSource::Default,
+    };
+    let mut config = match config_source {
+        ConfigSource::File(s) => read_file(volume, &s)?,
+        ConfigSource::Default => if !File::exists(CONFIG_FILE, volume)
+        && File::exists(CONFIG_FILE_JSON, volume) {
+            read_file(volume, CONFIG_FILE_JSON)?
+        } else {
+            read_file(volume, CONFIG_FILE)?
+        },
+        ConfigSource::Given(c) => c,
+    };
+    // drop all entries whose conditions don't apply to this machine
+    config.entries.retain(|key, entry| match &entry.condition {
+        Some(condition) => if condition.is_met(volume) {
+            true
+        } else {
+            debug!("skipping entry '{key}' as its conditions are not met");
+            false
+        },
+        None => true,
+    });
+    Ok(Some(config))
+}
+
+/// Try to read and parse the configuration from the given file.
+///
+/// Files ending in `.json` are parsed as JSON, everything else as TOML.
+fn read_file(volume: &mut Directory, file_name: &str) -> Result<Config, Status> {
+    let text: Vec<u8> = File::open(file_name, volume)?.try_into()?;
+    // parse into a generic value first, so that older schemas can be translated
+    let mut value: toml::Value = if file_name.to_lowercase().ends_with(".json") {
+        let mut json = serde_json::from_slice(text.as_slice()).map_err(|e| {
+            error!("failed to parse config file '{file_name}': {e}");
+            Status::INVALID_PARAMETER
+        })?;
+        remove_nulls(&mut json);
+        toml::Value::deserialize(json).map_err(|e| {
+            error!("failed to parse config file '{file_name}': {e}");
+            Status::INVALID_PARAMETER
+        })?
+    } else {
+        toml::from_slice(text.as_slice()).map_err(|e| {
+            error!("failed to parse config file '{file_name}': {e}");
+            Status::INVALID_PARAMETER
+        })?
+    };
+    migration::migrate(&mut value);
+    value.try_into().map_err(|e| {
+        error!("invalid config file '{file_name}': {e}");
+        Status::INVALID_PARAMETER
+    })
+}
+
+/// Remove the nulls from a parsed JSON configuration.
+///
+/// TOML has nothing like `null`, so a key that is set to it is treated as if
+/// it were missing. (Nulls in arrays are dropped.)
+fn remove_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(remove_nulls);
+        },
+        serde_json::Value::Array(array) => {
+            array.retain(|value| !value.is_null());
+            array.iter_mut().for_each(remove_nulls);
+        },
+        _ => (),
+    }
+}
+
+/// Parse the command line options.
+///
+/// See [`LoadOptionKey`] for available options.
+///
+/// This function errors, if the command line options are not valid.
+/// That is:
+/// * general reasons
+/// * keys without values
+/// * values without keys
+/// * invalid keys
+///
+/// [`LoadOptionKey`]: enum.LoadOptionKey.html
+fn parse_load_options(
+    load_options: &str,
+) -> Result<Option<ConfigSource>, Status> {
+    let options = LoadOptionKey::parse(load_options);
+    let mut config_file = None;
+    let mut kernel = None;
+    let mut log_level = None;
+    let mut modules = Vec::<&str>::new();
+    let mut quirks = BTreeSet::<Quirk>::new();
+    for option in options {
+        match option {
+            Ok((key, value)) => {
+                trace!("option: {key} => {value}");
+                match key {
+                    LoadOptionKey::Config => config_file = Some(value),
+                    LoadOptionKey::Kernel => kernel = Some(value),
+                    LoadOptionKey::LogLevel => log_level = Some(value),
+                    LoadOptionKey::Module => modules.push(value),
+                    LoadOptionKey::Quirk => {
+                        let parsed: Result<Quirk, value::Error> = Quirk::deserialize(
+                            value.into_deserializer()
+                        );
+                        if let Ok(parsed) = parsed {
+                            quirks.insert(parsed);
+                        } else {
+                            error!("invalid value for quirk: {value}");
+                            return Err(Status::INVALID_PARAMETER);
+                        }
+                    },
+                    LoadOptionKey::Help => {
+                        writeln!(
+                            unsafe { system_table().as_mut() }.stdout(),
+                            "Usage:\n{}", LoadOptionKey::help_text(),
+                        ).unwrap();
+                        return Ok(None)
+                    },
+                    LoadOptionKey::Version => {
+                        writeln!(
+                            unsafe { system_table().as_mut() }.stdout(),
+                            "This is {} {}{}, built as {} for {} on {}. It is licensed under the {}.",
+                            built_info::PKG_NAME,
+                            built_info::GIT_VERSION.unwrap(),
+                            if built_info::GIT_DIRTY.unwrap() {
+                                " (dirty)"
+                            } else {
+                                ""
+                            },
+                            built_info::PROFILE,
+                            built_info::TARGET,
+                            built_info::HOST,
+                            built_info::PKG_LICENSE,
+                        ).unwrap();
+                        return Ok(None)
+                    }
+                }
+            },
+            Err(e) => {
+                error!("failed parsing load options: {e:?}");
+                return Err(Status::INVALID_PARAMETER)
+            },
+        }
+    }
+    if let Some(kernel) = kernel {
+        let modules = modules.iter().map(|m| {
+            let (image, argv) = m.split_once(' ').unwrap_or((m, ""));
+            Module {
+                image: vec![image.to_string()],
+                directory: None,
+                argv: Some(argv.to_string()),
+                load_at: None,
+                random_seed: None,
+                decompress: false,
+            }
+        }).collect();
+        let (kernel_image, kernel_argv) = kernel.split_once(' ').unwrap_or((kernel, ""));
+        let mut entries = BTreeMap::new();
+        entries.insert("cli".to_string(), Entry {
+            argv: Some(kernel_argv.to_string()),
+            image: kernel_image.to_string(),
+            name: None,
+            quirks,
+            modules,
+            condition: None,
+            group: None,
+            hidden: false,
+            tries: None,
+            fallback: None,
+            resolution: None,
+            set_vars: Vec::new(),
+        });
+        Ok(Some(ConfigSource::Given(Config {
+            default: "cli".to_string(),
+            timeout: Some(0),
+            log_level: log_level.map(ToString::to_string),
+            log_targets: None,
+            log_file_size: None,
+            known_quirks: None,
+            prefer_last_successful: None,
+            menu: None,
+            background: None,
+            font: None,
+            reveal_key: None,
+            actions: None,
+            language: None,
+            serial: None,
+            serial_baud_rate: None,
+            beep: None,
+            beep_fallback: None,
+            validate: None,
+            watchdog: None,
+            reserved_memory: None,
+            read_chunk_size: None,
+            read_retries: None,
+            read_retry_delay: None,
+            show_timing: None,
+            resume: None,
+            theme: Theme::default(),
+            entries
+        })))
+    } else if let Some(c) = config_file {
+        Ok(Some(ConfigSource::File(c.to_string())))
+    } else {
+        Ok(Some(ConfigSource::Default))
+    }
+}
+
+enum ConfigSource {
+    /// `towboot.toml` or `towboot.json`, whichever exists
+    Default,
+    File(String),
+    Given(Config),
+}
+
+/// Available options.
+#[derive(Debug, Key)]
+enum LoadOptionKey {
+    /// Load the specified configuration file instead of the default one.
+    Config,
+    /// Don't load a configuration file, instead boot the specified kernel.
+    Kernel,
+    /// Set the log level. (This only applies if `-kernel` is specified.)
+    LogLevel,
+    /// Load a module with the given args. Can be specified multiple times.
+    Module,
+    /// Enable a specific quirk. (Only applies when loading a kernel.)
+    Quirk,
+    /// Displays all available options and how to use them.
+    Help,
+    /// Displays the version of towboot
+    Version,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    pub default: String,
+    /// How many seconds to wait before booting the default entry.
+    /// (`None` means waiting forever, this can be set as `-1` or `"none"`.)
+    #[serde(default, deserialize_with = "deserialize_timeout")]
+    pub timeout: Option<u8>,
+    /// How much to log (`error`, `warn`, `info`, `debug` or `trace`). (default: `trace`)
+    pub log_level: Option<String>,
+    /// Where to write the log to. (default: the console)
+    pub log_targets: Option<Vec<LogTarget>>,
+    /// How large the log file may get before it's rotated (in KiB). (default: 256)
+    pub log_file_size: Option<usize>,
+    /// Whether to apply quirks for known kernels automatically. (default: true)
+    pub known_quirks: Option<bool>,
+    /// Whether to prefer the entry that booted successfully the last time
+    /// over the default one. (default: false)
+    pub prefer_last_successful: Option<bool>,
+    /// How to display the menu. (default: text)
+    pub menu: Option<MenuType>,
+    /// A BMP image to display behind the graphical menu.
+    pub background: Option<String>,
+    /// A PSF font for the graphical menu. (default: the bundled one)
+    pub font: Option<String>,
+    /// The function key that reveals hidden entries in the menu. (default: "F8")
+    pub reveal_key: Option<String>,
+    /// The actions to list in the menu after the entries. (default: all supported ones)
+    pub actions: Option<Vec<Action>>,
+    /// The language of the menu. (`en` or `de`, default: `en`)
+    pub language: Option<String>,
+    /// Whether to mirror the menu to the serial console. (default: false)
+    pub serial: Option<bool>,
+    /// The baud rate of the serial port for the log.
+    /// (default: what the firmware has set, or 115200 if towboot drives it itself)
+    pub serial_baud_rate: Option<u64>,
+    /// Whether to beep when the menu is opened, the selection changes
+    /// and an entry is booted. (default: false)
+    pub beep: Option<bool>,
+    /// How to beep if the firmware can't. (default: `pc-speaker`)
+    pub beep_fallback: Option<BeepFallback>,
+    /// Whether to check the files of all entries before displaying the menu.
+    /// (default: false)
+    pub validate: Option<bool>,
+    /// After how many seconds the firmware should reset the machine
+    /// if no entry has been booted. (default: 0, never)
+    pub watchdog: Option<usize>,
+    /// Memory the kernel shouldn't use (and that towboot doesn't use, either).
+    pub reserved_memory: Option<Vec<ReservedMemory>>,
+    /// How much to read from a file at once (in KiB). (default: 1024)
+    pub read_chunk_size: Option<usize>,
+    /// How often to try again if opening or reading a file fails
+    /// because of the disk (eg. a flaky USB stick). (default: 3)
+    pub read_retries: Option<usize>,
+    /// How long to wait before trying again (in milliseconds). (default: 500)
+    pub read_retry_delay: Option<usize>,
+    /// Whether to show how long each step has taken before booting. (default: false)
+    pub show_timing: Option<bool>,
+    /// Which entry resumes a hibernated system (and how to find out whether it is).
+    pub resume: Option<Resume>,
+    /// How the menu looks.
+    #[serde(default)]
+    pub theme: Theme,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+/// Parse the timeout, which may be `-1` or `"none"` to wait forever.
+fn deserialize_timeout<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+    struct TimeoutVisitor;
+    
+    impl<'de> Visitor<'de> for TimeoutVisitor {
+        type Value = Option<u8>;
+        
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "a number of seconds, -1 or \"none\"")
+        }
+        
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+            match v {
+                -1 => Ok(None),
+                _ => u8::try_from(v).map(Some)
+                    .map_err(|_| E::invalid_value(Unexpected::Signed(v), &self)),
+            }
+        }
+        
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+            u8::try_from(v).map(Some).map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+        }
+        
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            match v {
+                "none" => Ok(None),
+                _ => Err(E::invalid_value(Unexpected::Str(v), &self)),
+            }
+        }
+    }
+    
+    deserializer.deserialize_any(TimeoutVisitor)
+}
+
+/// A range of physical memory that is passed to the kernel as reserved.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReservedMemory {
+    /// Where it starts.
+    pub start: u64,
+    /// How large it is (in bytes).
+    pub size: u64,
+}
+
+/// Booting a hibernated system without displaying the menu.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Resume {
+    /// The entry to boot if the system is hibernated.
+    pub entry: String,
+    /// The unique GUID of the swap partition the system hibernates to.
+    pub swap: String,
+}
+
+/// The kinds of menus.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MenuType {
+    /// Use the text console.
+    Text,
+    /// Draw directly to the framebuffer.
+    Graphical,
+    /// Display nothing during the timeout and the text menu if a key is pressed.
+    Hidden,
+}
+
+/// Where the log can go.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    /// The text console.
+    Console,
+    /// `\EFI\towboot\towboot.log` on the volume we've been loaded from.
+    File,
+    /// The serial port.
+    Serial,
+    /// QEMU's debug console (or Bochs' port 0xE9 hack). (This only works on x86.)
+    Debugcon,
+    /// A module named `towboot.log` for the kernel. (This only works for Multiboot kernels.)
+    Kernel,
+}
+
+/// Ways to beep without the firmware.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BeepFallback {
+    /// Use the PC speaker. (This only works on x86.)
+    PcSpeaker,
+    /// Send a bell character to the console.
+    Console,
+    /// Don't beep at all.
+    None,
+}
+
+/// Things the menu can do besides booting an entry.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Reboot the machine.
+    Reboot,
+    /// Power the machine off.
+    Poweroff,
+    /// Reboot into the firmware's setup.
+    Firmware,
+    /// Test the memory. (This is not listed by default.)
+    Memtest,
+}
+
+/// How the menu looks.
+///
+/// This applies to both the text and the graphical menu.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Theme {
+    /// The title above the entries. (default: "towboot")
+    pub title: Option<String>,
+    /// Some text to display below the title. (It may contain multiple lines.)
+    pub banner: Option<String>,
+    /// The color of the text. (default: light gray)
+    pub foreground: Option<ThemeColor>,
+    /// The color behind the text. (default: black)
+    pub background: Option<ThemeColor>,
+    /// The color of the text of the selected entry. (default: black)
+    pub highlight_foreground: Option<ThemeColor>,
+    /// The color behind the selected entry. (default: light gray)
+    pub highlight_background: Option<ThemeColor>,
+    /// How many columns to leave empty on the left. (default: 2)
+    pub margin_left: Option<usize>,
+    /// How many lines to leave empty at the top. (default: 1)
+    pub margin_top: Option<usize>,
+}
+
+/// The colors of the text console.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThemeColor {
+    Black,
+    Blue,
+    Green,
+    Cyan,
+    Red,
+    Magenta,
+    Brown,
+    LightGray,
+    DarkGray,
+    LightBlue,
+    LightGreen,
+    LightCyan,
+    LightRed,
+    LightMagenta,
+    Yellow,
+    White,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub argv: Option<String>,
+    pub image: String,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub quirks: BTreeSet<Quirk>,
+    #[serde(default)]
+    pub modules: Vec<Module>,
+    pub condition: Option<Condition>,
+    /// The submenu to list this entry in.
+    pub group: Option<String>,
+    /// Whether to list this entry only after the reveal key has been pressed.
+    #[serde(default)]
+    pub hidden: bool,
+    /// How often this entry may be booted without being marked as good.
+    pub tries: Option<u8>,
+    /// The entry to boot instead if there are no tries left.
+    pub fallback: Option<String>,
+    /// The resolution to use if the kernel doesn't want one or if its one
+    /// isn't available (like `"1024x768"`).
+    #[serde(default, deserialize_with = "deserialize_resolution")]
+    pub resolution: Option<(usize, usize)>,
+    /// UEFI variables to set right before booting this entry.
+    #[serde(default)]
+    pub set_vars: Vec<SetVariable>,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name.as_ref().unwrap_or(&self.image))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Module {
+    pub argv: Option<String>,
+    /// The files to load. If there are multiple ones, they're concatenated.
+    /// (This can be set as a single string or as a list.)
+    #[serde(default, deserialize_with = "deserialize_images")]
+    pub image: Vec<String>,
+    /// A directory to pack into a cpio archive, which is appended to the files.
+    pub directory: Option<String>,
+    /// The physical address to load the module to. (default: anywhere below 4 GB)
+    pub load_at: Option<u64>,
+    /// How many random bytes to append (eg. as a seed for the kernel).
+    pub random_seed: Option<usize>,
+    /// Whether to decompress the files while loading them (only gzip is supported).
+    /// (default: false)
+    #[serde(default)]
+    pub decompress: bool,
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = self.image.clone();
+        if let Some(directory) = &self.directory {
+            parts.push(format!("{directory}\\"));
+        }
+        if let Some(length) = self.random_seed {
+            parts.push(format!("{length} random bytes"));
+        }
+        write!(f, "{}", parts.join(" + "))
+    }
+}
+
+/// A UEFI variable to set before booting.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SetVariable {
+    pub name: String,
+    /// The vendor GUID (like `8be4df61-93ca-11d2-aa0d-00e098032b8c`). (default: towboot's)
+    pub guid: Option<String>,
+    /// The content. (This can be set as a string, which is written as UTF-8,
+    /// or as a list of bytes.)
+    #[serde(deserialize_with = "deserialize_value")]
+    pub value: Vec<u8>,
+    /// Whether to restore the previous content if the entry can't be booted.
+    /// (default: false)
+    #[serde(default)]
+    pub restore: bool,
+}
+
+/// Parse the content of a variable, which may be a string or a list of bytes.
+fn deserialize_value<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    struct ValueVisitor;
+    
+    impl<'de> Visitor<'de> for ValueVisitor {
+        type Value = Vec<u8>;
+        
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "a string or a list of bytes")
+        }
+        
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(v.as_bytes().to_vec())
+        }
+        
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::new();
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+    
+    deserializer.deserialize_any(ValueVisitor)
+}
+
+/// Parse the image of a module, which may be a single file or a list of them.
+fn deserialize_images<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    struct ImagesVisitor;
+    
+    impl<'de> Visitor<'de> for ImagesVisitor {
+        type Value = Vec<String>;
+        
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "a file name or a non-empty list of them")
+        }
+        
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(vec![v.to_string()])
+        }
+        
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut images = Vec::new();
+            while let Some(image) = seq.next_element()? {
+                images.push(image);
+            }
+            if images.is_empty() {
+                return Err(de::Error::invalid_length(0, &self))
+            }
+            Ok(images)
+        }
+    }
+    
+    deserializer.deserialize_any(ImagesVisitor)
+}
+
+/// Parse a resolution like `1024x768`.
+fn deserialize_resolution<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<(usize, usize)>, D::Error> {
+    struct ResolutionVisitor;
+    
+    impl<'de> Visitor<'de> for ResolutionVisitor {
+        type Value = Option<(usize, usize)>;
+        
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "a resolution like \"1024x768\"")
+        }
+        
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            v.split_once('x')
+                .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                .filter(|(width, height)| *width != 0 && *height != 0)
+                .map(Some)
+                .ok_or_else(|| E::invalid_value(Unexpected::Str(v), &self))
+        }
+    }
+    
+    deserializer.deserialize_str(ResolutionVisitor)
+}
+
+/// Facts about the platform an entry can depend on.
+///
+/// All specified conditions have to be met for an entry to be available.
+/// They are evaluated once, when the configuration is loaded.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Condition {
+    /// The architecture towboot has been built for (`i686`, `x86_64` or `aarch64`).
+    pub arch: Option<String>,
+    /// A part of the firmware vendor string (case-sensitive).
+    pub firmware_vendor: Option<String>,
+    /// Whether Secure Boot has to be enabled or disabled.
+    pub secure_boot: Option<bool>,
+    /// A file that has to exist on the volume we're loaded from.
+    pub file_exists: Option<String>,
+}
+
+impl Condition {
+    /// Check whether this condition is met on the current machine.
+    fn is_met(&self, volume: &mut Directory) -> bool {
+        if let Some(arch) = &self.arch {
+            if arch != ARCH {
+                return false
+            }
+        }
+        if let Some(vendor) = &self.firmware_vendor {
+            let firmware_vendor = unsafe { system_table().as_ref() }
+                .firmware_vendor().to_string();
+            if !firmware_vendor.contains(vendor.as_str()) {
+                return false
+            }
+        }
+        if let Some(secure_boot) = self.secure_boot {
+            if secure_boot != secure_boot_enabled() {
+                return false
+            }
+        }
+        if let Some(file) = &self.file_exists {
+            if !File::exists(file, volume) {
+                return false
+            }
+        }
+        true
+    }
+}
+
+/// The architecture we're running on, as used in conditions.
+#[cfg(target_arch = "x86")]
+const ARCH: &str = "i686";
+#[cfg(target_arch = "x86_64")]
+const ARCH: &str = "x86_64";
+#[cfg(target_arch = "aarch64")]
+const ARCH: &str = "aarch64";
+
+/// Check whether Secure Boot is enabled.
+///
+/// If the `SecureBoot` variable can't be read, this assumes that it's disabled.
+fn secure_boot_enabled() -> bool {
+    let mut name_buf = [0; 16];
+    let name = CStr16::from_str_with_buf("SecureBoot", &mut name_buf).unwrap();
+    let mut buf = [0; 1];
+    match unsafe { system_table().as_ref() }.runtime_services().get_variable(
+        name, &VariableVendor::GLOBAL_VARIABLE, &mut buf,
+    ) {
+        Ok((value, _attributes)) => value == [1],
+        Err(e) => {
+            debug!("failed to read the SecureBoot variable: {e:?}");
+            false
+        },
+    }
+}
+
+/// Runtime options to override information in kernel images.
+#[derive(Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Quirk {
+    /// Treat the kernel always as an ELF file.
+    /// This ignores bit 16 of the kernel's Multiboot header.
+    ForceElf,
+    /// Ignore the kernel's preferred resolution and just keep the current one.
+    KeepResolution,
+    /// Pass the memory map to the kernel as it is, without merging adjacent entries.
+    KeepMemoryMapEntries,
+    /// Don't set up the video and don't tell the kernel about a framebuffer.
+    NoFramebuffer,
+    /// Place modules below 200 MB.
+    ModulesBelow200Mb,
+    /// Place modules anywhere, also above 4 GB.
+    /// This is only for kernels that can handle 64-bit addresses (not for Multiboot).
+    ModulesAbove4Gb,
+    /// Mask the legacy PICs and the local APIC timer before jumping to the kernel.
+    MaskInterrupts,
+    /// Log the Multiboot information (and the memory map) before exiting boot services.
+    DumpMultibootInformation,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::{remove_nulls, Config};
+
+    #[test]
+    fn json_nulls() {
+        let mut json = serde_json::from_str(r#"{
+            "default": "a", "timeout": null, "log_targets": [null, "debugcon"],
+            "entries": { "a": { "image": "\\kernel", "argv": null, "modules": [null] } }
+        }"#).unwrap();
+        remove_nulls(&mut json);
+        let config: Config = toml::Value::deserialize(json).unwrap().try_into().unwrap();
+        assert_eq!(config.timeout, None);
+        assert_eq!(config.log_targets.map(|t| t.len()), Some(1));
+        assert_eq!(config.entries["a"].argv, None);
+        assert!(config.entries["a"].modules.is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+//! Packing directories into cpio archives
+//!
+//! Linux reads its initramfs from `newc` cpio archives (and accepts several of
+//! them concatenated). So, a directory on the ESP can be passed as a module
+//! without having to build the archive beforehand, eg. to add a few config files.
+//!
+//! All files get mode 0644 and directories 0755, owned by root. The timestamps
+//! are always 0, so the archive only depends on the contents.
+//!
+//! Archives can also be read (that's how embedded files are stored, see `embedded`,
+//! and what `loop:` paths look into, see `uri`). Paths in them are compared
+//! case-insensitively, like on FAT.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::proto::media::file::Directory;
+
+use log::debug;
+
+use super::file::{self, File};
+
+/// the magic number of the `newc` format
+const MAGIC: &str = "070701";
+/// the name of the last entry
+const TRAILER: &str = "TRAILER!!!";
+
+/// the size of an entry's header (the magic number and 13 fields of 8 hex digits)
+const HEADER_SIZE: usize = 6 + 13 * 8;
+
+const MODE_DIRECTORY: u32 = 0o040755;
+const MODE_FILE: u32 = 0o100644;
+/// the bits of the mode containing the type
+const MODE_TYPE: u32 = 0o170000;
+
+/// Pack a directory (and everything below it) into an archive.
+///
+/// The paths in the archive are relative to the directory.
+pub(crate) fn archive(path: &str, volume: &mut Directory) -> Result<Vec<u8>, Status> {
+    let mut archive = Vec::new();
+    let mut inode = 1;
+    add_directory(path.trim_end_matches('\\'), "", volume, &mut archive, &mut inode)?;
+    add_entry(&mut archive, 0, TRAILER, 0, &[]);
+    debug!("packed '{path}' into {} bytes", archive.len());
+    Ok(archive)
+}
+
+/// Iterate over the regular files in an archive, yielding their names and contents.
+///
+/// This stops at the trailer or at the first invalid entry.
+pub(crate) fn files(archive: &[u8]) -> impl Iterator<Item = (&str, &[u8])> {
+    let mut position = 0;
+    core::iter::from_fn(move || loop {
+        let header = archive.get(position..position + HEADER_SIZE)?;
+        if !header.starts_with(MAGIC.as_bytes()) {
+            return None
+        }
+        let field = |index: usize| core::str::from_utf8(&header[6 + index * 8..14 + index * 8])
+            .ok().and_then(|f| u32::from_str_radix(f, 16).ok());
+        let (mode, size, name_size) = (field(1)?, field(6)? as usize, field(11)? as usize);
+        let name_start = position + HEADER_SIZE;
+        let name = archive.get(name_start..name_start + name_size.checked_sub(1)?)
+            .and_then(|n| core::str::from_utf8(n).ok())?;
+        let content_start = align(name_start + name_size);
+        let content = archive.get(content_start..content_start + size)?;
+        position = align(content_start + size);
+        if name == TRAILER {
+            return None
+        }
+        if mode & MODE_TYPE == MODE_FILE & MODE_TYPE {
+            return Some((name, content))
+        }
+    })
+}
+
+/// Get the contents of a file in an archive.
+///
+/// The path may be written like one on the volume (eg. `\boot\kernel.elf`).
+pub(crate) fn get<'a>(archive: &'a [u8], path: &str) -> Option<&'a [u8]> {
+    let path = archive_path(path);
+    files(archive)
+        .find(|(name, _)| archive_path(name).eq_ignore_ascii_case(&path))
+        .map(|(_, content)| content)
+}
+
+/// List the contents of a directory in an archive (without `.` and `..`).
+///
+/// This returns the names and whether they are directories themselves.
+/// Directories only show up if there are files in them.
+pub(crate) fn list_directory(archive: &[u8], path: &str) -> Vec<(String, bool)> {
+    let directory = archive_path(path);
+    let mut entries: Vec<(String, bool)> = Vec::new();
+    for (name, _) in files(archive) {
+        let name = archive_path(name);
+        let rest = if directory.is_empty() {
+            Some(name.as_str())
+        } else {
+            name.get(..directory.len())
+                .filter(|start| start.eq_ignore_ascii_case(&directory))
+                .and_then(|_| name[directory.len()..].strip_prefix('/'))
+        };
+        let rest = match rest {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let entry = match rest.split_once('/') {
+            Some((subdirectory, _)) => (subdirectory.to_string(), true),
+            None => (rest.to_string(), false),
+        };
+        if !entries.iter().any(|(name, _)| name.eq_ignore_ascii_case(&entry.0)) {
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// Convert a path (on the volume or in an archive) to the form used in archives.
+///
+/// That's without leading (or trailing) slashes or `.` and with `/` as the separator.
+fn archive_path(path: &str) -> String {
+    path.replace('\\', "/").trim_start_matches("./").trim_matches('/').to_string()
+}
+
+/// Add the contents of a directory to the archive.
+///
+/// `path` is where it is on the volume, `prefix` where it ends up in the archive.
+fn add_directory(
+    path: &str, prefix: &str, volume: &mut Directory, archive: &mut Vec<u8>, inode: &mut u32,
+) -> Result<(), Status> {
+    for (name, is_directory) in file::list_directory(path, volume)? {
+        let source = format!("{path}\\{name}");
+        let destination = if prefix.is_empty() { name } else { format!("{prefix}/{name}") };
+        *inode += 1;
+        if is_directory {
+            add_entry(archive, *inode, &destination, MODE_DIRECTORY, &[]);
+            add_directory(&source, &destination, volume, archive, inode)?;
+        } else {
+            let content: Vec<u8> = File::open(&source, volume)?.try_into()?;
+            add_entry(archive, *inode, &destination, MODE_FILE, &content);
+        }
+    }
+    Ok(())
+}
+
+/// Append an entry (header, name and content) to the archive.
+fn add_entry(archive: &mut Vec<u8>, inode: u32, name: &str, mode: u32, content: &[u8]) {
+    let nlink = if mode == MODE_DIRECTORY { 2 } else { 1 };
+    // inode, mode, uid, gid, nlink, mtime, size, dev major and minor,
+    // rdev major and minor, name size (including the NUL) and the (unused) checksum
+    let fields = [
+        inode, mode, 0, 0, nlink, 0, content.len().try_into().unwrap(), 0, 0, 0, 0,
+        (name.len() + 1).try_into().unwrap(), 0,
+    ];
+    archive.extend_from_slice(MAGIC.as_bytes());
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    pad(archive);
+    archive.extend_from_slice(content);
+    pad(archive);
+}
+
+/// Pad the archive to a multiple of 4 bytes.
+fn pad(archive: &mut Vec<u8>) {
+    archive.resize(align(archive.len()), 0);
+}
+
+/// Round up to a multiple of 4 bytes.
+fn align(position: usize) -> usize {
+    (position + 3) / 4 * 4
+}
@@ -0,0 +1,90 @@
+//! Files embedded into towboot's image
+//!
+//! `towbootctl embed` adds a section called `.towboot` to the executable which
+//! contains a `newc` cpio archive (see `cpio`) with the configuration and the
+//! files it refers to. This way, a single (signed) binary can carry everything
+//! that's needed to boot, like a unified kernel image.
+//!
+//! Embedded files take precedence over the ones on the volume. Their paths
+//! are relative to the root of the volume and (like FAT) case-insensitive.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{debug, info, warn};
+
+use super::cpio;
+
+/// the name of the section
+const SECTION: &[u8; 8] = b".towboot";
+/// the offset of the offset of the PE header
+const PE_OFFSET: usize = 0x3c;
+/// the size of a section header
+const SECTION_HEADER_SIZE: usize = 40;
+
+static START: AtomicUsize = AtomicUsize::new(0);
+static LENGTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Look for embedded files in our own image.
+///
+/// This needs the image as it has been loaded to memory.
+pub(crate) fn init(image_base: usize, image_size: usize) {
+    // This is safe because the firmware told us that this is where we are.
+    let image = unsafe { core::slice::from_raw_parts(image_base as *const u8, image_size) };
+    match find_section(image) {
+        Some(archive) => {
+            START.store(archive.as_ptr() as usize, Ordering::Relaxed);
+            LENGTH.store(archive.len(), Ordering::Relaxed);
+            match cpio::files(archive).count() {
+                0 => warn!("the embedded archive doesn't contain any files"),
+                count => info!("found {count} embedded files"),
+            }
+        },
+        None => debug!("there are no embedded files"),
+    }
+}
+
+/// Find our section in the image.
+fn find_section(image: &[u8]) -> Option<&[u8]> {
+    let read_u16 = |offset: usize| image.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let read_u32 = |offset: usize| image.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+    let pe = read_u32(PE_OFFSET)? as usize;
+    if image.get(pe..pe + 4)? != b"PE\0\0" {
+        return None
+    }
+    let sections = read_u16(pe + 6)? as usize;
+    let table = pe + 24 + read_u16(pe + 20)? as usize;
+    (0..sections).map(|index| table + index * SECTION_HEADER_SIZE)
+        .find(|header| image.get(*header..header + 8) == Some(SECTION))
+        .and_then(|header| {
+            let size = read_u32(header + 8)? as usize;
+            let address = read_u32(header + 12)? as usize;
+            image.get(address..address + size)
+        })
+}
+
+/// Get the embedded archive (which is empty if there is none).
+fn archive() -> &'static [u8] {
+    match START.load(Ordering::Relaxed) {
+        0 => &[],
+        // This is safe because `init` got this from our image, which stays where it is.
+        start => unsafe {
+            core::slice::from_raw_parts(start as *const u8, LENGTH.load(Ordering::Relaxed))
+        },
+    }
+}
+
+/// Get the contents of an embedded file.
+pub(crate) fn get(path: &str) -> Option<&'static [u8]> {
+    cpio::get(archive(), path)
+}
+
+/// List the embedded contents of a directory (without `.` and `..`).
+///
+/// This returns the names and whether they are directories themselves.
+pub(crate) fn list_directory(path: &str) -> Vec<(String, bool)> {
+    cpio::list_directory(archive(), path)
+}
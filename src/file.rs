@@ -1,40 +1,331 @@
 //! File handling
+//!
+//! Files may be anywhere `uri` can point to, not just on our own volume.
 
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
 use alloc::collections::btree_set::BTreeSet;
 use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
 use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::iter::Peekable;
+use core::ptr;
+use core::str::Chars;
+use core::sync::atomic::{self, AtomicUsize};
 
-use log::{info, error};
+use log::{debug, info, warn, error};
 
 use uefi::prelude::*;
-use uefi::CStr16;
+use uefi::{CStr16, Event};
 use uefi::proto::media::file::{
-    Directory, File as UefiFile, FileAttribute, FileInfo, FileMode, FileType, RegularFile
+    Directory, File as UefiFile, FileAttribute, FileHandle, FileInfo, FileMode, FileType,
+    RegularFile,
 };
+use uefi::table::boot::{EventType, Tpl};
 
+use super::boot::timing::{self, Step};
+use super::compression;
 use super::config::Quirk;
+use super::cpio;
+use super::embedded;
+use super::firmware::system_table;
 use super::mem::Allocation;
+use super::progress::{Progress, Style};
+use super::uri::{self, Location};
+
+/// How much to read at once by default (so that we can display the progress).
+const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// how much to read at once (see `set_chunk_size`)
+static CHUNK_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_CHUNK_SIZE);
+
+/// Set how much to read at once.
+///
+/// Some firmware handles very large reads poorly, so they're split up.
+pub(crate) fn set_chunk_size(size: usize) {
+    if size == 0 {
+        warn!("the chunk size can't be 0, using the default");
+        return
+    }
+    CHUNK_SIZE.store(size, atomic::Ordering::Relaxed);
+}
+
+/// Get how much to read at once.
+pub(crate) fn chunk_size() -> usize {
+    CHUNK_SIZE.load(atomic::Ordering::Relaxed)
+}
+
+/// How often to try again by default if the disk fails.
+const DEFAULT_RETRIES: usize = 3;
+
+/// How long to wait before trying again by default (in microseconds).
+const DEFAULT_RETRY_DELAY: usize = 500_000;
+
+/// how often to try again (see `set_retries`)
+static RETRIES: AtomicUsize = AtomicUsize::new(DEFAULT_RETRIES);
+
+/// how long to wait before trying again (see `set_retry_delay`)
+static RETRY_DELAY: AtomicUsize = AtomicUsize::new(DEFAULT_RETRY_DELAY);
+
+/// Set how often to try again if opening or reading a file fails because of the disk.
+///
+/// Flaky USB sticks and slow SD cards sometimes fail once and work afterwards.
+pub(crate) fn set_retries(retries: usize) {
+    RETRIES.store(retries, atomic::Ordering::Relaxed);
+}
+
+/// Set how long to wait before trying again (in microseconds).
+pub(crate) fn set_retry_delay(delay: usize) {
+    RETRY_DELAY.store(delay, atomic::Ordering::Relaxed);
+}
+
+/// Whether an error might go away when trying again.
+fn is_transient(status: Status) -> bool {
+    matches!(
+        status,
+        Status::DEVICE_ERROR | Status::NO_MEDIA | Status::MEDIA_CHANGED
+        | Status::NOT_READY | Status::TIMEOUT
+    )
+}
+
+/// Run an operation on a volume, trying again if it fails because of the disk.
+///
+/// Before each retry, this waits and replaces the volume with the result of
+/// `reopen` (if that works), in case the device has been reset.
+fn with_retries<T>(
+    name: &str, volume: &mut Directory, reopen: impl Fn() -> Result<Directory, Status>,
+    mut operation: impl FnMut(&mut Directory) -> Result<T, Status>,
+) -> Result<T, Status> {
+    let retries = RETRIES.load(atomic::Ordering::Relaxed);
+    let mut attempt = 0;
+    loop {
+        match operation(volume) {
+            Err(status) if is_transient(status) && attempt < retries => {
+                attempt += 1;
+                warn!("trying to load '{name}' again ({attempt}/{retries})...");
+                wait_before_retry();
+                match reopen() {
+                    Ok(reopened) => *volume = reopened,
+                    Err(e) => debug!("failed to reopen the volume: {e:?}, using the old one"),
+                }
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Wait for the configured delay.
+fn wait_before_retry() {
+    let delay = RETRY_DELAY.load(atomic::Ordering::Relaxed);
+    unsafe { system_table().as_ref() }.boot_services().stall(delay);
+}
+
+/// The File Protocol
+///
+/// See section 13.5 of the UEFI Specification.
+/// (The `uefi` crate doesn't have `ReadEx`, which came with revision 2.)
+#[repr(C)]
+struct FileProtocol {
+    revision: u64,
+    // we don't need these
+    _open: usize,
+    _close: usize,
+    _delete: usize,
+    _read: usize,
+    _write: usize,
+    _get_position: usize,
+    _set_position: usize,
+    _get_info: usize,
+    _set_info: usize,
+    _flush: usize,
+    _open_ex: usize,
+    read_ex: extern "efiapi" fn(this: &mut FileProtocol, token: &mut FileIoToken) -> Status,
+}
+
+/// the first revision of the File Protocol that has `ReadEx`
+const FILE_PROTOCOL_REVISION2: u64 = 0x0002_0000;
+
+/// A read that may finish in the background.
+#[repr(C)]
+struct FileIoToken {
+    /// signalled when the read is done
+    event: Event,
+    status: Status,
+    buffer_size: usize,
+    buffer: *mut u8,
+}
+
+/// Get the protocol behind a file if it has `ReadEx`.
+fn file_protocol(file: &mut RegularFile) -> Option<&mut FileProtocol> {
+    // `FileHandle` is just a transparent wrapper around the pointer.
+    let protocol = unsafe {
+        &mut **(file.handle() as *mut FileHandle as *mut *mut FileProtocol)
+    };
+    (protocol.revision >= FILE_PROTOCOL_REVISION2).then_some(protocol)
+}
+
+impl FileIoToken {
+    /// Prepare reading a file in the background (if the firmware can do that).
+    fn new(file: &mut File) -> Option<Box<Self>> {
+        match &mut file.source {
+            Source::Volume(volume_file) => file_protocol(volume_file)?,
+            Source::Memory(_) => return None,
+        };
+        // This is safe because there is no callback.
+        match unsafe { system_table().as_ref().boot_services().create_event(
+            EventType::empty(), Tpl::APPLICATION, None, None
+        ) } {
+            Ok(event) => Some(Box::new(Self {
+                event, status: Status::SUCCESS, buffer_size: 0, buffer: ptr::null_mut(),
+            })),
+            Err(e) => {
+                debug!("failed to create an event for reading '{}': {e:?}", file.name);
+                None
+            },
+        }
+    }
+
+    /// Start filling the buffer with the contents of the file, starting at `position`.
+    ///
+    /// The buffer has to stay until the event has been signalled.
+    /// If this returns `false`, the file can't be read in the background.
+    fn start(&mut self, file: &mut File, position: usize, buffer: &mut [u8]) -> bool {
+        let volume_file = match &mut file.source {
+            Source::Volume(volume_file) => volume_file,
+            Source::Memory(_) => return false,
+        };
+        // `ReadEx` reads from the current position.
+        if let Err(e) = volume_file.set_position(position as u64) {
+            debug!("failed to seek in file '{}': {e:?}", file.name);
+            return false
+        }
+        let protocol = match file_protocol(volume_file) {
+            Some(protocol) => protocol,
+            None => return false,
+        };
+        self.status = Status::SUCCESS;
+        self.buffer_size = buffer.len();
+        self.buffer = buffer.as_mut_ptr();
+        let status = (protocol.read_ex)(protocol, self);
+        if status.is_error() {
+            debug!("failed to read '{}' in the background: {status:?}", file.name);
+        }
+        !status.is_error()
+    }
+
+    /// Check whether `length` bytes have been read (after the event has been signalled).
+    fn succeeded(&self, name: &str, length: usize) -> bool {
+        if self.status.is_error() || self.buffer_size != length {
+            debug!("failed to read '{name}' in the background: {:?}", self.status);
+            return false
+        }
+        true
+    }
+
+    /// Close the event.
+    fn close(self) {
+        if let Err(e) = unsafe { system_table().as_ref() }.boot_services()
+            .close_event(self.event) {
+            debug!("failed to close the event for reading in the background: {e:?}");
+        }
+    }
+}
+
+/// Wait until one of the reads in the background is done and return its index.
+///
+/// This panics if the firmware can't wait: The reads would go on, but their
+/// buffers might be freed after returning an error.
+fn wait_for_any(tokens: &[&FileIoToken]) -> usize {
+    // this is safe because the events are only closed in `FileIoToken::close`
+    let mut events: Vec<Event> = tokens.iter()
+        .map(|token| unsafe { token.event.unsafe_clone() })
+        .collect();
+    let start = timing::now();
+    let result = unsafe { system_table().as_ref() }.boot_services().wait_for_event(&mut events);
+    timing::add(Step::FileReads, start);
+    result.unwrap_or_else(|e| panic!("failed to wait for reading files: {e:?}"))
+}
 
 /// An opened file.
 pub(crate) struct File<'a> {
     name: &'a str,
-    file: RegularFile,
+    source: Source,
     size: usize,
 }
 
+/// Where the contents of a file come from.
+enum Source {
+    /// a file on a volume
+    Volume(RegularFile),
+    /// a file that's already in memory (embedded into our image, downloaded
+    /// or taken from an archive)
+    Memory(Cow<'static, [u8]>),
+}
+
 impl<'a> File<'a> {
     /// Opens a file.
     ///
-    /// The path is relative to the volume we're loaded from.
+    /// The path is relative to the volume we're loaded from, unless it has a
+    /// scheme (see `uri`). Embedded files are preferred.
     ///
     /// Possible errors:
     /// * `Status::NOT_FOUND`: the file does not exist
     /// * `Status::UNSUPPORTED`: the given path does exist, but it's a directory
+    ///   (or the scheme isn't supported)
+    /// * `Status::INVALID_PARAMETER`: the path can't be parsed
+    /// * `Status::DEVICE_ERROR` (or similar): the disk has failed, even after
+    ///   trying again (see `set_retries`)
     pub(crate) fn open(name: &'a str, volume: &mut Directory) -> Result<Self, Status> {
-        info!("loading file '{name}'...");
+        let content = match Location::parse(name)? {
+            Location::Local(path) => match embedded::get(path) {
+                Some(content) => {
+                    info!("loading embedded file '{name}'...");
+                    Cow::Borrowed(content)
+                },
+                None => {
+                    info!("loading file '{name}'...");
+                    return with_retries(
+                        name, volume, uri::reopen_volume,
+                        |volume| Self::open_on_volume(name, path, volume),
+                    )
+                },
+            },
+            Location::Partition { guid, path } => {
+                info!("loading file '{name}'...");
+                return with_retries(
+                    name, &mut uri::open_partition(&guid)?, || uri::open_partition(&guid),
+                    |volume| Self::open_on_volume(name, path, volume),
+                )
+            },
+            Location::Tftp { server, path } => {
+                info!("downloading '{name}'...");
+                Cow::Owned(uri::tftp_read(server, path)?)
+            },
+            Location::Http(_) => {
+                error!("can't load '{name}', HTTP isn't supported yet (use tftp: instead)");
+                return Err(Status::UNSUPPORTED)
+            },
+            Location::Loop { archive, path } => {
+                let archive: Vec<u8> = File::open(archive, volume)?.try_into()?;
+                info!("loading '{name}' from the archive...");
+                Cow::Owned(cpio::get(&archive, path).ok_or_else(|| {
+                    error!("Failed to find file '{name}'");
+                    Status::NOT_FOUND
+                })?.to_vec())
+            },
+        };
+        Ok(Self { name, size: content.len(), source: Source::Memory(content) })
+    }
+
+    /// Opens a file on a volume.
+    ///
+    /// `name` is only used for messages.
+    fn open_on_volume(name: &'a str, path: &str, volume: &mut Directory) -> Result<Self, Status> {
         let mut filename_buf = [0; 1024];
         let file_handle = match volume.open(
-            CStr16::from_str_with_buf(name, &mut filename_buf)
+            CStr16::from_str_with_buf(path, &mut filename_buf)
             .map_err(|e| {
                 error!("filename is invalid because of {e:?}");
                 Status::PROTOCOL_ERROR
@@ -43,6 +334,10 @@ impl<'a> File<'a> {
             FileAttribute::READ_ONLY,
         ) {
             Ok(file_handle) => file_handle,
+            Err(e) if is_transient(e.status()) => return {
+                error!("Failed to open file '{name}': {e:?}");
+                Err(e.status())
+            },
             Err(e) => return {
                 error!("Failed to find file '{name}': {e:?}");
                 Err(Status::NOT_FOUND)
@@ -66,53 +361,939 @@ impl<'a> File<'a> {
         .expect("failed to get size of file metadata");
         info_vec.resize(info_size, 0);
         
+        // On i686, files may be larger than our address space.
         let size: usize = file.get_info::<FileInfo>(info_vec.as_mut_slice())
         .expect(&format!("Failed to get metadata of file '{name}'"))
-        .file_size().try_into().unwrap();
-        Ok(Self { name, file, size })
+        .file_size().try_into().map_err(|_e| {
+            error!("File '{name}' is too large");
+            Status::BAD_BUFFER_SIZE
+        })?;
+        Ok(Self { name, source: Source::Volume(file), size })
     }
     
-    /// Read a whole file into memory and return the resulting allocation.
+    /// Opens a file on a volume again, looking up the volume again.
+    fn reopen(name: &str) -> Result<RegularFile, Status> {
+        let (mut volume, path) = match Location::parse(name)? {
+            Location::Local(path) => (uri::reopen_volume()?, path),
+            Location::Partition { guid, path } => (uri::open_partition(&guid)?, path),
+            _ => return Err(Status::UNSUPPORTED),
+        };
+        match File::open_on_volume(name, path, &mut volume)?.source {
+            Source::Volume(file) => Ok(file),
+            Source::Memory(_) => unreachable!(),
+        }
+    }
+
+    /// Checks whether a file exists.
     ///
-    /// (The difference to `TryInto<Vec<u8>>` is that the allocated memory
-    /// is page-aligned and under 4GB.)
-    pub(crate) fn try_into_allocation(
-        mut self, quirks: &BTreeSet<Quirk>,
-    ) -> Result<Allocation, Status> {
-        let mut allocation = Allocation::new_under_4gb(self.size, quirks)?;
-        let read_size = self.file.read(allocation.as_mut_slice())
-        .map_err(|e| {
-            error!("Failed to read from file '{}': {:?}", self.name, e);
-            e.status()
+    /// This doesn't log anything if the file is missing.
+    /// (Files on TFTP servers or in archives have to be loaded to find out,
+    /// which is logged.) Directories don't count as files.
+    pub(crate) fn exists(name: &str, volume: &mut Directory) -> bool {
+        match Location::parse(name) {
+            Ok(Location::Local(path)) => {
+                embedded::get(path).is_some() || exists_on_volume(path, volume)
+            },
+            Ok(Location::Partition { guid, path }) => uri::open_partition(&guid)
+                .map_or(false, |mut partition| exists_on_volume(path, &mut partition)),
+            Ok(_) => Self::open(name, volume).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Reads the beginning of a file.
+    ///
+    /// This returns at most `length` bytes and doesn't log anything if the file is missing.
+    pub(crate) fn read_start(name: &str, volume: &mut Directory, length: usize) -> Option<Vec<u8>> {
+        match Location::parse(name).ok()? {
+            Location::Local(path) => match embedded::get(path) {
+                Some(content) => Some(content[..length.min(content.len())].to_vec()),
+                None => read_start_on_volume(path, volume, length),
+            },
+            Location::Partition { guid, path } => {
+                read_start_on_volume(path, &mut uri::open_partition(&guid).ok()?, length)
+            },
+            _ => Self::open(name, volume).ok()?.read_beginning(length).ok(),
+        }
+    }
+
+    /// Finds out whether a file is compressed (and how large it is unpacked).
+    ///
+    /// This doesn't log anything if the file is missing.
+    pub(crate) fn inspect(name: &str, volume: &mut Directory) -> Option<compression::Info> {
+        match Location::parse(name).ok()? {
+            Location::Local(path) => match embedded::get(path) {
+                Some(content) => compression::Info::of(&mut File {
+                    name, source: Source::Memory(Cow::Borrowed(content)), size: content.len(),
+                }).ok(),
+                None => inspect_on_volume(name, path, volume),
+            },
+            Location::Partition { guid, path } => {
+                inspect_on_volume(name, path, &mut uri::open_partition(&guid).ok()?)
+            },
+            _ => compression::Info::of(&mut Self::open(name, volume).ok()?).ok(),
+        }
+    }
+
+    /// Read a whole file into memory and return the resulting byte vector,
+    /// displaying the progress.
+    pub(crate) fn try_into_vec(mut self, style: Style) -> Result<Vec<u8>, Status> {
+        // Vec::with_size would allocate enough space, but won't fill it with zeros.
+        // file.read seems to need this.
+        // Reserving the exact size first lets us fail before reading anything.
+        let mut content_vec = Vec::<u8>::new();
+        content_vec.try_reserve_exact(self.size).map_err(|_e| {
+            error!("File '{}' is too large for the available memory", self.name);
+            Status::OUT_OF_RESOURCES
         })?;
-        if read_size == self.size {
-            Ok(allocation)
+        content_vec.resize(self.size, 0);
+        self.read_at(0, content_vec.as_mut_slice(), style)?;
+        Ok(content_vec)
+    }
+    
+    /// Gets the size of the opened file.
+    pub(crate) fn file_size(&self) -> usize {
+        self.size
+    }
+
+    /// Gets the name the file has been opened with.
+    pub(crate) fn name(&self) -> &'a str {
+        self.name
+    }
+    
+    /// Read the first `length` bytes of the file (or less, if it's shorter).
+    pub(crate) fn read_beginning(&mut self, length: usize) -> Result<Vec<u8>, Status> {
+        let mut content_vec = Vec::<u8>::new();
+        content_vec.resize(length.min(self.size), 0);
+        self.read_at(0, content_vec.as_mut_slice(), Style::None)?;
+        Ok(content_vec)
+    }
+    
+    /// Read the last `length` bytes of the file (or less, if it's shorter).
+    pub(crate) fn read_end(&mut self, length: usize) -> Result<Vec<u8>, Status> {
+        let mut content_vec = Vec::<u8>::new();
+        content_vec.resize(length.min(self.size), 0);
+        self.read_at(self.size - content_vec.len(), content_vec.as_mut_slice(), Style::None)?;
+        Ok(content_vec)
+    }
+    
+    /// Fill the buffer with the contents of the file, starting at `position`.
+    ///
+    /// This makes it possible to read a part of a file directly to where it's needed.
+    pub(crate) fn read_at(
+        &mut self, position: usize, buffer: &mut [u8], style: Style,
+    ) -> Result<(), Status> {
+        let mut progress = Progress::new(self.name, buffer.len(), style);
+        let retries = RETRIES.load(atomic::Ordering::Relaxed);
+        let mut attempt = 0;
+        match &mut self.source {
+            Source::Volume(file) => loop {
+                let result = file.set_position(position.try_into().unwrap()).map_err(|e| {
+                    error!("Failed to seek in file '{}': {:?}", self.name, e);
+                    e.status()
+                }).and_then(|()| {
+                    Self::read_chunked(self.name, file, buffer, |done| progress.update(done))
+                });
+                match result {
+                    Err(status) if is_transient(status) && attempt < retries => {
+                        attempt += 1;
+                        warn!("trying to read '{}' again ({attempt}/{retries})...", self.name);
+                        wait_before_retry();
+                        // the old handle may be useless if the device has been reset
+                        match Self::reopen(self.name) {
+                            Ok(reopened) => *file = reopened,
+                            Err(e) => debug!("failed to reopen the file: {e:?}, using the old one"),
+                        }
+                    },
+                    result => return result,
+                }
+            },
+            Source::Memory(content) => {
+                let part = content.get(position..position + buffer.len()).ok_or_else(|| {
+                    error!("Failed to fully read from file '{}'", self.name);
+                    Status::END_OF_FILE
+                })?;
+                buffer.copy_from_slice(part);
+                progress.update(buffer.len());
+                Ok(())
+            },
+        }
+    }
+    
+    /// Fill the buffer with the contents of the file, a chunk at a time.
+    ///
+    /// After each chunk, `on_progress` gets how many bytes have been read so far.
+    fn read_chunked(
+        name: &str, file: &mut RegularFile, buffer: &mut [u8], mut on_progress: impl FnMut(usize),
+    ) -> Result<(), Status> {
+        let mut read_size = 0;
+        for chunk in buffer.chunks_mut(chunk_size()) {
+            let start = timing::now();
+            let result = file.read(chunk);
+            timing::add(Step::FileReads, start);
+            let chunk_size = result.map_err(|e| {
+                error!("Failed to read from file '{name}': {e:?}");
+                e.status()
+            })?;
+            read_size += chunk_size;
+            on_progress(read_size);
+            if chunk_size < chunk.len() {
+                break
+            }
+        }
+        if read_size == buffer.len() {
+            Ok(())
         } else {
-            error!("Failed to fully read from file '{}", self.name);
+            error!("Failed to fully read from file '{name}'");
             Err(Status::END_OF_FILE)
         }
     }
 }
 
+/// Reads a file from its start, a chunk at a time.
+///
+/// If the firmware can read without blocking, the next chunk is read in the
+/// background while the caller works with the current one.
+/// Otherwise (or if that fails), each chunk is read when it's needed.
+pub(crate) struct ReadAhead<'f, 'a> {
+    file: &'f mut File<'a>,
+    /// where the next chunk starts
+    offset: usize,
+    /// for reading in the background (`None` if that doesn't work)
+    token: Option<Box<FileIoToken>>,
+    /// the buffer that's being read into in the background
+    pending: Option<Vec<u8>>,
+}
+
+impl<'f, 'a> ReadAhead<'f, 'a> {
+    pub(crate) fn new(file: &'f mut File<'a>) -> Self {
+        let token = FileIoToken::new(file);
+        Self { file, offset: 0, token, pending: None }
+    }
+
+    /// Replace `chunk` with the next chunk of the file (it's empty at the end).
+    pub(crate) fn next(&mut self, chunk: &mut Vec<u8>) -> Result<(), Status> {
+        let spare = match self.wait() {
+            Some(buffer) => core::mem::replace(chunk, buffer),
+            None => {
+                let length = chunk_size().min(self.file.size - self.offset);
+                chunk.resize(length, 0);
+                self.file.read_at(self.offset, chunk, Style::None)?;
+                Vec::new()
+            },
+        };
+        self.offset += chunk.len();
+        // The old chunk isn't needed anymore, so the one after this one is read into it.
+        self.start(spare);
+        Ok(())
+    }
+
+    /// Start reading the next chunk into `buffer` in the background (if possible).
+    fn start(&mut self, mut buffer: Vec<u8>) {
+        let length = chunk_size().min(self.file.size - self.offset);
+        let token = match &mut self.token {
+            Some(token) if length > 0 => token,
+            _ => return,
+        };
+        buffer.resize(length, 0);
+        if token.start(self.file, self.offset, &mut buffer) {
+            self.pending = Some(buffer);
+        } else {
+            self.stop();
+        }
+    }
+
+    /// Wait for the read in the background (if there is one).
+    ///
+    /// This returns the buffer if the whole chunk has been read.
+    fn wait(&mut self) -> Option<Vec<u8>> {
+        let buffer = self.pending.take()?;
+        let token = self.token.as_deref()?;
+        wait_for_any(&[token]);
+        token.succeeded(self.file.name, buffer.len()).then_some(buffer)
+    }
+
+    /// Stop reading in the background.
+    fn stop(&mut self) {
+        if let Some(token) = self.token.take() {
+            token.close();
+        }
+    }
+}
+
+impl Drop for ReadAhead<'_, '_> {
+    fn drop(&mut self) {
+        // The firmware mustn't write to the buffer after it's been freed.
+        self.wait();
+        self.stop();
+    }
+}
+
+/// A file that's being read by `read_concurrently`.
+struct Stream {
+    /// how much has been read
+    read: usize,
+    /// for reading in the background (`None` if that doesn't work)
+    token: Option<Box<FileIoToken>>,
+    /// how long the chunk is that's being read in the background (if any)
+    pending: Option<usize>,
+}
+
+impl Stream {
+    /// Start reading the next chunk in the background (if there is one).
+    fn start(&mut self, file: &mut File, buffer: &mut [u8]) {
+        let length = chunk_size().min(buffer.len() - self.read);
+        let token = match &mut self.token {
+            Some(token) if length > 0 => token,
+            _ => return,
+        };
+        if token.start(file, self.read, &mut buffer[self.read..self.read + length]) {
+            self.pending = Some(length);
+        } else {
+            self.stop();
+        }
+    }
+
+    /// Stop reading in the background.
+    fn stop(&mut self) {
+        if let Some(token) = self.token.take() {
+            token.close();
+        }
+    }
+}
+
+/// Fill several buffers with the contents of files (from their start) at the same time.
+///
+/// If the firmware can read in the background, a chunk of each file is being read
+/// at any time, so the reads of independent files are interleaved (and a fast disk
+/// can work on several of them at once). Files where this doesn't work are read
+/// afterwards, one after another. `name` is only used for the progress.
+pub(crate) fn read_concurrently(
+    name: &str, reads: &mut [(&mut File, &mut [u8])], style: Style,
+) -> Result<(), Status> {
+    let mut progress = Progress::new(name, reads.iter().map(|(_, b)| b.len()).sum(), style);
+    let mut done = 0;
+    let mut streams: Vec<Stream> = reads.iter_mut()
+        .map(|(file, _)| Stream { read: 0, token: FileIoToken::new(file), pending: None })
+        .collect();
+    for (stream, (file, buffer)) in streams.iter_mut().zip(reads.iter_mut()) {
+        stream.start(file, buffer);
+    }
+    loop {
+        let (in_flight, tokens): (Vec<usize>, Vec<&FileIoToken>) = streams.iter().enumerate()
+            .filter(|(_, stream)| stream.pending.is_some())
+            .filter_map(|(index, stream)| Some((index, stream.token.as_deref()?)))
+            .unzip();
+        if in_flight.is_empty() {
+            break
+        }
+        let index = in_flight[wait_for_any(&tokens)];
+        let (file, buffer) = &mut reads[index];
+        let stream = &mut streams[index];
+        let length = stream.pending.take().unwrap();
+        if stream.token.as_ref().map_or(false, |token| token.succeeded(file.name, length)) {
+            stream.read += length;
+            done += length;
+            progress.update(done);
+            stream.start(file, buffer);
+        } else {
+            stream.stop();
+        }
+    }
+    for stream in streams.iter_mut() {
+        stream.stop();
+    }
+    // whatever couldn't be read in the background
+    for (stream, (file, buffer)) in streams.iter().zip(reads.iter_mut()) {
+        let mut position = stream.read;
+        for chunk in buffer[stream.read..].chunks_mut(chunk_size()) {
+            file.read_at(position, chunk, Style::None)?;
+            position += chunk.len();
+            done += chunk.len();
+            progress.update(done);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+impl<'a> File<'a> {
+    /// Pretend that a file with this content has been opened.
+    pub(crate) fn in_memory(name: &'a str, content: Vec<u8>) -> Self {
+        Self { name, size: content.len(), source: Source::Memory(Cow::Owned(content)) }
+    }
+}
+
 impl<'a> TryFrom<File<'a>> for Vec<u8> {
     type Error = Status;
     
     /// Read a whole file into memory and return the resulting byte vector.
-    fn try_from(mut file: File) -> Result<Self, Self::Error> {
-        // Vec::with_size would allocate enough space, but won't fill it with zeros.
-        // file.read seems to need this.
-        let mut content_vec = Vec::<u8>::new();
-        content_vec.resize(file.size, 0);
-        let read_size = file.file.read(content_vec.as_mut_slice())
+    fn try_from(file: File) -> Result<Self, Self::Error> {
+        file.try_into_vec(Style::None)
+    }
+}
+
+/// Checks whether a file exists on a volume (without logging anything).
+fn exists_on_volume(path: &str, volume: &mut Directory) -> bool {
+    let mut filename_buf = [0; 1024];
+    match CStr16::from_str_with_buf(path, &mut filename_buf) {
+        Ok(filename) => matches!(
+            volume.open(filename, FileMode::Read, FileAttribute::READ_ONLY)
+            .map(|handle| handle.into_type()),
+            Ok(Ok(FileType::Regular(_)))
+        ),
+        Err(e) => {
+            error!("filename is invalid because of {e:?}");
+            false
+        },
+    }
+}
+
+/// Reads the beginning of a file on a volume (without logging anything).
+fn read_start_on_volume(path: &str, volume: &mut Directory, length: usize) -> Option<Vec<u8>> {
+    let mut filename_buf = [0; 1024];
+    let filename = CStr16::from_str_with_buf(path, &mut filename_buf).ok()?;
+    match volume.open(filename, FileMode::Read, FileAttribute::READ_ONLY).ok()?
+        .into_type().ok()? {
+        FileType::Regular(mut file) => {
+            let mut content_vec = Vec::<u8>::new();
+            content_vec.resize(length, 0);
+            let read_size = file.read(content_vec.as_mut_slice()).ok()?;
+            content_vec.truncate(read_size);
+            Some(content_vec)
+        },
+        FileType::Dir(_) => None,
+    }
+}
+
+/// Inspects a file on a volume (without logging anything if it's missing).
+fn inspect_on_volume(name: &str, path: &str, volume: &mut Directory) -> Option<compression::Info> {
+    let mut filename_buf = [0; 1024];
+    let filename = CStr16::from_str_with_buf(path, &mut filename_buf).ok()?;
+    match volume.open(filename, FileMode::Read, FileAttribute::READ_ONLY).ok()?
+        .into_type().ok()? {
+        FileType::Regular(mut file) => {
+            let size = file.get_boxed_info::<FileInfo>().ok()?
+                .file_size().try_into().ok()?;
+            compression::Info::of(&mut File { name, source: Source::Volume(file), size }).ok()
+        },
+        FileType::Dir(_) => None,
+    }
+}
+
+/// The parts of a module (see `read_into_allocations`).
+pub(crate) struct ModuleParts<'a> {
+    /// the files, which are concatenated
+    pub(crate) files: Vec<File<'a>>,
+    /// what's copied after the files
+    pub(crate) appended: Vec<u8>,
+    /// where to load the module
+    pub(crate) load_at: Option<u64>,
+    /// whether to decompress gzip files
+    pub(crate) decompress: bool,
+}
+
+/// Read modules into memory and return the resulting allocations.
+///
+/// (The difference to `TryInto<Vec<u8>>` is that the allocated memory
+/// is page-aligned and under 4GB.)
+/// If `load_at` is given, the memory is allocated there instead
+/// (or it's moved there later, see `Allocation::new_at`).
+///
+/// The files are read directly into the allocations, so there is only one copy in memory.
+/// If `decompress` is set, gzip files are decompressed first (see `compression::decompress`),
+/// the others are read afterwards, all at the same time (see `read_concurrently`).
+/// (Concatenating is what Linux expects for early microcode followed by the initramfs.)
+pub(crate) fn read_into_allocations(
+    modules: Vec<ModuleParts>, quirks: &BTreeSet<Quirk>, style: Style,
+) -> Result<Vec<Allocation>, Status> {
+    read_modules(modules, style, |name, size, load_at| allocate(name, size, load_at, quirks))
+}
+
+/// Read modules into the memory `allocate` returns (see `read_into_allocations`).
+fn read_modules<A: AsMut<[u8]>>(
+    mut modules: Vec<ModuleParts>, style: Style,
+    mut allocate: impl FnMut(&str, usize, Option<u64>) -> Result<A, Status>,
+) -> Result<Vec<A>, Status> {
+    let mut allocations = Vec::with_capacity(modules.len());
+    let mut sizes = Vec::with_capacity(modules.len());
+    for module in modules.iter_mut() {
+        let (allocation, file_sizes) = unpack(module, style, &mut allocate)?;
+        allocations.push(allocation);
+        sizes.push(file_sizes);
+    }
+    // The other files can now be read to where they belong.
+    let mut reads = Vec::new();
+    for ((allocation, file_sizes), module) in allocations.iter_mut().zip(&sizes)
+        .zip(modules.iter_mut()) {
+        let mut rest = allocation.as_mut();
+        for (file, (format, size)) in module.files.iter_mut().zip(file_sizes) {
+            let (target, after) = core::mem::take(&mut rest).split_at_mut(*size);
+            rest = after;
+            if *format != compression::Format::Gzip {
+                reads.push((file, target));
+            }
+        }
+    }
+    read_concurrently("modules", &mut reads, style)?;
+    Ok(allocations)
+}
+
+/// Allocate the memory for a module and decompress its gzip files into it.
+///
+/// This returns the allocation and how large each file is in it.
+fn unpack<A: AsMut<[u8]>>(
+    module: &mut ModuleParts, style: Style,
+    allocate: &mut impl FnMut(&str, usize, Option<u64>) -> Result<A, Status>,
+) -> Result<(A, Vec<(compression::Format, usize)>), Status> {
+    let name = module.files.iter().map(|f| f.name).collect::<Vec<_>>().join(" + ");
+    let decompress = module.decompress;
+    // how large each file is going to be in memory
+    let mut sizes = module.files.iter_mut()
+        .map(|file| if decompress {
+            compression::unpacked_size(file)
+        } else {
+            Ok((compression::Format::Uncompressed, file.size))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    'allocate: loop {
+        let size = sizes.iter().map(|(_, size)| size).sum::<usize>() + module.appended.len();
+        let mut allocation = allocate(&name, size, module.load_at)?;
+        let target = allocation.as_mut();
+        let mut position = 0;
+        for (file, (format, file_size)) in module.files.iter_mut().zip(sizes.iter_mut()) {
+            let end = position + *file_size;
+            if *format == compression::Format::Gzip {
+                match compression::decompress(file, &mut target[position..end], style) {
+                    // The trailer only has the size of the last member, so count them all.
+                    Err(Status::BUFFER_TOO_SMALL) => {
+                        warn!("'{}' is larger than its trailer says, counting its size", file.name);
+                        *file_size = compression::count(file, style)?;
+                        // The new size may not fit where the old one did.
+                        continue 'allocate
+                    },
+                    result => result?,
+                }
+            }
+            position = end;
+        }
+        target[position..size].copy_from_slice(&module.appended);
+        return Ok((allocation, sizes))
+    }
+}
+
+/// Allocate the memory for a module (see `read_into_allocations`).
+fn allocate(
+    name: &str, size: usize, load_at: Option<u64>, quirks: &BTreeSet<Quirk>,
+) -> Result<Allocation, Status> {
+    match load_at {
+        Some(address) => {
+            // Multiboot only has 32-bit addresses for modules.
+            if address.checked_add(size as u64).map_or(true, |end| end > u32::MAX.into()) {
+                error!("'{name}' can't be loaded to {address:#x}, it's above 4 GB");
+                return Err(Status::LOAD_ERROR)
+            }
+            Allocation::new_at(address.try_into().unwrap(), size, quirks)
+        },
+        None => {
+            let allocation = if quirks.contains(&Quirk::ModulesAbove4Gb) {
+                Allocation::new_anywhere(size)
+            } else {
+                Allocation::new_under_4gb(size, quirks)
+            };
+            allocation.map_err(|e| {
+                error!("'{name}' is too large for the available memory");
+                e
+            })
+        },
+    }
+}
+
+/// Creates a file and writes the content to it.
+///
+/// The path is relative to the volume we're loaded from.
+pub(crate) fn write(name: &str, volume: &mut Directory, content: &[u8]) -> Result<(), Status> {
+    let mut filename_buf = [0; 1024];
+    let filename = CStr16::from_str_with_buf(name, &mut filename_buf).map_err(|e| {
+        error!("filename is invalid because of {e:?}");
+        Status::PROTOCOL_ERROR
+    })?;
+    let file_handle = volume.open(filename, FileMode::CreateReadWrite, FileAttribute::empty())
         .map_err(|e| {
-            error!("Failed to read from file '{}': {:?}", file.name, e);
+            error!("Failed to create file '{name}': {e:?}");
             e.status()
         })?;
-        if read_size == file.size {
-            Ok(content_vec)
-        } else {
-            error!("Failed to fully read from file '{}", file.name);
-            Err(Status::END_OF_FILE)
+    let mut file = match file_handle.into_type().map_err(|e| e.status())? {
+        FileType::Regular(file) => file,
+        FileType::Dir(_) => return {
+            error!("File '{name}' is a directory");
+            Err(Status::UNSUPPORTED)
+        }
+    };
+    file.write(content).map_err(|e| {
+        error!("Failed to write to file '{name}': {e:?}");
+        e.status()
+    })?;
+    file.flush().map_err(|e| {
+        error!("Failed to flush file '{name}': {e:?}");
+        e.status()
+    })
+}
+
+/// Opens a file for appending, creating it (and its directory) if it doesn't exist.
+///
+/// The path is relative to the volume we're loaded from.
+/// The position is at the end of the file, so it's also the size.
+pub(crate) fn open_for_appending(
+    name: &str, volume: &mut Directory,
+) -> Result<RegularFile, Status> {
+    let mut filename_buf = [0; 1024];
+    let filename = CStr16::from_str_with_buf(name, &mut filename_buf).map_err(|e| {
+        error!("filename is invalid because of {e:?}");
+        Status::PROTOCOL_ERROR
+    })?;
+    let file_handle = match volume.open(
+        filename, FileMode::CreateReadWrite, FileAttribute::empty(),
+    ) {
+        Ok(handle) => handle,
+        // the directory may be missing
+        Err(e) if e.status() == Status::NOT_FOUND => {
+            if let Some((directory, _)) = name.rsplit_once('\\').filter(|(d, _)| !d.is_empty()) {
+                let mut directory_buf = [0; 1024];
+                let directory = CStr16::from_str_with_buf(directory, &mut directory_buf)
+                    .map_err(|_| Status::PROTOCOL_ERROR)?;
+                volume.open(directory, FileMode::CreateReadWrite, FileAttribute::DIRECTORY)
+                    .map_err(|e| {
+                        error!("Failed to create the directory of '{name}': {e:?}");
+                        e.status()
+                    })?;
+            }
+            volume.open(filename, FileMode::CreateReadWrite, FileAttribute::empty())
+                .map_err(|e| {
+                    error!("Failed to create file '{name}': {e:?}");
+                    e.status()
+                })?
+        },
+        Err(e) => return {
+            error!("Failed to open file '{name}': {e:?}");
+            Err(e.status())
+        },
+    };
+    let mut file = match file_handle.into_type().map_err(|e| e.status())? {
+        FileType::Regular(file) => file,
+        FileType::Dir(_) => return {
+            error!("File '{name}' is a directory");
+            Err(Status::UNSUPPORTED)
+        }
+    };
+    let size = file.get_boxed_info::<FileInfo>().map_err(|e| e.status())?.file_size();
+    file.set_position(size).map_err(|e| {
+        error!("Failed to seek in file '{name}': {e:?}");
+        e.status()
+    })?;
+    Ok(file)
+}
+
+/// Deletes a file.
+///
+/// Deleting a file that doesn't exist is not an error.
+pub(crate) fn delete(name: &str, volume: &mut Directory) -> Result<(), Status> {
+    let mut filename_buf = [0; 1024];
+    let filename = CStr16::from_str_with_buf(name, &mut filename_buf).map_err(|e| {
+        error!("filename is invalid because of {e:?}");
+        Status::PROTOCOL_ERROR
+    })?;
+    let file_handle = match volume.open(filename, FileMode::ReadWrite, FileAttribute::empty()) {
+        Ok(handle) => handle,
+        Err(e) if e.status() == Status::NOT_FOUND => return Ok(()),
+        Err(e) => return {
+            error!("Failed to open file '{name}': {e:?}");
+            Err(e.status())
+        },
+    };
+    file_handle.delete().map_err(|e| {
+        error!("Failed to delete file '{name}': {e:?}");
+        e.status()
+    })
+}
+
+/// Open a directory on the volume.
+fn open_directory(path: &str, volume: &mut Directory) -> Result<Directory, Status> {
+    let mut name_buf = [0; 1024];
+    let name = CStr16::from_str_with_buf(path, &mut name_buf).map_err(|e| {
+        error!("filename is invalid because of {e:?}");
+        Status::PROTOCOL_ERROR
+    })?;
+    let handle = volume.open(name, FileMode::Read, FileAttribute::READ_ONLY).map_err(|e| {
+        error!("Failed to find directory '{path}': {e:?}");
+        Status::NOT_FOUND
+    })?;
+    match handle.into_type().map_err(|e| e.status())? {
+        FileType::Dir(directory) => Ok(directory),
+        FileType::Regular(_) => {
+            error!("'{path}' is not a directory");
+            Err(Status::UNSUPPORTED)
+        },
+    }
+}
+
+/// List the contents of a directory (without `.` and `..`).
+///
+/// This returns the names and whether they are directories themselves.
+/// If there are embedded files in the directory, only these are listed.
+/// Directories on TFTP servers can't be listed.
+pub(crate) fn list_directory(
+    path: &str, volume: &mut Directory,
+) -> Result<Vec<(String, bool)>, Status> {
+    match Location::parse(path)? {
+        Location::Local(directory) => {
+            let embedded = embedded::list_directory(directory);
+            if !embedded.is_empty() {
+                return Ok(embedded)
+            }
+            list_directory_on_volume(directory, volume)
+        },
+        Location::Partition { guid, path } => {
+            list_directory_on_volume(path, &mut uri::open_partition(&guid)?)
+        },
+        Location::Loop { archive, path } => {
+            let archive: Vec<u8> = File::open(archive, volume)?.try_into()?;
+            Ok(cpio::list_directory(&archive, path))
+        },
+        Location::Tftp { .. } | Location::Http(_) => {
+            error!("can't list '{path}', the server doesn't support that");
+            Err(Status::UNSUPPORTED)
+        },
+    }
+}
+
+/// List the contents of a directory on a volume (without `.` and `..`).
+fn list_directory_on_volume(
+    path: &str, volume: &mut Directory,
+) -> Result<Vec<(String, bool)>, Status> {
+    let mut directory = open_directory(if path.is_empty() { "\\" } else { path }, volume)?;
+    // FileInfo needs to be aligned
+    let mut buf = [0u64; 128];
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), 128 * 8)
+    };
+    let mut entries = Vec::new();
+    while let Some(info) = directory.read_entry(buf).map_err(|e| {
+        error!("Failed to read the directory '{path}': {:?}", e.status());
+        e.status()
+    })? {
+        let name = info.file_name().to_string();
+        if name != "." && name != ".." {
+            entries.push((name, info.attribute().contains(FileAttribute::DIRECTORY)));
+        }
+    }
+    Ok(entries)
+}
+
+/// Checks whether a path contains a pattern (see `matches_pattern`).
+pub(crate) fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?'])
+}
+
+/// Find the files matching a path whose last component may be a pattern.
+///
+/// The matches are sorted by version (see `compare_versions`), so the newest one is last.
+/// Paths without a pattern are returned as they are (even if the file doesn't exist).
+/// If nothing matches, this fails with `Status::NOT_FOUND`.
+pub(crate) fn resolve_pattern(path: &str, volume: &mut Directory) -> Result<Vec<String>, Status> {
+    if !is_pattern(path) {
+        return Ok(vec![path.to_string()])
+    }
+    let location = Location::parse(path)?;
+    if matches!(location, Location::Tftp { .. } | Location::Http(_)) {
+        error!("'{path}' has a pattern, but the server can't list its files");
+        return Err(Status::UNSUPPORTED)
+    }
+    // the scheme and everything before the path (if there is one)
+    let prefix = &path[..path.len() - location.path().len()];
+    let (directory, pattern) = location.path().rsplit_once('\\').unwrap_or(("", location.path()));
+    if is_pattern(prefix) || is_pattern(directory) {
+        error!("'{path}' has a pattern in a directory, but only file names may have one");
+        return Err(Status::INVALID_PARAMETER)
+    }
+    let mut matches: Vec<String> = list_directory(
+        &format!("{prefix}{}", if directory.is_empty() { "\\" } else { directory }), volume,
+    )?.into_iter()
+        .filter(|(name, is_directory)| !is_directory && matches_pattern(pattern, name))
+        .map(|(name, _)| format!("{prefix}{directory}\\{name}"))
+        .collect();
+    if matches.is_empty() {
+        error!("no file matches '{path}'");
+        return Err(Status::NOT_FOUND)
+    }
+    matches.sort_unstable_by(|a, b| compare_versions(a, b));
+    debug!("'{path}' matches {matches:?}");
+    Ok(matches)
+}
+
+/// Compare two names, treating runs of digits as numbers.
+///
+/// This way, `vmlinuz-6.10` comes after `vmlinuz-6.9`.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                match x.len().cmp(&y.len()).then_with(|| x.cmp(y)) {
+                    Ordering::Equal => (),
+                    other => return other,
+                }
+            },
+            (Some(x), Some(y)) => match x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase()) {
+                Ordering::Equal => { a.next(); b.next(); },
+                other => return other,
+            },
         }
     }
 }
+
+/// Take the digits at the start.
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        digits.push(digit);
+    }
+    digits
+}
+
+/// Checks whether a file name matches a pattern.
+///
+/// `*` matches any number of characters, `?` matches exactly one.
+/// The comparison is case-insensitive, just like FAT.
+pub(crate) fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+    let (mut p, mut n) = (0, 0);
+    // where to continue if the last `*` has to match more characters
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            },
+            Some('?') => { p += 1; n += 1; },
+            Some(c) if *c == name[n] => { p += 1; n += 1; },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use alloc::collections::btree_set::BTreeSet;
+
+    use uefi::Status;
+    use uefi::table::boot::MemoryType;
+
+    use super::super::config::Quirk;
+    use super::super::firmware::mock::Mock;
+    use super::super::progress::Style;
+    use super::{allocate, read_concurrently, read_modules, File, ModuleParts};
+
+    /// "hello hello hello hello\n", compressed with gzip
+    const HELLO: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48,
+        0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x00, 0x88, 0x59,
+        0x0b, 0x18, 0x00, 0x00, 0x00,
+    ];
+
+    /// Read modules into vectors, returning them and the sizes that have been allocated.
+    fn read(modules: Vec<ModuleParts>) -> Result<(Vec<Vec<u8>>, Vec<usize>), Status> {
+        let mut sizes = Vec::new();
+        let allocations = read_modules(modules, Style::None, |_name, size, _load_at| {
+            sizes.push(size);
+            Ok(vec![0xaa; size])
+        })?;
+        Ok((allocations, sizes))
+    }
+
+    #[test]
+    fn concurrently() {
+        let mut first = File::in_memory("first", b"abc".to_vec());
+        let mut second = File::in_memory("second", b"defg".to_vec());
+        let (mut a, mut b) = ([0; 3], [0; 4]);
+        read_concurrently(
+            "test", &mut [(&mut first, &mut a[..]), (&mut second, &mut b[..])], Style::None,
+        ).unwrap();
+        assert_eq!((&a, &b), (b"abc", b"defg"));
+        // the file is shorter than expected
+        let mut c = [0; 5];
+        assert_eq!(
+            read_concurrently("test", &mut [(&mut first, &mut c[..])], Style::None),
+            Err(Status::END_OF_FILE),
+        );
+    }
+
+    #[test]
+    fn modules() {
+        let (modules, sizes) = read(vec![
+            ModuleParts {
+                files: vec![
+                    File::in_memory("first", b"first ".to_vec()),
+                    File::in_memory("second.gz", HELLO.to_vec()),
+                ],
+                appended: Vec::new(), load_at: None, decompress: false,
+            },
+            ModuleParts {
+                files: Vec::new(), appended: b"seed".to_vec(), load_at: Some(0x10_0000),
+                decompress: false,
+            },
+        ]).unwrap();
+        assert_eq!(sizes, [6 + HELLO.len(), 4]);
+        let mut first = b"first ".to_vec();
+        first.extend(HELLO);
+        assert_eq!(modules, [first, b"seed".to_vec()]);
+    }
+
+    #[test]
+    fn reallocation() {
+        // The trailer only has the size of the last member, so this is too small at first.
+        let mut gzip = HELLO.to_vec();
+        gzip.extend(HELLO);
+        let (modules, sizes) = read(vec![ModuleParts {
+            files: vec![
+                File::in_memory("initrd.gz", gzip),
+                File::in_memory("plain", b"plain".to_vec()),
+            ],
+            appended: b"!".to_vec(), load_at: None, decompress: true,
+        }]).unwrap();
+        assert_eq!(sizes, [24 + 5 + 1, 48 + 5 + 1]);
+        assert_eq!(modules[0], b"hello hello hello hello\nhello hello hello hello\nplain!");
+    }
+
+    #[test]
+    fn above_4gb() {
+        const MIB: u64 = 1024 * 1024;
+        Mock::install(&[
+            (MIB, MIB, MemoryType::CONVENTIONAL),
+            (4096 * MIB, 16 * MIB, MemoryType::CONVENTIONAL),
+        ]);
+        let below = allocate("initrd", 0x1000, None, &BTreeSet::new()).unwrap();
+        assert_eq!(below.as_ptr() as u64, 2 * MIB - 0x1000);
+        let quirks = BTreeSet::from([Quirk::ModulesAbove4Gb]);
+        let above = allocate("initrd", 0x1000, None, &quirks).unwrap();
+        assert_eq!(above.as_ptr() as u64, 4112 * MIB - 0x1000);
+    }
+}
@@ -0,0 +1,185 @@
+//! A simulated firmware
+//!
+//! This keeps a memory map and hands out pages from it like the real firmware
+//! would (from the top of the free memory, unless an address is requested).
+//! The memory itself doesn't exist, so nothing may be written to the addresses
+//! it returns; this is only good for checking where things would go.
+//! What's written to the console is collected.
+
+use core::cell::RefCell;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::proto::console::text::Color;
+use uefi::table::boot::{AllocateType, MemoryDescriptor, MemoryType};
+
+use super::Firmware;
+use super::super::mem::{Range, PAGE_SIZE};
+
+/// A simulated firmware.
+pub(crate) struct Mock {
+    memory_map: RefCell<Vec<MemoryDescriptor>>,
+    console: RefCell<String>,
+}
+
+impl Mock {
+    /// Create a firmware with the given memory (start, size in bytes and type).
+    ///
+    /// The regions should be page-aligned and may not overlap.
+    pub(crate) fn new(regions: &[(u64, u64, MemoryType)]) -> Self {
+        let memory_map = regions.iter()
+            .map(|(start, size, memory_type)| descriptor(
+                Range::new(*start, *size), *memory_type,
+            ))
+            .collect();
+        Self { memory_map: RefCell::new(memory_map), console: RefCell::new(String::new()) }
+    }
+
+    /// Create a firmware with the given memory and use it for the current test.
+    pub(crate) fn install(regions: &[(u64, u64, MemoryType)]) -> &'static Self {
+        let mock = Box::leak(Box::new(Self::new(regions)));
+        super::set(mock);
+        mock
+    }
+
+    /// Get everything that has been written to the console.
+    pub(crate) fn console(&self) -> String {
+        self.console.borrow().clone()
+    }
+
+    /// Find free memory for an allocation.
+    fn find(&self, allocate_type: AllocateType, size: u64) -> Option<Range> {
+        let limit = match allocate_type {
+            AllocateType::Address(address) => {
+                let range = Range::new(address as u64, size);
+                return self.memory_map.borrow().iter()
+                    .find(|d| d.ty == MemoryType::CONVENTIONAL
+                        && range.subtract(&[Range::from(*d)]).is_empty())
+                    .map(|_| range)
+            },
+            AllocateType::MaxAddress(address) => address as u64 + 1,
+            AllocateType::AnyPages => u64::MAX,
+        };
+        self.memory_map.borrow().iter()
+            .filter(|d| d.ty == MemoryType::CONVENTIONAL)
+            .map(Range::from)
+            .filter_map(|free| {
+                let end = free.end.min(limit) / PAGE_SIZE as u64 * PAGE_SIZE as u64;
+                end.checked_sub(size).filter(|start| *start >= free.start)
+                    .map(|start| Range::new(start, size))
+            })
+            .max_by_key(|range| range.start)
+    }
+
+    /// Change the type of a range that's inside a single descriptor.
+    fn retype(&self, range: Range, from: MemoryType, to: MemoryType) -> bool {
+        let mut memory_map = self.memory_map.borrow_mut();
+        let index = match memory_map.iter().position(
+            |d| d.ty == from && range.subtract(&[Range::from(d)]).is_empty(),
+        ) {
+            Some(index) => index,
+            None => return false,
+        };
+        let around = Range::from(&memory_map[index]).subtract(&[range]);
+        memory_map[index] = descriptor(range, to);
+        memory_map.extend(around.into_iter().map(|r| descriptor(r, from)));
+        memory_map.sort_unstable_by_key(|d| d.phys_start);
+        true
+    }
+}
+
+impl Firmware for Mock {
+    fn allocate_pages(
+        &self, allocate_type: AllocateType, memory_type: MemoryType, count: usize,
+    ) -> uefi::Result<u64> {
+        let size = (count * PAGE_SIZE) as u64;
+        match self.find(allocate_type, size) {
+            Some(range) => {
+                assert!(self.retype(range, MemoryType::CONVENTIONAL, memory_type));
+                Ok(range.start)
+            },
+            None => Err(Status::NOT_FOUND.into()),
+        }
+    }
+
+    fn free_pages(&self, address: u64, count: usize) -> uefi::Result {
+        let range = Range::new(address, (count * PAGE_SIZE) as u64);
+        let memory_type = self.memory_map.borrow().iter()
+            .find(|d| range.subtract(&[Range::from(*d)]).is_empty())
+            .map(|d| d.ty);
+        match memory_type {
+            Some(memory_type) if memory_type != MemoryType::CONVENTIONAL => {
+                assert!(self.retype(range, memory_type, MemoryType::CONVENTIONAL));
+                Ok(())
+            },
+            _ => Err(Status::NOT_FOUND.into()),
+        }
+    }
+
+    fn memory_map(&self) -> Result<Vec<MemoryDescriptor>, Status> {
+        Ok(self.memory_map.borrow().clone())
+    }
+
+    fn write_console(&self, text: &str, _color: Option<Color>) {
+        self.console.borrow_mut().push_str(text);
+    }
+}
+
+/// Create a descriptor for a range.
+fn descriptor(range: Range, memory_type: MemoryType) -> MemoryDescriptor {
+    let mut descriptor = MemoryDescriptor::default();
+    descriptor.ty = memory_type;
+    descriptor.phys_start = range.start;
+    descriptor.page_count = (range.end - range.start) / PAGE_SIZE as u64;
+    descriptor
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use uefi::table::boot::{AllocateType, MemoryType};
+
+    use super::{Firmware, Mock, Range};
+
+    #[test]
+    fn allocate_and_free() {
+        let mock = Mock::install(&[(0x10_0000, 0x10_0000, MemoryType::CONVENTIONAL)]);
+        // from the top
+        let top = mock.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 2).unwrap();
+        assert_eq!(top, 0x1f_e000);
+        // below the limit
+        let low = mock.allocate_pages(
+            AllocateType::MaxAddress(0x18_0fff), MemoryType::LOADER_DATA, 1,
+        ).unwrap();
+        assert_eq!(low, 0x18_0000);
+        // not twice
+        assert!(mock.allocate_pages(
+            AllocateType::Address(0x18_0000), MemoryType::LOADER_DATA, 1,
+        ).is_err());
+        mock.free_pages(low, 1).unwrap();
+        assert_eq!(mock.allocate_pages(
+            AllocateType::Address(0x18_0000), MemoryType::LOADER_DATA, 1,
+        ).unwrap(), 0x18_0000);
+        // only what has been allocated can be freed
+        assert!(mock.free_pages(0x10_0000, 1).is_err());
+        let memory_map = mock.memory_map().unwrap();
+        assert!(memory_map.windows(2).all(|d| d[0].phys_start < d[1].phys_start));
+        assert_eq!(
+            memory_map.iter().filter(|d| d.ty == MemoryType::LOADER_DATA)
+                .map(Range::from).collect::<Vec<_>>(),
+            [Range::new(0x18_0000, 0x1000), Range::new(0x1f_e000, 0x2000)],
+        );
+    }
+
+    #[test]
+    fn console() {
+        let mock = Mock::install(&[]);
+        mock.write_console("a", None);
+        super::super::get().write_console("b\n", None);
+        assert_eq!(mock.console(), "ab\n");
+    }
+}
@@ -0,0 +1,133 @@
+//! Access to the firmware
+//!
+//! The boot services towboot uses for memory (allocating and freeing pages,
+//! getting the memory map) and the console are reached through the `Firmware`
+//! trait instead of the system table. Normally, that's the real firmware
+//! (`Uefi`), but the logic on top of it (like translating the memory map or
+//! planning where the parts of a kernel end up) can also run against a
+//! simulated one (see `mock`) that a test installs with `set`.
+//!
+//! Files are still read through the volume's protocol (see `file`).
+//!
+//! The system table comes from `uefi_services`, which only exists on UEFI.
+//! When testing on the host, `system_table` panics, so only the parts that
+//! don't need anything else than this module can be tested.
+
+use core::fmt::Write;
+#[cfg(not(target_os = "uefi"))]
+use core::ptr::NonNull;
+
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::proto::console::text::Color;
+use uefi::table::boot::{AllocateType, MemoryDescriptor, MemoryType};
+#[cfg(target_os = "uefi")]
+pub(crate) use uefi_services::{init, system_table};
+
+use log::error;
+
+#[cfg(test)]
+pub(crate) mod mock;
+
+/// The boot services towboot needs.
+pub(crate) trait Firmware {
+    /// Allocate `count` pages of memory, returning the address.
+    fn allocate_pages(
+        &self, allocate_type: AllocateType, memory_type: MemoryType, count: usize,
+    ) -> uefi::Result<u64>;
+
+    /// Free pages that have been allocated with `allocate_pages`.
+    fn free_pages(&self, address: u64, count: usize) -> uefi::Result;
+
+    /// Get the current memory map.
+    fn memory_map(&self) -> Result<Vec<MemoryDescriptor>, Status>;
+
+    /// Write text to the console, optionally in a color.
+    fn write_console(&self, text: &str, color: Option<Color>);
+}
+
+/// Get the firmware in use.
+#[cfg(not(test))]
+pub(crate) fn get() -> &'static dyn Firmware {
+    &Uefi
+}
+
+#[cfg(test)]
+std::thread_local! {
+    /// the simulated firmware of the test running on this thread (see `set`)
+    static FIRMWARE: core::cell::Cell<Option<&'static dyn Firmware>> =
+        core::cell::Cell::new(None);
+}
+
+/// Get the firmware in use.
+///
+/// When testing, this is the one the current test has installed with `set`.
+#[cfg(test)]
+pub(crate) fn get() -> &'static dyn Firmware {
+    FIRMWARE.with(|f| f.get()).expect("the test hasn't set a firmware")
+}
+
+/// Use a simulated firmware for the current test.
+///
+/// (Each test runs on its own thread, so they don't get in each other's way.)
+#[cfg(test)]
+pub(crate) fn set(firmware: &'static dyn Firmware) {
+    FIRMWARE.with(|f| f.set(Some(firmware)));
+}
+
+/// Initialize `uefi_services`, which doesn't exist on the host.
+#[cfg(not(target_os = "uefi"))]
+pub(crate) fn init(_systab: &mut SystemTable<Boot>) -> uefi::Result {
+    panic!("there's no firmware on the host")
+}
+
+/// Get the system table, which doesn't exist on the host.
+#[cfg(not(target_os = "uefi"))]
+pub(crate) fn system_table() -> NonNull<SystemTable<Boot>> {
+    panic!("there's no firmware on the host")
+}
+
+/// The real firmware, through the system table.
+pub(crate) struct Uefi;
+
+impl Firmware for Uefi {
+    fn allocate_pages(
+        &self, allocate_type: AllocateType, memory_type: MemoryType, count: usize,
+    ) -> uefi::Result<u64> {
+        unsafe { system_table().as_ref() }.boot_services()
+            .allocate_pages(allocate_type, memory_type, count)
+    }
+
+    fn free_pages(&self, address: u64, count: usize) -> uefi::Result {
+        unsafe { system_table().as_ref() }.boot_services().free_pages(address, count)
+    }
+
+    fn memory_map(&self) -> Result<Vec<MemoryDescriptor>, Status> {
+        let boot_services = unsafe { system_table().as_ref() }.boot_services();
+        let mut buf = Vec::new();
+        // The docs say that we should allocate a little bit more memory than needed.
+        // (The descriptors are going to need memory, too.)
+        let size = boot_services.memory_map_size();
+        buf.resize(size.map_size + 8 * size.entry_size, 0);
+        let mut descriptors = Vec::with_capacity(buf.len() / size.entry_size);
+        let (_key, iterator) = boot_services.memory_map(buf.as_mut_slice()).map_err(|e| {
+            error!("failed to get the memory map: {e:?}");
+            e.status()
+        })?;
+        descriptors.extend(iterator.copied());
+        Ok(descriptors)
+    }
+
+    fn write_console(&self, text: &str, color: Option<Color>) {
+        let stdout = unsafe { system_table().as_mut() }.stdout();
+        // There's nowhere to report these to.
+        if let Some(color) = color {
+            let _ = stdout.set_color(color, Color::Black);
+        }
+        let _ = stdout.write_str(text);
+        if color.is_some() {
+            let _ = stdout.set_color(Color::LightGray, Color::Black);
+        }
+    }
+}
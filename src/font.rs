@@ -0,0 +1,219 @@
+//! Bitmap fonts
+//!
+//! Text on the framebuffer is drawn with a bitmap font. towboot bundles an 8x16
+//! font covering Latin-1 (derived from the public domain `fixed` 8x13 font of X11),
+//! but it can also load PC Screen Fonts (PSF, version 1 or 2), like the ones used
+//! by the Linux console.
+
+use alloc::borrow::Cow;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use uefi::proto::console::gop::BltPixel;
+use uefi::proto::media::file::Directory;
+
+use log::{debug, warn};
+
+use crate::file::File;
+
+/// 256 glyphs with 16 rows of 8 pixels each
+static BUILTIN: &[u8; 256 * 16] = include_bytes!("font.bin");
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02;
+const PSF1_SEPARATOR: u16 = 0xffff;
+const PSF1_START_SEQUENCE: u16 = 0xfffe;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_START_SEQUENCE: u8 = 0xfe;
+
+/// A bitmap font.
+pub(crate) struct Font {
+    /// the width of a glyph in pixels
+    pub(crate) width: usize,
+    /// the height of a glyph in pixels
+    pub(crate) height: usize,
+    /// the bitmaps of all glyphs (each row is padded to whole bytes)
+    glyphs: Cow<'static, [u8]>,
+    glyph_count: usize,
+    /// which glyph to use for a character (if the font says so)
+    unicode: Option<BTreeMap<char, usize>>,
+}
+
+impl Font {
+    /// Get the bundled font.
+    pub(crate) fn builtin() -> Self {
+        Self {
+            width: 8, height: 16, glyphs: Cow::Borrowed(BUILTIN), glyph_count: 256,
+            unicode: None,
+        }
+    }
+    
+    /// Load a PSF font from a file.
+    ///
+    /// Errors are logged and return `None`.
+    pub(crate) fn load(path: &str, volume: &mut Directory) -> Option<Self> {
+        let data: Vec<u8> = File::open(path, volume)
+            .and_then(|f| f.try_into())
+            .map_err(|e| warn!("failed to load the font: {e:?}"))
+            .ok()?;
+        let font = parse_psf(&data);
+        match &font {
+            Some(font) => debug!(
+                "loaded a font with {} glyphs of {}x{} pixels",
+                font.glyph_count, font.width, font.height,
+            ),
+            None => warn!("'{path}' is not a valid PSF font"),
+        }
+        font
+    }
+    
+    /// Get how many bytes one row of a glyph takes.
+    fn row_size(&self) -> usize {
+        (self.width + 7) / 8
+    }
+    
+    /// Get the bitmap for a character.
+    ///
+    /// Characters the font doesn't have are replaced by a question mark.
+    fn glyph(&self, chr: char) -> &[u8] {
+        let index = match &self.unicode {
+            Some(unicode) => unicode.get(&chr).or_else(|| unicode.get(&'?')).copied(),
+            None => Some(u32::from(chr) as usize).filter(|i| *i < self.glyph_count),
+        }.unwrap_or('?' as usize).min(self.glyph_count - 1);
+        let size = self.row_size() * self.height;
+        &self.glyphs[index * size..(index + 1) * size]
+    }
+    
+    /// Draw text into a buffer of pixels with the given width.
+    ///
+    /// `x` and `y` are the top left corner of the first character in pixels.
+    /// Every pixel of the font becomes a square of `scale` pixels.
+    /// Anything outside of the buffer is cut off.
+    pub(crate) fn draw_text(
+        &self, buffer: &mut [BltPixel], buffer_width: usize,
+        x: usize, y: usize, text: &str, color: BltPixel, scale: usize,
+    ) {
+        let buffer_height = buffer.len() / buffer_width.max(1);
+        let row_size = self.row_size();
+        for (index, chr) in text.chars().enumerate() {
+            let glyph = self.glyph(chr);
+            let glyph_x = x + index * self.width * scale;
+            for (row, bits) in glyph.chunks(row_size).enumerate() {
+                for column in 0..self.width {
+                    if bits[column / 8] & (0x80 >> (column % 8)) == 0 {
+                        continue
+                    }
+                    let (left, top) = (glyph_x + column * scale, y + row * scale);
+                    for pixel_y in top..(top + scale).min(buffer_height) {
+                        let begin = pixel_y * buffer_width + left.min(buffer_width);
+                        let end = pixel_y * buffer_width + (left + scale).min(buffer_width);
+                        buffer[begin..end].fill(color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read a little-endian integer from a slice.
+macro_rules! read_le {
+    ($type:ty, $data:expr, $offset:expr) => {
+        $data.get($offset..$offset + core::mem::size_of::<$type>())
+            .map(|b| <$type>::from_le_bytes(b.try_into().unwrap()))
+    };
+}
+
+/// Parse a PSF font (version 1 or 2).
+fn parse_psf(data: &[u8]) -> Option<Font> {
+    if data.get(0..2)? == PSF1_MAGIC {
+        let mode = *data.get(2)?;
+        let height = *data.get(3)? as usize;
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyphs = data.get(4..4 + glyph_count * height)?;
+        let unicode = (mode & PSF1_MODE_HAS_TABLE != 0).then(|| parse_psf1_table(
+            &data[4 + glyph_count * height..], glyph_count,
+        ));
+        check_font(Font {
+            width: 8, height, glyphs: Cow::Owned(glyphs.to_vec()), glyph_count, unicode,
+        })
+    } else if data.get(0..4)? == PSF2_MAGIC {
+        let header_size = read_le!(u32, data, 8)? as usize;
+        let flags = read_le!(u32, data, 12)?;
+        let glyph_count = read_le!(u32, data, 16)? as usize;
+        let glyph_size = read_le!(u32, data, 20)? as usize;
+        let height = read_le!(u32, data, 24)? as usize;
+        let width = read_le!(u32, data, 28)? as usize;
+        if glyph_size != (width + 7) / 8 * height {
+            return None
+        }
+        let end = header_size.checked_add(glyph_count.checked_mul(glyph_size)?)?;
+        let glyphs = data.get(header_size..end)?;
+        let unicode = (flags & PSF2_HAS_UNICODE_TABLE != 0).then(|| parse_psf2_table(
+            &data[end..], glyph_count,
+        ));
+        check_font(Font {
+            width, height, glyphs: Cow::Owned(glyphs.to_vec()), glyph_count, unicode,
+        })
+    } else {
+        None
+    }
+}
+
+/// Make sure that a font can actually be used.
+fn check_font(font: Font) -> Option<Font> {
+    if font.width > 0 && font.height > 0 && font.glyph_count > 0 {
+        Some(font)
+    } else {
+        None
+    }
+}
+
+/// Parse the Unicode table of a PSF1 font.
+///
+/// Each glyph has a list of UCS-2 characters, followed by sequences of characters
+/// (which we ignore) and a separator.
+fn parse_psf1_table(data: &[u8], glyph_count: usize) -> BTreeMap<char, usize> {
+    let mut table = BTreeMap::new();
+    let mut glyph = 0;
+    let mut in_sequence = false;
+    for value in data.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]])) {
+        if glyph >= glyph_count {
+            break
+        }
+        match value {
+            PSF1_SEPARATOR => {
+                glyph += 1;
+                in_sequence = false;
+            },
+            PSF1_START_SEQUENCE => in_sequence = true,
+            _ if in_sequence => (),
+            _ => if let Some(chr) = char::from_u32(value.into()) {
+                table.entry(chr).or_insert(glyph);
+            },
+        }
+    }
+    table
+}
+
+/// Parse the Unicode table of a PSF2 font.
+///
+/// Each glyph has a UTF-8 string of characters, followed by sequences of characters
+/// (which we ignore) and a separator.
+fn parse_psf2_table(data: &[u8], glyph_count: usize) -> BTreeMap<char, usize> {
+    let mut table = BTreeMap::new();
+    for (glyph, entry) in data.split(|b| *b == PSF2_SEPARATOR).take(glyph_count).enumerate() {
+        let characters = entry.split(|b| *b == PSF2_START_SEQUENCE).next().unwrap_or(&[]);
+        // invalid characters are skipped
+        for chr in String::from_utf8_lossy(characters).chars() {
+            if chr != char::REPLACEMENT_CHARACTER {
+                table.entry(chr).or_insert(glyph);
+            }
+        }
+    }
+    table
+}
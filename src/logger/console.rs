@@ -0,0 +1,20 @@
+//! Logging to the console
+//!
+//! Warnings and errors are colored. The console doesn't tell which colors
+//! are set, so the firmware's default ones are set again afterwards.
+
+use uefi::proto::console::text::Color;
+
+use log::Level;
+
+use super::super::firmware;
+
+/// Write a line to the console.
+pub(super) fn write(level: Level, line: &str) {
+    let color = match level {
+        Level::Error => Some(Color::LightRed),
+        Level::Warn => Some(Color::Yellow),
+        _ => None,
+    };
+    firmware::get().write_console(line, color);
+}
@@ -0,0 +1,16 @@
+//! Logging to QEMU's debug console (or Bochs' port 0xE9 hack)
+//!
+//! Everything written to the port ends up wherever the emulator has been told
+//! to put it (eg. `-debugcon file:towboot.log`). On real hardware, this port
+//! is usually unused, but it's better not to write to it there.
+
+use super::super::port::outb;
+
+const PORT: u16 = 0xe9;
+
+/// Write to the debug console.
+pub(super) fn write(text: &str) {
+    for byte in text.bytes() {
+        unsafe { outb(PORT, byte) };
+    }
+}
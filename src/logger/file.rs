@@ -0,0 +1,52 @@
+//! Logging to a file on the ESP
+//!
+//! The log is appended to `\EFI\towboot\towboot.log`. If it has grown too large,
+//! it's moved to `towboot.log.old` (replacing the previous one) first, so there
+//! are at most two of them.
+
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::proto::media::file::{Directory, File as UefiFile, RegularFile};
+
+use super::Local;
+use super::super::file::{self, File};
+
+const PATH: &str = "\\EFI\\towboot\\towboot.log";
+const OLD_PATH: &str = "\\EFI\\towboot\\towboot.log.old";
+
+/// the opened log file
+static FILE: Local<Option<RegularFile>> = Local::new(None);
+
+/// Write to the file (if it's open).
+///
+/// If writing fails, the file is closed and nothing is written to it anymore.
+pub(super) fn write(text: &str) {
+    FILE.with(|log_file| if let Some(file) = log_file {
+        if file.write(text.as_bytes()).is_err() || file.flush().is_err() {
+            *log_file = None;
+        }
+    })
+}
+
+/// Open the log file (rotating it if it's larger than `max_size`).
+pub(super) fn open(volume: &mut Directory, max_size: usize) -> Result<(), Status> {
+    let mut log_file = file::open_for_appending(PATH, volume)?;
+    let size = log_file.get_position().map_err(|e| e.status())?;
+    if size > max_size as u64 {
+        // This is not that large, so it can just be read.
+        drop(log_file);
+        let content: Vec<u8> = File::open(PATH, volume)?.try_into()?;
+        file::delete(OLD_PATH, volume)?;
+        file::write(OLD_PATH, volume, &content)?;
+        file::delete(PATH, volume)?;
+        log_file = file::open_for_appending(PATH, volume)?;
+    }
+    FILE.with(|f| *f = Some(log_file));
+    Ok(())
+}
+
+/// Close the log file.
+pub(super) fn close() {
+    FILE.with(|f| *f = None);
+}
@@ -0,0 +1,41 @@
+//! Keeping the log for the kernel
+//!
+//! The most recent lines are kept in memory, so that they can be passed to the
+//! kernel as a module (see `boot`). Once there are too many, the oldest ones
+//! are dropped.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+
+use super::Local;
+
+/// how much of the log is kept (in bytes)
+const SIZE: usize = 64 * 1024;
+
+/// the lines and how many bytes they take up
+static LINES: Local<(VecDeque<String>, usize)> = Local::new((VecDeque::new(), 0));
+
+/// Keep some text (consisting of whole lines).
+pub(super) fn write(text: &str) {
+    LINES.with(|(lines, size)| {
+        for line in text.split_inclusive('\n') {
+            *size += line.len();
+            lines.push_back(line.into());
+            while *size > SIZE {
+                match lines.pop_front() {
+                    Some(dropped) => *size -= dropped.len(),
+                    None => break,
+                }
+            }
+        }
+    });
+}
+
+/// Get everything that has been kept so far.
+pub(super) fn get() -> String {
+    LINES.with(|(lines, size)| {
+        let mut text = String::with_capacity(*size);
+        lines.iter().for_each(|line| text.push_str(line));
+        text
+    })
+}
@@ -0,0 +1,252 @@
+//! Logging
+//!
+//! Log records go to several targets, which can be chosen in the config
+//! (`log_targets`): the console (the default), a file on the ESP, a serial port,
+//! QEMU's debug console and a module for the kernel.
+//! Until the config has been read, only the console is used; the records are
+//! kept in memory and written to the other targets once they've been set up.
+//!
+//! Each line starts with the level and the phase of the boot process it has
+//! been logged in (see `set_phase`). On the console, warnings are yellow and
+//! errors are red, so they stand out while the lines are scrolling by.
+//!
+//! Nothing can be logged after exiting boot services, so `disable` has to be
+//! called right before.
+
+use core::cell::UnsafeCell;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use uefi::proto::media::file::Directory;
+
+use log::{warn, LevelFilter, Log, Metadata, Record};
+
+use super::config::{Config, LogTarget};
+
+mod console;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod debugcon;
+mod file;
+mod kernel;
+mod serial;
+
+/// the bits of `TARGETS`
+const CONSOLE: u8 = 1 << 0;
+const FILE: u8 = 1 << 1;
+const SERIAL: u8 = 1 << 2;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const DEBUGCON: u8 = 1 << 3;
+const KERNEL: u8 = 1 << 4;
+
+/// The default maximum size of the log file (in KiB).
+const DEFAULT_FILE_SIZE: usize = 256;
+/// how much to keep in memory until the targets have been set up
+const EARLY_LIMIT: usize = 64 * 1024;
+
+/// what towboot is doing right now (a `Phase`)
+static PHASE: AtomicU8 = AtomicU8::new(Phase::Startup as u8);
+/// where to log to
+static TARGETS: AtomicU8 = AtomicU8::new(CONSOLE);
+/// whether boot services are still there
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// whether a record is being logged right now
+///
+/// Writing to a file may log something itself, which then only goes to the console.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+static LOGGER: Logger = Logger;
+
+/// what has been logged before the targets have been set up (`None` after that)
+static EARLY: Local<Option<String>> = Local::new(Some(String::new()));
+
+/// State that is only accessed by the logger.
+///
+/// UEFI applications run on a single processor and `BUSY` makes sure that
+/// the logger doesn't get here twice at once, so this doesn't need a lock.
+struct Local<T>(UnsafeCell<T>);
+
+unsafe impl<T> Sync for Local<T> {}
+
+impl<T> Local<T> {
+    const fn new(value: T) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    /// Access the state. This must not be nested.
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(unsafe { &mut *self.0.get() })
+    }
+}
+
+/// The phases of the boot process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// finding out where we've been loaded from
+    Startup,
+    /// reading the configuration file
+    Config,
+    /// waiting for the user to choose an entry
+    Menu,
+    /// loading the kernel and the modules
+    Loading,
+    /// exiting boot services and jumping to the kernel
+    Booting,
+}
+
+impl Phase {
+    const ALL: [Self; 5] = [Self::Startup, Self::Config, Self::Menu, Self::Loading, Self::Booting];
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::Startup => "startup",
+            Self::Config => "config",
+            Self::Menu => "menu",
+            Self::Loading => "loading",
+            Self::Booting => "booting",
+        })
+    }
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        ENABLED.load(Ordering::Relaxed)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return
+        }
+        let line = format!(
+            "[{:>5}] {:>7}: {:>12}@{:03}: {}\n", record.level(), phase(),
+            record.file().unwrap_or("<unknown>"), record.line().unwrap_or(0), record.args(),
+        );
+        let targets = TARGETS.load(Ordering::Relaxed);
+        if targets & CONSOLE != 0 {
+            console::write(record.level(), &line);
+        }
+        if BUSY.swap(true, Ordering::Acquire) {
+            return
+        }
+        EARLY.with(|early| if let Some(early) = early {
+            if early.len() + line.len() <= EARLY_LIMIT {
+                early.push_str(&line);
+            }
+        });
+        if targets & FILE != 0 {
+            file::write(&line);
+        }
+        if targets & SERIAL != 0 {
+            serial::write(&line);
+        }
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if targets & DEBUGCON != 0 {
+            debugcon::write(&line);
+        }
+        if targets & KERNEL != 0 {
+            kernel::write(&line);
+        }
+        BUSY.store(false, Ordering::Release);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Start logging (to the console).
+///
+/// This has to be called right after initializing the UEFI services.
+pub(crate) fn init() {
+    log::set_logger(&LOGGER).expect("failed to set the logger");
+    log::set_max_level(LevelFilter::Trace);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Set what towboot is doing right now.
+pub(crate) fn set_phase(phase: Phase) {
+    PHASE.store(phase as u8, Ordering::Relaxed);
+}
+
+/// Get what towboot is doing right now.
+pub(crate) fn phase() -> Phase {
+    Phase::ALL[usize::from(PHASE.load(Ordering::Relaxed))]
+}
+
+/// Switch to the targets from the config.
+///
+/// Each target gets what has been logged so far. If setting up a target fails,
+/// this is logged (to the other targets).
+pub(crate) fn configure(config: &Config, volume: &mut Directory) {
+    BUSY.store(true, Ordering::Acquire);
+    let early = EARLY.with(Option::take).unwrap_or_default();
+    let mut targets = 0;
+    let mut failures = Vec::new();
+    for target in config.log_targets.as_deref().unwrap_or(&[LogTarget::Console]) {
+        let result = match target {
+            LogTarget::Console => Ok(CONSOLE),
+            LogTarget::File => file::open(
+                volume, config.log_file_size.unwrap_or(DEFAULT_FILE_SIZE) * 1024,
+            ).map(|()| {
+                file::write(&early);
+                FILE
+            }),
+            LogTarget::Serial => serial::open(config.serial_baud_rate).map(|()| {
+                serial::write(&early);
+                SERIAL
+            }),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            LogTarget::Debugcon => {
+                debugcon::write(&early);
+                Ok(DEBUGCON)
+            },
+            #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+            LogTarget::Debugcon => Err(uefi::Status::UNSUPPORTED),
+            LogTarget::Kernel => {
+                kernel::write(&early);
+                Ok(KERNEL)
+            },
+        };
+        match result {
+            Ok(target) => targets |= target,
+            Err(e) => failures.push((target, e)),
+        }
+    }
+    TARGETS.store(targets, Ordering::Relaxed);
+    BUSY.store(false, Ordering::Release);
+    for (target, e) in failures {
+        warn!("failed to set up logging to {target:?}, not logging there: {e:?}");
+    }
+}
+
+/// Get the log for the kernel, if it should get one.
+///
+/// This contains the most recent lines (see the `kernel` module).
+pub(crate) fn for_kernel() -> Option<String> {
+    if TARGETS.load(Ordering::Relaxed) & KERNEL == 0 {
+        return None
+    }
+    // Nothing can be logged while it's being copied.
+    BUSY.store(true, Ordering::Acquire);
+    let log = kernel::get();
+    BUSY.store(false, Ordering::Release);
+    Some(log)
+}
+
+/// Check whether we're still logging (which means that boot services are still there).
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Stop logging.
+///
+/// This has to be called before exiting boot services, it closes the log file.
+pub(crate) fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+    file::close();
+}
@@ -0,0 +1,72 @@
+//! Logging to a serial port
+//!
+//! This uses the firmware's Serial I/O protocol. If there's none, the first
+//! serial port is programmed directly (only on x86).
+
+use uefi::prelude::*;
+use uefi::proto::console::serial::Serial;
+
+use super::Local;
+use super::super::firmware::system_table;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use super::super::port;
+
+/// the baud rate for the first serial port if the firmware can't drive it
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const DEFAULT_BAUD_RATE: u64 = 115_200;
+
+enum Port {
+    Firmware(&'static mut Serial<'static>),
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Legacy,
+}
+
+impl Port {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            // There's nowhere to report this to.
+            Self::Firmware(serial) => { let _ = serial.write(bytes); },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Legacy => port::write_serial(bytes),
+        }
+    }
+}
+
+static PORT: Local<Option<Port>> = Local::new(None);
+
+/// Find the serial port and set its baud rate (if there is one).
+pub(super) fn open(baud_rate: Option<u64>) -> Result<(), Status> {
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    let port = match boot_services.locate_protocol::<Serial>() {
+        Ok(serial) => {
+            let serial = unsafe { &mut *serial.get() };
+            if let Some(baud_rate) = baud_rate {
+                let mut mode = *serial.io_mode();
+                mode.baud_rate = baud_rate;
+                serial.set_attributes(&mode).map_err(|e| e.status())?;
+            }
+            Port::Firmware(serial)
+        },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Err(_) => {
+            port::init_serial(baud_rate.unwrap_or(DEFAULT_BAUD_RATE));
+            Port::Legacy
+        },
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        Err(e) => return Err(e.status()),
+    };
+    PORT.with(|p| *p = Some(port));
+    Ok(())
+}
+
+/// Write a line to the serial port (with the line endings a terminal expects).
+pub(super) fn write(line: &str) {
+    PORT.with(|port| if let Some(port) = port {
+        for (index, part) in line.split('\n').enumerate() {
+            if index > 0 {
+                port.write(b"\r\n");
+            }
+            port.write(part.as_bytes());
+        }
+    })
+}
@@ -1,5 +1,6 @@
-#![no_std]
-#![no_main]
+// The tests run on the host (see the `firmware` module).
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(abi_efiapi)]
 #![feature(asm_const)]
 #![feature(result_flattening)] // used in boot/mod.rs
@@ -9,7 +10,7 @@
 extern crate alloc;
 
 use core::str::FromStr;
-use alloc::string::ToString;
+use alloc::string::{String, ToString};
 
 use uefi::prelude::*;
 use uefi::table::boot::OpenProtocolAttributes;
@@ -20,20 +21,43 @@ use uefi::proto::media::fs::SimpleFileSystem;
 
 use log::{debug, info, warn, error};
 
+mod args;
+mod beep;
 mod boot;
+mod compression;
 // contains several workarounds for bugs in the Rust UEFI targets
 mod hacks;
 mod config;
+mod cpio;
+mod embedded;
 mod file;
+mod firmware;
+mod font;
+mod logger;
 mod mem;
+mod memtest;
 mod menu;
+mod panic;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod port;
+mod power;
+mod progress;
+mod resume;
+mod uri;
+mod vars;
+
+/// the code the firmware logs if the watchdog fires (0 to 0xffff are reserved)
+const WATCHDOG_CODE: u64 = 0x1_0000;
 
 #[entry]
 fn efi_main(image: Handle, mut systab: SystemTable<Boot>) -> Status {
     // Putting this comment above the function breaks the entry annotation.
     //! This is the main function.
     //! Startup happens here.
-    uefi_services::init(&mut systab).expect("Failed to initialize utilities");
+    firmware::init(&mut systab).expect("Failed to initialize utilities");
+    logger::init();
+    panic::init(image);
+    boot::timing::init(&systab);
     
     // blocks are so cool, I wish the borrow checker was real
     //
@@ -66,6 +90,15 @@ fn efi_main(image: Handle, mut systab: SystemTable<Boot>) -> Status {
         .expect("Failed to open loaded image protocol");
         let loaded_image = unsafe { &mut *loaded_image.interface.get() };
         
+        // remember where we are, so that no kernel is loaded over us
+        let (image_base, image_size) = loaded_image.info();
+        debug!("we have been loaded to {image_base:?} (+{image_size:#x})");
+        mem::set_own_image(image_base as usize, image_size.try_into().unwrap());
+        // the configuration and the kernel may be part of our image
+        embedded::init(image_base as usize, image_size.try_into().unwrap());
+        // for files elsewhere (on other partitions or the network)
+        uri::init(image, loaded_image.device());
+        
         // get the load options
         let load_options = match loaded_image.load_options_as_cstr16() {
             Ok(s) => {
@@ -95,18 +128,26 @@ fn efi_main(image: Handle, mut systab: SystemTable<Boot>) -> Status {
         )
         .expect("Failed to open filesystem");
         let fs = unsafe { &mut *fs.interface.get() };
+        // for `$FSUUID` in command lines
+        args::read_volume_serial(loaded_image.device(), image, &systab);
+        // for the Multiboot information
+        boot::find_disks(loaded_image.device(), image, &systab);
         let mut volume = fs.open_volume().expect("Failed to open root directory");
         
+        logger::set_phase(logger::Phase::Config);
+        let start = boot::timing::now();
         let config = match config::get(
             &mut volume, load_options.as_deref(),
         ) {
             Ok(Some(c)) => c,
             Ok(None) => return Status::SUCCESS,
             Err(e) => {
+                // without entries, the menu starts the rescue prompt
                 error!("failed to get config: {e:?}");
-                return Status::INVALID_PARAMETER
+                config::Config::default()
             }
         };
+        boot::timing::add(boot::timing::Step::Config, start);
         if let Some(level) = &config.log_level {
             if let Ok(level) = log::LevelFilter::from_str(level) {
                 log::set_max_level(level);
@@ -114,23 +155,63 @@ fn efi_main(image: Handle, mut systab: SystemTable<Boot>) -> Status {
                 warn!("'{level}' is not a valid log level, using default");
             }
         }
+        logger::configure(&config, &mut volume);
         debug!("config: {config:?}");
+        // The firmware resets the machine if we haven't booted anything after
+        // five minutes, which would interrupt a menu without a timeout.
+        // (Exiting boot services disables the watchdog again.)
+        if let Err(e) = systab.boot_services().set_watchdog_timer(
+            config.watchdog.unwrap_or(0), WATCHDOG_CODE, None,
+        ) {
+            warn!("failed to set the watchdog timer: {e:?}");
+        }
+        mem::reserve(&mem::reserved_ranges(&config));
+        if let Some(size) = config.read_chunk_size {
+            match size.checked_mul(1024) {
+                Some(size) => file::set_chunk_size(size),
+                None => warn!("the chunk size of {size} KiB is too large, using the default"),
+            }
+        }
+        if let Some(retries) = config.read_retries {
+            file::set_retries(retries);
+        }
+        if let Some(delay) = config.read_retry_delay {
+            // (this would overflow on i686 for delays above an hour or so)
+            file::set_retry_delay(delay.saturating_mul(1000));
+        }
         (config, volume)
     };
-    let entry_to_boot = menu::choose(&config, &mut systab);
-    debug!("okay, trying to load {entry_to_boot:?}");
-    info!("loading {entry_to_boot}...");
-    
-    match boot::PreparedEntry::new(entry_to_boot, &mut volume, &systab) {
-        Ok(e) => {
-            info!("booting {entry_to_boot}...");
-            e.boot(image, systab);
-            unreachable!();
-        },
-        Err(e) => {
-            error!("failed to prepare the entry: {e:?}");
-            return e // give up
-            // TODO: perhaps redisplay the menu or something like that
-        },
-    };
+    // if preparing an entry fails, the menu is displayed again
+    let mut failure: Option<(String, Status)> = None;
+    loop {
+        logger::set_phase(logger::Phase::Menu);
+        let start = boot::timing::now();
+        let entry_to_boot = match menu::choose(
+            &config, failure.as_ref().map(|(entry, status)| (entry.as_str(), *status)), image,
+            &mut volume, &mut systab,
+        ) {
+            Some(e) => e,
+            // the menu couldn't be displayed, so give up
+            None => return failure.map_or(Status::ABORTED, |(_, status)| status),
+        };
+        boot::timing::add(boot::timing::Step::Menu, start);
+        logger::set_phase(logger::Phase::Loading);
+        debug!("okay, trying to load {entry_to_boot:?}");
+        info!("loading {entry_to_boot}...");
+        
+        match boot::PreparedEntry::new(&entry_to_boot, &config, &mut volume, &systab) {
+            Ok(e) => {
+                logger::set_phase(logger::Phase::Booting);
+                info!("booting {entry_to_boot}...");
+                boot::timing::report(&config, &mut systab);
+                beep::play(&config, beep::Sound::Booting);
+                e.boot(image, systab);
+                unreachable!();
+            },
+            Err(e) => {
+                error!("failed to prepare the entry: {e:?}");
+                failure = Some((entry_to_boot.to_string(), e));
+            },
+        };
+    }
 }
@@ -6,23 +6,84 @@
 //! We solve this by encapsulating it into a struct that implements `Drop`.
 //!
 //! Also, gathering memory map information for the kernel happens here.
+//!
+//! The firmware is only reached through the `firmware` module, so all of this
+//! also works with a simulated one.
 
 use alloc::alloc::{alloc, dealloc, Layout};
 use alloc::collections::{btree_map::BTreeMap, btree_set::BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use uefi::prelude::*;
 use uefi::table::boot::{AllocateType, MemoryDescriptor, MemoryType};
-use uefi_services::system_table;
 
 use log::{debug, warn, error};
 
-use super::config::Quirk;
+use super::config::{Config, Quirk};
+use super::firmware;
 
 // no multiboot import here as some of the types have the same name as the UEFI ones
 
 pub(super) const PAGE_SIZE: usize = 4096;
 
+/// Memory of this type is passed to the kernel as reserved.
+///
+/// (This is in the range UEFI leaves to the operating system loader.)
+const RESERVED_FOR_KERNEL: MemoryType = MemoryType::custom(0x8000_0000);
+
+/// where towboot's own image is (see `set_own_image`)
+static OWN_IMAGE_START: AtomicUsize = AtomicUsize::new(0);
+static OWN_IMAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// A range of physical memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Range {
+    pub(crate) start: u64,
+    /// exclusive
+    pub(crate) end: u64,
+}
+
+impl Range {
+    pub(crate) fn new(start: u64, length: u64) -> Self {
+        Self { start, end: start + length }
+    }
+    
+    pub(crate) fn overlaps(&self, other: &Range) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+    
+    /// Get the parts of this range that are not covered by any of the given ones.
+    pub(crate) fn subtract(&self, others: &[Range]) -> Vec<Range> {
+        let mut others = others.to_vec();
+        others.sort_unstable_by_key(|r| r.start);
+        let mut remaining = Vec::new();
+        let mut cursor = self.start;
+        for other in others {
+            if other.start >= self.end {
+                break
+            }
+            if other.start > cursor {
+                remaining.push(Range { start: cursor, end: other.start });
+            }
+            cursor = cursor.max(other.end);
+        }
+        if cursor < self.end {
+            remaining.push(Range { start: cursor, end: self.end });
+        }
+        remaining
+    }
+}
+
+impl From<&MemoryDescriptor> for Range {
+    fn from(descriptor: &MemoryDescriptor) -> Self {
+        Self::new(descriptor.phys_start, descriptor.page_count * PAGE_SIZE as u64)
+    }
+}
+
 /// Tracks our own allocations.
 pub(super) struct Allocation {
     ptr: u64,
@@ -38,7 +99,7 @@ impl Drop for Allocation {
     fn drop(&mut self) {
         // We can't free memory after we've exited boot services.
         // But this only happens in `PreparedEntry::boot` and this function doesn't return.
-        unsafe { system_table().as_ref() }.boot_services().free_pages(self.ptr, self.pages)
+        firmware::get().free_pages(self.ptr, self.pages)
         // let's just panic if we can't free
         .expect("failed to free the allocated memory");
     }
@@ -53,11 +114,22 @@ impl Allocation {
     /// it will print a warning and allocate it somewhere else instead.
     /// You can move the allocated memory later to the correct address by calling
     /// [`move_to_where_it_should_be`], but please keep its safety implications in mind.
+    /// (Memory that towboot itself still needs is never allowed as the destination.)
+    ///
+    /// The quirks of the entry are respected when allocating somewhere else.
     ///
     /// [`move_to_where_it_should_be`]: struct.Allocation.html#method.move_to_where_it_should_be
-    pub(crate) fn new_at(address: usize, size: usize) -> Result<Self, Status>{
+    pub(crate) fn new_at(
+        address: usize, size: usize, quirks: &BTreeSet<Quirk>,
+    ) -> Result<Self, Status>{
+        // This would fail anyway, but moving it there later would overwrite ourselves.
+        let destination = Range::new(address as u64, size as u64);
+        if let Some(own) = own_ranges(&memory_map()?).iter().find(|r| r.overlaps(&destination)) {
+            error!("can't load anything to {destination:x?}, towboot itself is at {own:x?}");
+            return Err(Status::LOAD_ERROR)
+        }
         let count_pages = Self::calculate_page_count(size);
-        match unsafe { system_table().as_ref() }.boot_services().allocate_pages(
+        match firmware::get().allocate_pages(
             AllocateType::Address(address),
             MemoryType::LOADER_DATA,
             count_pages
@@ -68,7 +140,7 @@ impl Allocation {
                 dump_memory_map();
                 warn!("going to allocate it somewhere else and try to move it later");
                 warn!("this might fail without notice");
-                Self::new_under_4gb(size, &BTreeSet::default()).map(|mut allocation| {
+                Self::new_under_4gb(size, quirks).map(|mut allocation| {
                     allocation.should_be_at = Some(address.try_into().unwrap());
                     allocation
                 })
@@ -80,14 +152,50 @@ impl Allocation {
     ///
     /// Note: This will round up to whole pages.
     pub(crate) fn new_under_4gb(size: usize, quirks: &BTreeSet<Quirk>) -> Result<Self, Status> {
-        let count_pages = Self::calculate_page_count(size);
-        let ptr = unsafe { system_table().as_ref() }.boot_services().allocate_pages(
+        Self::allocate_under_4gb(size, quirks, MemoryType::LOADER_DATA)
+    }
+    
+    /// Allocate memory page-aligned below 4GB that the kernel won't overwrite.
+    ///
+    /// It's marked as reserved in the memory map that is passed to the kernel.
+    ///
+    /// Note: This will round up to whole pages.
+    pub(crate) fn new_reserved_under_4gb(size: usize) -> Result<Self, Status> {
+        Self::allocate_under_4gb(size, &BTreeSet::new(), RESERVED_FOR_KERNEL)
+    }
+    
+    /// Allocate memory page-aligned anywhere.
+    ///
+    /// This is only for kernels that can handle 64-bit addresses.
+    ///
+    /// Note: This will round up to whole pages.
+    pub(crate) fn new_anywhere(size: usize) -> Result<Self, Status> {
+        Self::allocate(size, AllocateType::AnyPages, MemoryType::LOADER_DATA)
+    }
+    
+    /// Allocate memory of the given type page-aligned below 4GB.
+    fn allocate_under_4gb(
+        size: usize, quirks: &BTreeSet<Quirk>, memory_type: MemoryType,
+    ) -> Result<Self, Status> {
+        Self::allocate(
+            size,
             AllocateType::MaxAddress(if quirks.contains(&Quirk::ModulesBelow200Mb) {
                 200 * 1024 * 1024
             } else {
                 u32::MAX as usize
             }),
-            MemoryType::LOADER_DATA,
+            memory_type,
+        )
+    }
+    
+    /// Allocate memory of the given type page-aligned.
+    fn allocate(
+        size: usize, allocate_type: AllocateType, memory_type: MemoryType,
+    ) -> Result<Self, Status> {
+        let count_pages = Self::calculate_page_count(size);
+        let ptr = firmware::get().allocate_pages(
+            allocate_type,
+            memory_type,
             count_pages
         ).map_err(|e| {
             error!("failed to allocate {size} bytes of memory: {e:?}");
@@ -109,8 +217,11 @@ impl Allocation {
     }
     
     /// Checks whether a part of memory is allocated.
+    ///
+    /// (This calculates with 64 bits, so that it doesn't overflow on i686.)
     pub(crate) fn contains(&self, begin: u64, length: usize) -> bool {
-        self.ptr <= begin && self.ptr as usize + self.pages * PAGE_SIZE >= begin as usize + length
+        self.ptr <= begin
+        && self.ptr + (self.pages * PAGE_SIZE) as u64 >= begin + length as u64
     }
     
     /// Get the pointer inside.
@@ -118,14 +229,37 @@ impl Allocation {
         self.ptr as *const u8
     }
     
+    /// Get the address the memory is going to end up at.
+    ///
+    /// This is where it should be moved to (if it has to be moved), else where it is now.
+    pub(crate) fn final_address(&self) -> u64 {
+        self.should_be_at.unwrap_or(self.ptr)
+    }
+    
+    /// Get the address this should be moved to later (if any).
+    pub(crate) fn should_be_at(&self) -> Option<u64> {
+        self.should_be_at
+    }
+    
+    /// Get the size of the allocated memory (in bytes, whole pages).
+    pub(crate) fn allocated_size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+    
     /// Move to the desired location.
     ///
     /// This is unsafe: In the worst case we could overwrite ourselves, our variables,
     /// the Multiboot info struct or anything referenced therein.
+    /// To prevent this, pass everything that is still in use (see the `placement` module).
+    /// The destination may overlap with the current location.
     pub(crate) unsafe fn move_to_where_it_should_be(
-        &mut self, memory_map: &[multiboot::information::MemoryEntry]
+        &mut self, memory_map: &[multiboot::information::MemoryEntry], in_use: &[Range],
     ) {
         if let Some(a) = self.should_be_at {
+            let destination = Range::new(a, self.len as u64);
+            if let Some(range) = in_use.iter().find(|r| r.overlaps(&destination)) {
+                panic!("would overwrite {range:x?}, which is still in use");
+            }
             let mut filter = memory_map.iter().filter(|e|
                 e.base_address() <= a
                 && e.base_address() + e.length() >= a + self.len as u64
@@ -148,26 +282,141 @@ impl Allocation {
             self.should_be_at = None;
         }
     }
+    
+    /// Move the contents to a buffer and use that from now on.
+    ///
+    /// This frees the current memory for something else that is being moved.
+    /// Both allocations now refer to the same memory, so neither may be dropped.
+    /// (This only happens after exiting boot services, when nothing is dropped anymore.)
+    pub(crate) unsafe fn move_into(&mut self, buffer: &Allocation) {
+        assert!(buffer.allocated_size() >= self.len, "the buffer is too small");
+        core::ptr::copy_nonoverlapping(self.ptr as *const u8, buffer.ptr as *mut u8, self.len);
+        self.ptr = buffer.ptr;
+    }
+}
+
+impl AsMut<[u8]> for Allocation {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+
+#[cfg(test)]
+impl Allocation {
+    /// Allocate memory at `address` that should be moved to `destination` later.
+    pub(crate) fn new_moving(address: usize, size: usize, destination: u64) -> Self {
+        let mut allocation = Self::new_at(address, size, &BTreeSet::new()).unwrap();
+        assert_eq!(allocation.should_be_at, None, "{address:#x} is in use");
+        allocation.should_be_at = Some(destination);
+        allocation
+    }
+}
+
+/// Remember where towboot's own image has been loaded to.
+///
+/// This is called once at startup (with the information from the `LoadedImage` protocol).
+pub(crate) fn set_own_image(start: usize, size: usize) {
+    OWN_IMAGE_START.store(start, Ordering::Relaxed);
+    OWN_IMAGE_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Get the memory towboot needs until it jumps to the kernel.
+///
+/// This is its own image and its stack (or rather, the memory map entry it's in).
+pub(crate) fn own_ranges(memory_map: &[MemoryDescriptor]) -> Vec<Range> {
+    let mut ranges = Vec::new();
+    let size = OWN_IMAGE_SIZE.load(Ordering::Relaxed);
+    if size != 0 {
+        ranges.push(Range::new(OWN_IMAGE_START.load(Ordering::Relaxed) as u64, size as u64));
+    }
+    let stack_marker = 0u8;
+    let stack = Range::new(&stack_marker as *const u8 as u64, 1);
+    ranges.extend(memory_map.iter().map(Range::from).filter(|r| r.overlaps(&stack)));
+    ranges
+}
+
+/// Get the memory regions the user has reserved for the kernel.
+pub(crate) fn reserved_ranges(config: &Config) -> Vec<Range> {
+    config.reserved_memory.iter().flatten()
+        .filter(|region| region.size > 0)
+        .map(|region| Range::new(region.start, region.size))
+        .collect()
+}
+
+/// Keep towboot away from the memory regions the user has reserved for the kernel.
+///
+/// This allocates them (as reserved), so this only works if they're free.
+/// They are passed to the kernel as reserved in any case (see `prepare_information`).
+pub(crate) fn reserve(ranges: &[Range]) {
+    for range in ranges {
+        let start = range.start / PAGE_SIZE as u64 * PAGE_SIZE as u64;
+        let pages = Allocation::calculate_page_count((range.end - start).try_into().unwrap());
+        match firmware::get().allocate_pages(
+            AllocateType::Address(start.try_into().unwrap()), RESERVED_FOR_KERNEL, pages,
+        ) {
+            // This is never freed.
+            Ok(_) => debug!("reserved {range:x?}"),
+            Err(e) => warn!("failed to reserve {range:x?} ({e:?}), it may be in use already"),
+        }
+    }
+}
+
+/// Get the current memory map.
+pub(crate) fn memory_map() -> Result<Vec<MemoryDescriptor>, Status> {
+    firmware::get().memory_map()
 }
 
 /// Show the current memory map.
 fn dump_memory_map() {
     debug!("memory map:");
-    let mut buf = Vec::new();
-    // The docs say that we should allocate a little bit more memory than needed.
-    buf.resize(
-        unsafe { system_table().as_ref() }
-        .boot_services()
-        .memory_map_size().map_size + 100,
-        0
-    );
-    let (_key, iterator) = unsafe { system_table().as_ref() }.boot_services()
-    .memory_map(buf.as_mut_slice()).expect("failed to get memory map");
-    for descriptor in iterator {
-        debug!("{descriptor:?}");
+    // errors have already been logged
+    if let Ok(descriptors) = memory_map() {
+        for line in describe_memory_map(&descriptors) {
+            debug!("{line}");
+        }
     }
 }
 
+/// Describe the memory map, one line per descriptor (sorted by address).
+///
+/// Each line contains the range, the UEFI type and the type the kernel would get.
+/// (The map the kernel actually gets is sanitized and merged, see `prepare_information`.)
+pub(crate) fn describe_memory_map(descriptors: &[MemoryDescriptor]) -> Vec<String> {
+    let mut descriptors = descriptors.to_vec();
+    descriptors.sort_unstable_by_key(|d| d.phys_start);
+    descriptors.iter().map(|descriptor| {
+        let range = Range::from(descriptor);
+        format!(
+            "{:#014x}-{:#014x} {:<22} {:?}",
+            range.start, range.end.saturating_sub(1), format!("{:?}", descriptor.ty),
+            multiboot_type(descriptor.ty),
+        )
+    }).collect()
+}
+
+/// Get the type of memory the kernel gets for a UEFI memory type.
+fn multiboot_type(memory_type: MemoryType) -> multiboot::information::MemoryType {
+    match memory_type {
+        // after we've started the kernel, no-one needs our code or data
+        MemoryType::LOADER_CODE | MemoryType::LOADER_DATA
+        | MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA
+        => multiboot::information::MemoryType::Available,
+        // the kernel may want to use UEFI Runtime Services
+        MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA
+        => multiboot::information::MemoryType::Reserved,
+        // it's free memory!
+        MemoryType::CONVENTIONAL => multiboot::information::MemoryType::Available,
+        MemoryType::UNUSABLE => multiboot::information::MemoryType::Defect,
+        MemoryType::ACPI_RECLAIM => multiboot::information::MemoryType::ACPI,
+        MemoryType::ACPI_NON_VOLATILE => multiboot::information::MemoryType::NVS,
+        MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE | MemoryType::PAL_CODE
+        => multiboot::information::MemoryType::Reserved,
+        MemoryType::PERSISTENT_MEMORY => multiboot::information::MemoryType::Available,
+        // our GDT and the like
+        RESERVED_FOR_KERNEL => multiboot::information::MemoryType::Reserved,
+        _ => multiboot::information::MemoryType::Reserved, // better be safe than sorry
+    }
+}
 
 /// Proxy Rust's allocator to the multiboot crate.
 pub(super) struct MultibootAllocator {
@@ -179,6 +428,13 @@ impl MultibootAllocator {
     pub(super) fn new() -> Self {
         MultibootAllocator { allocations: BTreeMap::new() }
     }
+
+    /// Get the memory that has been allocated (and that the kernel is going to read).
+    pub(super) fn ranges(&self) -> impl Iterator<Item = Range> + '_ {
+        self.allocations.iter().map(|(address, layout)|
+            Range::new((*address).into(), layout.size() as u64)
+        )
+    }
 }
 
 impl multiboot::information::MemoryManagement for MultibootAllocator {
@@ -231,60 +487,26 @@ impl multiboot::information::MemoryManagement for MultibootAllocator {
 ///
 /// This needs to have a buffer to write to because we can't allocate memory anymore.
 /// (The buffer may be too large.)
+///
+/// The entries are sorted and don't overlap (see `sanitize_memory_map`).
+/// The regions in `reserved` are always marked as reserved.
+/// Adjacent entries of the same type are merged,
+/// unless the `KeepMemoryMapEntries` quirk is set.
 pub(super) fn prepare_information<'a, I>(
     multiboot: &mut multiboot::information::Multiboot, mmap_iter: I,
-    mb_mmap_buf: &'static mut[multiboot::information::MemoryEntry]
+    mb_mmap_buf: &'static mut[multiboot::information::MemoryEntry],
+    reserved: &[Range], quirks: &BTreeSet<Quirk>,
 ) -> &'static [multiboot::information::MemoryEntry]
 where I: ExactSizeIterator<Item = &'a MemoryDescriptor> {
     // Descriptors are the ones from UEFI, Entries are the ones from Multiboot.
-    let mut count = 0;
-    let mut entry_iter = mb_mmap_buf.iter_mut();
-    let mut current_entry = entry_iter.next().unwrap();
-    for descriptor in mmap_iter {
-        let next_entry = multiboot::information::MemoryEntry::new(
-            descriptor.phys_start, descriptor.page_count * PAGE_SIZE as u64, match descriptor.ty {
-                // after we've started the kernel, no-one needs our code or data
-                MemoryType::LOADER_CODE | MemoryType::LOADER_DATA
-                | MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA
-                => multiboot::information::MemoryType::Available,
-                // the kernel may want to use UEFI Runtime Services
-                MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA
-                => multiboot::information::MemoryType::Reserved,
-                // it's free memory!
-                MemoryType::CONVENTIONAL => multiboot::information::MemoryType::Available,
-                MemoryType::UNUSABLE => multiboot::information::MemoryType::Defect,
-                MemoryType::ACPI_RECLAIM => multiboot::information::MemoryType::ACPI,
-                MemoryType::ACPI_NON_VOLATILE => multiboot::information::MemoryType::NVS,
-                MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE | MemoryType::PAL_CODE
-                => multiboot::information::MemoryType::Reserved,
-                MemoryType::PERSISTENT_MEMORY => multiboot::information::MemoryType::Available,
-                _ => multiboot::information::MemoryType::Reserved, // better be safe than sorry
-            }
-        );
-        if count == 0 {
-            *current_entry = next_entry;
-            count += 1;
-        } else {
-            // join adjacent entries of the same type
-            if (
-                next_entry.memory_type() == current_entry.memory_type()
-            ) && (
-                next_entry.base_address() == (
-                    current_entry.base_address() + current_entry.length()
-                )
-            ) {
-                *current_entry = multiboot::information::MemoryEntry::new(
-                    current_entry.base_address(),
-                    current_entry.length() + next_entry.length(),
-                    current_entry.memory_type(),
-                );
-            } else {
-                current_entry = entry_iter.next().unwrap();
-                *current_entry = next_entry;
-                count += 1;
-            }
-        }
-    }
+    // Entries may be merged, but there are never more of them than descriptors.
+    // (The buffer is sized accordingly, but if it's not, don't pass a truncated map.)
+    assert!(
+        mmap_iter.len() + reserved.len() <= mb_mmap_buf.len(),
+        "the memory map has {} entries, but there's only room for {}",
+        mmap_iter.len() + reserved.len(), mb_mmap_buf.len(),
+    );
+    let count = translate_memory_map(mmap_iter, mb_mmap_buf, reserved, quirks);
     
     // "Lower" and "upper" memory as understood by a BIOS in kilobytes.
     // This means:
@@ -292,13 +514,303 @@ where I: ExactSizeIterator<Item = &'a MemoryDescriptor> {
     // adressable by just 20 bits (because the 8086's address bus had just 20 pins).
     // Upper memory is the part of the memory from 1 MB to the next memory hole
     // (usually a few megabytes).
-    let lower = 640; // If we had less than 640KB, we wouldn't fit into memory.
-    let upper = mb_mmap_buf.iter().find(|e| e.base_address() == 1024 * 1024)
-    .unwrap().length() / 1024;
-    multiboot.set_memory_bounds(Some((lower.try_into().unwrap(), upper.try_into().unwrap())));
+    // The firmware may have a hole anywhere, so don't assume anything.
+    // (Lower memory ends at 640 KB at the latest, the VGA memory comes afterwards.)
+    let lower = contiguous_available_memory(&mb_mmap_buf[..count], 0).min(640 * 1024) / 1024;
+    let upper = contiguous_available_memory(&mb_mmap_buf[..count], 1024 * 1024) / 1024;
+    multiboot.set_memory_bounds(Some((
+        lower.try_into().unwrap(), upper.try_into().unwrap_or(u32::MAX),
+    )));
     
     multiboot.set_memory_regions(Some((
         mb_mmap_buf.as_ptr() as multiboot::information::PAddr, count
     )));
     &mb_mmap_buf[0..count]
 }
+
+/// Convert the descriptors to the kernel's memory map (see `prepare_information`).
+///
+/// This works in place and returns how many entries there are.
+fn translate_memory_map<'a>(
+    mmap_iter: impl Iterator<Item = &'a MemoryDescriptor>,
+    mb_mmap_buf: &mut [multiboot::information::MemoryEntry],
+    reserved: &[Range], quirks: &BTreeSet<Quirk>,
+) -> usize {
+    let mut count = 0;
+    for (descriptor, entry) in mmap_iter.zip(mb_mmap_buf.iter_mut()) {
+        *entry = multiboot::information::MemoryEntry::new(
+            descriptor.phys_start, descriptor.page_count * PAGE_SIZE as u64,
+            multiboot_type(descriptor.ty),
+        );
+        count += 1;
+    }
+    // The regions the user has reserved overlap with the others, so they win when sanitizing.
+    for range in reserved {
+        mb_mmap_buf[count] = multiboot::information::MemoryEntry::new(
+            range.start, range.end - range.start, multiboot::information::MemoryType::Reserved,
+        );
+        count += 1;
+    }
+    count = sanitize_memory_map(mb_mmap_buf, count);
+    
+    // join adjacent entries of the same type
+    if !quirks.contains(&Quirk::KeepMemoryMapEntries) {
+        let mut merged = 0;
+        for index in 1..count {
+            let (current, next) = (&mb_mmap_buf[merged], &mb_mmap_buf[index]);
+            if next.memory_type() == current.memory_type()
+            && next.base_address() == current.base_address() + current.length() {
+                mb_mmap_buf[merged] = multiboot::information::MemoryEntry::new(
+                    current.base_address(), current.length() + next.length(),
+                    current.memory_type(),
+                );
+            } else {
+                merged += 1;
+                mb_mmap_buf.swap(merged, index);
+            }
+        }
+        count = count.min(merged + 1);
+    }
+    count
+}
+
+/// Get what the kernel's memory map would look like right now.
+///
+/// The final one is made after exiting boot services, so it may differ slightly.
+pub(super) fn preview_information(
+    reserved: &[Range], quirks: &BTreeSet<Quirk>,
+) -> Result<Vec<multiboot::information::MemoryEntry>, Status> {
+    let descriptors = memory_map()?;
+    // reserved regions may each split another entry
+    let mut entries = vec![
+        multiboot::information::MemoryEntry::default(); descriptors.len() + 2 * reserved.len()
+    ];
+    let count = translate_memory_map(descriptors.iter(), &mut entries, reserved, quirks);
+    entries.truncate(count);
+    Ok(entries)
+}
+
+/// Get the size of the available memory starting at `start` (up to the next hole).
+///
+/// The entries have to be sorted and may not overlap.
+fn contiguous_available_memory(
+    entries: &[multiboot::information::MemoryEntry], start: u64,
+) -> u64 {
+    let mut end = start;
+    for entry in entries {
+        if entry.memory_type() == multiboot::information::MemoryType::Available
+        && entry.base_address() <= end && entry.base_address() + entry.length() > end {
+            end = entry.base_address() + entry.length();
+        }
+    }
+    end - start
+}
+
+/// How much the kernel has to stay away from memory of this type.
+fn restrictiveness(memory_type: multiboot::information::MemoryType) -> u8 {
+    match memory_type {
+        multiboot::information::MemoryType::Available => 0,
+        multiboot::information::MemoryType::ACPI => 1,
+        multiboot::information::MemoryType::NVS => 2,
+        multiboot::information::MemoryType::Reserved => 3,
+        multiboot::information::MemoryType::Defect => 4,
+    }
+}
+
+/// Sort the memory map and get rid of overlaps and empty entries.
+///
+/// The firmware's memory map may be unsorted and may even contain overlapping entries.
+/// If entries overlap, the more restrictive type wins, so the kernel won't touch
+/// memory it shouldn't. This works in place (as we can't allocate memory anymore),
+/// so the buffer may need room for a few more entries than the first `count`.
+/// If it runs out of room, the rest of a split entry is dropped instead.
+/// Returns the new number of entries.
+fn sanitize_memory_map(
+    entries: &mut [multiboot::information::MemoryEntry], mut count: usize,
+) -> usize {
+    loop {
+        // drop empty entries
+        let mut kept = 0;
+        for index in 0..count {
+            if entries[index].length() != 0 {
+                entries.swap(kept, index);
+                kept += 1;
+            }
+        }
+        count = kept;
+        entries[..count].sort_unstable_by_key(|e| e.base_address());
+        // resolve the first overlap and start again
+        let index = match (1..count).find(
+            |i| entries[i - 1].base_address() + entries[i - 1].length() > entries[i].base_address()
+        ) {
+            Some(index) => index,
+            None => return count,
+        };
+        let (first, second) = (&entries[index - 1], &entries[index]);
+        let (first_start, first_type) = (first.base_address(), first.memory_type());
+        let first_end = first_start + first.length();
+        let (second_start, second_type) = (second.base_address(), second.memory_type());
+        let second_end = second_start + second.length();
+        if restrictiveness(second_type) >= restrictiveness(first_type) {
+            // cut the first one before the second one
+            entries[index - 1] = multiboot::information::MemoryEntry::new(
+                first_start, second_start - first_start, first_type,
+            );
+            // and add the rest after it (if there is one and there's room for it,
+            // otherwise the kernel just won't know about the less restrictive rest)
+            if first_end > second_end && count < entries.len() {
+                entries[count] = multiboot::information::MemoryEntry::new(
+                    second_end, first_end - second_end, first_type,
+                );
+                count += 1;
+            }
+        } else {
+            // let the second one start after the first one (it may be empty now)
+            entries[index] = multiboot::information::MemoryEntry::new(
+                first_end.min(second_end), second_end.saturating_sub(first_end), second_type,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::btree_set::BTreeSet;
+    use alloc::vec::Vec;
+
+    use uefi::prelude::*;
+    use uefi::table::boot::MemoryType;
+
+    use multiboot::information::{MemoryEntry, MemoryType as EntryType};
+
+    use super::{preview_information, reserve, sanitize_memory_map, Allocation, Range};
+    use super::super::config::Quirk;
+    use super::super::firmware::Firmware;
+    use super::super::firmware::mock::Mock;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// Get the start, length and type of the entries.
+    fn summarize(entries: &[MemoryEntry]) -> Vec<(u64, u64, EntryType)> {
+        entries.iter().map(|e| (e.base_address(), e.length(), e.memory_type())).collect()
+    }
+
+    #[test]
+    fn subtract() {
+        let range = Range::new(0x1000, 0x4000);
+        assert_eq!(range.subtract(&[]), [range]);
+        assert_eq!(
+            range.subtract(&[Range::new(0x3000, 0x1000), Range::new(0, 0x1800)]),
+            [Range { start: 0x1800, end: 0x3000 }, Range { start: 0x4000, end: 0x5000 }],
+        );
+        assert!(range.subtract(&[Range::new(0, 0x10000)]).is_empty());
+        assert!(!range.overlaps(&Range::new(0x5000, 0x1000)));
+    }
+
+    #[test]
+    fn allocate_at_free_address() {
+        let firmware = Mock::install(&[(MIB, 15 * MIB, MemoryType::CONVENTIONAL)]);
+        let allocation = Allocation::new_at(0x20_0000, 0x1800, &BTreeSet::new()).unwrap();
+        assert_eq!(allocation.as_ptr() as u64, 0x20_0000);
+        assert_eq!(allocation.allocated_size(), 0x2000);
+        assert_eq!(allocation.should_be_at(), None);
+        assert!(allocation.contains(0x20_1000, 0x1000));
+        assert!(!allocation.contains(0x20_1000, 0x1001));
+        assert!(firmware.memory_map().unwrap().iter().any(|d|
+            d.ty == MemoryType::LOADER_DATA && Range::from(d) == Range::new(0x20_0000, 0x2000)
+        ));
+        drop(allocation);
+        assert!(firmware.memory_map().unwrap().iter().all(|d| d.ty == MemoryType::CONVENTIONAL));
+    }
+
+    #[test]
+    fn allocate_somewhere_else_if_in_use() {
+        Mock::install(&[
+            (MIB, MIB, MemoryType::BOOT_SERVICES_DATA),
+            (2 * MIB, 14 * MIB, MemoryType::CONVENTIONAL),
+        ]);
+        let allocation = Allocation::new_at(MIB as usize, 0x1000, &BTreeSet::new()).unwrap();
+        assert_eq!(allocation.should_be_at(), Some(MIB));
+        assert_eq!(allocation.final_address(), MIB);
+        assert_eq!(allocation.as_ptr() as u64, 16 * MIB - 0x1000);
+    }
+
+    #[test]
+    fn allocate_below_200mb() {
+        Mock::install(&[(MIB, 1023 * MIB, MemoryType::CONVENTIONAL)]);
+        let allocation = Allocation::new_under_4gb(0x1000, &BTreeSet::new()).unwrap();
+        assert_eq!(allocation.as_ptr() as u64, 1024 * MIB - 0x1000);
+        let quirks = BTreeSet::from([Quirk::ModulesBelow200Mb]);
+        let allocation = Allocation::new_under_4gb(0x1000, &quirks).unwrap();
+        assert_eq!(allocation.as_ptr() as u64, 200 * MIB - 0x1000);
+    }
+
+    #[test]
+    fn nothing_free_below_4gb() {
+        Mock::install(&[(4096 * MIB, 16 * MIB, MemoryType::CONVENTIONAL)]);
+        assert_eq!(
+            Allocation::new_under_4gb(0x1000, &BTreeSet::new()).err(), Some(Status::LOAD_ERROR),
+        );
+        // but kernels that can handle it get memory above
+        let allocation = Allocation::new_anywhere(0x1000).unwrap();
+        assert_eq!(allocation.as_ptr() as u64, 4112 * MIB - 0x1000);
+    }
+
+    #[test]
+    fn reserved_regions() {
+        Mock::install(&[(MIB, 15 * MIB, MemoryType::CONVENTIONAL)]);
+        let reserved = [Range::new(0x20_0800, 0x1000)];
+        reserve(&reserved);
+        let entries = preview_information(&reserved, &BTreeSet::new()).unwrap();
+        assert_eq!(summarize(&entries), [
+            (MIB, MIB, EntryType::Available),
+            (2 * MIB, 0x2000, EntryType::Reserved),
+            (2 * MIB + 0x2000, 14 * MIB - 0x2000, EntryType::Available),
+        ]);
+        // the entries of the firmware are kept if requested
+        let quirks = BTreeSet::from([Quirk::KeepMemoryMapEntries]);
+        let entries = preview_information(&reserved, &quirks).unwrap();
+        assert_eq!(summarize(&entries)[1..4], [
+            (2 * MIB, 0x800, EntryType::Reserved),
+            (2 * MIB + 0x800, 0x1000, EntryType::Reserved),
+            (2 * MIB + 0x1800, 0x800, EntryType::Reserved),
+        ]);
+    }
+
+    #[test]
+    fn overlapping_entries() {
+        let mut entries = [
+            MemoryEntry::new(0x1000, 0x4000, EntryType::Available),
+            MemoryEntry::new(0x2000, 0x1000, EntryType::NVS),
+            MemoryEntry::new(0x8000, 0, EntryType::Available),
+            MemoryEntry::new(0x6000, 0x2000, EntryType::Reserved),
+            MemoryEntry::new(0x7000, 0x2000, EntryType::Available),
+            MemoryEntry::default(),
+        ];
+        let count = sanitize_memory_map(&mut entries, 5);
+        assert_eq!(summarize(&entries[..count]), [
+            // the more restrictive type wins
+            (0x1000, 0x1000, EntryType::Available),
+            (0x2000, 0x1000, EntryType::NVS),
+            (0x3000, 0x2000, EntryType::Available),
+            (0x6000, 0x2000, EntryType::Reserved),
+            (0x8000, 0x1000, EntryType::Available),
+        ]);
+    }
+
+    #[test]
+    fn overlapping_entries_without_room() {
+        let mut entries = [
+            MemoryEntry::new(0x1000, 0x8000, EntryType::Available),
+            MemoryEntry::new(0x2000, 0x1000, EntryType::Reserved),
+            MemoryEntry::new(0x4000, 0x1000, EntryType::ACPI),
+        ];
+        let count = sanitize_memory_map(&mut entries, 3);
+        assert_eq!(summarize(&entries[..count]), [
+            // there's no room for the rest after the second one, so it's dropped
+            (0x1000, 0x1000, EntryType::Available),
+            (0x2000, 0x1000, EntryType::Reserved),
+            (0x4000, 0x1000, EntryType::ACPI),
+        ]);
+    }
+}
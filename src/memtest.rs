@@ -0,0 +1,106 @@
+//! A quick memory test
+//!
+//! This writes a few patterns to the free memory and reads them back.
+//! It can only test memory the firmware considers to be free (and lets us allocate),
+//! so it's a sanity check and no replacement for a real memory tester.
+
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::table::boot::{AllocateType, MemoryType};
+
+use log::{debug, info, error};
+
+use super::firmware::system_table;
+use super::mem::{self, PAGE_SIZE};
+use super::progress::{Progress, Style};
+
+/// the patterns that are written and read back (in addition to each word's address)
+const PATTERNS: [u64; 4] = [0, !0, 0x5555_5555_5555_5555, 0xaaaa_aaaa_aaaa_aaaa];
+
+/// How many errors are logged. (The others are just counted.)
+const MAX_LOGGED_ERRORS: u64 = 16;
+
+/// What the memory test has found.
+pub(crate) struct Summary {
+    /// how many bytes have been tested
+    pub(crate) tested: u64,
+    /// how many words didn't contain what was written to them
+    pub(crate) errors: u64,
+}
+
+/// Test all free memory.
+pub(crate) fn run(style: Style) -> Result<Summary, Status> {
+    // Only conventional memory is free, everything we use is loader or boot services memory.
+    // Page 0 is left out, as we can't have a pointer to it.
+    let regions: Vec<(usize, usize)> = mem::memory_map()?.iter()
+        .filter(|d| d.ty == MemoryType::CONVENTIONAL)
+        .filter_map(|d| {
+            let (start, pages) = if d.phys_start == 0 {
+                (PAGE_SIZE as u64, d.page_count.checked_sub(1)?)
+            } else {
+                (d.phys_start, d.page_count)
+            };
+            // On i686, we can't reach memory above 4 GB (and the end has to be reachable, too).
+            let start: usize = start.try_into().ok()?;
+            let pages: usize = pages.try_into().ok()?;
+            start.checked_add(pages.checked_mul(PAGE_SIZE)?)?;
+            Some((start, pages)).filter(|(_, pages)| *pages > 0)
+        })
+        .collect();
+    let total = regions.iter()
+        .fold(0usize, |sum, (_, pages)| sum.saturating_add(pages * PAGE_SIZE));
+    info!("testing {} MiB of memory in {} regions...", total / 1024 / 1024, regions.len());
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    let mut progress = Progress::new("memtest", total, style);
+    let mut summary = Summary { tested: 0, errors: 0 };
+    for (start, pages) in regions {
+        // The memory map may have changed in the meantime, so just skip what's gone.
+        if let Err(e) = boot_services.allocate_pages(
+            AllocateType::Address(start), MemoryType::LOADER_DATA, pages,
+        ) {
+            debug!("skipping {start:#x} (+{:#x}): {e:?}", pages * PAGE_SIZE);
+            continue
+        }
+        let words = unsafe {
+            core::slice::from_raw_parts_mut(start as *mut u64, pages * PAGE_SIZE / 8)
+        };
+        for pattern in PATTERNS {
+            test_words(words, |_| pattern, &mut summary.errors);
+        }
+        test_words(words, |address| address, &mut summary.errors);
+        boot_services.free_pages(start as u64, pages)
+            .expect("failed to free the tested memory");
+        summary.tested += (pages * PAGE_SIZE) as u64;
+        progress.update(summary.tested.try_into().unwrap_or(usize::MAX));
+    }
+    if summary.errors == 0 {
+        info!("tested {} MiB of memory, no errors", summary.tested / 1024 / 1024);
+    } else {
+        error!(
+            "tested {} MiB of memory, {} errors", summary.tested / 1024 / 1024, summary.errors,
+        );
+    }
+    Ok(summary)
+}
+
+/// Write a value to every word, read them back and count the differences.
+///
+/// `value` gets the address of the word.
+fn test_words(words: &mut [u64], value: impl Fn(u64) -> u64, errors: &mut u64) {
+    // The accesses are volatile, so that they can't be optimized away.
+    for word in words.iter_mut() {
+        let address = word as *mut u64;
+        unsafe { address.write_volatile(value(address as u64)) };
+    }
+    for word in words.iter_mut() {
+        let address = word as *mut u64;
+        let (expected, actual) = (value(address as u64), unsafe { address.read_volatile() });
+        if expected != actual {
+            if *errors < MAX_LOGGED_ERRORS {
+                error!("memory error at {address:?}: expected {expected:#x}, got {actual:#x}");
+            }
+            *errors += 1;
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! The menu drawn directly to the framebuffer.
+//!
+//! Each frame is composed in memory and then copied to the screen at once.
+//! Text is drawn with a bitmap font. (see the `font` module)
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::proto::console::gop::{BltOp, BltPixel, BltRegion, GraphicsOutput};
+use uefi::proto::media::file::Directory;
+
+use log::{debug, warn};
+
+use crate::config::{Config, Entry, Theme, ThemeColor};
+use crate::file::File;
+use crate::firmware::system_table;
+use crate::font::Font;
+
+use super::{Editor, Frontend, List, Messages, fill};
+use super::pointer::Pointers;
+use super::serial::SerialConsole;
+
+/// An image in memory.
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<BltPixel>,
+}
+
+pub(super) struct GraphicalFrontend {
+    gop: &'static mut GraphicsOutput<'static>,
+    /// the frame that is being drawn
+    canvas: Image,
+    background: Option<Image>,
+    font: Font,
+    /// how much to enlarge the font
+    scale: usize,
+    theme: Theme,
+    background_color: BltPixel,
+    foreground_color: BltPixel,
+    highlight_foreground_color: BltPixel,
+    highlight_background_color: BltPixel,
+    messages: &'static Messages,
+    /// the mouse or touchscreen, if there is one
+    pointers: Option<Pointers>,
+    /// where the list has been drawn the last time
+    /// (the first line on the screen, the index of its item and the number of lines)
+    list_lines: Option<(usize, usize, usize)>,
+}
+
+impl GraphicalFrontend {
+    /// Find the graphics output and load the background image (if there is one).
+    pub(super) fn new(
+        config: &Config, messages: &'static Messages, volume: &mut Directory,
+    ) -> Result<Self, Status> {
+        let output = unsafe { system_table().as_ref() }.boot_services()
+            .locate_protocol::<GraphicsOutput>()
+            .map_err(|e| e.status())?;
+        let gop = unsafe { &mut *output.get() };
+        let (width, height) = gop.current_mode_info().resolution();
+        debug!("drawing the menu at {width}x{height}");
+        let background = config.background.as_ref().and_then(|path| {
+            let data: Vec<u8> = File::open(path, volume)
+                .and_then(|f| f.try_into())
+                .map_err(|e| warn!("failed to load the background image: {e:?}"))
+                .ok()?;
+            let image = parse_bmp(&data);
+            if image.is_none() {
+                warn!("'{path}' is not an uncompressed 24 or 32 bit BMP image");
+            }
+            image
+        });
+        let font = config.font.as_ref()
+            .and_then(|path| Font::load(path, volume))
+            .unwrap_or_else(Font::builtin);
+        // the defaults differ from the text menu's a bit, because we have more colors here
+        let theme = config.theme.clone();
+        let background_color = theme.background
+            .map_or(BltPixel::new(0x10, 0x10, 0x20), to_pixel);
+        let foreground_color = theme.foreground
+            .map_or(BltPixel::new(0xe0, 0xe0, 0xe0), to_pixel);
+        let highlight_foreground_color = theme.highlight_foreground
+            .map_or(foreground_color, to_pixel);
+        let highlight_background_color = theme.highlight_background
+            .map_or(BltPixel::new(0x50, 0x60, 0xa0), to_pixel);
+        Ok(Self {
+            gop,
+            canvas: Image { width, height, pixels: vec![background_color; width * height] },
+            background,
+            // keep about 45 lines on the screen, regardless of the resolution and the font
+            scale: (height / (45 * font.height)).max(1),
+            font,
+            theme,
+            background_color,
+            foreground_color,
+            highlight_foreground_color,
+            highlight_background_color,
+            messages,
+            pointers: Pointers::new(width, height),
+            list_lines: None,
+        })
+    }
+
+    /// Fill the canvas with the background color and image.
+    fn clear(&mut self) {
+        self.canvas.pixels.fill(self.background_color);
+        if let Some(background) = &self.background {
+            // center the image and crop it, if it's too large
+            let x_offset = self.canvas.width as isize - background.width as isize;
+            let y_offset = self.canvas.height as isize - background.height as isize;
+            for y in 0..background.height {
+                let canvas_y = y as isize + y_offset / 2;
+                if canvas_y < 0 || canvas_y >= self.canvas.height as isize {
+                    continue
+                }
+                for x in 0..background.width {
+                    let canvas_x = x as isize + x_offset / 2;
+                    if canvas_x < 0 || canvas_x >= self.canvas.width as isize {
+                        continue
+                    }
+                    self.canvas.pixels[canvas_y as usize * self.canvas.width + canvas_x as usize]
+                        = background.pixels[y * background.width + x];
+                }
+            }
+        }
+    }
+
+    /// Fill a rectangle on the canvas.
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: BltPixel) {
+        for row in y..(y + height).min(self.canvas.height) {
+            let begin = row * self.canvas.width + x.min(self.canvas.width);
+            let end = row * self.canvas.width + (x + width).min(self.canvas.width);
+            self.canvas.pixels[begin..end].fill(color);
+        }
+    }
+
+    /// Draw text onto the canvas, beginning at the given line and column.
+    fn draw_text(&mut self, line: usize, column: usize, text: &str, color: BltPixel) {
+        let (glyph_width, glyph_height) = self.glyph_size();
+        self.font.draw_text(
+            &mut self.canvas.pixels, self.canvas.width,
+            column * glyph_width, line * glyph_height, text, color, self.scale,
+        );
+    }
+
+    /// Get the size of a (scaled) character in pixels.
+    fn glyph_size(&self) -> (usize, usize) {
+        (self.font.width * self.scale, self.font.height * self.scale)
+    }
+
+    /// Highlight a whole line.
+    fn highlight_line(&mut self, line: usize, color: BltPixel) {
+        let (glyph_width, height) = self.glyph_size();
+        let margin = self.margin_left() * glyph_width;
+        let width = self.canvas.width.saturating_sub(2 * margin);
+        self.fill(margin, line * height, width, height, color);
+    }
+
+    /// How many columns to leave empty on the left.
+    fn margin_left(&self) -> usize {
+        self.theme.margin_left.unwrap_or(2)
+    }
+
+    /// Copy the canvas to the screen.
+    fn show(&mut self) -> uefi::Result {
+        self.gop.blt(BltOp::BufferToVideo {
+            buffer: &self.canvas.pixels,
+            src: BltRegion::Full,
+            dest: (0, 0),
+            dims: (self.canvas.width, self.canvas.height),
+        })
+    }
+}
+
+impl Frontend for GraphicalFrontend {
+    fn draw_timeout(
+        &mut self, key: &str, entry: &Entry, timeout: u8, _systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result {
+        self.clear();
+        self.list_lines = None;
+        let lines = self.canvas.height / self.glyph_size().1;
+        self.draw_text(
+            lines.saturating_sub(3), self.margin_left(),
+            &fill(
+                self.messages.countdown, &[&key, &entry.name.as_deref().unwrap_or(key), &timeout],
+            ),
+            self.foreground_color,
+        );
+        self.show()
+    }
+
+    fn draw_list(&mut self, list: &List, _systab: &mut SystemTable<Boot>) -> uefi::Result {
+        self.clear();
+        let margin_left = self.margin_left();
+        let mut line = self.theme.margin_top.unwrap_or(1);
+        let title = self.theme.title.clone().unwrap_or_else(|| "towboot".to_string());
+        self.draw_text(line, margin_left, &title, self.foreground_color);
+        line += 1;
+        if let Some(banner) = self.theme.banner.clone() {
+            for banner_line in banner.lines() {
+                self.draw_text(line, margin_left, banner_line, self.foreground_color);
+                line += 1;
+            }
+        }
+        line += 1;
+        if list.scroll > 0 {
+            self.draw_text(
+                line, margin_left + 1, &format!("^ {}", fill(self.messages.more, &[&list.scroll])),
+                self.foreground_color,
+            );
+        }
+        line += 1;
+        self.list_lines = Some((line, list.scroll, list.visible_items().count()));
+        for (index, item) in list.visible_items() {
+            let color = if index == list.selected {
+                self.highlight_line(line, self.highlight_background_color);
+                self.highlight_foreground_color
+            } else {
+                self.foreground_color
+            };
+            let text = format!("{index}. {}", list.text(item, self.messages));
+            self.draw_text(line, margin_left + 1, &text, color);
+            line += 1;
+        }
+        if list.hidden_below() > 0 {
+            self.draw_text(
+                line, margin_left + 1,
+                &format!("v {}", fill(self.messages.more, &[&list.hidden_below()])),
+                self.foreground_color,
+            );
+        }
+        let lines = self.canvas.height / self.glyph_size().1;
+        if let Some(choice) = &list.invalid_choice {
+            self.draw_text(
+                lines.saturating_sub(4), margin_left,
+                &format!("{}{choice}", self.messages.invalid_choice), self.foreground_color,
+            );
+        } else if let Some(level) = list.log_level {
+            self.draw_text(
+                lines.saturating_sub(4), margin_left, &fill(self.messages.log_level, &[&level]),
+                self.foreground_color,
+            );
+        }
+        self.draw_text(
+            lines.saturating_sub(3), margin_left,
+            &format!("{}{}_", self.messages.select_prompt, list.input),
+            self.foreground_color,
+        );
+        self.draw_text(
+            lines.saturating_sub(2), margin_left, self.messages.list_hint, self.foreground_color,
+        );
+        self.show()
+    }
+    
+    /// Calculate how many entries fit on the screen.
+    ///
+    /// This is the height of the screen minus the title, the banner,
+    /// the scroll indicators and the prompt.
+    fn list_rows(&mut self, _systab: &mut SystemTable<Boot>) -> uefi::Result<usize> {
+        let lines = self.canvas.height / self.glyph_size().1;
+        let header = self.theme.margin_top.unwrap_or(1) + 2
+            + self.theme.banner.as_ref().map_or(0, |b| b.lines().count());
+        Ok(lines.saturating_sub(header + 6).max(1))
+    }
+    
+    fn draw_editor(&mut self, editor: &Editor, _systab: &mut SystemTable<Boot>) -> uefi::Result {
+        self.clear();
+        self.list_lines = None;
+        let margin_left = self.margin_left();
+        let mut line = self.theme.margin_top.unwrap_or(1);
+        self.draw_text(
+            line, margin_left, &fill(self.messages.editing, &[&editor.key]),
+            self.foreground_color,
+        );
+        line += 2;
+        for (index, (image, text)) in editor.lines.iter().enumerate() {
+            self.draw_text(line, margin_left, &format!("{image}:"), self.foreground_color);
+            line += 1;
+            if index == editor.selected {
+                let (before, cursor, after) = editor.split_at_cursor();
+                let column = margin_left + 2 + before.chars().count();
+                self.highlight_line(line, self.highlight_background_color);
+                self.draw_text(line, margin_left + 2, before, self.highlight_foreground_color);
+                // draw the cursor as an inverted block
+                let (glyph_width, glyph_height) = self.glyph_size();
+                self.fill(
+                    column * glyph_width, line * glyph_height, glyph_width, glyph_height,
+                    self.highlight_foreground_color,
+                );
+                self.draw_text(line, column, cursor, self.highlight_background_color);
+                self.draw_text(line, column + 1, after, self.highlight_foreground_color);
+            } else {
+                self.draw_text(line, margin_left + 2, text, self.foreground_color);
+            }
+            line += 1;
+        }
+        let lines = self.canvas.height / self.glyph_size().1;
+        self.draw_text(
+            lines.saturating_sub(3), margin_left, self.messages.editor_hint, self.foreground_color,
+        );
+        self.show()
+    }
+    
+    fn draw_info(
+        &mut self, title: &str, lines: &[String], _systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result {
+        self.clear();
+        self.list_lines = None;
+        let margin_left = self.margin_left();
+        let mut line = self.theme.margin_top.unwrap_or(1);
+        self.draw_text(line, margin_left, title, self.foreground_color);
+        line += 2;
+        for text in lines {
+            self.draw_text(line, margin_left, text, self.foreground_color);
+            line += 1;
+        }
+        self.show()
+    }
+    
+    /// Save the last frame (which is still on the canvas).
+    fn screenshot(
+        &mut self, _list: &List, _systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result<(&'static str, Vec<u8>)> {
+        Ok(("bmp", encode_bmp(&self.canvas)))
+    }
+    
+    fn input_sources(&mut self) -> (Option<&mut SerialConsole>, Option<&mut Pointers>) {
+        (None, self.pointers.as_mut())
+    }
+    
+    /// Find the line of the list at this position (if it's currently displayed).
+    fn line_at(&self, x: usize, y: usize) -> Option<usize> {
+        let (first_line, scroll, count) = self.list_lines?;
+        let (glyph_width, glyph_height) = self.glyph_size();
+        let margin = self.margin_left() * glyph_width;
+        let line = y / glyph_height;
+        (x >= margin && x < self.canvas.width.saturating_sub(margin)
+            && line >= first_line && line < first_line + count
+        ).then(|| scroll + line - first_line)
+    }
+}
+
+/// Convert a color of the theme to a pixel.
+///
+/// This uses the usual VGA palette.
+fn to_pixel(color: ThemeColor) -> BltPixel {
+    let (red, green, blue) = match color {
+        ThemeColor::Black => (0x00, 0x00, 0x00),
+        ThemeColor::Blue => (0x00, 0x00, 0xaa),
+        ThemeColor::Green => (0x00, 0xaa, 0x00),
+        ThemeColor::Cyan => (0x00, 0xaa, 0xaa),
+        ThemeColor::Red => (0xaa, 0x00, 0x00),
+        ThemeColor::Magenta => (0xaa, 0x00, 0xaa),
+        ThemeColor::Brown => (0xaa, 0x55, 0x00),
+        ThemeColor::LightGray => (0xaa, 0xaa, 0xaa),
+        ThemeColor::DarkGray => (0x55, 0x55, 0x55),
+        ThemeColor::LightBlue => (0x55, 0x55, 0xff),
+        ThemeColor::LightGreen => (0x55, 0xff, 0x55),
+        ThemeColor::LightCyan => (0x55, 0xff, 0xff),
+        ThemeColor::LightRed => (0xff, 0x55, 0x55),
+        ThemeColor::LightMagenta => (0xff, 0x55, 0xff),
+        ThemeColor::Yellow => (0xff, 0xff, 0x55),
+        ThemeColor::White => (0xff, 0xff, 0xff),
+    };
+    BltPixel::new(red, green, blue)
+}
+
+/// Read a little-endian integer from a slice.
+macro_rules! read_le {
+    ($type:ty, $data:expr, $offset:expr) => {
+        $data.get($offset..$offset + core::mem::size_of::<$type>())
+            .map(|b| <$type>::from_le_bytes(b.try_into().unwrap()))
+    };
+}
+
+/// Parse an uncompressed BMP image with 24 or 32 bits per pixel.
+fn parse_bmp(data: &[u8]) -> Option<Image> {
+    if data.get(0..2)? != b"BM" {
+        return None
+    }
+    let pixel_offset = read_le!(u32, data, 10)? as usize;
+    let width = read_le!(i32, data, 18)?;
+    let height = read_le!(i32, data, 22)?;
+    let bpp = read_le!(u16, data, 28)? as usize;
+    let compression = read_le!(u32, data, 30)?;
+    // 0 is uncompressed, 3 are bitfields (which we just assume to be BGRA)
+    if !(compression == 0 || (compression == 3 && bpp == 32)) || !(bpp == 24 || bpp == 32) {
+        return None
+    }
+    let width: usize = width.try_into().ok()?;
+    // negative heights mean that the image is stored top-down
+    let top_down = height < 0;
+    let height = height.unsigned_abs() as usize;
+    // rows are padded to 4 bytes
+    // (The header may be broken, so make sure that the file is as large as it says.)
+    let stride = width.checked_mul(bpp)?.checked_add(31)? / 32 * 4;
+    if pixel_offset.checked_add(height.checked_mul(stride)?)? > data.len() {
+        return None
+    }
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = if top_down { y } else { height - 1 - y };
+        for x in 0..width {
+            let offset = pixel_offset + row * stride + x * bpp / 8;
+            let pixel = data.get(offset..offset + 3)?;
+            pixels.push(BltPixel::new(pixel[2], pixel[1], pixel[0]));
+        }
+    }
+    Some(Image { width, height, pixels })
+}
+
+/// Encode an image as an uncompressed 32 bit BMP file.
+fn encode_bmp(image: &Image) -> Vec<u8> {
+    const HEADER_SIZE: u32 = 14 + 40;
+    let size = HEADER_SIZE + image.pixels.len() as u32 * 4;
+    let mut data = Vec::with_capacity(size as usize);
+    // the file header
+    data.extend_from_slice(b"BM");
+    data.extend_from_slice(&size.to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&HEADER_SIZE.to_le_bytes());
+    // the info header
+    data.extend_from_slice(&40u32.to_le_bytes());
+    data.extend_from_slice(&(image.width as i32).to_le_bytes());
+    // store the image top-down, just like the canvas
+    data.extend_from_slice(&(-(image.height as i32)).to_le_bytes());
+    // one plane, 32 bits per pixel
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&32u16.to_le_bytes());
+    // no compression, the default resolution and no palette
+    data.extend_from_slice(&[0; 24]);
+    for pixel in &image.pixels {
+        data.extend_from_slice(&[pixel.blue, pixel.green, pixel.red, 0]);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use uefi::proto::console::gop::BltPixel;
+
+    use super::{encode_bmp, parse_bmp, Image};
+
+    #[test]
+    fn bmp_roundtrip() {
+        let pixels = (0..6).map(|i| BltPixel::new(i, 2 * i, 3 * i)).collect();
+        let image = Image { width: 3, height: 2, pixels };
+        let parsed = parse_bmp(&encode_bmp(&image)).unwrap();
+        assert_eq!((parsed.width, parsed.height), (3, 2));
+        let colors = |image: &Image| image.pixels.iter()
+            .map(|p| (p.red, p.green, p.blue)).collect::<Vec<_>>();
+        assert_eq!(colors(&parsed), colors(&image));
+    }
+
+    #[test]
+    fn bmp_too_large() {
+        let image = Image { width: 1, height: 1, pixels: [BltPixel::new(0, 0, 0)].into() };
+        let mut data = encode_bmp(&image);
+        data[18..22].copy_from_slice(&100_000i32.to_le_bytes());
+        data[22..26].copy_from_slice(&100_000i32.to_le_bytes());
+        assert!(parse_bmp(&data).is_none());
+        // so large that calculating the size overflows on 32 bits
+        data[18..22].copy_from_slice(&i32::MAX.to_le_bytes());
+        data[22..26].copy_from_slice(&i32::MIN.to_le_bytes());
+        data[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse_bmp(&data).is_none());
+        // cut off
+        let mut data = encode_bmp(&image);
+        data.pop();
+        assert!(parse_bmp(&data).is_none());
+    }
+}
@@ -0,0 +1,237 @@
+//! Reading keys (and pointer movements)
+//!
+//! Keys can come from the keyboard (via the UEFI console) or from the serial console.
+//! If the firmware supports the extended input protocol, it's preferred,
+//! as it reports the state of the modifier keys.
+//! The graphical menu also supports mice and touchscreens. (see the `pointer` module)
+//!
+//! Some firmware never signals that a key has been pressed, so the keyboard is
+//! also polled periodically.
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+
+use uefi::prelude::*;
+use uefi::proto::Protocol;
+use uefi::proto::console::text::{Key, RawKey, ScanCode};
+use uefi::table::boot::{EventType, TimerTrigger, Tpl};
+use uefi::{unsafe_guid, Char16, Event};
+
+use log::debug;
+
+use super::pointer::Pointers;
+use super::serial::SerialConsole;
+use super::super::firmware::system_table;
+
+/// Something the user did.
+pub(super) enum Input {
+    Key(KeyPress),
+    /// the pointer has been moved or clicked (at a position in pixels)
+    Pointer { x: usize, y: usize, clicked: bool },
+}
+
+/// A key and the modifiers that were held down while it was pressed.
+pub(super) struct KeyPress {
+    pub(super) key: Key,
+    pub(super) shift: bool,
+    pub(super) control: bool,
+    pub(super) alt: bool,
+}
+
+impl KeyPress {
+    /// Interpret a key without any modifiers.
+    ///
+    /// Terminals (and some firmware) report Ctrl+letter as a control character,
+    /// so these are translated back.
+    pub(super) fn new(key: Key) -> Self {
+        if let Key::Printable(c) = key {
+            let chr = char::from(c);
+            if matches!(chr, '\u{1}'..='\u{1a}') && !matches!(chr, '\u{8}' | '\t' | '\n' | '\r') {
+                let letter = char::from(b'a' + chr as u8 - 1);
+                return Self {
+                    key: Key::Printable(Char16::try_from(letter).unwrap()),
+                    shift: false, control: true, alt: false,
+                }
+            }
+        }
+        Self { key, shift: false, control: false, alt: false }
+    }
+}
+
+/// `EFI_SIMPLE_TEXT_INPUT_EX_PROTOCOL`
+///
+/// (This is not (yet) provided by the `uefi` crate.)
+#[repr(C)]
+#[unsafe_guid("dd9e7534-7762-4698-8c14-f58517a625aa")]
+#[derive(Protocol)]
+struct InputEx {
+    _reset: extern "efiapi" fn(this: &mut InputEx, extended_verification: bool) -> Status,
+    read_key_stroke_ex: extern "efiapi" fn(this: &mut InputEx, key: *mut KeyData) -> Status,
+    wait_for_key_ex: Event,
+    // we don't need these
+    _set_state: usize,
+    _register_key_notify: usize,
+    _unregister_key_notify: usize,
+}
+
+/// `EFI_KEY_DATA`
+#[repr(C)]
+struct KeyData {
+    key: RawKey,
+    shift_state: u32,
+    _toggle_state: u8,
+}
+
+/// whether `shift_state` is valid
+const SHIFT_STATE_VALID: u32 = 0x8000_0000;
+const RIGHT_SHIFT_PRESSED: u32 = 0x01;
+const LEFT_SHIFT_PRESSED: u32 = 0x02;
+const RIGHT_CONTROL_PRESSED: u32 = 0x04;
+const LEFT_CONTROL_PRESSED: u32 = 0x08;
+const RIGHT_ALT_PRESSED: u32 = 0x10;
+const LEFT_ALT_PRESSED: u32 = 0x20;
+
+impl InputEx {
+    /// Read a key, if there is one.
+    fn read_key(&mut self) -> uefi::Result<Option<KeyPress>> {
+        let mut data = MaybeUninit::<KeyData>::uninit();
+        match (self.read_key_stroke_ex)(self, data.as_mut_ptr()) {
+            Status::NOT_READY => Ok(None),
+            status => status.into_with_val(|| unsafe { data.assume_init() }).map(|data| {
+                // only a modifier has been pressed
+                if data.key.scan_code == ScanCode::NULL && data.key.unicode_char == 0 {
+                    return None
+                }
+                let mut key = KeyPress::new(data.key.into());
+                if data.shift_state & SHIFT_STATE_VALID != 0 {
+                    let state = data.shift_state;
+                    key.shift |= state & (LEFT_SHIFT_PRESSED | RIGHT_SHIFT_PRESSED) != 0;
+                    key.control |= state & (LEFT_CONTROL_PRESSED | RIGHT_CONTROL_PRESSED) != 0;
+                    key.alt |= state & (LEFT_ALT_PRESSED | RIGHT_ALT_PRESSED) != 0;
+                }
+                Some(key)
+            }),
+        }
+    }
+}
+
+/// how often to poll the keyboard (in 100ns)
+const KEY_POLL_INTERVAL: u64 = 500_000;
+
+/// the periodic timer to poll the keyboard (see `key_poll_event`)
+static KEY_POLL: KeyPoll = KeyPoll(UnsafeCell::new(None));
+
+struct KeyPoll(UnsafeCell<Option<Event>>);
+
+// This is only written once, from the menu.
+unsafe impl Sync for KeyPoll {}
+
+/// Get the timer that polls the keyboard, creating it the first time.
+///
+/// This returns `None` if the timer can't be created.
+fn key_poll_event() -> Option<&'static Event> {
+    let poll = unsafe { &mut *KEY_POLL.0.get() };
+    if poll.is_none() {
+        let boot_services = unsafe { system_table().as_ref() }.boot_services();
+        // This is safe because there is no callback.
+        match unsafe { boot_services.create_event(
+            EventType::TIMER, Tpl::APPLICATION, None, None
+        ) }.and_then(|event| {
+            boot_services.set_timer(&event, TimerTrigger::Periodic(KEY_POLL_INTERVAL))
+                .map(|()| event)
+        }) {
+            Ok(event) => *poll = Some(event),
+            Err(e) => debug!("failed to create a timer to poll the keyboard: {e:?}"),
+        }
+    }
+    poll.as_ref()
+}
+
+/// Find the extended input protocol.
+///
+/// Some firmware only reports some keys via the extended protocol.
+fn input_ex() -> Option<&'static mut InputEx> {
+    unsafe { system_table().as_ref() }.boot_services()
+        .locate_protocol::<InputEx>().ok()
+        .map(|p| unsafe { &mut *p.get() })
+}
+
+/// Read a key from the keyboard, if there is one.
+fn read_keyboard(
+    input_ex: &mut Option<&mut InputEx>, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Option<KeyPress>> {
+    match input_ex {
+        Some(input) => input.read_key(),
+        None => Ok(systab.stdin().read_key()?.map(KeyPress::new)),
+    }
+}
+
+/// Wait for a key to be pressed (or for the pointer to be used).
+///
+/// If a timer is given, this returns `None` once it fires.
+/// The serial console has no event, so it's polled periodically (and so is
+/// the keyboard, in case the firmware doesn't signal its event).
+pub(super) fn wait_for_input(
+    timer: Option<&Event>, mut serial: Option<&mut SerialConsole>,
+    mut pointers: Option<&mut Pointers>, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Option<Input>> {
+    // the serial console might still have some bytes of an escape sequence
+    if let Some(key) = serial.as_mut().and_then(|s| s.read_key()) {
+        return Ok(Some(Input::Key(key)))
+    }
+    let mut input_ex = input_ex();
+    loop {
+        // this is safe because we're never calling close_event
+        let mut events: Vec<Event> = Vec::new();
+        events.push(match &input_ex {
+            Some(input) => unsafe { input.wait_for_key_ex.unsafe_clone() },
+            None => unsafe { systab.stdin().wait_for_key_event().unsafe_clone() },
+        });
+        if let Some(timer) = timer {
+            events.push(unsafe { timer.unsafe_clone() });
+        }
+        if let Some(serial) = &serial {
+            events.push(unsafe { serial.poll_event().unsafe_clone() });
+        }
+        if let Some(pointers) = &pointers {
+            pointers.push_events(&mut events);
+        }
+        if let Some(poll) = key_poll_event() {
+            events.push(unsafe { poll.unsafe_clone() });
+        }
+        match systab.boot_services().wait_for_event(&mut events).discard_errdata()? {
+            0 => if let Some(key) = read_keyboard(&mut input_ex, systab)? {
+                return Ok(Some(Input::Key(key)))
+            },
+            1 if timer.is_some() => return Ok(None),
+            _ => {
+                if let Some(key) = read_keyboard(&mut input_ex, systab)? {
+                    return Ok(Some(Input::Key(key)))
+                }
+                if let Some(key) = serial.as_mut().and_then(|s| s.read_key()) {
+                    return Ok(Some(Input::Key(key)))
+                }
+                if let Some(input) = pointers.as_mut().and_then(|p| p.read()) {
+                    return Ok(Some(input))
+                }
+            },
+        }
+    }
+}
+
+/// Check for a key (or for the pointer being used) without waiting.
+///
+/// This is for firmware whose timers don't work.
+pub(super) fn poll_input(
+    mut serial: Option<&mut SerialConsole>, mut pointers: Option<&mut Pointers>,
+    systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Option<Input>> {
+    if let Some(key) = read_keyboard(&mut input_ex(), systab)? {
+        return Ok(Some(Input::Key(key)))
+    }
+    if let Some(key) = serial.as_mut().and_then(|s| s.read_key()) {
+        return Ok(Some(Input::Key(key)))
+    }
+    Ok(pointers.as_mut().and_then(|p| p.read()))
+}
@@ -0,0 +1,156 @@
+//! Translations of the menu
+//!
+//! Messages containing placeholders like `{0}` have to be filled in with `fill`.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::fmt::Display;
+
+use log::warn;
+
+use crate::config::{Action, Config};
+
+/// All user-facing strings of the menu.
+pub(super) struct Messages {
+    /// `{0}`: key, `{1}`: name, `{2}`: seconds
+    pub countdown: &'static str,
+    pub select_prompt: &'static str,
+    pub list_hint: &'static str,
+    pub invalid_choice: &'static str,
+    /// `{0}`: level
+    pub log_level: &'static str,
+    /// `{0}`: number of lines
+    pub more: &'static str,
+    /// `{0}`: key
+    pub editing: &'static str,
+    pub editor_hint: &'static str,
+    pub kernel: &'static str,
+    pub command_line: &'static str,
+    pub modules: &'static str,
+    pub quirks: &'static str,
+    pub video: &'static str,
+    /// `{0}`: wanted resolution, `{1}`: used resolution
+    pub video_substitution: &'static str,
+    pub none: &'static str,
+    /// `{0}`: size
+    pub bytes: &'static str,
+    /// `{0}`: compression format, `{1}`: size after unpacking
+    pub compressed: &'static str,
+    pub missing: &'static str,
+    /// `{0}`: how many
+    pub random_bytes: &'static str,
+    pub broken: &'static str,
+    pub back_hint: &'static str,
+    /// `{0}`: entry
+    pub boot_failed: &'static str,
+    pub reboot: &'static str,
+    pub power_off: &'static str,
+    pub firmware_setup: &'static str,
+    pub memtest: &'static str,
+    /// `{0}`: MiB, `{1}`: number of errors
+    pub memtest_result: &'static str,
+    /// `{0}`: page, `{1}`: number of pages
+    pub memory_map: &'static str,
+    pub memory_map_hint: &'static str,
+}
+
+impl Messages {
+    /// Get the name of an action.
+    pub(super) fn action(&self, action: Action) -> &'static str {
+        match action {
+            Action::Reboot => self.reboot,
+            Action::Poweroff => self.power_off,
+            Action::Firmware => self.firmware_setup,
+            Action::Memtest => self.memtest,
+        }
+    }
+}
+
+static ENGLISH: Messages = Messages {
+    countdown: "booting {0} ({1}) in {2} seconds... (press any key to change)",
+    select_prompt: "please select an entry to boot: ",
+    list_hint: "(arrows, PgUp/PgDn, Enter or type a number or key; \
+        e: edit, i: details, c: prompt, m: memory map, l: log level)",
+    invalid_choice: "invalid choice: ",
+    log_level: "log level: {0}",
+    more: "({0} more)",
+    editing: "editing {0} (only for this boot)",
+    editor_hint: "(press Enter to boot or ESC to go back)",
+    kernel: "kernel",
+    command_line: "command line",
+    modules: "modules",
+    quirks: "quirks",
+    video: "video",
+    video_substitution: "{0} isn't available, {1} will be used",
+    none: "none",
+    bytes: "{0} bytes",
+    compressed: "{0}, {1} bytes unpacked",
+    missing: "missing",
+    random_bytes: "{0} random bytes",
+    broken: "(broken)",
+    back_hint: "(press any key to go back)",
+    boot_failed: "failed to boot {0}:",
+    reboot: "Reboot",
+    power_off: "Power off",
+    firmware_setup: "Firmware setup",
+    memtest: "Memory test",
+    memtest_result: "{0} MiB tested, {1} errors",
+    memory_map: "memory map (UEFI and Multiboot type), page {0} of {1}",
+    memory_map_hint: "(arrows or PgUp/PgDn to scroll, any other key to go back)",
+};
+
+static GERMAN: Messages = Messages {
+    countdown: "starte {0} ({1}) in {2} Sekunden... (beliebige Taste zum Ändern)",
+    select_prompt: "bitte einen Eintrag zum Starten auswählen: ",
+    list_hint: "(Pfeile, Bild auf/ab, Enter oder Nummer oder Schlüssel tippen; \
+        e: bearbeiten, i: Details, c: Eingabeaufforderung, m: Speicherbelegung, l: Log-Level)",
+    invalid_choice: "ungültige Auswahl: ",
+    log_level: "Log-Level: {0}",
+    more: "({0} weitere)",
+    editing: "bearbeite {0} (nur für diesen Start)",
+    editor_hint: "(Enter zum Starten, ESC zum Zurückkehren)",
+    kernel: "Kernel",
+    command_line: "Kommandozeile",
+    modules: "Module",
+    quirks: "Quirks",
+    video: "Video",
+    video_substitution: "{0} ist nicht verfügbar, {1} wird verwendet",
+    none: "keine",
+    bytes: "{0} Bytes",
+    compressed: "{0}, {1} Bytes entpackt",
+    missing: "fehlt",
+    random_bytes: "{0} zufällige Bytes",
+    broken: "(defekt)",
+    back_hint: "(beliebige Taste zum Zurückkehren)",
+    boot_failed: "{0} konnte nicht gestartet werden:",
+    reboot: "Neustart",
+    power_off: "Ausschalten",
+    firmware_setup: "Firmware-Einstellungen",
+    memtest: "Speichertest",
+    memtest_result: "{0} MiB getestet, {1} Fehler",
+    memory_map: "Speicherbelegung (UEFI- und Multiboot-Typ), Seite {0} von {1}",
+    memory_map_hint: "(Pfeile oder Bild auf/ab zum Blättern, andere Taste zum Zurückkehren)",
+};
+
+/// Get the messages for the configured language.
+///
+/// This falls back to English for unknown languages.
+pub(super) fn get(config: &Config) -> &'static Messages {
+    match config.language.as_deref() {
+        None | Some("en") => &ENGLISH,
+        Some("de") => &GERMAN,
+        Some(language) => {
+            warn!("there is no translation for '{language}', using English");
+            &ENGLISH
+        },
+    }
+}
+
+/// Fill in the placeholders of a message.
+pub(super) fn fill(message: &str, args: &[&dyn Display]) -> String {
+    let mut result = message.to_string();
+    for (index, arg) in args.iter().enumerate() {
+        result = result.replace(&format!("{{{index}}}"), &arg.to_string());
+    }
+    result
+}
@@ -0,0 +1,1283 @@
+//! Select an entry to boot by displaying a menu.
+//!
+//! The menu can either be displayed on the text console or be drawn directly
+//! to the framebuffer. (see the `text` and `graphical` modules)
+//! It can also be mirrored to a serial console. (see the `serial` module)
+//! The graphical menu can also be used with a mouse or a touchscreen.
+//! (see the `pointer` module)
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::proto::media::file::Directory;
+use uefi::table::boot::{EventType, TimerTrigger, Tpl};
+use uefi::{Char16, Event};
+
+use log::{debug, info, error, warn, LevelFilter};
+
+use crate::beep::{self, Sound};
+use crate::config::{Action, Config, Entry, MenuType};
+use crate::file::{self, File};
+use crate::{boot, compression, mem, memtest, power, progress, resume, vars};
+
+mod graphical;
+mod input;
+mod messages;
+mod pointer;
+mod serial;
+mod shell;
+mod text;
+
+use input::{Input, KeyPress};
+use messages::{fill, Messages};
+use pointer::Pointers;
+use serial::SerialConsole;
+
+/// If `default` is set to this, use the entry that has been chosen the last time.
+const SAVED_DEFAULT: &str = "saved";
+/// how often to check for keys per second if there's no timer
+const POLLS_PER_SECOND: usize = 20;
+
+/// Choose an entry to boot.
+///
+/// Pass in a parsed config, get out the entry portion that was selected.
+/// This will print a message and then wait for the timeout or for a key to be pressed.
+/// On timeout, it will boot the default entry.
+/// On a key press, it will list the available entries and ask which one to boot.
+///
+/// If the `TowbootBootNext` variable is set, it will clear it and boot
+/// the entry named therein without displaying anything.
+///
+/// If the default is `saved`, the entry that has been chosen explicitly the
+/// last time is the default one. (It's stored in the `TowbootSavedEntry` variable.)
+///
+/// If the chosen entry has used up all of its tries, its fallback is booted instead.
+///
+/// If `prefer_last_successful` is set, the entry that booted successfully the
+/// last time is the default one.
+/// (A boot counts as successful if the operating system deletes the
+/// `TowbootBootPending` variable.)
+///
+/// The command lines of the selected entry can be changed for this boot
+/// and entries can be created in the command prompt.
+/// (That's why this might return an owned entry.)
+///
+/// If booting the previously chosen entry failed, the reason is displayed
+/// and the menu is always shown (without a timeout).
+/// If it can't be displayed, this returns `None`.
+///
+/// If the default entry is missing, it will try to use the first one instead.
+/// If there are no entries (eg. because the configuration is broken),
+/// it will start the rescue prompt.
+pub fn choose<'a>(
+    config: &'a Config, failure: Option<(&str, Status)>, image: Handle,
+    volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> Option<Cow<'a, Entry>> {
+    if config.entries.is_empty() {
+        error!("there are no entries to boot, starting the rescue prompt");
+        return match shell::rescue(failure, image, volume, systab) {
+            Ok(entry) => Some(Cow::Owned(entry)),
+            Err(e) => {
+                error!("failed to run the rescue prompt: {e:?}");
+                None
+            },
+        }
+    }
+    if failure.is_none() && config.prefer_last_successful.unwrap_or(false) {
+        record_last_successful();
+    }
+    Some(match choose_without_fallback(config, failure, image, volume, systab)? {
+        (Some(key), entry) => {
+            let (key, entry) = check_tries(config, key, entry);
+            if config.prefer_last_successful.unwrap_or(false) {
+                // errors have already been logged and are not fatal
+                let _ = vars::set_string(vars::LAST_BOOTED, key);
+                let _ = vars::set_string(vars::BOOT_PENDING, key);
+            }
+            entry
+        },
+        // entries from the command prompt are not in the configuration
+        (None, entry) => entry,
+    })
+}
+
+/// Check whether the last boot was successful and remember the entry if it was.
+fn record_last_successful() {
+    if vars::get_string(vars::BOOT_PENDING).is_some() {
+        warn!("the last boot has not been marked as successful");
+        return
+    }
+    if let Some(last_booted) = vars::get_string(vars::LAST_BOOTED) {
+        if vars::get_string(vars::LAST_SUCCESSFUL).as_ref() != Some(&last_booted) {
+            debug!("the last boot ({last_booted}) was successful");
+            // errors have already been logged and are not fatal
+            let _ = vars::set_string(vars::LAST_SUCCESSFUL, &last_booted);
+        }
+    }
+}
+
+/// Choose an entry to boot, ignoring boot counting.
+///
+/// This returns the key of the entry, if it's in the configuration.
+fn choose_without_fallback<'a>(
+    config: &'a Config, failure: Option<(&str, Status)>, image: Handle,
+    volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> Option<(Option<&'a String>, Cow<'a, Entry>)> {
+    if let Some(resume) = config.resume.as_ref().filter(|_| failure.is_none()) {
+        // booting anything else could corrupt the hibernated system
+        if resume::is_pending(resume, image) {
+            match config.entries.get_key_value(&resume.entry) {
+                Some((key, entry)) => {
+                    info!("the system is hibernated, resuming it with {key}");
+                    return Some((Some(key), Cow::Borrowed(entry)))
+                },
+                None => warn!(
+                    "the system is hibernated, but the resume entry {} doesn't exist",
+                    resume.entry,
+                ),
+            }
+        }
+    }
+    if let Some(key) = vars::get_string(vars::BOOT_NEXT).filter(|_| failure.is_none()) {
+        // clear this first, so that we don't end up in a boot loop
+        if vars::delete(vars::BOOT_NEXT).is_ok() {
+            match config.entries.get_key_value(&key) {
+                Some((key, entry)) => {
+                    info!("booting {key} once as requested");
+                    return Some((Some(key), Cow::Borrowed(entry)))
+                },
+                None => warn!("{} is set to {key}, but this entry doesn't exist", vars::BOOT_NEXT),
+            }
+        } else {
+            warn!("failed to clear {}, ignoring it", vars::BOOT_NEXT);
+        }
+    }
+    // the operating system may ask for the menu, eg. so that the user can pick another entry
+    let mut timeout = config.timeout;
+    if failure.is_none() && vars::exists(vars::SHOW_MENU) {
+        // clear this first, so that the menu isn't displayed on every boot
+        if vars::delete(vars::SHOW_MENU).is_ok() {
+            info!("displaying the menu as requested");
+            timeout = None;
+        } else {
+            warn!("failed to clear {}, ignoring it", vars::SHOW_MENU);
+        }
+    }
+    let last_successful = if config.prefer_last_successful.unwrap_or(false) {
+        vars::get_string(vars::LAST_SUCCESSFUL).filter(|k| config.entries.contains_key(k))
+    } else {
+        None
+    };
+    let default_key = if let Some(key) = last_successful {
+        debug!("using the last successful entry {key} as the default");
+        key
+    } else if config.default == SAVED_DEFAULT {
+        vars::get_string(vars::SAVED_ENTRY).unwrap_or_else(|| {
+            debug!("there is no saved entry yet");
+            String::new()
+        })
+    } else {
+        config.default.clone()
+    };
+    let (default_key, default_entry) = config.entries.get_key_value(&default_key)
+    .unwrap_or_else(|| {
+        warn!("default entry is missing, trying the first one");
+        config.entries.iter().next().expect("no entries")
+    });
+    if let (Some(0), None) = (timeout, failure) {
+        return Some((Some(default_key), Cow::Borrowed(default_entry)))
+    }
+    match display_menu(
+        config, timeout, failure, default_key, default_entry, image, volume, systab,
+    ) {
+        Ok(key_and_entry) => Some(key_and_entry),
+        Err(err) => {
+            error!("failed to display menu: {err:?}");
+            if failure.is_some() {
+                return None
+            }
+            warn!("booting default entry");
+            Some((Some(default_key), Cow::Borrowed(default_entry)))
+        }
+    }
+}
+
+/// Count a boot attempt of the entry or use its fallback if there are no tries left.
+///
+/// Entries with `tries` set may only be booted that often without the
+/// operating system deleting their `TowbootTries-<key>` variable.
+fn check_tries<'a>(
+    config: &'a Config, key: &'a String, entry: Cow<'a, Entry>,
+) -> (&'a String, Cow<'a, Entry>) {
+    let (mut key, mut entry) = (key, entry);
+    // fallbacks may have fallbacks, but let's not loop forever
+    for _ in 0..config.entries.len() {
+        let tries = match entry.tries {
+            Some(t) => t,
+            None => return (key, entry),
+        };
+        let name = vars::tries_name(key);
+        if !vars::is_valid_name(&name) {
+            // the error has already been logged
+            warn!("not counting the boot attempts of {key}");
+            return (key, entry)
+        }
+        let attempts: u8 = vars::get_string(&name)
+            .and_then(|a| a.parse().ok())
+            .unwrap_or(0);
+        if attempts < tries {
+            info!("this is try {} of {tries} for {key}", attempts + 1);
+            // errors have already been logged, but we can't count without the variable
+            if vars::set_string(&name, &(attempts + 1).to_string()).is_err() {
+                warn!("failed to count the boot attempt");
+            }
+            return (key, entry)
+        }
+        match entry.fallback.as_ref().and_then(|f| config.entries.get_key_value(f)) {
+            Some((fallback_key, fallback_entry)) => {
+                warn!("{key} failed to boot {tries} times, falling back to {fallback_key}");
+                key = fallback_key;
+                entry = Cow::Borrowed(fallback_entry);
+            },
+            None => {
+                warn!("{key} failed to boot {tries} times, but there is no (valid) fallback");
+                return (key, entry)
+            },
+        }
+    }
+    warn!("the fallbacks contain a loop, booting {key}");
+    (key, entry)
+}
+
+/// Display the menu. This can fail.
+///
+/// The countdown lasts `timeout` seconds (`None` means waiting forever).
+/// If the last boot attempt failed, its reason is shown instead of the countdown.
+fn display_menu<'a>(
+    config: &'a Config, timeout: Option<u8>, failure: Option<(&str, Status)>,
+    default_key: &'a String, default_entry: &'a Entry, image: Handle,
+    volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<(Option<&'a String>, Cow<'a, Entry>)> {
+    let messages = messages::get(config);
+    let broken = broken_entries(config, volume);
+    let mut frontend = frontend(config, messages, volume);
+    if let Some((entry, status)) = failure {
+        frontend.draw_info(
+            &fill(messages.boot_failed, &[&entry]),
+            &[format!("{status:?}"), String::new(), messages.back_hint.to_string()],
+            systab,
+        )?;
+        read_key(frontend.as_mut(), systab)?;
+    }
+    // the hidden menu keeps the screen as it is (eg. with the vendor logo)
+    // and gets displayed once any key is pressed
+    let hidden = config.menu == Some(MenuType::Hidden);
+    // the key that interrupted the countdown, to be handled by the menu
+    let mut first_key = None;
+    // don't count down to a default entry that won't boot anyway
+    let default_broken = broken.contains(default_key.as_str());
+    if default_broken {
+        warn!("the default entry {default_key} is broken, not booting it automatically");
+    }
+    if let (Some(timeout), None, false) = (timeout, failure, default_broken) {
+        let mut remaining = timeout;
+        if !hidden {
+            frontend.draw_timeout(default_key, default_entry, remaining, systab)?;
+        }
+        // tick every second to update the countdown
+        let timer = countdown_timer(systab).map_err(|e| {
+            warn!("failed to create a timer ({e:?}), counting down without it");
+        }).ok();
+        let timed_out = loop {
+            let input = match &timer {
+                Some(timer) => frontend.read_input(Some(timer), systab)?,
+                None => poll_for_a_second(frontend.as_mut(), systab)?,
+            };
+            match input {
+                // ESC just opens the menu, other keys are passed on to it
+                Some(Input::Key(KeyPress { key: Key::Special(ScanCode::ESCAPE), .. })) => {
+                    break false
+                },
+                Some(Input::Key(key)) => {
+                    first_key = Some(key);
+                    break false
+                },
+                // moving the mouse doesn't count
+                Some(Input::Pointer { clicked: true, .. }) => break false,
+                Some(Input::Pointer { .. }) => (),
+                // timer
+                None => {
+                    remaining -= 1;
+                    if !hidden {
+                        frontend.draw_timeout(default_key, default_entry, remaining, systab)?;
+                    }
+                    if remaining == 0 {
+                        break true
+                    }
+                },
+            }
+        };
+        if let Some(timer) = &timer {
+            systab.boot_services().set_timer(timer, TimerTrigger::Cancel)?;
+        }
+        if timed_out {
+            return Ok((Some(default_key), Cow::Borrowed(default_entry)))
+        }
+    }
+    let (key, entry) = select_entry(
+        config, default_key, first_key, broken, messages, frontend.as_mut(),
+        image, volume, systab,
+    )?;
+    if let (Some(key), SAVED_DEFAULT) = (key, config.default.as_str()) {
+        // errors have already been logged and are not fatal
+        let _ = vars::set_string(vars::SAVED_ENTRY, key);
+    }
+    Ok((key, entry))
+}
+
+/// Create a timer that fires every second.
+fn countdown_timer(systab: &SystemTable<Boot>) -> uefi::Result<Event> {
+    // This is safe because there is no callback.
+    let timer = unsafe { systab.boot_services().create_event(
+        EventType::TIMER, Tpl::APPLICATION, None, None
+    ) }?;
+    systab.boot_services().set_timer(&timer, TimerTrigger::Periodic(10_000_000))?;
+    Ok(timer)
+}
+
+/// Wait for a second (or until a key is pressed) without a timer.
+///
+/// Some firmware fails to create timers, but stalling still works there.
+/// This returns `None` after a second without input.
+fn poll_for_a_second(
+    frontend: &mut dyn Frontend, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Option<Input>> {
+    for _ in 0..POLLS_PER_SECOND {
+        if let Some(input) = frontend.poll_input(systab)? {
+            return Ok(Some(input))
+        }
+        systab.boot_services().stall(1_000_000 / POLLS_PER_SECOND);
+    }
+    Ok(None)
+}
+
+/// Find the entries whose files are missing or whose kernel is not a Multiboot kernel.
+///
+/// This is only done if `validate` is set, as it has to open all files.
+fn broken_entries<'a>(config: &'a Config, volume: &mut Directory) -> BTreeSet<&'a str> {
+    if !config.validate.unwrap_or(false) {
+        return BTreeSet::new()
+    }
+    config.entries.iter()
+        .filter(|(key, entry)| !boot::check(key, entry, volume))
+        .map(|(key, _)| key.as_str())
+        .collect()
+}
+
+/// Let the user select an entry.
+///
+/// All entries are listed and the selected one is highlighted.
+/// (At first, that's the default entry.)
+/// If a key has already been pressed, it's handled first.
+/// The arrow keys (and PgUp and PgDn) move the selection and Enter boots the selected entry.
+/// Shift and the up or down arrow keys (or Home and End) select the first or last line.
+/// If there are more entries than fit on the screen, the list scrolls.
+/// Alternatively, the index or the key of an entry can be typed in.
+/// Pressing `e` (while nothing has been typed) opens the editor for the selected entry,
+/// pressing Tab or `i` shows its details, pressing `c` opens the command prompt
+/// pressing `m` shows the memory map and pressing `l` cycles the log level
+/// (so that more (or less) is logged from now on).
+/// These also work with Ctrl while something has been typed.
+///
+/// In the graphical menu, pointing at a line selects it and clicking the
+/// selected line is the same as pressing Enter.
+///
+/// After the entries, actions like rebooting are listed.
+///
+/// If `beep` is set, opening the menu and changing the selection beeps.
+///
+/// Entries with a group are only listed once their group has been expanded
+/// (with Enter or the right arrow key, the left one collapses it again).
+/// Hidden entries are only listed (and can only be selected) after the reveal key
+/// has been pressed.
+///
+/// Pressing F10 saves a screenshot of the menu. (see `save_screenshot`)
+/// Pressing F12 opens the debug console, which isn't mentioned in the menu.
+///
+/// Broken entries are marked as such, but can still be selected.
+fn select_entry<'a>(
+    config: &'a Config, default_key: &str, first_key: Option<KeyPress>,
+    broken: BTreeSet<&'a str>, messages: &Messages, frontend: &mut dyn Frontend, image: Handle,
+    volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<(Option<&'a String>, Cow<'a, Entry>)> {
+    let reveal_key = reveal_key(config);
+    let mut list = List::new(&config.entries, actions(config), default_key, broken);
+    let mut pending_key = first_key;
+    beep::play(config, Sound::MenuOpened);
+    let mut last_selected = list.selected;
+    loop {
+        if list.selected != last_selected {
+            beep::play(config, Sound::SelectionChanged);
+            last_selected = list.selected;
+        }
+        list.scroll_to_selection(frontend.list_rows(systab)?);
+        frontend.draw_list(&list, systab)?;
+        let pressed = match pending_key.take() {
+            Some(pressed) => pressed,
+            None => match read_list_input(&mut list, frontend, systab)? {
+                Some(pressed) => pressed,
+                // the selection has changed
+                None => continue,
+            },
+        };
+        match pressed.key {
+            Key::Special(ScanCode::HOME) => {
+                list.selected = 0;
+                list.input.clear();
+            },
+            Key::Special(ScanCode::UP) if pressed.shift => {
+                list.selected = 0;
+                list.input.clear();
+            },
+            Key::Special(ScanCode::UP) => {
+                list.selected = list.selected.saturating_sub(1);
+                list.input.clear();
+            },
+            Key::Special(ScanCode::END) => {
+                list.selected = list.items.len().saturating_sub(1);
+                list.input.clear();
+            },
+            Key::Special(ScanCode::DOWN) if pressed.shift => {
+                list.selected = list.items.len().saturating_sub(1);
+                list.input.clear();
+            },
+            Key::Special(ScanCode::DOWN) => {
+                if list.selected + 1 < list.items.len() {
+                    list.selected += 1;
+                }
+                list.input.clear();
+            },
+            Key::Special(ScanCode::PAGE_UP) => {
+                list.selected = list.selected.saturating_sub(list.rows);
+                list.input.clear();
+            },
+            Key::Special(ScanCode::PAGE_DOWN) => {
+                list.selected = (list.selected + list.rows)
+                    .min(list.items.len().saturating_sub(1));
+                list.input.clear();
+            },
+            Key::Special(ScanCode::RIGHT) => {
+                if let Some(Item::Group { name, expanded: false }) = list.selected_item() {
+                    list.toggle(name);
+                }
+            },
+            Key::Special(ScanCode::LEFT) => match list.selected_item() {
+                Some(Item::Group { name, expanded: true }) => list.toggle(name),
+                Some(Item::Entry(_, Entry { group: Some(name), .. })) => list.toggle(name),
+                _ => (),
+            },
+            Key::Special(scan_code) if scan_code == reveal_key => {
+                list.show_hidden = true;
+                list.update();
+            },
+            Key::Special(ScanCode::FUNCTION_10) => {
+                // errors have already been logged and are not fatal
+                let _ = save_screenshot(&list, frontend, volume, systab);
+            },
+            Key::Special(ScanCode::FUNCTION_12) => {
+                if let Some(entry) = shell::debug(image, volume, systab)? {
+                    return Ok((None, Cow::Owned(entry)))
+                }
+            },
+            Key::Printable(c) => match c.into() {
+                // enter
+                '\r' => {
+                    let input = core::mem::take(&mut list.input);
+                    // support lookup by both index and key
+                    let choice = if input.is_empty() {
+                        list.selected_item()
+                    } else if let Ok(index) = input.parse::<usize>() {
+                        list.items.get(index).copied()
+                    } else {
+                        list.entries.get_key_value(&input)
+                            .filter(|(_, e)| list.show_hidden || !e.hidden)
+                            .map(|(k, e)| Item::Entry(k, e))
+                    };
+                    match choice {
+                        Some(Item::Entry(key, entry)) => {
+                            return Ok((Some(key), Cow::Borrowed(entry)))
+                        },
+                        Some(Item::Group { name, .. }) => list.toggle(name),
+                        Some(Item::Action(action)) => {
+                            run_action(action, config, messages, frontend, systab)?;
+                        },
+                        None if input.is_empty() => (),
+                        None => {
+                            list.log_level = None;
+                            list.invalid_choice = Some(input);
+                        },
+                    }
+                },
+                'e' if pressed.control || list.input.is_empty() => {
+                    if let Some(Item::Entry(key, entry)) = list.selected_item() {
+                        if let Some(edited) = edit_entry(key, entry, frontend, systab)? {
+                            return Ok((Some(key), Cow::Owned(edited)))
+                        }
+                    }
+                },
+                '\t' | 'i' if pressed.control || list.input.is_empty() => {
+                    if let Some(Item::Entry(key, entry)) = list.selected_item() {
+                        show_details(key, entry, config, messages, frontend, volume, systab)?;
+                    }
+                },
+                'c' if pressed.control || list.input.is_empty() => {
+                    if let Some(entry) = shell::run(image, volume, systab)? {
+                        return Ok((None, Cow::Owned(entry)))
+                    }
+                },
+                'm' if pressed.control || list.input.is_empty() => {
+                    show_memory_map(messages, frontend, systab)?;
+                },
+                'l' if pressed.control || list.input.is_empty() => {
+                    list.invalid_choice = None;
+                    list.log_level = Some(cycle_log_level());
+                },
+                '\u{8}' => {list.input.pop();}, // backspace
+                // other hotkeys aren't typed in
+                _ if pressed.control || pressed.alt => (),
+                chr => list.input.push(chr),
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Save a screenshot of the list to the root of the volume.
+///
+/// The files are numbered, so that existing ones don't get overwritten.
+fn save_screenshot(
+    list: &List, frontend: &mut dyn Frontend,
+    volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> uefi::Result {
+    let (extension, content) = frontend.screenshot(list, systab).map_err(|e| {
+        error!("failed to capture the screen: {e:?}");
+        e
+    })?;
+    let name = (0..1000)
+        .map(|index| format!("\\towboot-screenshot-{index}.{extension}"))
+        .find(|name| !File::exists(name, volume))
+        .ok_or_else(|| {
+            error!("there are too many screenshots already");
+            Status::VOLUME_FULL
+        })?;
+    file::write(&name, volume, &content)?;
+    info!("saved a screenshot to '{name}'");
+    Ok(())
+}
+
+/// Wait for a key to be pressed in the list.
+///
+/// Pointing at a line selects it, clicking the selected line activates it.
+/// This returns `None` if the selection has changed.
+fn read_list_input(
+    list: &mut List, frontend: &mut dyn Frontend, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Option<KeyPress>> {
+    loop {
+        match frontend.read_input(None, systab)? {
+            Some(Input::Key(key)) => return Ok(Some(key)),
+            Some(Input::Pointer { x, y, clicked }) => match frontend.line_at(x, y) {
+                Some(line) if line == list.selected => if clicked {
+                    list.input.clear();
+                    let enter = Key::Printable(Char16::try_from('\r').unwrap());
+                    return Ok(Some(KeyPress::new(enter)))
+                },
+                Some(line) => {
+                    list.selected = line;
+                    list.input.clear();
+                    return Ok(None)
+                },
+                None => (),
+            },
+            None => (),
+        }
+    }
+}
+
+/// Wait for a key to be pressed, ignoring the pointer.
+fn read_key(frontend: &mut dyn Frontend, systab: &mut SystemTable<Boot>) -> uefi::Result<KeyPress> {
+    loop {
+        if let Some(Input::Key(key)) = frontend.read_input(None, systab)? {
+            return Ok(key)
+        }
+    }
+}
+
+/// Show everything about an entry and wait for a key.
+fn show_details(
+    key: &str, entry: &Entry, config: &Config, messages: &Messages, frontend: &mut dyn Frontend,
+    volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> uefi::Result {
+    let mut lines = vec![
+        format!(
+            "{}: {} ({})",
+            messages.kernel, entry.image, describe_file(&entry.image, messages, volume),
+        ),
+        format!("{}: {}", messages.command_line, entry.argv.as_deref().unwrap_or("")),
+    ];
+    if entry.modules.is_empty() {
+        lines.push(format!("{}: {}", messages.modules, messages.none));
+    } else {
+        lines.push(format!("{}:", messages.modules));
+        for module in &entry.modules {
+            // concatenated modules list each file on its own line
+            let parts = module.image.len() + usize::from(module.directory.is_some())
+                + usize::from(module.random_seed.is_some());
+            let indent = if parts > 1 {
+                lines.push(format!("  {module}"));
+                "    "
+            } else {
+                "  "
+            };
+            for image in &module.image {
+                lines.push(format!(
+                    "{indent}{image} ({})", describe_file(image, messages, volume),
+                ));
+            }
+            if let Some(directory) = &module.directory {
+                lines.push(format!("{indent}{directory}\\ (cpio)"));
+            }
+            if let Some(length) = module.random_seed {
+                lines.push(format!("{indent}{}", fill(messages.random_bytes, &[&length])));
+            }
+            lines.push(format!(
+                "    {}: {}", messages.command_line, module.argv.as_deref().unwrap_or(""),
+            ));
+        }
+    }
+    if entry.quirks.is_empty() {
+        lines.push(format!("{}: {}", messages.quirks, messages.none));
+    } else {
+        lines.push(format!("{}: {:?}", messages.quirks, entry.quirks));
+    }
+    // the mode is chosen when booting, so this is what would happen now
+    if let Some(substitution) = boot::video_substitution(entry, config, volume) {
+        let (wanted, used) = (substitution.wanted, substitution.used);
+        lines.push(format!("{}: {}", messages.video, fill(messages.video_substitution, &[
+            &format!("{}x{}", wanted.0, wanted.1), &format!("{}x{}", used.0, used.1),
+        ])));
+    }
+    lines.push(String::new());
+    lines.push(messages.back_hint.to_string());
+    frontend.draw_info(&format!("{key}: {entry}"), &lines, systab)?;
+    read_key(frontend, systab)?;
+    Ok(())
+}
+
+/// Switch to the next log level (wrapping around after `trace`).
+///
+/// This changes the level for all log targets, until the next boot.
+fn cycle_log_level() -> LevelFilter {
+    let level = match log::max_level() {
+        LevelFilter::Off | LevelFilter::Trace => LevelFilter::Error,
+        LevelFilter::Error => LevelFilter::Warn,
+        LevelFilter::Warn => LevelFilter::Info,
+        LevelFilter::Info => LevelFilter::Debug,
+        LevelFilter::Debug => LevelFilter::Trace,
+    };
+    log::set_max_level(level);
+    info!("changed the log level to {level}");
+    level
+}
+
+/// Show the current memory map (and what the kernel would get) until a key is pressed.
+///
+/// The arrow keys and PgUp and PgDn switch between the pages.
+fn show_memory_map(
+    messages: &Messages, frontend: &mut dyn Frontend, systab: &mut SystemTable<Boot>,
+) -> uefi::Result {
+    let lines = mem::memory_map().map(|m| mem::describe_memory_map(&m))?;
+    let rows = frontend.list_rows(systab)?;
+    let pages = ((lines.len() + rows - 1) / rows).max(1);
+    let mut page = 0;
+    loop {
+        let mut visible: Vec<String> = lines.iter()
+            .skip(page * rows).take(rows).cloned().collect();
+        visible.push(String::new());
+        visible.push(messages.memory_map_hint.to_string());
+        frontend.draw_info(&fill(messages.memory_map, &[&(page + 1), &pages]), &visible, systab)?;
+        match read_key(frontend, systab)?.key {
+            Key::Special(ScanCode::UP | ScanCode::PAGE_UP) => page = page.saturating_sub(1),
+            Key::Special(ScanCode::DOWN | ScanCode::PAGE_DOWN) => {
+                page = (page + 1).min(pages - 1);
+            },
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Check whether a file exists and get its size (and compression).
+fn describe_file(name: &str, messages: &Messages, volume: &mut Directory) -> String {
+    match File::inspect(name, volume) {
+        Some(compression::Info { format: compression::Format::Uncompressed, size, .. })
+        => fill(messages.bytes, &[&size]),
+        Some(compression::Info { format, size, unpacked_size }) => format!(
+            "{}, {}", fill(messages.bytes, &[&size]), match unpacked_size {
+                Some(unpacked_size) => fill(messages.compressed, &[&format, &unpacked_size]),
+                None => format.to_string(),
+            },
+        ),
+        None => messages.missing.to_string(),
+    }
+}
+
+/// Let the user change the command lines of an entry for this boot.
+///
+/// The arrow keys move the cursor (by words while holding Ctrl), Enter boots the edited entry.
+/// Ctrl+K deletes everything after the cursor, Ctrl+U everything before it.
+/// Escape discards the changes and returns `None`.
+fn edit_entry(
+    key: &str, entry: &Entry, frontend: &mut dyn Frontend, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Option<Entry>> {
+    let mut editor = Editor {
+        key,
+        lines: core::iter::once((Cow::Borrowed(entry.image.as_str()), entry.argv.clone()))
+            .chain(entry.modules.iter().map(|m| (Cow::Owned(m.to_string()), m.argv.clone())))
+            .map(|(image, argv)| (image, argv.unwrap_or_default()))
+            .collect(),
+        selected: 0,
+        cursor: entry.argv.as_deref().map_or(0, |a| a.chars().count()),
+    };
+    loop {
+        frontend.draw_editor(&editor, systab)?;
+        let line_length = editor.lines[editor.selected].1.chars().count();
+        let pressed = read_key(frontend, systab)?;
+        match pressed.key {
+            Key::Special(ScanCode::ESCAPE) => return Ok(None),
+            Key::Special(ScanCode::UP) => {
+                editor.selected = editor.selected.saturating_sub(1);
+                editor.cursor = editor.lines[editor.selected].1.chars().count();
+            },
+            Key::Special(ScanCode::DOWN) => {
+                if editor.selected + 1 < editor.lines.len() {
+                    editor.selected += 1;
+                }
+                editor.cursor = editor.lines[editor.selected].1.chars().count();
+            },
+            Key::Special(ScanCode::LEFT) if pressed.control => {
+                editor.cursor = editor.previous_word();
+            },
+            Key::Special(ScanCode::LEFT) => editor.cursor = editor.cursor.saturating_sub(1),
+            Key::Special(ScanCode::RIGHT) if pressed.control => editor.cursor = editor.next_word(),
+            Key::Special(ScanCode::RIGHT) => {
+                editor.cursor = (editor.cursor + 1).min(line_length);
+            },
+            Key::Special(ScanCode::HOME) => editor.cursor = 0,
+            Key::Special(ScanCode::END) => editor.cursor = line_length,
+            Key::Special(ScanCode::DELETE) => if editor.cursor < line_length {
+                let index = editor.byte_index();
+                editor.lines[editor.selected].1.remove(index);
+            },
+            Key::Printable(c) => match c.into() {
+                // enter
+                '\r' => {
+                    let mut edited = entry.clone();
+                    let mut lines = editor.lines.into_iter()
+                        .map(|(_, line)| (!line.is_empty()).then_some(line));
+                    edited.argv = lines.next().unwrap();
+                    for (module, argv) in edited.modules.iter_mut().zip(lines) {
+                        module.argv = argv;
+                    }
+                    info!("booting the edited entry {key}");
+                    return Ok(Some(edited))
+                },
+                // backspace
+                '\u{8}' => if editor.cursor > 0 {
+                    editor.cursor -= 1;
+                    let index = editor.byte_index();
+                    editor.lines[editor.selected].1.remove(index);
+                },
+                'k' if pressed.control => {
+                    let index = editor.byte_index();
+                    editor.lines[editor.selected].1.truncate(index);
+                },
+                'u' if pressed.control => {
+                    let index = editor.byte_index();
+                    editor.lines[editor.selected].1.replace_range(..index, "");
+                    editor.cursor = 0;
+                },
+                _ if pressed.control || pressed.alt => (),
+                chr => {
+                    let index = editor.byte_index();
+                    editor.lines[editor.selected].1.insert(index, chr);
+                    editor.cursor += 1;
+                },
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Get the actions to list in the menu.
+fn actions(config: &Config) -> Vec<Action> {
+    match &config.actions {
+        Some(actions) => actions.clone(),
+        None => {
+            let mut actions = vec![Action::Reboot, Action::Poweroff];
+            if power::firmware_setup_supported() {
+                actions.push(Action::Firmware);
+            }
+            actions
+        },
+    }
+}
+
+/// Run an action.
+///
+/// This only returns if the action failed (or, for the memory test, is done).
+fn run_action(
+    action: Action, config: &Config, messages: &Messages, frontend: &mut dyn Frontend,
+    systab: &mut SystemTable<Boot>,
+) -> uefi::Result {
+    match action {
+        Action::Reboot => power::reboot(),
+        Action::Poweroff => power::power_off(),
+        Action::Firmware => {
+            // the error has already been logged
+            let _ = power::reboot_to_firmware_setup();
+        },
+        Action::Memtest => {
+            let mut lines = match memtest::run(progress::Style::from_config(config)) {
+                Ok(summary) => vec![fill(
+                    messages.memtest_result, &[&(summary.tested / 1024 / 1024), &summary.errors],
+                )],
+                Err(status) => vec![format!("{status:?}")],
+            };
+            lines.push(String::new());
+            lines.push(messages.back_hint.to_string());
+            frontend.draw_info(messages.memtest, &lines, systab)?;
+            read_key(frontend, systab)?;
+        },
+    }
+    Ok(())
+}
+
+/// Get the key that reveals hidden entries.
+fn reveal_key(config: &Config) -> ScanCode {
+    let name = config.reveal_key.as_deref().unwrap_or("F8");
+    match name.to_ascii_uppercase().as_str() {
+        "F1" => ScanCode::FUNCTION_1,
+        "F2" => ScanCode::FUNCTION_2,
+        "F3" => ScanCode::FUNCTION_3,
+        "F4" => ScanCode::FUNCTION_4,
+        "F5" => ScanCode::FUNCTION_5,
+        "F6" => ScanCode::FUNCTION_6,
+        "F7" => ScanCode::FUNCTION_7,
+        "F8" => ScanCode::FUNCTION_8,
+        "F9" => ScanCode::FUNCTION_9,
+        "F10" => ScanCode::FUNCTION_10,
+        "F11" => ScanCode::FUNCTION_11,
+        "F12" => ScanCode::FUNCTION_12,
+        _ => {
+            warn!("'{name}' is not a valid reveal key, using F8");
+            ScanCode::FUNCTION_8
+        },
+    }
+}
+
+/// The state of the list of entries.
+struct List<'a> {
+    /// all entries of the configuration
+    entries: &'a BTreeMap<String, Entry>,
+    /// the actions to list after the entries
+    actions: Vec<Action>,
+    /// the lines that are currently displayed
+    items: Vec<Item<'a>>,
+    /// the index of the highlighted line
+    selected: usize,
+    /// the index of the first displayed line
+    scroll: usize,
+    /// how many lines fit on the screen
+    rows: usize,
+    /// the groups that are currently expanded
+    expanded: BTreeSet<&'a str>,
+    /// whether the reveal key has been pressed
+    show_hidden: bool,
+    /// the keys of the entries that failed the validation
+    broken: BTreeSet<&'a str>,
+    /// what the user has typed so far
+    input: String,
+    /// what the user typed the last time, if it was invalid
+    invalid_choice: Option<String>,
+    /// the log level, if it has been changed in the menu
+    /// (only one of this and the invalid choice is shown)
+    log_level: Option<LevelFilter>,
+}
+
+impl<'a> List<'a> {
+    /// List the entries, selecting the default one.
+    ///
+    /// If the default entry is in a group, the group is expanded.
+    fn new(
+        entries: &'a BTreeMap<String, Entry>, actions: Vec<Action>, default_key: &str,
+        broken: BTreeSet<&'a str>,
+    ) -> Self {
+        let mut list = Self {
+            entries,
+            actions,
+            items: Vec::new(),
+            selected: 0,
+            scroll: 0,
+            rows: usize::MAX,
+            expanded: BTreeSet::new(),
+            show_hidden: false,
+            broken,
+            input: String::new(),
+            invalid_choice: None,
+            log_level: None,
+        };
+        if let Some(group) = entries.get(default_key).and_then(|e| e.group.as_deref()) {
+            list.expanded.insert(group);
+        }
+        list.update();
+        list.selected = list.items.iter().position(|i| matches!(
+            i, Item::Entry(k, _) if *k == default_key
+        )).unwrap_or(0);
+        list
+    }
+    
+    /// Scroll so that the highlighted line is visible.
+    fn scroll_to_selection(&mut self, rows: usize) {
+        self.rows = rows;
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + rows {
+            self.scroll = self.selected + 1 - rows;
+        }
+        // don't leave empty space at the end
+        self.scroll = self.scroll.min(self.items.len().saturating_sub(rows));
+    }
+    
+    /// Get the lines that fit on the screen (with their indices).
+    fn visible_items(&self) -> impl Iterator<Item = (usize, &Item<'a>)> {
+        self.items.iter().enumerate().skip(self.scroll).take(self.rows)
+    }
+    
+    /// Get how many lines are below the screen.
+    fn hidden_below(&self) -> usize {
+        self.items.len().saturating_sub(self.scroll.saturating_add(self.rows))
+    }
+    
+    /// Get the text to display for a line, marking broken entries.
+    fn text(&self, item: &Item, messages: &Messages) -> String {
+        match item {
+            Item::Entry(key, _) if self.broken.contains(key.as_str()) => {
+                format!("{} {}", item.text(messages), messages.broken)
+            },
+            _ => item.text(messages),
+        }
+    }
+    
+    /// Get the highlighted line.
+    fn selected_item(&self) -> Option<Item<'a>> {
+        self.items.get(self.selected).copied()
+    }
+    
+    /// Expand or collapse a group and select it.
+    fn toggle(&mut self, group: &'a str) {
+        if !self.expanded.remove(group) {
+            self.expanded.insert(group);
+        }
+        self.update();
+        self.selected = self.items.iter().position(|i| matches!(
+            i, Item::Group { name, .. } if *name == group
+        )).unwrap_or(0);
+    }
+    
+    /// Rebuild the displayed lines, keeping the selection if possible.
+    ///
+    /// Groups are listed at the position of their first entry.
+    fn update(&mut self) {
+        let selected = self.selected_item();
+        self.items.clear();
+        let show_hidden = self.show_hidden;
+        let visible = self.entries.iter().filter(|(_, e)| show_hidden || !e.hidden);
+        for (key, entry) in visible.clone() {
+            match entry.group.as_deref() {
+                None => self.items.push(Item::Entry(key, entry)),
+                Some(group) => {
+                    if self.items.iter().any(|i| matches!(
+                        i, Item::Group { name, .. } if *name == group
+                    )) {
+                        continue
+                    }
+                    let expanded = self.expanded.contains(group);
+                    self.items.push(Item::Group { name: group, expanded });
+                    if expanded {
+                        self.items.extend(
+                            visible.clone()
+                            .filter(|(_, e)| e.group.as_deref() == Some(group))
+                            .map(|(k, e)| Item::Entry(k, e))
+                        );
+                    }
+                },
+            }
+        }
+        self.items.extend(self.actions.iter().copied().map(Item::Action));
+        self.selected = selected
+            .and_then(|s| self.items.iter().position(|i| *i == s))
+            .unwrap_or(0);
+    }
+}
+
+/// A line in the list of entries.
+#[derive(Clone, Copy)]
+enum Item<'a> {
+    Entry(&'a String, &'a Entry),
+    /// a group of entries
+    Group { name: &'a str, expanded: bool },
+    Action(Action),
+}
+
+impl PartialEq for Item<'_> {
+    /// Lines are the same if they refer to the same entry or group.
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Item::Entry(a, _), Item::Entry(b, _)) => a == b,
+            (Item::Group { name: a, .. }, Item::Group { name: b, .. }) => a == b,
+            (Item::Action(a), Item::Action(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Item<'_> {
+    /// Get the text to display for this line.
+    fn text(&self, messages: &Messages) -> String {
+        match self {
+            // indent the entries in groups
+            Item::Entry(key, entry) if entry.group.is_some() => format!("  [{key}] {entry}"),
+            Item::Entry(key, entry) => format!("[{key}] {entry}"),
+            Item::Group { name, expanded: true } => format!("- {name}"),
+            Item::Group { name, expanded: false } => format!("+ {name}"),
+            Item::Action(action) => messages.action(*action).to_string(),
+        }
+    }
+}
+
+/// The state of the editor.
+struct Editor<'a> {
+    /// the key of the entry that is being edited
+    key: &'a str,
+    /// the image and the command line of the kernel, then those of the modules
+    lines: Vec<(Cow<'a, str>, String)>,
+    /// the index of the line that is being edited
+    selected: usize,
+    /// the position of the cursor in the selected line (in characters)
+    cursor: usize,
+}
+
+impl Editor<'_> {
+    /// Get the position of the cursor in the selected line in bytes.
+    fn byte_index(&self) -> usize {
+        let line = &self.lines[self.selected].1;
+        line.char_indices().nth(self.cursor).map_or(line.len(), |(index, _)| index)
+    }
+    
+    /// Get the position of the start of the word before the cursor.
+    fn previous_word(&self) -> usize {
+        let chars: Vec<char> = self.lines[self.selected].1.chars().collect();
+        let mut position = self.cursor;
+        while position > 0 && chars[position - 1] == ' ' {
+            position -= 1;
+        }
+        while position > 0 && chars[position - 1] != ' ' {
+            position -= 1;
+        }
+        position
+    }
+    
+    /// Get the position of the end of the word after the cursor.
+    fn next_word(&self) -> usize {
+        let chars: Vec<char> = self.lines[self.selected].1.chars().collect();
+        let mut position = self.cursor;
+        while position < chars.len() && chars[position] == ' ' {
+            position += 1;
+        }
+        while position < chars.len() && chars[position] != ' ' {
+            position += 1;
+        }
+        position
+    }
+    
+    /// Split the selected line into the part before the cursor,
+    /// the character under the cursor and the part after it.
+    ///
+    /// At the end of the line, the character under the cursor is a space.
+    fn split_at_cursor(&self) -> (&str, &str, &str) {
+        let line = &self.lines[self.selected].1;
+        let (before, rest) = line.split_at(self.byte_index());
+        match rest.chars().next() {
+            Some(chr) => (before, &rest[..chr.len_utf8()], &rest[chr.len_utf8()..]),
+            None => (before, " ", ""),
+        }
+    }
+}
+
+/// A way to display the menu.
+trait Frontend {
+    /// Tell the user that the default entry will be booted after the timeout.
+    ///
+    /// This is called again every second with the remaining time.
+    fn draw_timeout(
+        &mut self, key: &str, entry: &Entry, timeout: u8, systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result;
+    
+    /// Calculate how many lines of the list fit on the screen.
+    fn list_rows(&mut self, systab: &mut SystemTable<Boot>) -> uefi::Result<usize>;
+    
+    /// Display the list of entries.
+    ///
+    /// This only needs to display the visible lines.
+    fn draw_list(&mut self, list: &List, systab: &mut SystemTable<Boot>) -> uefi::Result;
+    
+    /// Display the command lines of an entry that is being edited.
+    fn draw_editor(&mut self, editor: &Editor, systab: &mut SystemTable<Boot>) -> uefi::Result;
+    
+    /// Display some lines of information below a title.
+    fn draw_info(
+        &mut self, title: &str, lines: &[String], systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result;
+    
+    /// Capture the list of entries as it's displayed.
+    ///
+    /// Return the file extension and the contents of the file.
+    fn screenshot(
+        &mut self, list: &List, systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result<(&'static str, Vec<u8>)>;
+    
+    /// Get the serial console and the pointing devices, if this frontend uses them.
+    ///
+    /// The keyboard is always used.
+    fn input_sources(&mut self) -> (Option<&mut SerialConsole>, Option<&mut Pointers>) {
+        (None, None)
+    }
+    
+    /// Find the line of the list at this position (in pixels).
+    fn line_at(&self, _x: usize, _y: usize) -> Option<usize> {
+        None
+    }
+    
+    /// Wait for a key to be pressed (or for the pointer to be used).
+    ///
+    /// If a timer is given, this returns `None` once it fires.
+    fn read_input(
+        &mut self, timer: Option<&Event>, systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result<Option<Input>> {
+        let (serial, pointers) = self.input_sources();
+        input::wait_for_input(timer, serial, pointers, systab)
+    }
+    
+    /// Check whether a key has been pressed (or the pointer has been used) without waiting.
+    fn poll_input(&mut self, systab: &mut SystemTable<Boot>) -> uefi::Result<Option<Input>> {
+        let (serial, pointers) = self.input_sources();
+        input::poll_input(serial, pointers, systab)
+    }
+}
+
+/// Display the menu on two frontends at once.
+///
+/// The serial console is taken from the second one, the pointing devices from the first one.
+struct Mirror {
+    primary: Box<dyn Frontend>,
+    secondary: Box<dyn Frontend>,
+}
+
+impl Frontend for Mirror {
+    fn draw_timeout(
+        &mut self, key: &str, entry: &Entry, timeout: u8, systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result {
+        self.primary.draw_timeout(key, entry, timeout, systab)?;
+        self.secondary.draw_timeout(key, entry, timeout, systab)
+    }
+    
+    /// Use the smaller of both screens.
+    fn list_rows(&mut self, systab: &mut SystemTable<Boot>) -> uefi::Result<usize> {
+        Ok(self.primary.list_rows(systab)?.min(self.secondary.list_rows(systab)?))
+    }
+    
+    fn draw_list(&mut self, list: &List, systab: &mut SystemTable<Boot>) -> uefi::Result {
+        self.primary.draw_list(list, systab)?;
+        self.secondary.draw_list(list, systab)
+    }
+    
+    fn draw_editor(&mut self, editor: &Editor, systab: &mut SystemTable<Boot>) -> uefi::Result {
+        self.primary.draw_editor(editor, systab)?;
+        self.secondary.draw_editor(editor, systab)
+    }
+    
+    fn draw_info(
+        &mut self, title: &str, lines: &[String], systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result {
+        self.primary.draw_info(title, lines, systab)?;
+        self.secondary.draw_info(title, lines, systab)
+    }
+    
+    fn screenshot(
+        &mut self, list: &List, systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result<(&'static str, Vec<u8>)> {
+        self.primary.screenshot(list, systab)
+    }
+    
+    fn input_sources(&mut self) -> (Option<&mut SerialConsole>, Option<&mut Pointers>) {
+        (self.secondary.input_sources().0, self.primary.input_sources().1)
+    }
+    
+    fn line_at(&self, x: usize, y: usize) -> Option<usize> {
+        self.primary.line_at(x, y)
+    }
+}
+
+/// Get the frontend for the configured type of menu.
+///
+/// If the graphical menu can't be displayed, this falls back to the text one.
+/// If `serial` is set, the menu is mirrored to the serial console.
+fn frontend(
+    config: &Config, messages: &'static Messages, volume: &mut Directory,
+) -> Box<dyn Frontend> {
+    let primary: Box<dyn Frontend> = match config.menu {
+        Some(MenuType::Graphical) => match graphical::GraphicalFrontend::new(
+            config, messages, volume,
+        ) {
+            Ok(f) => Box::new(f),
+            Err(e) => {
+                warn!("failed to initialize the graphical menu: {e:?}, using the text one");
+                Box::new(text::TextFrontend::new(config.theme.clone(), messages, None))
+            },
+        },
+        Some(MenuType::Text) | Some(MenuType::Hidden) | None => Box::new(
+            text::TextFrontend::new(config.theme.clone(), messages, None)
+        ),
+    };
+    if !config.serial.unwrap_or(false) {
+        return primary
+    }
+    match serial::SerialConsole::new() {
+        Ok(serial) => Box::new(Mirror {
+            primary,
+            secondary: Box::new(
+                text::TextFrontend::new(config.theme.clone(), messages, Some(serial))
+            ),
+        }),
+        Err(e) => {
+            warn!("failed to open the serial console: {e:?}");
+            primary
+        },
+    }
+}
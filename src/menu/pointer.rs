@@ -0,0 +1,172 @@
+//! Mice and touchscreens
+//!
+//! Mice are supported via the Simple Pointer protocol, touchscreens via the
+//! Absolute Pointer protocol. Both are only used by the graphical menu.
+
+use alloc::vec::Vec;
+use core::mem::MaybeUninit;
+
+use uefi::prelude::*;
+use uefi::proto::Protocol;
+use uefi::proto::console::pointer::Pointer;
+use uefi::{unsafe_guid, Event};
+
+use log::debug;
+
+use super::input::Input;
+use super::super::firmware::system_table;
+
+/// `EFI_ABSOLUTE_POINTER_PROTOCOL`
+///
+/// (This is not (yet) provided by the `uefi` crate.)
+#[repr(C)]
+#[unsafe_guid("8d59d32b-c655-4ae9-9b15-f25904992a43")]
+#[derive(Protocol)]
+struct AbsolutePointer {
+    _reset: extern "efiapi" fn(this: &mut AbsolutePointer, extended_verification: bool) -> Status,
+    get_state: extern "efiapi" fn(
+        this: &mut AbsolutePointer, state: *mut AbsolutePointerState,
+    ) -> Status,
+    wait_for_input: Event,
+    mode: *const AbsolutePointerMode,
+}
+
+/// `EFI_ABSOLUTE_POINTER_MODE`
+#[repr(C)]
+struct AbsolutePointerMode {
+    min_x: u64,
+    min_y: u64,
+    _min_z: u64,
+    max_x: u64,
+    max_y: u64,
+    _max_z: u64,
+    _attributes: u32,
+}
+
+/// `EFI_ABSOLUTE_POINTER_STATE`
+#[repr(C)]
+struct AbsolutePointerState {
+    current_x: u64,
+    current_y: u64,
+    _current_z: u64,
+    active_buttons: u32,
+}
+
+/// whether the screen is being touched
+const TOUCH_ACTIVE: u32 = 0x1;
+
+impl AbsolutePointer {
+    /// Read the current state, if it has changed.
+    fn read_state(&mut self) -> Option<AbsolutePointerState> {
+        let mut state = MaybeUninit::<AbsolutePointerState>::uninit();
+        match (self.get_state)(self, state.as_mut_ptr()) {
+            Status::SUCCESS => Some(unsafe { state.assume_init() }),
+            _ => None,
+        }
+    }
+
+    fn mode(&self) -> &AbsolutePointerMode {
+        unsafe { &*self.mode }
+    }
+}
+
+/// All pointing devices and the position of the pointer.
+pub(super) struct Pointers {
+    mouse: Option<&'static mut Pointer<'static>>,
+    touchscreen: Option<&'static mut AbsolutePointer>,
+    /// the position of the pointer (in pixels)
+    x: usize,
+    y: usize,
+    /// the size of the screen (in pixels)
+    width: usize,
+    height: usize,
+    /// whether the button has been held down the last time
+    pressed: bool,
+}
+
+impl Pointers {
+    /// Find the pointing devices.
+    ///
+    /// This returns `None` if there are none.
+    pub(super) fn new(width: usize, height: usize) -> Option<Self> {
+        let boot_services = unsafe { system_table().as_ref() }.boot_services();
+        let mouse = boot_services.locate_protocol::<Pointer>().ok()
+            .map(|p| unsafe { &mut *p.get() });
+        let touchscreen = boot_services.locate_protocol::<AbsolutePointer>().ok()
+            .map(|p| unsafe { &mut *p.get() });
+        if mouse.is_none() && touchscreen.is_none() {
+            return None
+        }
+        debug!(
+            "found a mouse: {}, found a touchscreen: {}", mouse.is_some(), touchscreen.is_some(),
+        );
+        Some(Self {
+            mouse, touchscreen,
+            x: width / 2, y: height / 2,
+            width, height,
+            pressed: false,
+        })
+    }
+
+    /// Add the events that signal input.
+    pub(super) fn push_events(&self, events: &mut Vec<Event>) {
+        // this is safe because we're never calling close_event
+        if let Some(mouse) = &self.mouse {
+            events.push(unsafe { mouse.wait_for_input_event().unsafe_clone() });
+        }
+        if let Some(touchscreen) = &self.touchscreen {
+            events.push(unsafe { touchscreen.wait_for_input.unsafe_clone() });
+        }
+    }
+
+    /// Check whether the pointer has been used.
+    ///
+    /// A click is only reported when the button is pressed, not when it's released.
+    pub(super) fn read(&mut self) -> Option<Input> {
+        let mut changed = false;
+        let mut pressed = self.pressed;
+        if let Some(mouse) = &mut self.mouse {
+            if let Ok(Some(state)) = mouse.read_state() {
+                let resolution = mouse.mode().resolution;
+                self.x = move_by(self.x, state.relative_movement[0], resolution[0], self.width);
+                self.y = move_by(self.y, state.relative_movement[1], resolution[1], self.height);
+                pressed = state.button[0];
+                changed = true;
+            }
+        }
+        if let Some(touchscreen) = &mut self.touchscreen {
+            if let Some(state) = touchscreen.read_state() {
+                let mode = touchscreen.mode();
+                self.x = scale(state.current_x, mode.min_x, mode.max_x, self.width);
+                self.y = scale(state.current_y, mode.min_y, mode.max_y, self.height);
+                pressed = state.active_buttons & TOUCH_ACTIVE != 0;
+                changed = true;
+            }
+        }
+        let clicked = pressed && !self.pressed;
+        self.pressed = pressed;
+        changed.then_some(Input::Pointer { x: self.x, y: self.y, clicked })
+    }
+}
+
+/// Move a coordinate by a relative movement, staying on the screen.
+///
+/// The movement is in counts, the resolution in counts per millimeter.
+/// (Some mice report 0 if they don't know it.)
+fn move_by(position: usize, movement: i32, resolution: u64, size: usize) -> usize {
+    // one millimeter is roughly four pixels
+    let pixels = match resolution {
+        0 => movement as i64,
+        r => movement as i64 * 4 / r as i64,
+    };
+    (position as i64 + pixels).clamp(0, size as i64 - 1) as usize
+}
+
+/// Convert an absolute coordinate to the screen.
+fn scale(value: u64, min: u64, max: u64, size: usize) -> usize {
+    if max <= min {
+        return 0
+    }
+    let value = value.clamp(min, max) - min;
+    ((value * size as u64 / (max - min + 1)) as usize).min(size - 1)
+}
@@ -0,0 +1,217 @@
+//! The menu on a serial console
+//!
+//! This is meant for headless machines. The text menu is rendered with ANSI
+//! escape sequences and input is read from the serial port, too.
+
+use core::fmt;
+
+use uefi::prelude::*;
+use uefi::Char16;
+use uefi::Event;
+use uefi::proto::console::serial::{ControlBits, Serial};
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::table::boot::{EventType, TimerTrigger, Tpl};
+
+use log::debug;
+
+use crate::config::ThemeColor;
+use crate::firmware::system_table;
+
+use super::input::KeyPress;
+use super::text::Console;
+
+/// A serial port with a terminal attached to it.
+pub(super) struct SerialConsole {
+    serial: &'static mut Serial<'static>,
+    /// a periodic timer to poll the serial port for input
+    poll: Event,
+}
+
+impl SerialConsole {
+    /// Find the serial port.
+    pub(super) fn new() -> Result<Self, Status> {
+        let boot_services = unsafe { system_table().as_ref() }.boot_services();
+        let serial = boot_services.locate_protocol::<Serial>().map_err(|e| e.status())?;
+        let serial = unsafe { &mut *serial.get() };
+        debug!("using the serial port with {:?}", serial.io_mode());
+        // This is safe because there is no callback.
+        let poll = unsafe { boot_services.create_event(
+            EventType::TIMER, Tpl::APPLICATION, None, None
+        ) }.map_err(|e| e.status())?;
+        boot_services.set_timer(&poll, TimerTrigger::Periodic(500_000))
+            .map_err(|e| e.status())?;
+        Ok(Self { serial, poll })
+    }
+
+    /// Get the event that signals that the serial port should be polled.
+    pub(super) fn poll_event(&self) -> &Event {
+        &self.poll
+    }
+
+    /// Read a key, if there is one.
+    ///
+    /// This understands the escape sequences of common terminals
+    /// (including the modifiers).
+    pub(super) fn read_key(&mut self) -> Option<KeyPress> {
+        let key = match self.read_byte()? {
+            0x1b => match self.read_byte_after_escape() {
+                Some(b'[') => return self.read_escape_sequence(),
+                Some(b'O') => Key::Special(match self.read_byte_after_escape()? {
+                    b'P' => ScanCode::FUNCTION_1,
+                    b'Q' => ScanCode::FUNCTION_2,
+                    b'R' => ScanCode::FUNCTION_3,
+                    b'S' => ScanCode::FUNCTION_4,
+                    b'H' => ScanCode::HOME,
+                    b'F' => ScanCode::END,
+                    _ => return None,
+                }),
+                _ => Key::Special(ScanCode::ESCAPE),
+            },
+            b'\r' | b'\n' => printable('\r')?,
+            0x7f | 0x08 => printable('\u{8}')?,
+            byte if byte.is_ascii() => printable(byte as char)?,
+            // we don't decode UTF-8 here
+            _ => return None,
+        };
+        Some(KeyPress::new(key))
+    }
+
+    /// Read a byte, if there is one.
+    fn read_byte(&mut self) -> Option<u8> {
+        match self.serial.get_control_bits() {
+            Ok(bits) if !bits.contains(ControlBits::INPUT_BUFFER_EMPTY) => {
+                let mut buf = [0];
+                self.serial.read(&mut buf).ok().map(|()| buf[0])
+            },
+            _ => None,
+        }
+    }
+
+    /// Read the next byte of an escape sequence.
+    ///
+    /// The rest of the sequence might not have arrived yet, so wait a bit.
+    fn read_byte_after_escape(&mut self) -> Option<u8> {
+        self.read_byte().or_else(|| {
+            unsafe { system_table().as_ref() }.boot_services().stall(10_000);
+            self.read_byte()
+        })
+    }
+
+    /// Read the part of a CSI sequence after `ESC [`.
+    ///
+    /// The first parameter is the key (if it ends with `~`),
+    /// the second one contains the modifiers.
+    fn read_escape_sequence(&mut self) -> Option<KeyPress> {
+        // further parameters end up in the last slot and are ignored
+        let mut parameters = [0u8; 3];
+        let mut index = 0;
+        loop {
+            let scan_code = match self.read_byte_after_escape()? {
+                digit @ b'0'..=b'9' => {
+                    let parameter = &mut parameters[index];
+                    *parameter = parameter.saturating_mul(10).saturating_add(digit - b'0');
+                    continue
+                },
+                b';' => {
+                    index = (index + 1).min(2);
+                    continue
+                },
+                b'A' => ScanCode::UP,
+                b'B' => ScanCode::DOWN,
+                b'C' => ScanCode::RIGHT,
+                b'D' => ScanCode::LEFT,
+                b'H' => ScanCode::HOME,
+                b'F' => ScanCode::END,
+                b'~' => match parameters[0] {
+                    1 | 7 => ScanCode::HOME,
+                    2 => ScanCode::INSERT,
+                    3 => ScanCode::DELETE,
+                    4 | 8 => ScanCode::END,
+                    5 => ScanCode::PAGE_UP,
+                    6 => ScanCode::PAGE_DOWN,
+                    11 => ScanCode::FUNCTION_1,
+                    12 => ScanCode::FUNCTION_2,
+                    13 => ScanCode::FUNCTION_3,
+                    14 => ScanCode::FUNCTION_4,
+                    15 => ScanCode::FUNCTION_5,
+                    17 => ScanCode::FUNCTION_6,
+                    18 => ScanCode::FUNCTION_7,
+                    19 => ScanCode::FUNCTION_8,
+                    20 => ScanCode::FUNCTION_9,
+                    21 => ScanCode::FUNCTION_10,
+                    23 => ScanCode::FUNCTION_11,
+                    24 => ScanCode::FUNCTION_12,
+                    _ => return None,
+                },
+                _ => return None,
+            };
+            // this is 1 + a bitmask of Shift, Alt and Ctrl
+            let modifiers = parameters[1].saturating_sub(1);
+            return Some(KeyPress {
+                key: Key::Special(scan_code),
+                shift: modifiers & 1 != 0,
+                control: modifiers & 4 != 0,
+                alt: modifiers & 2 != 0,
+            })
+        }
+    }
+}
+
+/// Create a printable key.
+fn printable(chr: char) -> Option<Key> {
+    Char16::try_from(chr).ok().map(Key::Printable)
+}
+
+impl fmt::Write for SerialConsole {
+    /// Write a string, translating newlines for the terminal.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (index, line) in s.split('\n').enumerate() {
+            if index > 0 {
+                self.serial.write(b"\r\n").map_err(|_| fmt::Error)?;
+            }
+            self.serial.write(line.as_bytes()).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Console for SerialConsole {
+    fn clear(&mut self) -> uefi::Result {
+        fmt::Write::write_str(self, "\x1b[2J\x1b[H").map_err(|_| Status::DEVICE_ERROR.into())
+    }
+
+    fn set_color(&mut self, foreground: ThemeColor, background: ThemeColor) -> uefi::Result {
+        fmt::Write::write_fmt(self, format_args!(
+            "\x1b[0;{};{}m", ansi_color(foreground), ansi_color(background) + 10,
+        )).map_err(|_| Status::DEVICE_ERROR.into())
+    }
+
+    /// Assume a standard terminal, as we can't ask it.
+    fn rows(&mut self) -> uefi::Result<usize> {
+        Ok(24)
+    }
+}
+
+/// Get the ANSI code of a foreground color.
+///
+/// (Add 10 for the background color.)
+fn ansi_color(color: ThemeColor) -> u8 {
+    match color {
+        ThemeColor::Black => 30,
+        ThemeColor::Red => 31,
+        ThemeColor::Green => 32,
+        ThemeColor::Brown => 33,
+        ThemeColor::Blue => 34,
+        ThemeColor::Magenta => 35,
+        ThemeColor::Cyan => 36,
+        ThemeColor::LightGray => 37,
+        ThemeColor::DarkGray => 90,
+        ThemeColor::LightRed => 91,
+        ThemeColor::LightGreen => 92,
+        ThemeColor::Yellow => 93,
+        ThemeColor::LightBlue => 94,
+        ThemeColor::LightMagenta => 95,
+        ThemeColor::LightCyan => 96,
+        ThemeColor::White => 97,
+    }
+}
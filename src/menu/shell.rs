@@ -0,0 +1,526 @@
+//! A minimal command prompt.
+//!
+//! This can be used to look around and to boot kernels that are not in the
+//! configuration (eg. because it is broken).
+//! If there are no entries at all, it's used as a rescue prompt.
+//!
+//! The debug console is the same prompt with a few more commands for looking
+//! at the firmware (handles and their protocols, variables and memory).
+
+use alloc::collections::btree_set::BTreeSet;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use uefi::prelude::*;
+use uefi::{CStr16, Guid, Identify};
+use uefi::proto::console::gop::GraphicsOutput;
+use uefi::proto::console::serial::Serial;
+use uefi::proto::console::text::{Input, Key, Output, ScanCode};
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::file::{
+    Directory, File as UefiFile, FileAttribute, FileMode, FileType,
+};
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::proto::rng::Rng;
+use uefi::table::boot::{
+    LoadImageSource, OpenProtocolAttributes, OpenProtocolParams, SearchType,
+};
+use uefi::table::runtime::VariableVendor;
+
+use crate::config::{Entry, Module};
+use crate::file::File;
+use crate::firmware::system_table;
+use crate::vars;
+
+use super::input;
+
+const HELP: &str = "available commands:
+  help                        show this help
+  ls [directory]              list the files in a directory
+  cat <file>                  print a file
+  hexdump <file>              print the first 256 bytes of a file
+  memmap                      print the memory map
+  lsmode                      list the video modes
+  kernel <image> [args...]    set the kernel to boot
+  module <image> [args...]    add a module
+  boot [image [modules...]]   boot the kernel that has been set (or the given one)
+  chainload <image> [args...] run an EFI binary
+  exit                        go back to the menu";
+
+const DEBUG_HELP: &str = "debug commands:
+  handles                     list all handles and their protocols
+  getvar <name> [global]      print a variable (towboot's or a global one)
+  setvar <name> [value]       set one of towboot's variables (or delete it)
+  memdump <address> [length]  print memory (256 bytes by default)";
+
+/// how much memory `memdump` prints at most
+const MEMDUMP_LIMIT: usize = 4096;
+
+/// the protocols `handles` knows the names of
+const KNOWN_PROTOCOLS: &[(Guid, &str)] = &[
+    (BlockIO::GUID, "BlockIO"),
+    (DevicePath::GUID, "DevicePath"),
+    (GraphicsOutput::GUID, "GraphicsOutput"),
+    (Input::GUID, "SimpleTextInput"),
+    (LoadedImage::GUID, "LoadedImage"),
+    (Output::GUID, "SimpleTextOutput"),
+    (Rng::GUID, "Rng"),
+    (Serial::GUID, "SerialIO"),
+    (SimpleFileSystem::GUID, "SimpleFileSystem"),
+];
+
+/// What the prompt is used for.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// opened from the menu
+    Normal,
+    /// there is no menu
+    Rescue,
+    /// opened from the menu, with the debug commands
+    Debug,
+}
+
+/// Run the command prompt.
+///
+/// This returns the entry to boot or `None` if the user wants to go back.
+pub(super) fn run(
+    image: Handle, volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Option<Entry>> {
+    systab.stdout().clear()?;
+    writeln!(systab.stdout(), "towboot command prompt (type 'help' for help)").unwrap();
+    prompt(Mode::Normal, image, volume, systab)
+}
+
+/// Run the debug console.
+///
+/// This is the command prompt with the debug commands.
+/// It returns the entry to boot or `None` if the user wants to go back.
+pub(super) fn debug(
+    image: Handle, volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Option<Entry>> {
+    systab.stdout().clear()?;
+    writeln!(systab.stdout(), "towboot debug console (type 'help' for help)").unwrap();
+    prompt(Mode::Debug, image, volume, systab)
+}
+
+/// Run the rescue prompt.
+///
+/// This is used if there is nothing else to boot, so it only returns
+/// once a kernel has been chosen.
+/// (The screen is not cleared, so that the errors stay visible.)
+pub(super) fn rescue(
+    failure: Option<(&str, Status)>, image: Handle,
+    volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Entry> {
+    let stdout = systab.stdout();
+    writeln!(stdout).unwrap();
+    writeln!(stdout, "towboot rescue prompt").unwrap();
+    if let Some((entry, status)) = failure {
+        writeln!(stdout, "failed to boot {entry}: {status:?}").unwrap();
+    }
+    writeln!(stdout, "There are no entries to boot. (Is the configuration broken?)").unwrap();
+    writeln!(stdout, "Set a kernel with 'kernel' and 'module' and boot it with 'boot'").unwrap();
+    writeln!(stdout, "or run an EFI binary with 'chainload'. (Type 'help' for help.)").unwrap();
+    loop {
+        if let Some(entry) = prompt(Mode::Rescue, image, volume, systab)? {
+            return Ok(entry)
+        }
+    }
+}
+
+/// Read and execute commands until an entry is to be booted.
+///
+/// In the rescue prompt, there is no menu to go back to.
+/// The debug commands only work in the debug console.
+fn prompt(
+    mode: Mode, image: Handle, volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> uefi::Result<Option<Entry>> {
+    let mut entry = None;
+    loop {
+        write!(systab.stdout(), "> ").unwrap();
+        let line = match read_line(systab)? {
+            Some(line) => line,
+            None if mode == Mode::Rescue => continue,
+            None => return Ok(None),
+        };
+        let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        let args = args.trim();
+        let stdout = systab.stdout();
+        match command {
+            "" => (),
+            "help" if mode == Mode::Debug => {
+                writeln!(stdout, "{HELP}\n{DEBUG_HELP}").unwrap();
+            },
+            "help" => writeln!(stdout, "{HELP}").unwrap(),
+            "ls" => list_directory(if args.is_empty() { "\\" } else { args }, volume, systab),
+            "cat" => if let Ok(data) = read_file(args, volume, systab) {
+                writeln!(systab.stdout(), "{}", String::from_utf8_lossy(&data)).unwrap();
+            },
+            "hexdump" => if let Ok(data) = read_file(args, volume, systab) {
+                hexdump(&data[..data.len().min(256)], 0, systab);
+            },
+            "memmap" => memory_map(systab),
+            "lsmode" => video_modes(systab),
+            "kernel" => if args.is_empty() {
+                writeln!(stdout, "usage: kernel <image> [args...]").unwrap();
+            } else {
+                entry = Some(new_entry(args));
+            },
+            "module" => match entry.as_mut() {
+                Some(_) if args.is_empty() => {
+                    writeln!(stdout, "usage: module <image> [args...]").unwrap();
+                },
+                Some(entry) => entry.modules.push(new_module(args)),
+                None => writeln!(stdout, "please set a kernel first").unwrap(),
+            },
+            "boot" => if args.is_empty() {
+                match entry {
+                    Some(entry) => return Ok(Some(entry)),
+                    None => writeln!(stdout, "please set a kernel first").unwrap(),
+                }
+            } else {
+                let mut images = args.split_whitespace();
+                let mut entry = new_entry(images.next().unwrap());
+                entry.modules.extend(images.map(new_module));
+                return Ok(Some(entry))
+            },
+            "chainload" => if args.is_empty() {
+                writeln!(stdout, "usage: chainload <image> [args...]").unwrap();
+            } else {
+                chainload(args, image, volume, systab);
+            },
+            "handles" if mode == Mode::Debug => list_handles(systab),
+            "getvar" if mode == Mode::Debug => get_variable(args, systab),
+            "setvar" if mode == Mode::Debug => set_variable(args, systab),
+            "memdump" if mode == Mode::Debug => dump_memory(args, systab),
+            "exit" if mode == Mode::Rescue => writeln!(stdout, "there is no menu to go back to").unwrap(),
+            "exit" => return Ok(None),
+            _ => writeln!(stdout, "unknown command '{command}' (type 'help' for help)").unwrap(),
+        }
+    }
+}
+
+/// Read a line, echoing it.
+///
+/// This returns `None` if escape has been pressed.
+fn read_line(systab: &mut SystemTable<Boot>) -> uefi::Result<Option<String>> {
+    let mut line = String::new();
+    loop {
+        // this also polls the keyboard, in case the firmware doesn't signal keys
+        let key = match input::wait_for_input(None, None, None, systab)? {
+            Some(input::Input::Key(pressed)) => Some(pressed.key),
+            _ => None,
+        };
+        match key {
+            Some(Key::Special(ScanCode::ESCAPE)) => {
+                writeln!(systab.stdout()).unwrap();
+                return Ok(None)
+            },
+            Some(Key::Printable(c)) => match c.into() {
+                // enter
+                '\r' => {
+                    writeln!(systab.stdout()).unwrap();
+                    return Ok(Some(line))
+                },
+                // backspace
+                '\u{8}' => if line.pop().is_some() {
+                    write!(systab.stdout(), "\u{8} \u{8}").unwrap();
+                },
+                chr => {
+                    line.push(chr);
+                    write!(systab.stdout(), "{chr}").unwrap();
+                },
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Create an entry from a string like `image args...`.
+fn new_entry(kernel: &str) -> Entry {
+    let (image, argv) = kernel.split_once(' ').unwrap_or((kernel, ""));
+    Entry {
+        argv: Some(argv.to_string()),
+        image: image.to_string(),
+        name: None,
+        quirks: BTreeSet::new(),
+        modules: Vec::new(),
+        condition: None,
+        group: None,
+        hidden: false,
+        tries: None,
+        fallback: None,
+        resolution: None,
+        set_vars: Vec::new(),
+    }
+}
+
+/// Create a module from a string like `image args...`.
+fn new_module(module: &str) -> Module {
+    let (image, argv) = module.split_once(' ').unwrap_or((module, ""));
+    Module {
+        image: vec![image.to_string()],
+        directory: None,
+        argv: Some(argv.to_string()),
+        load_at: None,
+        random_seed: None,
+        decompress: false,
+    }
+}
+
+/// Load an EFI binary and start it, passing the arguments as load options.
+///
+/// This only returns if the binary fails to start or exits.
+fn chainload(args: &str, image: Handle, volume: &mut Directory, systab: &mut SystemTable<Boot>) {
+    let (path, options) = args.split_once(' ').unwrap_or((args, ""));
+    let data = match read_file(path, volume, systab) {
+        Ok(data) => data,
+        Err(_) => return,
+    };
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    let handle = match boot_services.load_image(
+        image, LoadImageSource::FromBuffer { buffer: &data, file_path: None },
+    ) {
+        Ok(handle) => handle,
+        Err(e) => {
+            writeln!(systab.stdout(), "failed to load '{path}': {:?}", e.status()).unwrap();
+            return
+        },
+    };
+    // the load options are a null-terminated UCS-2 string
+    let options: Vec<u16> = options.encode_utf16().chain(core::iter::once(0)).collect();
+    match boot_services.open_protocol::<LoadedImage>(
+        OpenProtocolParams { handle, agent: image, controller: None },
+        OpenProtocolAttributes::Exclusive,
+    ) {
+        Ok(loaded_image) => unsafe {
+            (*loaded_image.interface.get()).set_load_options(
+                options.as_ptr().cast(), (options.len() * 2).try_into().unwrap(),
+            );
+        },
+        Err(e) => {
+            writeln!(systab.stdout(), "failed to set the arguments: {:?}", e.status()).unwrap();
+        },
+    }
+    match boot_services.start_image(handle) {
+        Ok(()) => writeln!(systab.stdout(), "'{path}' exited").unwrap(),
+        Err(e) => writeln!(systab.stdout(), "'{path}' failed: {:?}", e.status()).unwrap(),
+    }
+}
+
+/// Read a whole file, printing errors.
+fn read_file(
+    name: &str, volume: &mut Directory, systab: &mut SystemTable<Boot>,
+) -> Result<Vec<u8>, Status> {
+    if name.is_empty() {
+        writeln!(systab.stdout(), "please specify a file").unwrap();
+        return Err(Status::INVALID_PARAMETER)
+    }
+    File::open(name, volume).and_then(|f| f.try_into()).map_err(|e| {
+        writeln!(systab.stdout(), "failed to read '{name}': {e:?}").unwrap();
+        e
+    })
+}
+
+/// Print the files in a directory.
+fn list_directory(name: &str, volume: &mut Directory, systab: &mut SystemTable<Boot>) {
+    let mut name_buf = [0; 1024];
+    let mut directory = match CStr16::from_str_with_buf(name, &mut name_buf).ok()
+        .and_then(|n| volume.open(n, FileMode::Read, FileAttribute::READ_ONLY).ok())
+        .and_then(|h| h.into_type().ok())
+    {
+        Some(FileType::Dir(directory)) => directory,
+        Some(FileType::Regular(_)) => {
+            writeln!(systab.stdout(), "'{name}' is not a directory").unwrap();
+            return
+        },
+        None => {
+            writeln!(systab.stdout(), "failed to open '{name}'").unwrap();
+            return
+        },
+    };
+    // FileInfo needs to be aligned
+    let mut buf = [0u64; 128];
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), 128 * 8)
+    };
+    loop {
+        match directory.read_entry(buf) {
+            Ok(Some(info)) => {
+                let name = info.file_name();
+                if info.attribute().contains(FileAttribute::DIRECTORY) {
+                    writeln!(systab.stdout(), "{:>12}  {name}\\", "<dir>").unwrap();
+                } else {
+                    writeln!(systab.stdout(), "{:>12}  {name}", info.file_size()).unwrap();
+                }
+            },
+            Ok(None) => break,
+            Err(e) => {
+                writeln!(systab.stdout(), "failed to read '{name}': {:?}", e.status()).unwrap();
+                break
+            },
+        }
+    }
+}
+
+/// Print data in hexadecimal and as ASCII.
+///
+/// The offsets start at `base`.
+fn hexdump(data: &[u8], base: usize, systab: &mut SystemTable<Boot>) {
+    for (index, chunk) in data.chunks(16).enumerate() {
+        let mut line = String::new();
+        write!(line, "{:08x} ", base + index * 16).unwrap();
+        for byte in chunk {
+            write!(line, " {byte:02x}").unwrap();
+        }
+        write!(line, "{:1$}  ", "", (16 - chunk.len()) * 3).unwrap();
+        line.extend(chunk.iter().map(|b| if b.is_ascii_graphic() { *b as char } else { '.' }));
+        writeln!(systab.stdout(), "{line}").unwrap();
+    }
+}
+
+/// Print the current memory map.
+fn memory_map(systab: &mut SystemTable<Boot>) {
+    let mut buf = Vec::new();
+    // The docs say that we should allocate a little bit more memory than needed.
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    buf.resize(boot_services.memory_map_size().map_size + 100, 0);
+    let descriptors: Vec<_> = match boot_services.memory_map(buf.as_mut_slice()) {
+        Ok((_key, iterator)) => iterator.copied().collect(),
+        Err(e) => {
+            writeln!(systab.stdout(), "failed to get the memory map: {e:?}").unwrap();
+            return
+        },
+    };
+    for descriptor in descriptors {
+        writeln!(
+            systab.stdout(), "{:016x}-{:016x} {:?}",
+            descriptor.phys_start,
+            descriptor.phys_start + descriptor.page_count * 4096,
+            descriptor.ty,
+        ).unwrap();
+    }
+}
+
+/// Print the available video modes.
+fn video_modes(systab: &mut SystemTable<Boot>) {
+    let output = match unsafe { system_table().as_ref() }.boot_services()
+        .locate_protocol::<GraphicsOutput>() {
+        Ok(output) => unsafe { &mut *output.get() },
+        Err(e) => {
+            writeln!(systab.stdout(), "failed to find a graphics output: {e:?}").unwrap();
+            return
+        },
+    };
+    let current = output.current_mode_info().resolution();
+    let modes: Vec<_> = output.modes().map(|m| *m.info()).collect();
+    for info in modes {
+        let (width, height) = info.resolution();
+        writeln!(
+            systab.stdout(), "{}{width}x{height} {:?}",
+            if (width, height) == current { "* " } else { "  " },
+            info.pixel_format(),
+        ).unwrap();
+    }
+}
+
+/// Print all handles and the protocols they support.
+fn list_handles(systab: &mut SystemTable<Boot>) {
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    let handles = match boot_services.locate_handle_buffer(SearchType::AllHandles) {
+        Ok(handles) => handles,
+        Err(e) => {
+            writeln!(systab.stdout(), "failed to list the handles: {:?}", e.status()).unwrap();
+            return
+        },
+    };
+    for handle in handles.handles() {
+        writeln!(systab.stdout(), "{handle:?}").unwrap();
+        let protocols = match boot_services.protocols_per_handle(*handle) {
+            Ok(protocols) => protocols,
+            Err(e) => {
+                writeln!(systab.stdout(), "  failed to get the protocols: {:?}", e.status())
+                    .unwrap();
+                continue
+            },
+        };
+        for guid in protocols.protocols() {
+            match KNOWN_PROTOCOLS.iter().find(|(known, _)| known == *guid) {
+                Some((_, name)) => writeln!(systab.stdout(), "  {name}").unwrap(),
+                None => writeln!(systab.stdout(), "  {guid}").unwrap(),
+            }
+        }
+    }
+}
+
+/// Print a variable, as text (if it is) and in hexadecimal.
+fn get_variable(args: &str, systab: &mut SystemTable<Boot>) {
+    let (name, vendor) = match args.split_once(' ') {
+        None if !args.is_empty() => (args, vars::VENDOR),
+        Some((name, "global")) => (name, VariableVendor::GLOBAL_VARIABLE),
+        _ => {
+            writeln!(systab.stdout(), "usage: getvar <name> [global]").unwrap();
+            return
+        },
+    };
+    let mut name_buf = [0; 128];
+    let cname = match CStr16::from_str_with_buf(name, &mut name_buf) {
+        Ok(cname) => cname,
+        Err(e) => {
+            writeln!(systab.stdout(), "'{name}' is not a valid name: {e:?}").unwrap();
+            return
+        },
+    };
+    let runtime_services = unsafe { system_table().as_ref() }.runtime_services();
+    let mut buf = vec![0; runtime_services.get_variable_size(cname, &vendor).unwrap_or(0)];
+    match runtime_services.get_variable(cname, &vendor, &mut buf) {
+        Ok((value, attributes)) => {
+            let value = value.to_vec();
+            writeln!(systab.stdout(), "{} bytes, {attributes:?}", value.len()).unwrap();
+            if let Ok(text) = core::str::from_utf8(&value) {
+                writeln!(systab.stdout(), "{text}").unwrap();
+            }
+            hexdump(&value, 0, systab);
+        },
+        Err(e) => writeln!(systab.stdout(), "failed to read '{name}': {:?}", e.status()).unwrap(),
+    }
+}
+
+/// Set one of our variables to a string (or delete it if there's no value).
+fn set_variable(args: &str, systab: &mut SystemTable<Boot>) {
+    // the errors have already been logged
+    let result = match args.split_once(' ') {
+        _ if args.is_empty() => {
+            writeln!(systab.stdout(), "usage: setvar <name> [value]").unwrap();
+            return
+        },
+        Some((name, value)) => vars::set_string(name, value.trim()),
+        None => vars::delete(args),
+    };
+    if let Err(e) = result {
+        writeln!(systab.stdout(), "failed to set '{args}': {e:?}").unwrap();
+    }
+}
+
+/// Print a part of the memory.
+///
+/// Reading memory that isn't there may crash the machine, that's up to the user.
+fn dump_memory(args: &str, systab: &mut SystemTable<Boot>) {
+    let mut args = args.split_whitespace().map(|a| usize::from_str_radix(
+        a.trim_start_matches("0x"), 16,
+    ));
+    let (address, length) = match (args.next(), args.next().unwrap_or(Ok(256))) {
+        (Some(Ok(address)), Ok(length)) => (address, length.min(MEMDUMP_LIMIT)),
+        _ => {
+            writeln!(systab.stdout(), "usage: memdump <address> [length] (in hexadecimal)")
+                .unwrap();
+            return
+        },
+    };
+    let data = unsafe { core::slice::from_raw_parts(address as *const u8, length) };
+    hexdump(data, address, systab);
+}
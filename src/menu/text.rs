@@ -0,0 +1,303 @@
+//! The menu on the text console.
+//!
+//! This can also be displayed on a serial console (see the `serial` module).
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use uefi::prelude::*;
+use uefi::proto::console::text::{Color, Output};
+
+use crate::config::{Entry, Theme, ThemeColor};
+
+use super::{Editor, Frontend, List, Messages, fill};
+use super::pointer::Pointers;
+use super::serial::SerialConsole;
+
+/// Something to display text on.
+pub(super) trait Console: Write {
+    /// Clear the whole screen with the current background color.
+    fn clear(&mut self) -> uefi::Result;
+    
+    /// Set the colors for the following text.
+    fn set_color(&mut self, foreground: ThemeColor, background: ThemeColor) -> uefi::Result;
+    
+    /// Get the height of the screen.
+    fn rows(&mut self) -> uefi::Result<usize>;
+}
+
+impl Console for Output<'_> {
+    fn clear(&mut self) -> uefi::Result {
+        Output::clear(self)
+    }
+    
+    fn set_color(&mut self, foreground: ThemeColor, background: ThemeColor) -> uefi::Result {
+        Output::set_color(self, to_color(foreground), to_color(background))
+    }
+    
+    fn rows(&mut self) -> uefi::Result<usize> {
+        Ok(self.current_mode()?.map_or(25, |mode| mode.rows()))
+    }
+}
+
+/// A console that just remembers the text. (This is used for screenshots.)
+struct Capture {
+    text: String,
+    rows: usize,
+}
+
+impl Write for Capture {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.text.push_str(s);
+        Ok(())
+    }
+}
+
+impl Console for Capture {
+    fn clear(&mut self) -> uefi::Result {
+        self.text.clear();
+        Ok(())
+    }
+    
+    fn set_color(&mut self, _foreground: ThemeColor, _background: ThemeColor) -> uefi::Result {
+        Ok(())
+    }
+    
+    fn rows(&mut self) -> uefi::Result<usize> {
+        Ok(self.rows)
+    }
+}
+
+pub(super) struct TextFrontend {
+    style: Style,
+    /// draw to this instead of the UEFI console
+    serial: Option<SerialConsole>,
+}
+
+impl TextFrontend {
+    /// Create a text menu on the UEFI console or on the serial console.
+    pub(super) fn new(
+        theme: Theme, messages: &'static Messages, serial: Option<SerialConsole>,
+    ) -> Self {
+        Self { style: Style { theme, messages }, serial }
+    }
+    
+    /// Get the style and the console to draw to.
+    fn console<'s>(
+        &'s mut self, systab: &'s mut SystemTable<Boot>,
+    ) -> (&'s Style, &'s mut dyn Console) {
+        match &mut self.serial {
+            Some(serial) => (&self.style, serial),
+            None => (&self.style, systab.stdout()),
+        }
+    }
+}
+
+/// How to draw the menu.
+struct Style {
+    theme: Theme,
+    messages: &'static Messages,
+}
+
+impl Style {
+    /// Set the normal colors of the theme.
+    fn set_normal_color(&self, stdout: &mut dyn Console) -> uefi::Result {
+        stdout.set_color(
+            self.theme.foreground.unwrap_or(ThemeColor::LightGray),
+            self.theme.background.unwrap_or(ThemeColor::Black),
+        )
+    }
+
+    /// Set the colors of the theme for the selected entry.
+    fn set_highlight_color(&self, stdout: &mut dyn Console) -> uefi::Result {
+        stdout.set_color(
+            self.theme.highlight_foreground.unwrap_or(ThemeColor::Black),
+            self.theme.highlight_background.unwrap_or(ThemeColor::LightGray),
+        )
+    }
+
+    /// Print the left margin.
+    fn margin(&self, stdout: &mut dyn Console) {
+        write!(stdout, "{:1$}", "", self.theme.margin_left.unwrap_or(2)).unwrap();
+    }
+
+    /// Clear the console and list all entries, highlighting the selected one.
+    fn draw_list(&self, list: &List, stdout: &mut dyn Console) -> uefi::Result {
+        self.set_normal_color(stdout)?;
+        // this fills the whole screen with the background color
+        stdout.clear()?;
+        for _ in 0..self.theme.margin_top.unwrap_or(1) {
+            writeln!(stdout).unwrap();
+        }
+        self.margin(stdout);
+        writeln!(stdout, "{}", self.theme.title.as_deref().unwrap_or("towboot")).unwrap();
+        if let Some(banner) = &self.theme.banner {
+            for line in banner.lines() {
+                self.margin(stdout);
+                writeln!(stdout, "{line}").unwrap();
+            }
+        }
+        writeln!(stdout).unwrap();
+        self.margin(stdout);
+        if list.scroll > 0 {
+            write!(stdout, " ^ {}", fill(self.messages.more, &[&list.scroll])).unwrap();
+        }
+        writeln!(stdout).unwrap();
+        for (index, item) in list.visible_items() {
+            self.margin(stdout);
+            if index == list.selected {
+                self.set_highlight_color(stdout)?;
+            }
+            write!(stdout, "{index}. {}", list.text(item, self.messages)).unwrap();
+            if index == list.selected {
+                self.set_normal_color(stdout)?;
+            }
+            writeln!(stdout).unwrap();
+        }
+        self.margin(stdout);
+        if list.hidden_below() > 0 {
+            write!(stdout, " v {}", fill(self.messages.more, &[&list.hidden_below()])).unwrap();
+        }
+        writeln!(stdout).unwrap();
+        writeln!(stdout).unwrap();
+        self.margin(stdout);
+        writeln!(stdout, "{}", self.messages.list_hint).unwrap();
+        if let Some(choice) = &list.invalid_choice {
+            self.margin(stdout);
+            writeln!(stdout, "{}{choice}", self.messages.invalid_choice).unwrap();
+        } else if let Some(level) = list.log_level {
+            self.margin(stdout);
+            writeln!(stdout, "{}", fill(self.messages.log_level, &[&level])).unwrap();
+        }
+        self.margin(stdout);
+        write!(stdout, "{}{}", self.messages.select_prompt, list.input).unwrap();
+        Ok(())
+    }
+}
+
+impl Frontend for TextFrontend {
+    fn draw_timeout(
+        &mut self, key: &str, entry: &Entry, timeout: u8, systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result {
+        let (this, stdout) = self.console(systab);
+        this.set_normal_color(stdout)?;
+        // overwrite the previous countdown (the padding covers a shorter number)
+        write!(
+            stdout, "\rtowboot: {}  ",
+            fill(
+                this.messages.countdown, &[&key, &entry.name.as_deref().unwrap_or(key), &timeout],
+            ),
+        ).unwrap();
+        if timeout == 0 {
+            writeln!(stdout).unwrap();
+        }
+        Ok(())
+    }
+    
+    /// Clear the screen and list all entries, highlighting the selected one.
+    fn draw_list(&mut self, list: &List, systab: &mut SystemTable<Boot>) -> uefi::Result {
+        let (this, stdout) = self.console(systab);
+        this.draw_list(list, stdout)
+    }
+    
+    /// Calculate how many entries fit on the screen.
+    ///
+    /// This is the height of the console minus the title, the banner,
+    /// the scroll indicators and the prompt.
+    fn list_rows(&mut self, systab: &mut SystemTable<Boot>) -> uefi::Result<usize> {
+        let (this, stdout) = self.console(systab);
+        let rows = stdout.rows()?;
+        let header = this.theme.margin_top.unwrap_or(1) + 2
+            + this.theme.banner.as_ref().map_or(0, |b| b.lines().count());
+        Ok(rows.saturating_sub(header + 6).max(1))
+    }
+    
+    /// Clear the screen and list all command lines, highlighting the cursor.
+    fn draw_editor(&mut self, editor: &Editor, systab: &mut SystemTable<Boot>) -> uefi::Result {
+        let (this, stdout) = self.console(systab);
+        this.set_normal_color(stdout)?;
+        stdout.clear()?;
+        for _ in 0..this.theme.margin_top.unwrap_or(1) {
+            writeln!(stdout).unwrap();
+        }
+        this.margin(stdout);
+        writeln!(stdout, "{}", fill(this.messages.editing, &[&editor.key])).unwrap();
+        writeln!(stdout).unwrap();
+        for (index, (image, line)) in editor.lines.iter().enumerate() {
+            this.margin(stdout);
+            writeln!(stdout, "{image}:").unwrap();
+            this.margin(stdout);
+            if index == editor.selected {
+                let (before, cursor, after) = editor.split_at_cursor();
+                write!(stdout, "  {before}").unwrap();
+                this.set_highlight_color(stdout)?;
+                write!(stdout, "{cursor}").unwrap();
+                this.set_normal_color(stdout)?;
+                writeln!(stdout, "{after}").unwrap();
+            } else {
+                writeln!(stdout, "  {line}").unwrap();
+            }
+        }
+        writeln!(stdout).unwrap();
+        this.margin(stdout);
+        writeln!(stdout, "{}", this.messages.editor_hint).unwrap();
+        Ok(())
+    }
+    
+    fn draw_info(
+        &mut self, title: &str, lines: &[String], systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result {
+        let (this, stdout) = self.console(systab);
+        this.set_normal_color(stdout)?;
+        stdout.clear()?;
+        for _ in 0..this.theme.margin_top.unwrap_or(1) {
+            writeln!(stdout).unwrap();
+        }
+        this.margin(stdout);
+        writeln!(stdout, "{title}").unwrap();
+        writeln!(stdout).unwrap();
+        for line in lines {
+            this.margin(stdout);
+            writeln!(stdout, "{line}").unwrap();
+        }
+        Ok(())
+    }
+    
+    /// Draw the list again, but only keep the text.
+    fn screenshot(
+        &mut self, list: &List, systab: &mut SystemTable<Boot>,
+    ) -> uefi::Result<(&'static str, Vec<u8>)> {
+        let (this, stdout) = self.console(systab);
+        let mut capture = Capture { text: String::new(), rows: stdout.rows()? };
+        this.draw_list(list, &mut capture)?;
+        Ok(("txt", capture.text.into_bytes()))
+    }
+    
+    /// Read from the serial console, too, if we're using it.
+    fn input_sources(&mut self) -> (Option<&mut SerialConsole>, Option<&mut Pointers>) {
+        (self.serial.as_mut(), None)
+    }
+}
+
+/// Convert a color of the theme to one of the console.
+fn to_color(color: ThemeColor) -> Color {
+    match color {
+        ThemeColor::Black => Color::Black,
+        ThemeColor::Blue => Color::Blue,
+        ThemeColor::Green => Color::Green,
+        ThemeColor::Cyan => Color::Cyan,
+        ThemeColor::Red => Color::Red,
+        ThemeColor::Magenta => Color::Magenta,
+        ThemeColor::Brown => Color::Brown,
+        ThemeColor::LightGray => Color::LightGray,
+        ThemeColor::DarkGray => Color::DarkGray,
+        ThemeColor::LightBlue => Color::LightBlue,
+        ThemeColor::LightGreen => Color::LightGreen,
+        ThemeColor::LightCyan => Color::LightCyan,
+        ThemeColor::LightRed => Color::LightRed,
+        ThemeColor::LightMagenta => Color::LightMagenta,
+        ThemeColor::Yellow => Color::Yellow,
+        ThemeColor::White => Color::White,
+    }
+}
@@ -0,0 +1,161 @@
+//! Handling panics
+//!
+//! If something goes wrong while boot services are still there, a screen shows
+//! what has happened (and while doing what), where the kernel and the Multiboot
+//! information are and what can be done now: The machine can be rebooted
+//! (into the firmware's setup, if it supports that) or towboot can be left,
+//! so that the firmware shows its boot menu (or starts towboot again).
+//!
+//! After exiting boot services, there's no console anymore, so the message is
+//! only written to the first serial port (on x86) before halting.
+
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use uefi::prelude::*;
+use uefi::proto::console::text::{Color, Key};
+
+use log::error;
+
+use super::firmware::system_table;
+use super::logger;
+use super::mem::Allocation;
+use super::power;
+
+/// whether we're already handling a panic
+static PANICKING: AtomicBool = AtomicBool::new(false);
+/// where the kernel is going to be (0 if there's none)
+static KERNEL_START: AtomicU64 = AtomicU64::new(0);
+static KERNEL_END: AtomicU64 = AtomicU64::new(0);
+/// where the Multiboot information is (0 if there's none)
+static MULTIBOOT_INFORMATION: AtomicU64 = AtomicU64::new(0);
+
+/// our image handle, to leave towboot
+static IMAGE: ImageHandle = ImageHandle(UnsafeCell::new(None));
+
+struct ImageHandle(UnsafeCell<Option<Handle>>);
+
+// This is only written once, at the start.
+unsafe impl Sync for ImageHandle {}
+
+/// Remember our image handle.
+///
+/// This has to be called at the start, before anything could panic.
+pub(crate) fn init(image: Handle) {
+    unsafe { *IMAGE.0.get() = Some(image) };
+}
+
+/// Remember where the parts of the kernel are going to be.
+pub(crate) fn set_kernel(parts: &[Allocation]) {
+    let start = parts.iter().map(Allocation::final_address).min().unwrap_or(0);
+    let end = parts.iter().map(|p| p.final_address() + p.len as u64).max().unwrap_or(0);
+    KERNEL_START.store(start, Ordering::Relaxed);
+    KERNEL_END.store(end, Ordering::Relaxed);
+}
+
+/// Remember where the Multiboot information is.
+pub(crate) fn set_multiboot_information(address: u64) {
+    MULTIBOOT_INFORMATION.store(address, Ordering::Relaxed);
+}
+
+#[cfg_attr(not(test), panic_handler)]
+#[cfg_attr(test, allow(dead_code))] // the tests run with std's handler
+fn panic(info: &PanicInfo) -> ! {
+    // If showing the screen panics, too, there's nothing left to do.
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        halt()
+    }
+    // Logging is disabled right before exiting boot services.
+    if !logger::is_enabled() {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let _ = writeln!(Serial, "towboot: {info}");
+        halt()
+    }
+    error!("{info}");
+    let systab = unsafe { system_table().as_mut() };
+    let firmware_setup = power::firmware_setup_supported();
+    // There's no other way to tell the user about failures here.
+    let _ = show(systab, info, firmware_setup);
+    let _ = systab.stdin().reset(false);
+    let key_event = unsafe { systab.stdin().wait_for_key_event().unsafe_clone() };
+    let key = systab.boot_services().wait_for_event(&mut [key_event]).ok()
+        .and_then(|_| systab.stdin().read_key().ok().flatten());
+    // This closes the log file.
+    logger::disable();
+    match key {
+        Some(Key::Printable(c)) if matches!(char::from(c), 'r' | 'R') => power::reboot(),
+        Some(Key::Printable(c)) if firmware_setup && matches!(char::from(c), 'f' | 'F') => {
+            // This only returns if it failed.
+            power::reboot_to_firmware_setup();
+        },
+        _ => (),
+    }
+    match unsafe { *IMAGE.0.get() } {
+        Some(image) => unsafe {
+            systab.boot_services().exit(image, Status::ABORTED, 0, core::ptr::null_mut())
+        },
+        None => halt(),
+    }
+}
+
+/// Draw the panic screen.
+fn show(
+    systab: &mut SystemTable<Boot>, info: &PanicInfo, firmware_setup: bool,
+) -> core::fmt::Result {
+    let stdout = systab.stdout();
+    let _ = stdout.set_color(Color::White, Color::Red);
+    let _ = stdout.clear();
+    writeln!(stdout, "towboot has crashed:")?;
+    writeln!(stdout, "{info}")?;
+    writeln!(stdout)?;
+    writeln!(stdout, "phase: {}", logger::phase())?;
+    let kernel_end = KERNEL_END.load(Ordering::Relaxed);
+    if kernel_end != 0 {
+        writeln!(
+            stdout, "kernel: {:#x} - {kernel_end:#x}", KERNEL_START.load(Ordering::Relaxed),
+        )?;
+    }
+    let multiboot_information = MULTIBOOT_INFORMATION.load(Ordering::Relaxed);
+    if multiboot_information != 0 {
+        writeln!(stdout, "Multiboot information: {multiboot_information:#x}")?;
+    }
+    writeln!(stdout)?;
+    if firmware_setup {
+        writeln!(
+            stdout,
+            "press R to reboot, F to reboot into the firmware setup \
+            or any other key to leave towboot",
+        )
+    } else {
+        writeln!(stdout, "press R to reboot or any other key to leave towboot")
+    }
+}
+
+/// Stop doing anything.
+fn halt() -> ! {
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// The first serial port, assuming the firmware has set it up.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+struct Serial;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Write for Serial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for line in s.split_inclusive('\n') {
+            match line.strip_suffix('\n') {
+                Some(line) => {
+                    super::port::write_serial(line.as_bytes());
+                    super::port::write_serial(b"\r\n");
+                },
+                None => super::port::write_serial(line.as_bytes()),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! Port I/O
+//!
+//! Some legacy hardware (like the PC speaker or the PICs) can only be reached this way.
+
+use core::arch::asm;
+
+/// Write a byte to a port.
+pub(crate) unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack));
+}
+
+/// Read a byte from a port.
+pub(crate) unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack));
+    value
+}
+
+/// the first serial port
+const COM1: u16 = 0x3f8;
+/// the clock of the serial port's UART, divided by 16
+const UART_CLOCK: u64 = 115_200;
+
+/// Program the first serial port for 8N1 at the given baud rate.
+pub(crate) fn init_serial(baud_rate: u64) {
+    let divisor = (UART_CLOCK / baud_rate.max(1)).clamp(1, u16::MAX.into()) as u16;
+    unsafe {
+        // no interrupts
+        outb(COM1 + 1, 0x00);
+        // set the divisor (while the divisor latch is enabled)
+        outb(COM1 + 3, 0x80);
+        outb(COM1, divisor as u8);
+        outb(COM1 + 1, (divisor >> 8) as u8);
+        // 8 data bits, no parity, one stop bit
+        outb(COM1 + 3, 0x03);
+        // enable and clear the FIFOs
+        outb(COM1 + 2, 0xc7);
+        // DTR and RTS
+        outb(COM1 + 4, 0x03);
+    }
+}
+
+/// Write to the first serial port, assuming that it has been set up.
+pub(crate) fn write_serial(bytes: &[u8]) {
+    for byte in bytes {
+        // wait until the transmitter is empty
+        while unsafe { inb(COM1 + 5) } & 0x20 == 0 {}
+        unsafe { outb(COM1, *byte) };
+    }
+}
@@ -0,0 +1,71 @@
+//! Rebooting and powering off
+//!
+//! This also supports rebooting into the firmware's setup via `OsIndications`.
+
+use log::{debug, error, info};
+
+use uefi::prelude::*;
+use uefi::CStr16;
+use uefi::table::runtime::{ResetType, VariableAttributes, VariableVendor};
+
+use super::firmware::system_table;
+
+/// Ask the firmware to display its setup on the next boot.
+/// (`EFI_OS_INDICATIONS_BOOT_TO_FW_UI`)
+const BOOT_TO_FW_UI: u64 = 1;
+
+/// Reboot the machine.
+pub(crate) fn reboot() -> ! {
+    info!("rebooting...");
+    unsafe { system_table().as_ref() }.runtime_services()
+        .reset(ResetType::Cold, Status::SUCCESS, None)
+}
+
+/// Power the machine off.
+pub(crate) fn power_off() -> ! {
+    info!("powering off...");
+    unsafe { system_table().as_ref() }.runtime_services()
+        .reset(ResetType::Shutdown, Status::SUCCESS, None)
+}
+
+/// Check whether the firmware supports rebooting into its setup.
+pub(crate) fn firmware_setup_supported() -> bool {
+    read_u64("OsIndicationsSupported").map_or(false, |v| v & BOOT_TO_FW_UI != 0)
+}
+
+/// Reboot into the firmware's setup.
+///
+/// This only returns if setting `OsIndications` failed.
+pub(crate) fn reboot_to_firmware_setup() -> Status {
+    let mut name_buf = [0; 16];
+    let name = CStr16::from_str_with_buf("OsIndications", &mut name_buf).unwrap();
+    // keep the other indications that might already be set
+    let value = read_u64("OsIndications").unwrap_or(0) | BOOT_TO_FW_UI;
+    if let Err(e) = unsafe { system_table().as_ref() }.runtime_services().set_variable(
+        name, &VariableVendor::GLOBAL_VARIABLE,
+        VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS,
+        &value.to_le_bytes(),
+    ) {
+        error!("failed to set OsIndications: {e:?}");
+        return e.status()
+    }
+    info!("rebooting into the firmware setup...");
+    reboot()
+}
+
+/// Read a global variable containing a 64 bit number.
+fn read_u64(name: &str) -> Option<u64> {
+    let mut name_buf = [0; 32];
+    let cname = CStr16::from_str_with_buf(name, &mut name_buf).unwrap();
+    let mut buf = [0; 8];
+    match unsafe { system_table().as_ref() }.runtime_services().get_variable(
+        cname, &VariableVendor::GLOBAL_VARIABLE, &mut buf,
+    ) {
+        Ok((value, _attributes)) => value.try_into().ok().map(u64::from_le_bytes),
+        Err(e) => {
+            debug!("failed to read the {name} variable: {e:?}");
+            None
+        },
+    }
+}
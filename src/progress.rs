@@ -0,0 +1,107 @@
+//! Progress bars
+//!
+//! Loading large modules can take a while, so this shows how far we've got.
+
+use core::fmt::Write;
+
+use uefi::proto::console::gop::{BltOp, BltPixel, GraphicsOutput};
+
+use crate::config::{Config, MenuType};
+use crate::firmware::system_table;
+
+/// Files smaller than this are loaded without a progress bar.
+const MIN_SIZE: usize = 4 * 1024 * 1024;
+
+/// How wide the text progress bar is (in characters).
+const TEXT_WIDTH: usize = 40;
+
+/// How to display the progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Style {
+    /// on the text console
+    Text,
+    /// as a bar drawn to the framebuffer
+    Graphical,
+    /// not at all
+    None,
+}
+
+impl Style {
+    /// Use the same kind of display as the menu.
+    pub(crate) fn from_config(config: &Config) -> Self {
+        match config.menu {
+            Some(MenuType::Graphical) => Style::Graphical,
+            _ => Style::Text,
+        }
+    }
+}
+
+/// A progress bar for loading a file.
+pub(crate) struct Progress<'a> {
+    name: &'a str,
+    total: usize,
+    /// the last displayed percentage
+    percent: Option<usize>,
+    style: Style,
+    gop: Option<&'static mut GraphicsOutput<'static>>,
+}
+
+impl<'a> Progress<'a> {
+    /// Prepare a progress bar for a file of the given size.
+    pub(crate) fn new(name: &'a str, total: usize, style: Style) -> Self {
+        let style = if total < MIN_SIZE { Style::None } else { style };
+        let gop = match style {
+            Style::Graphical => unsafe { system_table().as_ref() }.boot_services()
+                .locate_protocol::<GraphicsOutput>().ok()
+                .map(|g| unsafe { &mut *g.get() }),
+            _ => None,
+        };
+        // fall back to text if there's no framebuffer
+        let style = match (style, &gop) {
+            (Style::Graphical, None) => Style::Text,
+            (style, _) => style,
+        };
+        Self { name, total, percent: None, style, gop }
+    }
+
+    /// Display how many bytes have been loaded.
+    pub(crate) fn update(&mut self, done: usize) {
+        let percent = (done as u64 * 100 / self.total.max(1) as u64) as usize;
+        if self.percent == Some(percent) {
+            return
+        }
+        self.percent = Some(percent);
+        match self.style {
+            Style::Text => {
+                let filled = percent * TEXT_WIDTH / 100;
+                let stdout = unsafe { system_table().as_mut() }.stdout();
+                // errors here are purely cosmetic
+                let _ = write!(
+                    stdout, "\r{} [{:=<filled$}{:width$}] {percent:3}% ({}/{} MiB)",
+                    self.name, "", "", done / 1024 / 1024, self.total / 1024 / 1024,
+                    width = TEXT_WIDTH - filled,
+                );
+                if done >= self.total {
+                    let _ = writeln!(stdout);
+                }
+            },
+            Style::Graphical => if let Some(gop) = &mut self.gop {
+                let (width, height) = gop.current_mode_info().resolution();
+                let (bar_width, bar_height) = (width / 2, 16);
+                let (x, y) = (width / 4, height - 4 * bar_height);
+                let filled = percent * bar_width / 100;
+                let _ = gop.blt(BltOp::VideoFill {
+                    color: BltPixel::new(0x50, 0x60, 0xa0),
+                    dest: (x, y),
+                    dims: (filled.max(1), bar_height),
+                });
+                let _ = gop.blt(BltOp::VideoFill {
+                    color: BltPixel::new(0x30, 0x30, 0x40),
+                    dest: (x + filled, y),
+                    dims: ((bar_width - filled).max(1), bar_height),
+                });
+            },
+            Style::None => (),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Resuming hibernated systems
+//!
+//! Booting another system (or the same kernel without resuming) while one is
+//! hibernated may corrupt its filesystems. Linux marks its swap partition when
+//! it hibernates to it, so towboot can check the configured partition and boot
+//! the resume entry right away, without displaying the menu.
+//! (There's no `OsIndications` bit for this, so the swap partition is the only
+//! hint. Hibernating to a swap file isn't detected.)
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::proto::media::block::BlockIO;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+
+use log::{debug, warn};
+
+use super::boot::device;
+use super::config::Resume;
+use super::firmware::system_table;
+use super::uri;
+
+/// the page sizes Linux may use (the signature is at the end of the first page)
+const PAGE_SIZES: [usize; 3] = [4096, 16384, 65536];
+/// how long the signature may be
+const SIGNATURE_LENGTH: usize = 10;
+/// the signatures of swap partitions containing a hibernation image
+/// (the kernel's own, uswsusp's and the one of the old hibernate scripts)
+const HIBERNATION_SIGNATURES: [&[u8]; 3] = [b"S1SUSPEND", b"ULSUSPEND", b"LINHIB0001"];
+
+/// Check whether the system has hibernated to the configured swap partition.
+///
+/// If the partition can't be read, this assumes that it hasn't.
+pub(crate) fn is_pending(resume: &Resume, image: Handle) -> bool {
+    let guid = match uri::parse_guid(&resume.swap) {
+        Some(guid) => guid,
+        None => {
+            warn!("'{}' is not a GUID, not checking for hibernation", resume.swap);
+            return false
+        },
+    };
+    let start = match read_start(&guid, image, PAGE_SIZES[PAGE_SIZES.len() - 1]) {
+        Some(start) => start,
+        None => return false,
+    };
+    let pending = PAGE_SIZES.iter()
+        .filter_map(|size| start.get(size - SIGNATURE_LENGTH..*size))
+        .any(|signature| HIBERNATION_SIGNATURES.iter().any(|s| signature.starts_with(s)));
+    debug!("the swap partition {} contains a hibernation image: {pending}", resume.swap);
+    pending
+}
+
+/// Read the beginning of the partition with the given unique GUID.
+///
+/// This returns less than `length` bytes if the partition is smaller.
+fn read_start(guid: &[u8; 16], image: Handle, length: usize) -> Option<Vec<u8>> {
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    let handle = boot_services.find_handles::<BlockIO>().unwrap_or_default()
+        .into_iter()
+        .find(|handle| device::partition_guid(*handle, image, boot_services) == Some(*guid));
+    let handle = match handle {
+        Some(handle) => handle,
+        None => {
+            warn!("there's no swap partition with the GUID {guid:02x?}");
+            return None
+        },
+    };
+    let block_io = boot_services.open_protocol::<BlockIO>(
+        OpenProtocolParams {
+            handle,
+            agent: image,
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    ).map_err(|e| warn!("failed to open the swap partition: {e:?}")).ok()?;
+    let block_io = unsafe { &*block_io.interface.get() };
+    let media = block_io.media();
+    if !media.is_media_present() {
+        warn!("the swap partition has no media");
+        return None
+    }
+    let block_size = usize::try_from(media.block_size()).unwrap();
+    let blocks = ((length + block_size - 1) / block_size)
+        .min(usize::try_from(media.last_block() + 1).unwrap_or(usize::MAX));
+    // the firmware may need the buffer to be aligned
+    let align = usize::try_from(media.io_align()).unwrap().max(1);
+    let mut buffer = vec![0; blocks * block_size + align];
+    let offset = buffer.as_ptr().align_offset(align);
+    let data = &mut buffer[offset..offset + blocks * block_size];
+    block_io.read_blocks(media.media_id(), 0, data)
+        .map_err(|e| warn!("failed to read the swap partition: {e:?}")).ok()?;
+    Some(data.to_vec())
+}
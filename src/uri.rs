@@ -0,0 +1,356 @@
+//! Where files come from
+//!
+//! Every path towboot reads from (kernels, modules, the configuration, the
+//! background and the font) may start with a scheme saying where the file is:
+//!
+//! * `file:\path` (or just `\path`): on the volume we've been loaded from
+//!   (or embedded into our image, see `embedded`)
+//! * `part-uuid:<GUID>:\path`: on the GPT partition with this unique GUID,
+//!   if the firmware can read its filesystem
+//! * `tftp:<server>/path`: on a TFTP server (an IPv4 address, or nothing for
+//!   the one the DHCP server has named, eg. `tftp:/kernel.elf`), reached via the
+//!   network card we've been loaded from (or the first one)
+//! * `loop:<archive>!\path`: in an (uncompressed) `newc` cpio archive, which
+//!   can be anywhere itself (eg. `loop:tftp:/boot.cpio!\kernel.elf`)
+//! * `http:` and `https:`: recognized, but not supported yet
+//!   (loading fails with `UNSUPPORTED`)
+//!
+//! `file::File` resolves these, so everything reading files supports all of them.
+//! Patterns work everywhere but on TFTP (which can't list directories).
+
+use core::cell::UnsafeCell;
+
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::CStr8;
+use uefi::proto::media::file::Directory;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::proto::network::IpAddress;
+use uefi::proto::network::pxe::{BaseCode, DhcpV4Packet};
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+
+use log::{debug, error, info};
+
+use super::boot::device;
+use super::firmware::system_table;
+
+/// where we've been loaded from (see `init`)
+static ORIGIN: OriginCell = OriginCell(UnsafeCell::new(None));
+
+struct OriginCell(UnsafeCell<Option<Origin>>);
+
+// This is only written at the start and when the volume has to be looked up again.
+unsafe impl Sync for OriginCell {}
+
+struct Origin {
+    /// our image handle
+    image: Handle,
+    /// the device we've been loaded from
+    device: Handle,
+    /// its device path (to find it again, see `reopen_volume`)
+    device_path: Option<Vec<Vec<u8>>>,
+}
+
+/// Remember our image handle and the device we've been loaded from.
+///
+/// This has to be called before opening files elsewhere.
+pub(crate) fn init(image: Handle, device: Handle) {
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    let device_path = device::device_path(device, image, boot_services);
+    unsafe { *ORIGIN.0.get() = Some(Origin { image, device, device_path }) };
+}
+
+/// Get our image handle and the device we've been loaded from.
+fn handles() -> (Handle, Handle) {
+    let origin = unsafe { &*ORIGIN.0.get() }.as_ref().expect("uri::init hasn't been called");
+    (origin.image, origin.device)
+}
+
+/// Where a file is (see above).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Location<'a> {
+    /// a path on the volume we've been loaded from
+    Local(&'a str),
+    /// a path on the partition with this GUID (in the byte order of the GPT)
+    Partition { guid: [u8; 16], path: &'a str },
+    /// a path on a TFTP server (`None` is the one named by DHCP)
+    Tftp { server: Option<[u8; 4]>, path: &'a str },
+    /// an URL
+    Http(&'a str),
+    /// a path inside an archive (which is a location itself)
+    Loop { archive: &'a str, path: &'a str },
+}
+
+impl<'a> Location<'a> {
+    /// Find out where a file is.
+    ///
+    /// Paths without a scheme are on the volume we've been loaded from.
+    pub(crate) fn parse(uri: &'a str) -> Result<Self, Status> {
+        let (scheme, rest) = match uri.split_once(':') {
+            Some((scheme, rest)) if !scheme.contains(['\\', '/']) => (scheme, rest),
+            _ => return Ok(Self::Local(uri)),
+        };
+        match scheme.to_ascii_lowercase().as_str() {
+            "file" => Ok(Self::Local(rest)),
+            "part-uuid" => {
+                let (guid, path) = rest.split_once(':').ok_or_else(|| {
+                    error!("'{uri}' should have the form part-uuid:<GUID>:<path>");
+                    Status::INVALID_PARAMETER
+                })?;
+                let guid = parse_guid(guid).ok_or_else(|| {
+                    error!("'{guid}' is not a GUID");
+                    Status::INVALID_PARAMETER
+                })?;
+                Ok(Self::Partition { guid, path })
+            },
+            "tftp" => {
+                let (server, path) = rest.split_once('/').unwrap_or((rest, ""));
+                if path.is_empty() {
+                    error!("'{uri}' doesn't contain a path");
+                    return Err(Status::INVALID_PARAMETER)
+                }
+                let server = match server {
+                    "" => None,
+                    server => Some(parse_ipv4(server).ok_or_else(|| {
+                        error!("'{server}' is not an IPv4 address");
+                        Status::INVALID_PARAMETER
+                    })?),
+                };
+                Ok(Self::Tftp { server, path })
+            },
+            "http" | "https" => Ok(Self::Http(uri)),
+            "loop" => {
+                let (archive, path) = rest.rsplit_once('!').ok_or_else(|| {
+                    error!("'{uri}' should have the form loop:<archive>!<path>");
+                    Status::INVALID_PARAMETER
+                })?;
+                Ok(Self::Loop { archive, path })
+            },
+            _ => {
+                error!("'{uri}' has an unknown scheme ('{scheme}')");
+                Err(Status::INVALID_PARAMETER)
+            },
+        }
+    }
+
+    /// Get the path (without the scheme and where it's relative to).
+    ///
+    /// For TFTP, that's what is sent to the server; for HTTP, the whole URL.
+    pub(crate) fn path(&self) -> &'a str {
+        match self {
+            Self::Local(path) | Self::Http(path) => path,
+            Self::Partition { path, .. } | Self::Tftp { path, .. } => path,
+            Self::Loop { path, .. } => path,
+        }
+    }
+}
+
+/// Parse a GUID (like `01234567-89ab-cdef-0123-456789abcdef`).
+///
+/// The result is in the byte order of the GPT, where the first three parts
+/// are little-endian.
+pub(crate) fn parse_guid(guid: &str) -> Option<[u8; 16]> {
+    let guid = guid.trim_start_matches('{').trim_end_matches('}');
+    let parts: Vec<&str> = guid.split('-').collect();
+    if parts.iter().map(|p| p.len()).collect::<Vec<_>>() != [8, 4, 4, 4, 12] {
+        return None
+    }
+    let hex: Vec<u8> = parts.concat().as_bytes().chunks(2)
+        .map(|digits| core::str::from_utf8(digits).ok()
+            .and_then(|d| u8::from_str_radix(d, 16).ok()))
+        .collect::<Option<_>>()?;
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hex);
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Some(bytes)
+}
+
+/// Parse an IPv4 address (like `192.168.0.1`).
+fn parse_ipv4(address: &str) -> Option<[u8; 4]> {
+    let parts = address.split('.').map(|p| p.parse().ok()).collect::<Option<Vec<u8>>>()?;
+    parts.try_into().ok()
+}
+
+/// Open the root directory of a partition.
+pub(crate) fn open_partition(guid: &[u8; 16]) -> Result<Directory, Status> {
+    let (image, _) = handles();
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    let handle = boot_services.find_handles::<SimpleFileSystem>().unwrap_or_default()
+        .into_iter()
+        .find(|handle| device::partition_guid(*handle, image, boot_services) == Some(*guid))
+        .ok_or_else(|| {
+            error!("there's no partition with the GUID {guid:02x?} and a filesystem");
+            Status::NOT_FOUND
+        })?;
+    let fs = boot_services.open_protocol::<SimpleFileSystem>(
+        OpenProtocolParams {
+            handle,
+            agent: image,
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    ).map_err(|e| {
+        error!("failed to open the filesystem of the partition: {e:?}");
+        e.status()
+    })?;
+    let fs = unsafe { &mut *fs.interface.get() };
+    fs.open_volume().map_err(|e| {
+        error!("failed to open the root directory of the partition: {e:?}");
+        e.status()
+    })
+}
+
+/// Open the root directory of the volume we've been loaded from again.
+///
+/// If the device has gone away (eg. because a USB stick has been reconnected),
+/// this looks for a filesystem with the same device path.
+pub(crate) fn reopen_volume() -> Result<Directory, Status> {
+    let (image, device) = handles();
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    let params = |handle| OpenProtocolParams {
+        handle,
+        agent: image,
+        controller: None,
+    };
+    let fs = match boot_services.open_protocol::<SimpleFileSystem>(
+        params(device), OpenProtocolAttributes::GetProtocol,
+    ) {
+        Ok(fs) => fs,
+        Err(e) => {
+            debug!("failed to open the filesystem we've been loaded from ({e:?}), looking for it");
+            let origin = unsafe { &mut *ORIGIN.0.get() }.as_mut().unwrap();
+            let handle = origin.device_path.as_ref().and_then(|path| {
+                boot_services.find_handles::<SimpleFileSystem>().unwrap_or_default()
+                    .into_iter()
+                    .find(|handle| {
+                        device::device_path(*handle, image, boot_services).as_ref() == Some(path)
+                    })
+            }).ok_or_else(|| {
+                error!("the volume we've been loaded from has gone away");
+                Status::NO_MEDIA
+            })?;
+            origin.device = handle;
+            boot_services.open_protocol::<SimpleFileSystem>(
+                params(handle), OpenProtocolAttributes::GetProtocol,
+            ).map_err(|e| {
+                error!("failed to open the filesystem we've been loaded from: {e:?}");
+                e.status()
+            })?
+        },
+    };
+    let fs = unsafe { &mut *fs.interface.get() };
+    fs.open_volume().map_err(|e| {
+        error!("failed to open the root directory of the volume: {e:?}");
+        e.status()
+    })
+}
+
+/// Download a file via TFTP.
+///
+/// If the network card hasn't been set up yet, this gets an address via DHCP.
+pub(crate) fn tftp_read(server: Option<[u8; 4]>, path: &str) -> Result<Vec<u8>, Status> {
+    let (image, device) = handles();
+    let boot_services = unsafe { system_table().as_ref() }.boot_services();
+    let handles = boot_services.find_handles::<BaseCode>().unwrap_or_default();
+    // prefer the network card we've been loaded from
+    let handle = handles.iter().find(|h| **h == device).or_else(|| handles.first())
+        .copied().ok_or_else(|| {
+            error!("there's no network card that supports PXE");
+            Status::NOT_FOUND
+        })?;
+    let base_code = boot_services.open_protocol::<BaseCode>(
+        OpenProtocolParams {
+            handle,
+            agent: image,
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    ).map_err(|e| {
+        error!("failed to open the network card: {e:?}");
+        e.status()
+    })?;
+    let base_code = unsafe { &mut *base_code.interface.get() };
+    if !base_code.mode().started {
+        base_code.start(false).map_err(|e| {
+            error!("failed to start the network card: {e:?}");
+            e.status()
+        })?;
+    }
+    if !base_code.mode().dhcp_ack_received {
+        info!("getting an address via DHCP...");
+        base_code.dhcp(false).map_err(|e| {
+            error!("failed to get an address via DHCP: {e:?}");
+            e.status()
+        })?;
+    }
+    let server = match server {
+        Some(server) => server,
+        None => {
+            let ack: &DhcpV4Packet = base_code.mode().dhcp_ack.as_ref();
+            ack.bootp_si_addr
+        },
+    };
+    if server == [0; 4] {
+        error!("the DHCP server hasn't named a TFTP server, please add one to '{path}'");
+        return Err(Status::NOT_FOUND)
+    }
+    debug!("loading '{path}' from {server:?}");
+    if !path.is_ascii() || path.contains('\0') {
+        error!("'{path}' can't be requested via TFTP");
+        return Err(Status::INVALID_PARAMETER)
+    }
+    let mut name = Vec::with_capacity(path.len() + 1);
+    name.extend_from_slice(path.as_bytes());
+    name.push(0);
+    // This is safe because the name is ASCII and ends with its only NUL.
+    let name = unsafe { CStr8::from_bytes_with_nul_unchecked(&name) };
+    let server = IpAddress::new_v4(server);
+    let size: usize = base_code.tftp_get_file_size(&server, name).map_err(|e| {
+        error!("failed to find '{path}' on the TFTP server: {e:?}");
+        e.status()
+    })?.try_into().map_err(|_e| {
+        error!("'{path}' is too large");
+        Status::BAD_BUFFER_SIZE
+    })?;
+    let mut content = Vec::new();
+    content.try_reserve_exact(size).map_err(|_e| {
+        error!("'{path}' is too large for the available memory");
+        Status::OUT_OF_RESOURCES
+    })?;
+    content.resize(size, 0);
+    base_code.tftp_read_file(&server, name, Some(&mut content)).map_err(|e| {
+        error!("failed to download '{path}': {e:?}");
+        e.status()
+    })?;
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Location;
+
+    #[test]
+    fn schemes() {
+        assert_eq!(Location::parse("\\kernel.elf"), Ok(Location::Local("\\kernel.elf")));
+        assert_eq!(Location::parse("FILE:\\kernel.elf"), Ok(Location::Local("\\kernel.elf")));
+        assert_eq!(
+            Location::parse("tftp:/boot/kernel.elf"),
+            Ok(Location::Tftp { server: None, path: "boot/kernel.elf" }),
+        );
+        assert_eq!(
+            Location::parse("tftp:10.0.0.1/kernel.elf"),
+            Ok(Location::Tftp { server: Some([10, 0, 0, 1]), path: "kernel.elf" }),
+        );
+        assert_eq!(
+            Location::parse("loop:tftp:/boot.cpio!\\kernel.elf"),
+            Ok(Location::Loop { archive: "tftp:/boot.cpio", path: "\\kernel.elf" }),
+        );
+        // recognized, but loading fails with `UNSUPPORTED`
+        for uri in ["http://example.com/kernel.elf", "HTTPS://example.com/kernel.elf"] {
+            assert_eq!(Location::parse(uri), Ok(Location::Http(uri)));
+        }
+    }
+}
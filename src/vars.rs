@@ -0,0 +1,268 @@
+//! UEFI variables
+//!
+//! towboot keeps some state in UEFI variables, so that it survives reboots and
+//! can be changed by the operating system.
+//! All of them live under our own vendor GUID and contain UTF-8 strings
+//! (without a terminating null byte).
+//!
+//! Entries can also set arbitrary variables before booting (see `apply`).
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use log::{debug, error, info, warn};
+
+use uefi::prelude::*;
+use uefi::{CStr16, Guid};
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+
+use super::config::SetVariable;
+use super::firmware::system_table;
+
+/// The vendor GUID of all our variables.
+pub(crate) const VENDOR: VariableVendor = VariableVendor(Guid::from_values(
+    0x2c0b_b3a1, 0x6f43, 0x4b6d, 0x9c1e, 0x7a45_e0d2_83f6,
+));
+
+/// If this is set, boot the entry with this key once, skipping the menu.
+pub(crate) const BOOT_NEXT: &str = "TowbootBootNext";
+
+/// If this exists, display the menu (without a timeout) once.
+///
+/// Its content doesn't matter, it's deleted when it's read.
+pub(crate) const SHOW_MENU: &str = "TowbootShowMenu";
+
+/// The entry that has been chosen explicitly the last time (for `default = "saved"`).
+pub(crate) const SAVED_ENTRY: &str = "TowbootSavedEntry";
+
+/// The entry that is about to be booted.
+///
+/// The operating system should delete this once it has booted successfully.
+pub(crate) const BOOT_PENDING: &str = "TowbootBootPending";
+
+/// The entry that has been booted the last time.
+pub(crate) const LAST_BOOTED: &str = "TowbootLastBooted";
+
+/// The entry that has been booted successfully the last time.
+pub(crate) const LAST_SUCCESSFUL: &str = "TowbootLastSuccessful";
+
+/// How long the steps of the last boot have taken (see `boot::timing`).
+pub(crate) const TIMING: &str = "TowbootTiming";
+
+/// The name of the variable counting the boot attempts of an entry.
+///
+/// The operating system should delete it once it has booted successfully.
+pub(crate) fn tries_name(key: &str) -> String {
+    format!("TowbootTries-{key}")
+}
+
+/// Convert a variable name to UCS-2 and call the function with it.
+///
+/// This fails if the name is too long or contains characters that UCS-2 doesn't have.
+/// (Names may come from the configuration, eg. `tries_name`.)
+fn with_name<T>(name: &str, f: impl FnOnce(&CStr16) -> T) -> Result<T, Status> {
+    let mut name_buf = [0; 128];
+    let cname = CStr16::from_str_with_buf(name, &mut name_buf).map_err(|e| {
+        error!("'{name}' can't be the name of a variable: {e:?}");
+        Status::INVALID_PARAMETER
+    })?;
+    Ok(f(cname))
+}
+
+/// Check whether a string can be the name of a variable.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    with_name(name, |_| ()).is_ok()
+}
+
+/// Read a variable as a string.
+///
+/// Returns `None` if the variable doesn't exist or is not valid UTF-8.
+pub(crate) fn get_string(name: &str) -> Option<String> {
+    let rt = unsafe { system_table().as_ref() }.runtime_services();
+    with_name(name, |cname| {
+        let size = rt.get_variable_size(cname, &VENDOR).ok()?;
+        let mut buf = Vec::new();
+        buf.resize(size, 0);
+        let (value, _attributes) = rt.get_variable(cname, &VENDOR, &mut buf).map_err(|e| {
+            debug!("failed to read variable {name}: {e:?}");
+        }).ok()?;
+        String::from_utf8(value.to_vec()).map_err(|e| {
+            error!("variable {name} is not valid UTF-8: {e:?}");
+        }).ok()
+    }).ok().flatten()
+}
+
+/// Check whether a variable exists.
+pub(crate) fn exists(name: &str) -> bool {
+    let rt = unsafe { system_table().as_ref() }.runtime_services();
+    with_name(name, |cname| rt.get_variable_size(cname, &VENDOR).is_ok()).unwrap_or(false)
+}
+
+/// Write a string into a non-volatile variable.
+///
+/// The variable is accessible to the operating system.
+pub(crate) fn set_string(name: &str, value: &str) -> Result<(), Status> {
+    let rt = unsafe { system_table().as_ref() }.runtime_services();
+    with_name(name, |cname| rt.set_variable(
+        cname, &VENDOR,
+        VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS,
+        value.as_bytes(),
+    ).map_err(|e| {
+        error!("failed to write variable {name}: {e:?}");
+        e.status()
+    }))?
+}
+
+/// Write a string into a volatile variable (which is gone after a reboot).
+///
+/// The variable is accessible to the operating system.
+pub(crate) fn set_volatile_string(name: &str, value: &str) -> Result<(), Status> {
+    let rt = unsafe { system_table().as_ref() }.runtime_services();
+    with_name(name, |cname| rt.set_variable(
+        cname, &VENDOR,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        value.as_bytes(),
+    ).map_err(|e| {
+        error!("failed to write variable {name}: {e:?}");
+        e.status()
+    }))?
+}
+
+/// Delete a variable.
+///
+/// Deleting a variable that doesn't exist is not an error.
+pub(crate) fn delete(name: &str) -> Result<(), Status> {
+    let rt = unsafe { system_table().as_ref() }.runtime_services();
+    with_name(name, |cname| match rt.set_variable(
+        cname, &VENDOR, VariableAttributes::empty(), &[],
+    ) {
+        Ok(()) => Ok(()),
+        Err(e) if e.status() == Status::NOT_FOUND => Ok(()),
+        Err(e) => {
+            error!("failed to delete variable {name}: {e:?}");
+            Err(e.status())
+        },
+    })?
+}
+
+/// Set the variables of an entry.
+///
+/// If one of them can't be set, the ones that have already been set are
+/// restored (if they're configured to be) and the error is returned.
+pub(crate) fn apply(variables: &[SetVariable]) -> Result<(), Status> {
+    // check all of them before setting anything
+    for variable in variables {
+        with_name(&variable.name, |_| ())?;
+    }
+    let vendors = variables.iter().map(parse_vendor).collect::<Result<Vec<_>, _>>()?;
+    // the previous contents (and attributes) of the variables that have been set
+    let mut previous = Vec::new();
+    for (variable, vendor) in variables.iter().zip(vendors) {
+        match set_variable(&variable.name, &vendor, &variable.value) {
+            Ok(old) => previous.push((variable, vendor, old)),
+            Err(e) => {
+                for (variable, vendor, old) in previous.into_iter().rev() {
+                    if variable.restore {
+                        restore_variable(&variable.name, &vendor, old);
+                    }
+                }
+                return Err(e)
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Get the vendor of a variable of an entry (ours if none is given).
+fn parse_vendor(variable: &SetVariable) -> Result<VariableVendor, Status> {
+    let guid = match &variable.guid {
+        Some(guid) => guid,
+        None => return Ok(VENDOR),
+    };
+    parse_guid(guid).map(VariableVendor).ok_or_else(|| {
+        error!("the GUID '{guid}' of variable {} is invalid", variable.name);
+        Status::INVALID_PARAMETER
+    })
+}
+
+/// Parse a GUID (like `8be4df61-93ca-11d2-aa0d-00e098032b8c`).
+fn parse_guid(guid: &str) -> Option<Guid> {
+    let parts: Vec<&str> = guid.split('-').collect();
+    if parts.iter().map(|p| p.len()).collect::<Vec<_>>() != [8, 4, 4, 4, 12] {
+        return None
+    }
+    Some(Guid::from_values(
+        u32::from_str_radix(parts[0], 16).ok()?,
+        u16::from_str_radix(parts[1], 16).ok()?,
+        u16::from_str_radix(parts[2], 16).ok()?,
+        u16::from_str_radix(parts[3], 16).ok()?,
+        u64::from_str_radix(parts[4], 16).ok()?,
+    ))
+}
+
+/// Set a variable, returning its previous content and attributes (if it existed).
+///
+/// The attributes of existing variables are kept, new ones are non-volatile
+/// and accessible to the operating system.
+fn set_variable(
+    name: &str, vendor: &VariableVendor, value: &[u8],
+) -> Result<Option<(Vec<u8>, VariableAttributes)>, Status> {
+    let rt = unsafe { system_table().as_ref() }.runtime_services();
+    with_name(name, |cname| {
+        let old = rt.get_variable_size(cname, vendor).ok().and_then(|size| {
+            let mut buf = Vec::new();
+            buf.resize(size, 0);
+            rt.get_variable(cname, vendor, &mut buf).ok()
+                .map(|(value, attributes)| (value.to_vec(), attributes))
+        });
+        let attributes = old.as_ref().map_or(
+            VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+            |(_, attributes)| *attributes,
+        );
+        rt.set_variable(cname, vendor, attributes, value).map_err(|e| {
+            error!("failed to write variable {name}: {e:?}");
+            e.status()
+        })?;
+        info!("set variable {name}");
+        Ok(old)
+    })?
+}
+
+/// Restore the previous content of a variable (or delete it if it didn't exist).
+///
+/// Errors are only logged.
+fn restore_variable(
+    name: &str, vendor: &VariableVendor, old: Option<(Vec<u8>, VariableAttributes)>,
+) {
+    let rt = unsafe { system_table().as_ref() }.runtime_services();
+    // The name has already been checked when setting the variable.
+    let _ = with_name(name, |cname| {
+        let result = match &old {
+            Some((value, attributes)) => rt.set_variable(cname, vendor, *attributes, value),
+            None => rt.set_variable(cname, vendor, VariableAttributes::empty(), &[]),
+        };
+        match result {
+            Ok(()) => debug!("restored variable {name}"),
+            Err(e) => warn!("failed to restore variable {name}: {e:?}"),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_name, tries_name};
+
+    #[test]
+    fn names() {
+        assert!(is_valid_name(&tries_name("linux")));
+        assert!(is_valid_name(&tries_name(&"a".repeat(114))));
+        // too long (with the null byte at the end)
+        assert!(!is_valid_name(&tries_name(&"a".repeat(115))));
+        // UCS-2 only has the Basic Multilingual Plane
+        assert!(is_valid_name(&tries_name("übung")));
+        assert!(!is_valid_name(&tries_name("\u{1f427}")));
+    }
+}
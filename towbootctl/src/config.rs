@@ -0,0 +1,214 @@
+//! Finding the files a configuration refers to
+//!
+//! Paths in the configuration are absolute paths on the ESP (like
+//! `\kernels\kernel.elf`). On the host, they're taken relative to the directory
+//! containing the configuration file, so that directory mirrors the ESP.
+//! This only looks at the parts of the configuration that are files, everything
+//! else is towboot's business (it validates the configuration when booting).
+
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, warn};
+use toml::Value;
+
+/// The files to put on the ESP: the path on the ESP (with `/` as the
+/// separator and without a leading one) mapped to the file on the host.
+pub(crate) type Files = BTreeMap<String, PathBuf>;
+
+/// Read a configuration file.
+///
+/// Only TOML is supported here, JSON configurations can still be added as files.
+pub(crate) fn read(path: &Path) -> Result<Value> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let config: Value = text.parse()
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    if config.get("entries").and_then(Value::as_table).is_none() {
+        bail!("{} doesn't contain any entries", path.display());
+    }
+    Ok(config)
+}
+
+/// Add all files the configuration refers to.
+///
+/// `root` is the directory on the host that corresponds to the root of the ESP.
+/// Files that are already in `files` are kept as they are.
+pub(crate) fn add_referenced_files(files: &mut Files, config: &Value, root: &Path) -> Result<()> {
+    for key in ["background", "font"] {
+        if let Some(path) = config.get(key).and_then(Value::as_str) {
+            add_file(files, root, path)?;
+        }
+    }
+    let entries = config.get("entries").and_then(Value::as_table)
+        .ok_or_else(|| anyhow!("the configuration doesn't contain any entries"))?;
+    for (name, entry) in entries {
+        let image = entry.get("image").and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("entry {name} doesn't have an image"))?;
+        add_file(files, root, image)
+            .with_context(|| format!("failed to find the kernel of entry {name}"))?;
+        let modules = entry.get("modules").and_then(Value::as_array)
+            .map(Vec::as_slice).unwrap_or_default();
+        for module in modules {
+            match module.get("image") {
+                Some(Value::String(image)) => add_file(files, root, image),
+                Some(Value::Array(images)) => images.iter()
+                    .map(|i| i.as_str().ok_or_else(|| anyhow!("images have to be strings")))
+                    .try_for_each(|i| add_file(files, root, i?)),
+                Some(_) => Err(anyhow!("images have to be strings")),
+                None => Ok(()),
+            }.with_context(|| format!("failed to find a module of entry {name}"))?;
+            if let Some(directory) = module.get("directory").and_then(Value::as_str) {
+                add_directory(files, root, directory)
+                    .with_context(|| format!("failed to find a module of entry {name}"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse files given on the command line (as `source:destination`).
+pub(crate) fn extra_files(extra: &[String]) -> Result<Files> {
+    let mut files = Files::new();
+    for file in extra {
+        let (source, destination) = file.split_once(':')
+            .ok_or_else(|| anyhow!("{file} should have the form source:destination"))?;
+        files.insert(esp_path(destination), PathBuf::from(source));
+    }
+    Ok(files)
+}
+
+/// Convert a path on the ESP to the form used in `Files`.
+pub(crate) fn esp_path(path: &str) -> String {
+    path.split(['\\', '/']).filter(|p| !p.is_empty()).collect::<Vec<_>>().join("/")
+}
+
+/// Get the path on the ESP of a path in the configuration.
+///
+/// Paths may also point elsewhere (like `tftp:` or `part-uuid:`), these are
+/// `None`. For files inside archives (`loop:`), this is the archive.
+fn local_path(path: &str) -> Option<&str> {
+    match path.split_once(':') {
+        Some((scheme, rest)) if !scheme.contains(['\\', '/']) => {
+            match scheme.to_ascii_lowercase().as_str() {
+                "file" => Some(rest),
+                "loop" => rest.rsplit_once('!').and_then(|(archive, _)| local_path(archive)),
+                _ => None,
+            }
+        },
+        _ => Some(path),
+    }
+}
+
+/// Add a file (or all files matching a pattern).
+///
+/// Files that aren't on the ESP are skipped.
+fn add_file(files: &mut Files, root: &Path, path: &str) -> Result<()> {
+    let path = match local_path(path) {
+        Some(local) => esp_path(local),
+        None => {
+            debug!("{path} isn't on the ESP, skipping it");
+            return Ok(())
+        },
+    };
+    let (directory, name) = path.rsplit_once('/').unwrap_or(("", &path));
+    if files.contains_key(&path) {
+        return Ok(())
+    }
+    if !name.contains(['*', '?']) {
+        let source = root.join(&path);
+        if !source.is_file() {
+            bail!("{} doesn't exist", source.display());
+        }
+        debug!("adding {} as {path}", source.display());
+        files.insert(path, source);
+        return Ok(())
+    }
+    let host_directory = root.join(directory);
+    let mut found = false;
+    for file in fs::read_dir(&host_directory)
+        .with_context(|| format!("failed to list {}", host_directory.display()))? {
+        let file = file?;
+        let file_name = file.file_name().to_string_lossy().into_owned();
+        if file.file_type()?.is_file() && matches_pattern(name, &file_name) {
+            let destination = if directory.is_empty() {
+                file_name
+            } else {
+                format!("{directory}/{file_name}")
+            };
+            found = true;
+            if let Entry::Vacant(entry) = files.entry(destination) {
+                debug!("adding {} as {} (matching {name})", file.path().display(), entry.key());
+                entry.insert(file.path());
+            }
+        }
+    }
+    if !found {
+        warn!("nothing in {} matches {name}", host_directory.display());
+    }
+    Ok(())
+}
+
+/// Add a directory with everything in it.
+///
+/// Directories that aren't on the ESP are skipped. (For directories inside
+/// archives, that's the whole archive.)
+fn add_directory(files: &mut Files, root: &Path, path: &str) -> Result<()> {
+    if path.to_ascii_lowercase().starts_with("loop:") {
+        return add_file(files, root, path)
+    }
+    let path = match local_path(path) {
+        Some(local) => esp_path(local),
+        None => {
+            debug!("{path} isn't on the ESP, skipping it");
+            return Ok(())
+        },
+    };
+    let source = root.join(&path);
+    for file in fs::read_dir(&source)
+        .with_context(|| format!("failed to list {}", source.display()))? {
+        let file = file?;
+        let destination = format!("{path}/{}", file.file_name().to_string_lossy());
+        if file.file_type()?.is_dir() {
+            add_directory(files, root, &destination)?;
+        } else if let Entry::Vacant(entry) = files.entry(destination) {
+            debug!("adding {} as {}", file.path().display(), entry.key());
+            entry.insert(file.path());
+        }
+    }
+    Ok(())
+}
+
+/// Check whether a file name matches a pattern (the same way towboot does).
+///
+/// `*` matches any number of characters, `?` matches exactly one.
+/// The comparison is case-insensitive, just like FAT.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+    let (mut p, mut n) = (0, 0);
+    // where to continue if the last `*` has to match more characters
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            },
+            Some('?') => { p += 1; n += 1; },
+            Some(c) if *c == name[n] => { p += 1; n += 1; },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,331 @@
+//! Managing boot entries via efivarfs (on Linux)
+//!
+//! A boot entry is a `Boot####` variable containing an `EFI_LOAD_OPTION`: a
+//! description, a device path (the partition and the file on it) and the load
+//! options (which are passed to towboot). `BootOrder` lists the entries in the
+//! order the firmware tries them.
+//!
+//! The variables in efivarfs are files starting with their attributes. Existing
+//! ones are immutable, so that flag has to be cleared before writing them.
+
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info};
+
+/// where efivarfs is mounted
+const EFIVARS: &str = "/sys/firmware/efi/efivars";
+/// the GUID of the global variables
+const GLOBAL: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+/// non-volatile, boot service access and runtime access
+const ATTRIBUTES: u32 = 0x7;
+/// the boot entry is active
+const LOAD_OPTION_ACTIVE: u32 = 1;
+
+/// the type of media device path nodes
+const MEDIA: u8 = 0x04;
+/// the subtype of device path nodes for partitions (MBR or GPT)
+const HARD_DRIVE: u8 = 0x01;
+/// the subtype of device path nodes for file paths
+const FILE_PATH: u8 = 0x04;
+/// the node ending a device path
+const END: [u8; 4] = [0x7f, 0xff, 0x04, 0x00];
+/// the immutable flag of a file (`FS_IMMUTABLE_FL`)
+const IMMUTABLE: libc::c_int = 0x10;
+
+/// A partition as it's known to the firmware.
+struct Partition {
+    number: u32,
+    /// the first block (in logical blocks)
+    start: u64,
+    /// the size (in logical blocks)
+    size: u64,
+    /// the partition's GUID (GPT) or the disk's signature (MBR)
+    signature: [u8; 16],
+    /// whether this is a GPT partition (or an MBR one)
+    gpt: bool,
+}
+
+/// Create or update the boot entry for `loader` (on the filesystem mounted at `esp`).
+///
+/// An existing entry with the same description is replaced. The entry is
+/// moved to the front of `BootOrder`.
+pub(crate) fn register(esp: &Path, loader: &str, description: &str, options: &str) -> Result<()> {
+    if !Path::new(EFIVARS).is_dir() {
+        bail!("{EFIVARS} doesn't exist (this system hasn't been booted via UEFI)");
+    }
+    let partition = find_partition(esp)?;
+    let entry = load_option(&partition, loader, description, options);
+    let number = match find_entry(description)? {
+        Some(number) => {
+            info!("updating Boot{number:04X}");
+            number
+        },
+        None => {
+            let number = (0..=u16::MAX).find(|n| !variable_path(&format!("Boot{n:04X}")).exists())
+                .ok_or_else(|| anyhow!("there are no free boot entries"))?;
+            info!("creating Boot{number:04X}");
+            number
+        },
+    };
+    write(&format!("Boot{number:04X}"), &entry)?;
+    let mut order: Vec<u16> = read("BootOrder")?.unwrap_or_default()
+        .chunks_exact(2).map(|n| u16::from_le_bytes([n[0], n[1]]))
+        .filter(|n| *n != number)
+        .collect();
+    order.insert(0, number);
+    write("BootOrder", &order.iter().flat_map(|n| n.to_le_bytes()).collect::<Vec<_>>())
+}
+
+/// Find the boot entry with the given description.
+fn find_entry(description: &str) -> Result<Option<u16>> {
+    for file in fs::read_dir(EFIVARS).context("failed to list the variables")? {
+        let name = file?.file_name().to_string_lossy().into_owned();
+        let Some(number) = name.strip_suffix(&format!("-{GLOBAL}"))
+            .and_then(|n| n.strip_prefix("Boot"))
+            .filter(|n| n.len() == 4)
+            .and_then(|n| u16::from_str_radix(n, 16).ok()) else {
+            continue
+        };
+        let Some(data) = read(&format!("Boot{number:04X}"))? else {
+            continue
+        };
+        // the description starts after the attributes and the length of the path
+        let existing: Vec<u16> = data.get(6..).unwrap_or_default().chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+        if String::from_utf16_lossy(&existing) == description {
+            return Ok(Some(number))
+        }
+    }
+    Ok(None)
+}
+
+/// Build an `EFI_LOAD_OPTION`.
+fn load_option(partition: &Partition, loader: &str, description: &str, options: &str) -> Vec<u8> {
+    let mut path = Vec::new();
+    // the hard drive node
+    path.extend([MEDIA, HARD_DRIVE]);
+    path.extend(42u16.to_le_bytes());
+    path.extend(partition.number.to_le_bytes());
+    path.extend(partition.start.to_le_bytes());
+    path.extend(partition.size.to_le_bytes());
+    path.extend(partition.signature);
+    // the partition format and the signature type are the same: 1 for MBR, 2 for GPT
+    path.extend(if partition.gpt { [2, 2] } else { [1, 1] });
+    // the file path node
+    let file = ucs2(loader);
+    path.extend([MEDIA, FILE_PATH]);
+    path.extend(u16::try_from(4 + file.len()).unwrap().to_le_bytes());
+    path.extend(file);
+    path.extend(END);
+
+    let mut option = Vec::new();
+    option.extend(LOAD_OPTION_ACTIVE.to_le_bytes());
+    option.extend(u16::try_from(path.len()).unwrap().to_le_bytes());
+    option.extend(ucs2(description));
+    option.extend(path);
+    option.extend(ucs2(options));
+    option
+}
+
+/// Find the partition a path is on.
+fn find_partition(esp: &Path) -> Result<Partition> {
+    let device = fs::metadata(esp)
+        .with_context(|| format!("failed to get information about {}", esp.display()))?
+        .dev();
+    let (major, minor) = (libc::major(device), libc::minor(device));
+    let sysfs = fs::canonicalize(format!("/sys/dev/block/{major}:{minor}"))
+        .with_context(|| format!("{} doesn't seem to be on a block device", esp.display()))?;
+    let attribute = |path: PathBuf| -> Result<u64> {
+        fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .trim().parse().with_context(|| format!("failed to parse {}", path.display()))
+    };
+    if !sysfs.join("partition").exists() {
+        bail!("{} isn't on a partition", esp.display());
+    }
+    let number = attribute(sysfs.join("partition"))?.try_into()?;
+    // sysfs counts in 512 byte sectors, the firmware in logical blocks
+    let block_size = attribute(sysfs.parent().unwrap().join("queue/logical_block_size"))?;
+    let start = attribute(sysfs.join("start"))? * 512 / block_size;
+    let size = attribute(sysfs.join("size"))? * 512 / block_size;
+    let name = sysfs.file_name().unwrap().to_os_string();
+    let partuuid = fs::read_dir("/dev/disk/by-partuuid")
+        .context("failed to list /dev/disk/by-partuuid")?
+        .filter_map(|link| link.ok())
+        .find(|link| fs::canonicalize(link.path()).ok()
+            .and_then(|p| p.file_name().map(|n| n == name)).unwrap_or(false))
+        .map(|link| link.file_name().to_string_lossy().into_owned())
+        .ok_or_else(|| anyhow!("failed to find the PARTUUID of {}", esp.display()))?;
+    debug!("{} is on partition {number} ({partuuid}), blocks {start}+{size}", esp.display());
+    let (signature, gpt) = parse_partuuid(&partuuid)?;
+    Ok(Partition { number, start, size, signature, gpt })
+}
+
+/// Parse a PARTUUID.
+///
+/// For GPT, this is the partition's GUID. For MBR, it's the disk's signature
+/// followed by the partition's number (like `1234abcd-01`).
+fn parse_partuuid(partuuid: &str) -> Result<([u8; 16], bool)> {
+    let invalid = || anyhow!("{partuuid} isn't a valid PARTUUID");
+    let parts: Vec<&str> = partuuid.split('-').collect();
+    let lengths: Vec<usize> = parts.iter().map(|p| p.len()).collect();
+    if lengths != [8, 2] && lengths != [8, 4, 4, 4, 12] {
+        return Err(invalid())
+    }
+    let mut signature = [0; 16];
+    match parts.as_slice() {
+        [disk, _] => {
+            let disk = u32::from_str_radix(disk, 16).map_err(|_| invalid())?;
+            signature[..4].copy_from_slice(&disk.to_le_bytes());
+            Ok((signature, false))
+        },
+        [a, b, c, d, e] => {
+            // the first three parts are little endian, the rest is big endian
+            let a = u32::from_str_radix(a, 16).map_err(|_| invalid())?;
+            let b = u16::from_str_radix(b, 16).map_err(|_| invalid())?;
+            let c = u16::from_str_radix(c, 16).map_err(|_| invalid())?;
+            let de = u64::from_str_radix(&format!("{d}{e}"), 16).map_err(|_| invalid())?;
+            signature[0..4].copy_from_slice(&a.to_le_bytes());
+            signature[4..6].copy_from_slice(&b.to_le_bytes());
+            signature[6..8].copy_from_slice(&c.to_le_bytes());
+            signature[8..16].copy_from_slice(&de.to_be_bytes());
+            Ok((signature, true))
+        },
+        _ => Err(invalid()),
+    }
+}
+
+/// Encode a string as null-terminated UCS-2.
+fn ucs2(string: &str) -> Vec<u8> {
+    string.encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect()
+}
+
+/// Get the path of a global variable.
+fn variable_path(name: &str) -> PathBuf {
+    Path::new(EFIVARS).join(format!("{name}-{GLOBAL}"))
+}
+
+/// Read a global variable (without its attributes).
+fn read(name: &str) -> Result<Option<Vec<u8>>> {
+    match fs::read(variable_path(name)) {
+        Ok(data) => Ok(Some(data.get(4..).unwrap_or_default().to_vec())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {name}")),
+    }
+}
+
+/// Write a global variable.
+///
+/// efivarfs wants this to happen in a single write.
+fn write(name: &str, data: &[u8]) -> Result<()> {
+    let path = variable_path(name);
+    if path.exists() {
+        make_mutable(&path).with_context(|| format!("failed to make {name} writable"))?;
+    }
+    let mut content = ATTRIBUTES.to_le_bytes().to_vec();
+    content.extend(data);
+    fs::write(&path, content).with_context(|| format!("failed to write {name}"))
+}
+
+/// Clear the immutable flag of a file.
+fn make_mutable(path: &Path) -> Result<()> {
+    let file = File::open(path)?;
+    // the kernel uses an int for these, despite the name of the ioctls
+    let mut flags: libc::c_int = 0;
+    // This is safe because the ioctls only read or write `flags`.
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(std::io::Error::last_os_error().into())
+    }
+    flags &= !IMMUTABLE;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(std::io::Error::last_os_error().into())
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a GPT partition's GUID (0fc63daf-8483-4772-8e79-3d69d8477de4)
+    const GUID: [u8; 16] = [
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47,
+        0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4,
+    ];
+
+    #[test]
+    fn partuuids() {
+        assert_eq!(parse_partuuid("0fc63daf-8483-4772-8e79-3d69d8477de4").unwrap(), (GUID, true));
+        assert_eq!(parse_partuuid("0FC63DAF-8483-4772-8E79-3D69D8477DE4").unwrap(), (GUID, true));
+        let mut signature = [0; 16];
+        signature[..4].copy_from_slice(&[0xcd, 0xab, 0x34, 0x12]);
+        assert_eq!(parse_partuuid("1234abcd-01").unwrap(), (signature, false));
+        for invalid in [
+            "", "1234abcd", "1234abcx-01", "1234abcd-1", "0fc63daf-8483-4772-8e79",
+            "a-b-c-d-e", "0fc63daf-8483-4772-8e-793d69d8477de4",
+        ] {
+            assert!(parse_partuuid(invalid).is_err(), "{invalid} should be invalid");
+        }
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(ucs2(""), [0, 0]);
+        assert_eq!(ucs2("tö"), [b't', 0, 0xf6, 0, 0, 0]);
+    }
+
+    #[test]
+    fn gpt_load_option() {
+        let partition = Partition {
+            number: 1, start: 2048, size: 204800, signature: GUID, gpt: true,
+        };
+        let option = load_option(&partition, "\\EFI\\towboot\\towboot.efi", "towboot", "-c x");
+        assert_eq!(option, [
+            0x01, 0x00, 0x00, 0x00, 0x64, 0x00, 0x74, 0x00, 0x6f, 0x00, 0x77, 0x00,
+            0x62, 0x00, 0x6f, 0x00, 0x6f, 0x00, 0x74, 0x00, 0x00, 0x00, 0x04, 0x01,
+            0x2a, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x20, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaf, 0x3d,
+            0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47,
+            0x7d, 0xe4, 0x02, 0x02, 0x04, 0x04, 0x36, 0x00, 0x5c, 0x00, 0x45, 0x00,
+            0x46, 0x00, 0x49, 0x00, 0x5c, 0x00, 0x74, 0x00, 0x6f, 0x00, 0x77, 0x00,
+            0x62, 0x00, 0x6f, 0x00, 0x6f, 0x00, 0x74, 0x00, 0x5c, 0x00, 0x74, 0x00,
+            0x6f, 0x00, 0x77, 0x00, 0x62, 0x00, 0x6f, 0x00, 0x6f, 0x00, 0x74, 0x00,
+            0x2e, 0x00, 0x65, 0x00, 0x66, 0x00, 0x69, 0x00, 0x00, 0x00, 0x7f, 0xff,
+            0x04, 0x00, 0x2d, 0x00, 0x63, 0x00, 0x20, 0x00, 0x78, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn mbr_load_option() {
+        let (signature, gpt) = parse_partuuid("1234abcd-02").unwrap();
+        let partition = Partition { number: 2, start: 63, size: 1000, signature, gpt };
+        let option = load_option(&partition, "\\a", "t", "");
+        assert_eq!(option, [
+            // active, the length of the device path and the description
+            0x01, 0x00, 0x00, 0x00, 0x38, 0x00, 0x74, 0x00, 0x00, 0x00,
+            // the hard drive node
+            0x04, 0x01, 0x2a, 0x00, 0x02, 0x00, 0x00, 0x00,
+            0x3f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xe8, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xcd, 0xab, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01,
+            // the file path node, the end and the (empty) options
+            0x04, 0x04, 0x0a, 0x00, 0x5c, 0x00, 0x61, 0x00, 0x00, 0x00,
+            0x7f, 0xff, 0x04, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn variable_paths() {
+        assert_eq!(
+            variable_path("Boot0001"),
+            Path::new("/sys/firmware/efi/efivars/Boot0001-8be4df61-93ca-11d2-aa0d-00e098032b8c"),
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! Embedding files into towboot's executable
+//!
+//! The configuration and everything it refers to are packed into a `newc`
+//! cpio archive, which is added to the executable as a new section called
+//! `.towboot`. towboot reads its files from there first, so a single binary
+//! carries everything that's needed to boot (like a unified kernel image).
+//! This has to happen before signing the binary for Secure Boot.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use argh::FromArgs;
+use log::{debug, info};
+
+use super::config::{self, Files};
+use super::image::PE_OFFSET;
+
+/// the name of the section (section names are at most 8 bytes long)
+const SECTION: &[u8; 8] = b".towboot";
+/// the size of a section header
+const SECTION_HEADER_SIZE: usize = 40;
+/// initialized data, readable
+const SECTION_CHARACTERISTICS: u32 = 0x4000_0040;
+/// the index of the certificate table in the data directories
+const CERTIFICATE_TABLE: usize = 4;
+
+/// the magic number of the `newc` format
+const CPIO_MAGIC: &str = "070701";
+/// the name of the last entry
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const CPIO_MODE_FILE: u32 = 0o100644;
+
+/// Embed the configuration and the files it refers to into towboot.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "embed")]
+pub(crate) struct EmbedCommand {
+    /// where to write the resulting executable to
+    #[argh(option, short = 'o')]
+    target: PathBuf,
+    /// the configuration file (the files it refers to are taken from its directory)
+    #[argh(option, short = 'c', default = "PathBuf::from(\"towboot.toml\")")]
+    config: PathBuf,
+    /// towboot's executable
+    #[argh(option)]
+    efi: PathBuf,
+    /// additional files, as source:destination (eg. `build/kernel.elf:\kernel.elf`),
+    /// these replace files from the configuration's directory
+    #[argh(option)]
+    file: Vec<String>,
+}
+
+impl EmbedCommand {
+    pub(crate) fn run(self) -> Result<()> {
+        let mut files = config::extra_files(&self.file)?;
+        let root = self.config.parent().map(Path::to_path_buf).unwrap_or_default();
+        config::add_referenced_files(&mut files, &config::read(&self.config)?, &root)?;
+        files.insert(String::from("towboot.toml"), self.config.clone());
+        let archive = archive(&files)?;
+        let mut executable = fs::read(&self.efi)
+            .with_context(|| format!("failed to read {}", self.efi.display()))?;
+        add_section(&mut executable, &archive)
+            .with_context(|| format!("failed to add a section to {}", self.efi.display()))?;
+        fs::write(&self.target, executable)
+            .with_context(|| format!("failed to write {}", self.target.display()))?;
+        info!(
+            "created {} ({} files, {} KiB embedded)",
+            self.target.display(), files.len(), archive.len() / 1024,
+        );
+        Ok(())
+    }
+}
+
+/// Pack the files into a `newc` cpio archive.
+///
+/// The paths in the archive are relative to the root of the ESP.
+fn archive(files: &Files) -> Result<Vec<u8>> {
+    let mut archive = Vec::new();
+    for (inode, (destination, source)) in files.iter().enumerate() {
+        let content = fs::read(source)
+            .with_context(|| format!("failed to read {}", source.display()))?;
+        debug!("embedding {} as {destination}", source.display());
+        add_entry(&mut archive, (inode + 1).try_into()?, destination, CPIO_MODE_FILE, &content)?;
+    }
+    add_entry(&mut archive, 0, CPIO_TRAILER, 0, &[])?;
+    Ok(archive)
+}
+
+/// Append an entry (header, name and content) to the archive.
+fn add_entry(
+    archive: &mut Vec<u8>, inode: u32, name: &str, mode: u32, content: &[u8],
+) -> Result<()> {
+    let size: u32 = content.len().try_into()
+        .map_err(|_| anyhow!("{name} is too large for a cpio archive"))?;
+    // inode, mode, uid, gid, nlink, mtime, size, dev major and minor,
+    // rdev major and minor, name size (including the NUL) and the (unused) checksum
+    let fields = [
+        inode, mode, 0, 0, 1, 0, size, 0, 0, 0, 0, (name.len() + 1).try_into()?, 0,
+    ];
+    archive.extend_from_slice(CPIO_MAGIC.as_bytes());
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(content);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    Ok(())
+}
+
+/// Add the section to a PE executable.
+///
+/// The section header has to fit between the existing ones and the first
+/// section. The data is appended to the end of the file.
+fn add_section(executable: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    let read_u16 = |executable: &[u8], offset: usize| executable.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| anyhow!("the executable is truncated"));
+    let read_u32 = |executable: &[u8], offset: usize| executable.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| anyhow!("the executable is truncated"));
+    let pe = read_u32(executable, PE_OFFSET)? as usize;
+    if executable.get(pe..pe + 4) != Some(b"PE\0\0") {
+        bail!("this isn't an EFI executable");
+    }
+    let sections = read_u16(executable, pe + 6)? as usize;
+    let optional = pe + 24;
+    let table = optional + read_u16(executable, pe + 20)? as usize;
+    // PE32 and PE32+ only differ in where the data directories start
+    let directories = match read_u16(executable, optional)? {
+        0x10b => optional + 96,
+        0x20b => optional + 112,
+        magic => bail!("unknown optional header ({magic:#x})"),
+    };
+    let directory_count = read_u32(executable, directories - 4)? as usize;
+    if directory_count > CERTIFICATE_TABLE
+        && read_u32(executable, directories + CERTIFICATE_TABLE * 8 + 4)? != 0 {
+        bail!("the executable is already signed, please embed the files before signing it");
+    }
+    let section_alignment = read_u32(executable, optional + 32)?;
+    let file_alignment = read_u32(executable, optional + 36)?;
+    let size_of_headers = read_u32(executable, optional + 60)? as usize;
+    let mut end_of_image = 0;
+    let mut first_data = size_of_headers;
+    for index in 0..sections {
+        let header = table + index * SECTION_HEADER_SIZE;
+        if executable.get(header..header + 8) == Some(SECTION) {
+            bail!("the executable already contains embedded files");
+        }
+        let virtual_end = read_u32(executable, header + 8)? + read_u32(executable, header + 12)?;
+        end_of_image = end_of_image.max(virtual_end);
+        let raw_start = read_u32(executable, header + 20)? as usize;
+        if raw_start != 0 {
+            first_data = first_data.min(raw_start);
+        }
+    }
+    let header = table + sections * SECTION_HEADER_SIZE;
+    if header + SECTION_HEADER_SIZE > first_data
+        || executable[header..header + SECTION_HEADER_SIZE].iter().any(|b| *b != 0) {
+        bail!("there's no room for another section header");
+    }
+    let size: u32 = data.len().try_into().map_err(|_| anyhow!("there's too much to embed"))?;
+    let virtual_address = end_of_image.next_multiple_of(section_alignment);
+    let raw_size = size.next_multiple_of(file_alignment);
+    let raw_start: u32 = executable.len().next_multiple_of(file_alignment as usize).try_into()?;
+    debug!("adding the section at {virtual_address:#x} ({size} bytes)");
+
+    let mut section = Vec::with_capacity(SECTION_HEADER_SIZE);
+    section.extend(SECTION);
+    section.extend(size.to_le_bytes());
+    section.extend(virtual_address.to_le_bytes());
+    section.extend(raw_size.to_le_bytes());
+    section.extend(raw_start.to_le_bytes());
+    // no relocations or line numbers
+    section.extend([0; 12]);
+    section.extend(SECTION_CHARACTERISTICS.to_le_bytes());
+    executable[header..header + SECTION_HEADER_SIZE].copy_from_slice(&section);
+
+    let write_u32 = |executable: &mut Vec<u8>, offset: usize, value: u32| {
+        executable[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    let sections: u16 = (sections + 1).try_into()?;
+    executable[pe + 6..pe + 8].copy_from_slice(&sections.to_le_bytes());
+    let initialized_data = read_u32(executable, optional + 8)?;
+    write_u32(executable, optional + 8, initialized_data + raw_size);
+    let size_of_image = (virtual_address + size).next_multiple_of(section_alignment);
+    write_u32(executable, optional + 56, size_of_image);
+    // the checksum isn't checked by UEFI, but it would be wrong now
+    write_u32(executable, optional + 64, 0);
+    executable.resize(raw_start as usize, 0);
+    executable.extend_from_slice(data);
+    executable.resize((raw_start + raw_size) as usize, 0);
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! Creating FAT filesystems
+//!
+//! The filesystem can be anywhere inside of a file (eg. in a partition),
+//! see `Slice`.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use anyhow::{Context, Result};
+use fatfs::{FileSystem, FormatVolumeOptions, FsOptions, ReadWriteSeek};
+use log::debug;
+
+use super::config::Files;
+
+/// the size of a cluster we assume when estimating the size
+const CLUSTER_SIZE: u64 = 4096;
+
+/// Estimate how large a filesystem containing these files needs to be.
+///
+/// This is rounded up to full MiB (but at least `minimum` bytes) and leaves
+/// some room for the FAT and the directories.
+pub(crate) fn size_for(files: &Files, minimum: u64) -> Result<u64> {
+    let mut size = 0;
+    for source in files.values() {
+        let length = fs::metadata(source)
+            .with_context(|| format!("failed to get the size of {}", source.display()))?
+            .len();
+        // each file also needs a directory entry (or a few for long names)
+        size += length.div_ceil(CLUSTER_SIZE) * CLUSTER_SIZE + CLUSTER_SIZE;
+    }
+    let size = size + size / 4 + 1024 * 1024;
+    Ok((size.div_ceil(1024 * 1024) * 1024 * 1024).max(minimum))
+}
+
+/// Format `storage` and copy the files into it.
+pub(crate) fn write<T: ReadWriteSeek>(mut storage: T, files: &Files) -> Result<()> {
+    fatfs::format_volume(&mut storage, FormatVolumeOptions::new().volume_label(*b"TOWBOOT    "))
+        .context("failed to format the filesystem")?;
+    let filesystem = FileSystem::new(storage, FsOptions::new())
+        .context("failed to open the filesystem")?;
+    debug!("created a {:?} filesystem", filesystem.fat_type());
+    for (destination, source) in files {
+        let (directories, name) = destination.rsplit_once('/').unwrap_or(("", destination));
+        let mut directory = filesystem.root_dir();
+        for part in directories.split('/').filter(|p| !p.is_empty()) {
+            directory = directory.create_dir(part)
+                .with_context(|| format!("failed to create {directories}"))?;
+        }
+        let mut file = directory.create_file(name)
+            .with_context(|| format!("failed to create {destination}"))?;
+        file.truncate()?;
+        let mut source_file = File::open(source)
+            .with_context(|| format!("failed to open {}", source.display()))?;
+        io::copy(&mut source_file, &mut file)
+            .with_context(|| format!("failed to copy {} to {destination}", source.display()))?;
+    }
+    filesystem.unmount().context("failed to write the filesystem")?;
+    Ok(())
+}
+
+/// A part of a file (eg. a partition of a disk image).
+pub(crate) struct Slice<T> {
+    inner: T,
+    start: u64,
+    length: u64,
+    position: u64,
+}
+
+impl<T: Seek> Slice<T> {
+    pub(crate) fn new(mut inner: T, start: u64, length: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(Self { inner, start, length, position: 0 })
+    }
+
+    /// How many bytes can be read or written from the current position.
+    fn remaining(&self, wanted: usize) -> usize {
+        wanted.min(self.length.saturating_sub(self.position).try_into().unwrap_or(usize::MAX))
+    }
+}
+
+impl<T: Read + Seek> Read for Slice<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.remaining(buf.len());
+        let read = self.inner.read(&mut buf[..length])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Write + Seek> Write for Slice<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = self.remaining(buf.len());
+        if length == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "the filesystem is full"))
+        }
+        let written = self.inner.write(&buf[..length])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Slice<T> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+        }.filter(|p| *p <= self.length).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput, "tried to seek outside of the filesystem",
+        ))?;
+        self.inner.seek(SeekFrom::Start(self.start + position))?;
+        self.position = position;
+        Ok(position)
+    }
+}
@@ -0,0 +1,429 @@
+//! Generating a configuration from an existing `/boot`
+//!
+//! The entries come from two places: Boot Loader Specification entries (in
+//! `loader/entries`) and the kernels directly in the directory. Only kernels
+//! with a Multiboot header are used, because towboot can't boot anything else
+//! (so a plain Linux kernel is skipped with a warning). For kernels that don't
+//! come from an entry, initrds with the same version are added as modules.
+//!
+//! The root filesystem is taken from `/etc/fstab` (or the mounted root) and
+//! passed as `root=` unless the command line already contains it.
+//!
+//! The directory is assumed to be the root of the partition towboot is on.
+
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+use anyhow::{bail, Context, Result};
+use argh::FromArgs;
+use log::{debug, info, warn};
+use toml::Value;
+use toml::value::Table;
+
+/// the Multiboot header has to be in the first 8 KiB of the kernel
+const HEADER_SEARCH: usize = 8192;
+const MULTIBOOT_MAGIC: u32 = 0x1bad_b002;
+/// the names of initrds, `{}` is the version of the kernel
+const INITRD_NAMES: [&str; 4] = ["initramfs-{}.img", "initrd.img-{}", "initrd-{}", "initrd-{}.img"];
+
+/// Generate a configuration from the kernels in /boot.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "generate-config")]
+pub(crate) struct GenerateConfigCommand {
+    /// the directory containing the kernels, which has to be the root of the
+    /// partition towboot is on (default: /boot)
+    #[argh(option, default = "PathBuf::from(\"/boot\")")]
+    boot: PathBuf,
+    /// the fstab to find the root filesystem in (default: /etc/fstab)
+    #[argh(option, default = "PathBuf::from(\"/etc/fstab\")")]
+    fstab: PathBuf,
+    /// where to write the configuration to (default: standard output)
+    #[argh(option, short = 'o')]
+    output: Option<PathBuf>,
+    /// the timeout of the menu in seconds (default: 5)
+    #[argh(option, default = "5")]
+    timeout: u8,
+}
+
+/// An entry for the configuration.
+struct Candidate {
+    id: String,
+    name: String,
+    version: String,
+    /// the path of the kernel on the ESP
+    image: String,
+    argv: String,
+    /// the paths of the initrds on the ESP
+    initrds: Vec<String>,
+}
+
+impl GenerateConfigCommand {
+    pub(crate) fn run(self) -> Result<()> {
+        let root = root_from_fstab(&self.fstab).or_else(root_from_mounts);
+        match &root {
+            Some(root) => info!("the root filesystem is {root}"),
+            None => warn!("failed to find the root filesystem, not passing root="),
+        }
+        let mut candidates = bls_entries(&self.boot)?;
+        let used: BTreeSet<String> = candidates.iter().map(|c| c.image.clone()).collect();
+        candidates.extend(kernels(&self.boot, &used)?);
+        if candidates.is_empty() {
+            bail!(
+                "didn't find any Multiboot kernels in {} (towboot can't boot anything else)",
+                self.boot.display(),
+            );
+        }
+        for candidate in &mut candidates {
+            if let Some(root) = &root {
+                if !candidate.argv.split_whitespace().any(|a| a.starts_with("root=")) {
+                    candidate.argv = format!("{} root={root}", candidate.argv).trim().to_string();
+                }
+            }
+        }
+        // the newest one comes first and is the default
+        candidates.sort_by(|a, b| compare_versions(&b.version, &a.version));
+        let text = toml::to_string(&build_config(&candidates, self.timeout))?;
+        match &self.output {
+            Some(path) => {
+                fs::write(path, text)
+                    .with_context(|| format!("failed to write {}", path.display()))?;
+                info!("wrote {} entries to {}", candidates.len(), path.display());
+            },
+            None => print!("{text}"),
+        }
+        Ok(())
+    }
+}
+
+/// Build the configuration.
+fn build_config(candidates: &[Candidate], timeout: u8) -> Value {
+    let mut entries = Table::new();
+    for candidate in candidates {
+        let mut entry = Table::new();
+        entry.insert(String::from("name"), Value::String(candidate.name.clone()));
+        entry.insert(String::from("image"), Value::String(candidate.image.clone()));
+        entry.insert(String::from("argv"), Value::String(candidate.argv.clone()));
+        if !candidate.initrds.is_empty() {
+            entry.insert(String::from("modules"), Value::Array(candidate.initrds.iter()
+                .map(|initrd| {
+                    let mut module = Table::new();
+                    module.insert(String::from("image"), Value::String(initrd.clone()));
+                    Value::Table(module)
+                })
+                .collect()));
+        }
+        entries.insert(candidate.id.clone(), Value::Table(entry));
+    }
+    let mut config = Table::new();
+    config.insert(String::from("config_version"), Value::Integer(2));
+    config.insert(String::from("default"), Value::String(candidates[0].id.clone()));
+    config.insert(String::from("timeout"), Value::Integer(timeout.into()));
+    config.insert(String::from("entries"), Value::Table(entries));
+    Value::Table(config)
+}
+
+/// Read the Boot Loader Specification entries.
+fn bls_entries(boot: &Path) -> Result<Vec<Candidate>> {
+    let directory = boot.join("loader/entries");
+    let Ok(files) = fs::read_dir(&directory) else {
+        debug!("there are no entries in {}", directory.display());
+        return Ok(Vec::new())
+    };
+    let mut candidates = Vec::new();
+    for file in files {
+        let path = file?.path();
+        if path.extension().map(|e| e != "conf").unwrap_or(true) {
+            continue
+        }
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let id = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let (mut title, mut version, mut linux, mut options) = (None, None, None, Vec::new());
+        let mut initrds = Vec::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim();
+            match key {
+                "title" => title = Some(value.to_string()),
+                "version" => version = Some(value.to_string()),
+                "linux" => linux = Some(value.to_string()),
+                "initrd" => initrds.push(esp_path(value)),
+                "options" => options.push(value.to_string()),
+                _ => (),
+            }
+        }
+        let Some(linux) = linux else {
+            debug!("{} doesn't contain a kernel, skipping it", path.display());
+            continue
+        };
+        match is_multiboot(&boot.join(linux.trim_start_matches('/'))) {
+            Ok(true) => (),
+            Ok(false) => {
+                warn!("{linux} (from {}) isn't a Multiboot kernel, skipping it", path.display());
+                continue
+            },
+            Err(e) => {
+                warn!("{e:#} (from {}), skipping it", path.display());
+                continue
+            },
+        }
+        candidates.push(Candidate {
+            name: title.unwrap_or_else(|| id.clone()),
+            version: version.unwrap_or_default(),
+            id,
+            image: esp_path(&linux),
+            argv: options.join(" "),
+            initrds,
+        });
+    }
+    Ok(candidates)
+}
+
+/// Find the kernels directly in the directory (which haven't been used yet).
+fn kernels(boot: &Path, used: &BTreeSet<String>) -> Result<Vec<Candidate>> {
+    let mut candidates = Vec::new();
+    let mut names: Vec<String> = fs::read_dir(boot)
+        .with_context(|| format!("failed to list {}", boot.display()))?
+        .filter_map(|file| file.ok())
+        .filter(|file| file.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .map(|file| file.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    for name in &names {
+        let path = boot.join(name);
+        if used.contains(&esp_path(name)) || !is_multiboot(&path)? {
+            continue
+        }
+        let version = name.split_once('-').map(|(_, v)| v).unwrap_or_default();
+        let initrds = if version.is_empty() {
+            Vec::new()
+        } else {
+            INITRD_NAMES.iter()
+                .map(|pattern| pattern.replace("{}", version))
+                .filter(|initrd| names.contains(initrd))
+                .map(|initrd| esp_path(&initrd))
+                .collect()
+        };
+        debug!("found {name} with {} initrds", initrds.len());
+        candidates.push(Candidate {
+            id: name.clone(),
+            name: name.clone(),
+            version: version.to_string(),
+            image: esp_path(name),
+            argv: String::new(),
+            initrds,
+        });
+    }
+    Ok(candidates)
+}
+
+/// Check whether a file has a valid Multiboot header.
+fn is_multiboot(path: &Path) -> Result<bool> {
+    let mut start = Vec::new();
+    File::open(path).with_context(|| format!("failed to open {}", path.display()))?
+        .take(HEADER_SEARCH as u64).read_to_end(&mut start)?;
+    Ok(start.chunks_exact(4).enumerate().any(|(index, word)| {
+        u32::from_le_bytes(word.try_into().unwrap()) == MULTIBOOT_MAGIC
+            && start.get(index * 4 + 4..index * 4 + 12).map(|rest| {
+                let flags = u32::from_le_bytes(rest[..4].try_into().unwrap());
+                let checksum = u32::from_le_bytes(rest[4..].try_into().unwrap());
+                MULTIBOOT_MAGIC.wrapping_add(flags).wrapping_add(checksum) == 0
+            }).unwrap_or(false)
+    }))
+}
+
+/// Convert a path relative to the directory to a path on the ESP.
+fn esp_path(path: &str) -> String {
+    format!("\\{}", path.trim_start_matches('/').replace('/', "\\"))
+}
+
+/// Find the root filesystem in the fstab.
+fn root_from_fstab(fstab: &Path) -> Option<String> {
+    fs::read_to_string(fstab).ok()?.lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.get(1) == Some(&"/"))
+        .map(|fields| fields[0].to_string())
+}
+
+/// Find the device that's mounted as the root filesystem.
+fn root_from_mounts() -> Option<String> {
+    fs::read_to_string("/proc/self/mounts").ok()?.lines()
+        .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.get(1) == Some(&"/") && fields[0].starts_with("/dev/"))
+        .map(|fields| fields[0].to_string())
+}
+
+/// Compare two versions, with numbers being compared by their value (like towboot does).
+///
+/// This way, `6.10` comes after `6.9`.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                match x.len().cmp(&y.len()).then_with(|| x.cmp(y)) {
+                    Ordering::Equal => (),
+                    other => return other,
+                }
+            },
+            (Some(x), Some(y)) => match x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase()) {
+                Ordering::Equal => { a.next(); b.next(); },
+                other => return other,
+            },
+        }
+    }
+}
+
+/// Take the digits at the start.
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        digits.push(digit);
+    }
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty directory to work in.
+    fn directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("towbootctl-generate-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// the start of a kernel with a valid Multiboot header (at offset 8)
+    fn multiboot_kernel() -> Vec<u8> {
+        let mut kernel = vec![0x90; 8];
+        kernel.extend(MULTIBOOT_MAGIC.to_le_bytes());
+        kernel.extend(0u32.to_le_bytes());
+        kernel.extend(0u32.wrapping_sub(MULTIBOOT_MAGIC).to_le_bytes());
+        kernel.extend([0; 64]);
+        kernel
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(compare_versions("6.10", "6.9"), Ordering::Greater);
+        assert_eq!(compare_versions("6.9", "6.10"), Ordering::Less);
+        assert_eq!(compare_versions("6.09", "6.9"), Ordering::Equal);
+        assert_eq!(compare_versions("6.1", "6.1.1"), Ordering::Less);
+        assert_eq!(compare_versions("6.1-RC1", "6.1-rc1"), Ordering::Equal);
+        assert_eq!(compare_versions("6.1-rc2", "6.1-rc10"), Ordering::Less);
+        assert_eq!(compare_versions("", ""), Ordering::Equal);
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(esp_path("kernel.elf"), "\\kernel.elf");
+        assert_eq!(esp_path("/boot/kernel.elf"), "\\boot\\kernel.elf");
+    }
+
+    #[test]
+    fn multiboot_headers() {
+        let directory = directory("headers");
+        let path = directory.join("kernel");
+        let mut kernel = multiboot_kernel();
+        fs::write(&path, &kernel).unwrap();
+        assert!(is_multiboot(&path).unwrap());
+        // a wrong checksum
+        kernel[16] ^= 1;
+        fs::write(&path, &kernel).unwrap();
+        assert!(!is_multiboot(&path).unwrap());
+        // a header that's cut off
+        fs::write(&path, &multiboot_kernel()[..16]).unwrap();
+        assert!(!is_multiboot(&path).unwrap());
+        // a header too far back
+        let mut kernel = vec![0; HEADER_SEARCH];
+        kernel.extend(multiboot_kernel());
+        fs::write(&path, &kernel).unwrap();
+        assert!(!is_multiboot(&path).unwrap());
+        assert!(is_multiboot(&directory.join("missing")).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn fstab() {
+        let directory = directory("fstab");
+        let path = directory.join("fstab");
+        fs::write(&path, "\
+            # / is on this one\n\
+            # UUID=old / ext4 defaults 0 1\n\
+            UUID=1234 /boot/efi vfat umask=0077 0 2\n\
+            \n\
+            UUID=abcd / ext4 defaults 0 1\n\
+        ").unwrap();
+        assert_eq!(root_from_fstab(&path).as_deref(), Some("UUID=abcd"));
+        fs::write(&path, "tmpfs /tmp tmpfs defaults 0 0\n").unwrap();
+        assert_eq!(root_from_fstab(&path), None);
+        assert_eq!(root_from_fstab(&directory.join("missing")), None);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn candidates() {
+        let boot = directory("boot");
+        fs::create_dir_all(boot.join("loader/entries")).unwrap();
+        fs::write(boot.join("kernel-6.9"), multiboot_kernel()).unwrap();
+        fs::write(boot.join("kernel-6.10"), multiboot_kernel()).unwrap();
+        fs::write(boot.join("initrd.img-6.10"), "").unwrap();
+        fs::write(boot.join("vmlinuz-6.10"), "not a Multiboot kernel").unwrap();
+        fs::write(boot.join("loader/entries/custom.conf"), "\
+            title My kernel\n\
+            version 6.9\n\
+            linux /kernel-6.9\n\
+            initrd /initrd-custom\n\
+            options quiet\n\
+            options splash\n\
+        ").unwrap();
+        fs::write(boot.join("loader/entries/linux.conf"), "linux /vmlinuz-6.10\n").unwrap();
+        fs::write(boot.join("loader/entries/empty.conf"), "# nothing\n").unwrap();
+
+        let entries = bls_entries(&boot).unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!((entry.id.as_str(), entry.name.as_str()), ("custom", "My kernel"));
+        assert_eq!((entry.image.as_str(), entry.version.as_str()), ("\\kernel-6.9", "6.9"));
+        assert_eq!(entry.argv, "quiet splash");
+        assert_eq!(entry.initrds, ["\\initrd-custom"]);
+
+        let used = BTreeSet::from([entry.image.clone()]);
+        let found = kernels(&boot, &used).unwrap();
+        assert_eq!(found.len(), 1);
+        let kernel = &found[0];
+        assert_eq!((kernel.id.as_str(), kernel.image.as_str()), ("kernel-6.10", "\\kernel-6.10"));
+        assert_eq!(kernel.version, "6.10");
+        assert_eq!(kernel.initrds, ["\\initrd.img-6.10"]);
+        fs::remove_dir_all(&boot).unwrap();
+
+        let config = build_config(&found, 3);
+        // ids with dots have to be quoted
+        assert_eq!(toml::from_str::<Value>(&toml::to_string(&config).unwrap()).unwrap(), config);
+        assert_eq!(config, toml::from_str::<Value>(r#"
+            config_version = 2
+            default = "kernel-6.10"
+            timeout = 3
+
+            [entries."kernel-6.10"]
+            name = "kernel-6.10"
+            image = "\\kernel-6.10"
+            argv = ""
+            modules = [{ image = "\\initrd.img-6.10" }]
+        "#).unwrap());
+    }
+}
@@ -0,0 +1,226 @@
+//! Creating disks with a GUID partition table
+//!
+//! The disks created here contain exactly one partition (the ESP), which starts
+//! at 1 MiB. There's a protective MBR in front and the backup of the partition
+//! table at the end, as required by the UEFI specification.
+
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Seek, SeekFrom, Write};
+
+use anyhow::{bail, Context, Result};
+
+/// the size of a logical block
+pub(crate) const BLOCK_SIZE: u64 = 512;
+/// where the partition starts (and how much room is left at the end)
+const ALIGNMENT: u64 = 1024 * 1024 / BLOCK_SIZE;
+/// how many entries the partition table has
+const ENTRIES: u32 = 128;
+/// the size of one entry of the partition table
+const ENTRY_SIZE: u32 = 128;
+/// how many blocks the partition table takes up
+const ENTRY_BLOCKS: u64 = (ENTRIES * ENTRY_SIZE) as u64 / BLOCK_SIZE;
+/// the size of the header of the partition table
+const HEADER_SIZE: u32 = 92;
+
+/// the type of an EFI system partition (C12A7328-F81F-11D2-BA4B-00A0C93EC93B)
+const ESP_TYPE: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11,
+    0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+/// the partition type of a protective MBR
+const PROTECTIVE_TYPE: u8 = 0xee;
+
+/// Create a disk containing a single ESP of `size` bytes at `target`.
+///
+/// This returns where the partition is (offset and length in bytes).
+pub(crate) fn create(target: &File, size: u64) -> Result<(u64, u64)> {
+    let partition_blocks = size.div_ceil(BLOCK_SIZE);
+    let partition_start = ALIGNMENT;
+    let partition_end = partition_start + partition_blocks - 1;
+    let blocks = partition_end + 1 + ALIGNMENT;
+    if blocks > u64::from(u32::MAX) {
+        bail!("the image would be too large");
+    }
+    let mut disk = target;
+    disk.set_len(blocks * BLOCK_SIZE).context("failed to resize the image")?;
+
+    let mut entries = vec![0; (ENTRIES * ENTRY_SIZE) as usize];
+    entries[0..16].copy_from_slice(&ESP_TYPE);
+    entries[16..32].copy_from_slice(&random_guid());
+    entries[32..40].copy_from_slice(&partition_start.to_le_bytes());
+    entries[40..48].copy_from_slice(&partition_end.to_le_bytes());
+    // no attributes
+    for (name, unit) in entries[56..128].chunks_exact_mut(2).zip("towboot".encode_utf16()) {
+        name.copy_from_slice(&unit.to_le_bytes());
+    }
+    let disk_guid = random_guid();
+    let last_block = blocks - 1;
+    let primary = header(1, last_block, 2, &disk_guid, &entries, last_block);
+    let backup = header(
+        last_block, 1, last_block - ENTRY_BLOCKS, &disk_guid, &entries, last_block,
+    );
+
+    disk.seek(SeekFrom::Start(0))?;
+    disk.write_all(&protective_mbr(blocks))?;
+    disk.write_all(&primary)?;
+    disk.write_all(&entries)?;
+    disk.seek(SeekFrom::Start((last_block - ENTRY_BLOCKS) * BLOCK_SIZE))?;
+    disk.write_all(&entries)?;
+    disk.write_all(&backup)?;
+    disk.flush().context("failed to write the partition table")?;
+    Ok((partition_start * BLOCK_SIZE, partition_blocks * BLOCK_SIZE))
+}
+
+/// Create an MBR with a single partition spanning the whole disk.
+fn protective_mbr(blocks: u64) -> [u8; BLOCK_SIZE as usize] {
+    let mut mbr = [0; BLOCK_SIZE as usize];
+    let entry = &mut mbr[446..462];
+    // the CHS addresses are the ones from the specification
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[4] = PROTECTIVE_TYPE;
+    entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&u32::try_from(blocks - 1).unwrap_or(u32::MAX).to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+    mbr
+}
+
+/// Create the header of a partition table (padded to a block).
+fn header(
+    my_block: u64, alternate_block: u64, entries_block: u64,
+    disk_guid: &[u8; 16], entries: &[u8], last_block: u64,
+) -> [u8; BLOCK_SIZE as usize] {
+    let mut header = [0; BLOCK_SIZE as usize];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&HEADER_SIZE.to_le_bytes());
+    // the checksum at 16 is calculated over the header with the field being 0
+    header[24..32].copy_from_slice(&my_block.to_le_bytes());
+    header[32..40].copy_from_slice(&alternate_block.to_le_bytes());
+    header[40..48].copy_from_slice(&(2 + ENTRY_BLOCKS).to_le_bytes());
+    header[48..56].copy_from_slice(&(last_block - 1 - ENTRY_BLOCKS).to_le_bytes());
+    header[56..72].copy_from_slice(disk_guid);
+    header[72..80].copy_from_slice(&entries_block.to_le_bytes());
+    header[80..84].copy_from_slice(&ENTRIES.to_le_bytes());
+    header[84..88].copy_from_slice(&ENTRY_SIZE.to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+    let checksum = crc32(&header[..HEADER_SIZE as usize]);
+    header[16..20].copy_from_slice(&checksum.to_le_bytes());
+    header
+}
+
+/// Calculate the CRC32 (as used by GPT and zlib).
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// Create a random (version 4) GUID.
+pub(crate) fn random_guid() -> [u8; 16] {
+    let mut guid = [0; 16];
+    for half in guid.chunks_exact_mut(8) {
+        // This is seeded randomly by the operating system.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(std::process::id().into());
+        half.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    guid
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use super::*;
+
+    const GUID: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(&[0; 16384]), 0xab54_d286);
+    }
+
+    #[test]
+    fn guids() {
+        let guid = random_guid();
+        assert_eq!(guid[7] >> 4, 4);
+        assert_eq!(guid[8] >> 6, 0b10);
+        assert_ne!(guid, random_guid());
+    }
+
+    #[test]
+    fn mbr() {
+        let mbr = protective_mbr(6144);
+        assert!(mbr[..446].iter().all(|b| *b == 0));
+        assert_eq!(mbr[446..462], [
+            0x00, 0x00, 0x02, 0x00, 0xee, 0xff, 0xff, 0xff,
+            0x01, 0x00, 0x00, 0x00, 0xff, 0x17, 0x00, 0x00,
+        ]);
+        assert!(mbr[462..510].iter().all(|b| *b == 0));
+        assert_eq!(mbr[510..], [0x55, 0xaa]);
+        // disks larger than 2 TiB are covered as far as possible
+        let mbr = protective_mbr(1 << 33);
+        assert_eq!(mbr[458..462], [0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn primary_header() {
+        let header = header(1, 8191, 2, &GUID, &[0; 16384], 8191);
+        assert_eq!(header[..92], [
+            0x45, 0x46, 0x49, 0x20, 0x50, 0x41, 0x52, 0x54,
+            0x00, 0x00, 0x01, 0x00, 0x5c, 0x00, 0x00, 0x00,
+            0x2f, 0x4d, 0xc1, 0x3f, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xff, 0x1f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xde, 0x1f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+            0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x80, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00,
+            0x86, 0xd2, 0x54, 0xab,
+        ]);
+        assert!(header[92..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn backup_header() {
+        let header = header(8191, 1, 8191 - ENTRY_BLOCKS, &GUID, &[0; 16384], 8191);
+        assert_eq!(header[16..20], 0x40ad_797fu32.to_le_bytes());
+        assert_eq!(header[24..32], 8191u64.to_le_bytes());
+        assert_eq!(header[32..40], 1u64.to_le_bytes());
+        assert_eq!(header[72..80], 8159u64.to_le_bytes());
+    }
+
+    #[test]
+    fn disk() {
+        let path = std::env::temp_dir().join(format!("towbootctl-gpt-{}", std::process::id()));
+        let (offset, length) = create(&File::create(&path).unwrap(), 1024 * 1024).unwrap();
+        let disk = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((offset, length), (1024 * 1024, 1024 * 1024));
+        // 1 MiB in front, the partition and 1 MiB at the end
+        assert_eq!(disk.len(), 3 * 1024 * 1024);
+        assert_eq!(disk[..512], protective_mbr(6144));
+
+        let block = |number: usize| &disk[number * 512..(number + 1) * 512];
+        let entries = &disk[2 * 512..34 * 512];
+        assert_eq!(entries[0..16], ESP_TYPE);
+        assert_eq!(entries[32..40], 2048u64.to_le_bytes());
+        assert_eq!(entries[40..48], 4095u64.to_le_bytes());
+        assert!(entries[128..].iter().all(|b| *b == 0));
+        assert_eq!(&disk[6111 * 512..6143 * 512], entries);
+        let guid: [u8; 16] = block(1)[56..72].try_into().unwrap();
+        assert_eq!(block(1), header(1, 6143, 2, &guid, entries, 6143));
+        assert_eq!(block(6143), header(6143, 1, 6111, &guid, entries, 6143));
+    }
+}
@@ -0,0 +1,103 @@
+//! Creating bootable disk images
+//!
+//! The image contains a GPT with a single ESP. towboot is put at the default
+//! path of removable media for its architecture (eg. `\EFI\BOOT\BOOTIA32.EFI`),
+//! the configuration at `\towboot.toml` and everything the configuration
+//! refers to at the same place as next to the configuration on the host.
+
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use argh::FromArgs;
+use log::info;
+
+use super::config::{self, Files};
+use super::fat::{self, Slice};
+use super::gpt;
+
+/// the smallest ESP we create (in bytes), some firmware doesn't like tiny ones
+pub(crate) const MINIMUM_SIZE: u64 = 32 * 1024 * 1024;
+/// the offset of the offset of the PE header in an EFI executable
+pub(crate) const PE_OFFSET: usize = 0x3c;
+
+/// Create a bootable disk image.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "image")]
+pub(crate) struct ImageCommand {
+    /// where to write the image to
+    #[argh(option, short = 'o')]
+    target: PathBuf,
+    /// the configuration file (the files it refers to are taken from its directory)
+    #[argh(option, short = 'c', default = "PathBuf::from(\"towboot.toml\")")]
+    config: PathBuf,
+    /// towboot's executable (can be given once per architecture)
+    #[argh(option)]
+    efi: Vec<PathBuf>,
+    /// additional files, as source:destination (eg. `build/kernel.elf:\kernel.elf`),
+    /// these replace files from the configuration's directory
+    #[argh(option)]
+    file: Vec<String>,
+    /// the size of the ESP in MiB (by default, this is calculated)
+    #[argh(option)]
+    size: Option<u64>,
+}
+
+impl ImageCommand {
+    pub(crate) fn run(self) -> Result<()> {
+        let files = collect_files(&self.config, &self.efi, &self.file)?;
+        let size = match self.size {
+            Some(size) => size * 1024 * 1024,
+            None => fat::size_for(&files, MINIMUM_SIZE)?,
+        };
+        create_image(&self.target, size, &files)?;
+        info!(
+            "created {} ({} MiB, {} files)", self.target.display(), size / 1024 / 1024, files.len(),
+        );
+        Ok(())
+    }
+}
+
+/// Collect everything that goes onto the ESP.
+pub(crate) fn collect_files(config: &Path, efis: &[PathBuf], extra: &[String]) -> Result<Files> {
+    if efis.is_empty() {
+        bail!("there has to be at least one EFI executable (pass it with --efi)");
+    }
+    // these take precedence over what's next to the configuration
+    let mut files = config::extra_files(extra)?;
+    let root = config.parent().map(Path::to_path_buf).unwrap_or_default();
+    config::add_referenced_files(&mut files, &config::read(config)?, &root)?;
+    files.insert(String::from("towboot.toml"), config.to_path_buf());
+    for efi in efis {
+        files.insert(format!("EFI/BOOT/{}", removable_media_path(efi)?), efi.clone());
+    }
+    Ok(files)
+}
+
+/// Create a disk image with an ESP of `size` bytes containing the files.
+pub(crate) fn create_image(target: &Path, size: u64, files: &Files) -> Result<()> {
+    let image = OpenOptions::new()
+        .read(true).write(true).create(true).truncate(true).open(target)
+        .with_context(|| format!("failed to create {}", target.display()))?;
+    let (start, length) = gpt::create(&image, size)?;
+    fat::write(Slice::new(&image, start, length)?, files)
+}
+
+/// Get the file name of towboot's executable on removable media.
+///
+/// This depends on the architecture the executable has been built for.
+pub(crate) fn removable_media_path(efi: &Path) -> Result<&'static str> {
+    let data = fs::read(efi).with_context(|| format!("failed to read {}", efi.display()))?;
+    let machine = data.get(PE_OFFSET..PE_OFFSET + 4)
+        .map(|o| u32::from_le_bytes(o.try_into().unwrap()) as usize)
+        .and_then(|offset| data.get(offset..offset + 6))
+        .filter(|header| header.starts_with(b"PE\0\0"))
+        .map(|header| u16::from_le_bytes([header[4], header[5]]))
+        .ok_or_else(|| anyhow!("{} isn't an EFI executable", efi.display()))?;
+    match machine {
+        0x014c => Ok("BOOTIA32.EFI"),
+        0x8664 => Ok("BOOTX64.EFI"),
+        0xaa64 => Ok("BOOTAA64.EFI"),
+        _ => bail!("{} has an unknown architecture ({machine:#x})", efi.display()),
+    }
+}
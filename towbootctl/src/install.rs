@@ -0,0 +1,104 @@
+//! Installing towboot to an ESP
+//!
+//! towboot is copied to `\EFI\<name>\towboot.efi` and the configuration to
+//! `\EFI\<name>\towboot.toml`, the files the configuration refers to go to
+//! their paths on the ESP (see `config`). A boot entry for
+//! `\EFI\<name>\towboot.efi -c \EFI\<name>\towboot.toml` is then created.
+//!
+//! Unless disabled, towboot is also copied to the default path of removable
+//! media (eg. `\EFI\BOOT\BOOTX64.EFI`) with the configuration at
+//! `\towboot.toml`, so that the ESP stays bootable if the firmware forgets
+//! the boot entry.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use log::{debug, info};
+
+use super::config::{self, Files};
+use super::image::removable_media_path;
+
+/// Install towboot to an ESP.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "install")]
+pub(crate) struct InstallCommand {
+    /// where the ESP is mounted (eg. /boot/efi)
+    #[argh(option)]
+    esp: PathBuf,
+    /// the configuration file (the files it refers to are taken from its directory)
+    #[argh(option, short = 'c', default = "PathBuf::from(\"towboot.toml\")")]
+    config: PathBuf,
+    /// towboot's executable
+    #[argh(option)]
+    efi: PathBuf,
+    /// additional files, as source:destination (eg. `build/kernel.elf:\kernel.elf`),
+    /// these replace files from the configuration's directory
+    #[argh(option)]
+    file: Vec<String>,
+    /// the directory in \EFI to install to (default: towboot)
+    #[argh(option, default = "String::from(\"towboot\")")]
+    name: String,
+    /// the description of the boot entry (default: towboot)
+    #[argh(option, default = "String::from(\"towboot\")")]
+    label: String,
+    /// don't install to the default path for removable media
+    #[argh(switch)]
+    no_fallback: bool,
+    /// don't create or update a boot entry
+    #[argh(switch)]
+    no_boot_entry: bool,
+}
+
+impl InstallCommand {
+    pub(crate) fn run(self) -> Result<()> {
+        let directory = format!("EFI/{}", config::esp_path(&self.name));
+        let mut files = config::extra_files(&self.file)?;
+        let root = self.config.parent().map(Path::to_path_buf).unwrap_or_default();
+        config::add_referenced_files(&mut files, &config::read(&self.config)?, &root)?;
+        files.insert(format!("{directory}/towboot.efi"), self.efi.clone());
+        files.insert(format!("{directory}/towboot.toml"), self.config.clone());
+        if !self.no_fallback {
+            files.insert(
+                format!("EFI/BOOT/{}", removable_media_path(&self.efi)?), self.efi.clone(),
+            );
+            files.insert(String::from("towboot.toml"), self.config.clone());
+        }
+        copy_files(&self.esp, &files)?;
+        info!("installed {} files to {}", files.len(), self.esp.display());
+        if !self.no_boot_entry {
+            let directory = directory.replace('/', "\\");
+            let loader = format!("\\{directory}\\towboot.efi");
+            let options = format!("towboot.efi -c \\{directory}\\towboot.toml");
+            register(&self.esp, &loader, &self.label, &options)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy the files to the directory the ESP is mounted at.
+fn copy_files(esp: &Path, files: &Files) -> Result<()> {
+    for (destination, source) in files {
+        let target = esp.join(destination);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        debug!("copying {} to {}", source.display(), target.display());
+        fs::copy(source, &target).with_context(|| format!(
+            "failed to copy {} to {}", source.display(), target.display(),
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn register(esp: &Path, loader: &str, description: &str, options: &str) -> Result<()> {
+    super::efivars::register(esp, loader, description, options)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn register(_esp: &Path, _loader: &str, _description: &str, _options: &str) -> Result<()> {
+    anyhow::bail!("creating boot entries is only supported on Linux (pass --no-boot-entry)")
+}
@@ -0,0 +1,335 @@
+//! Creating bootable ISOs
+//!
+//! The ISO is an ISO 9660 filesystem with an El Torito boot catalog. It has a
+//! single (no emulation) entry for UEFI that points to an ESP image, which
+//! contains the same files as a disk image (see `image`). The ESP image is
+//! also visible as `\EFIBOOT.IMG` on the disc.
+//!
+//! The layout is fixed:
+//! * sector 16: the primary volume descriptor
+//! * sector 17: the boot record (pointing to the boot catalog)
+//! * sector 18: the end of the volume descriptors
+//! * sectors 19 and 20: the path tables (little and big endian)
+//! * sector 21: the root directory
+//! * sector 22: the boot catalog
+//! * sector 23 and after: the ESP image
+
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use argh::FromArgs;
+use log::{info, warn};
+
+use super::config::Files;
+use super::fat::{self, Slice};
+use super::image::collect_files;
+
+/// the size of a sector on a CD
+const SECTOR_SIZE: u64 = 2048;
+/// the size of a sector as counted by El Torito
+const VIRTUAL_SECTOR_SIZE: u64 = 512;
+
+const PRIMARY_VOLUME_DESCRIPTOR: u64 = 16;
+const BOOT_RECORD: u64 = 17;
+const TERMINATOR: u64 = 18;
+const L_PATH_TABLE: u64 = 19;
+const M_PATH_TABLE: u64 = 20;
+const ROOT_DIRECTORY: u64 = 21;
+const BOOT_CATALOG: u64 = 22;
+const ESP: u64 = 23;
+
+/// the name of the ESP image on the disc
+const ESP_NAME: &[u8] = b"EFIBOOT.IMG;1";
+/// the platform ID of UEFI in the boot catalog
+const PLATFORM_EFI: u8 = 0xef;
+/// the size of an entry of the path table (with a name of one byte)
+const PATH_TABLE_SIZE: u32 = 10;
+
+/// Create a bootable ISO.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "iso")]
+pub(crate) struct IsoCommand {
+    /// where to write the ISO to
+    #[argh(option, short = 'o')]
+    target: PathBuf,
+    /// the configuration file (the files it refers to are taken from its directory)
+    #[argh(option, short = 'c', default = "PathBuf::from(\"towboot.toml\")")]
+    config: PathBuf,
+    /// towboot's executable (can be given once per architecture)
+    #[argh(option)]
+    efi: Vec<PathBuf>,
+    /// additional files, as source:destination (eg. `build/kernel.elf:\kernel.elf`),
+    /// these replace files from the configuration's directory
+    #[argh(option)]
+    file: Vec<String>,
+    /// the size of the ESP image in MiB (by default, this is calculated)
+    #[argh(option)]
+    size: Option<u64>,
+    /// the volume label (at most 32 characters, default: TOWBOOT)
+    #[argh(option, default = "String::from(\"TOWBOOT\")")]
+    label: String,
+}
+
+impl IsoCommand {
+    pub(crate) fn run(self) -> Result<()> {
+        let files = collect_files(&self.config, &self.efi, &self.file)?;
+        let size = match self.size {
+            Some(size) => size * 1024 * 1024,
+            None => fat::size_for(&files, 0)?,
+        };
+        create_iso(&self.target, &self.label, size, &files)?;
+        info!(
+            "created {} (ESP: {} MiB, {} files)",
+            self.target.display(), size / 1024 / 1024, files.len(),
+        );
+        Ok(())
+    }
+}
+
+/// Create an ISO with an ESP image of `size` bytes containing the files.
+pub(crate) fn create_iso(target: &Path, label: &str, size: u64, files: &Files) -> Result<()> {
+    let valid = |c: u8| c.is_ascii_uppercase() || c.is_ascii_digit() || c == b'_';
+    if label.len() > 32 || !label.bytes().all(valid) {
+        bail!("the label may only contain up to 32 uppercase letters, digits and underscores");
+    }
+    let esp_sectors = size.div_ceil(SECTOR_SIZE);
+    let sectors = ESP + esp_sectors;
+    let (Ok(sectors), Ok(esp_size)) = (u32::try_from(sectors), u32::try_from(size)) else {
+        bail!("the ISO would be too large");
+    };
+    let mut iso = OpenOptions::new()
+        .read(true).write(true).create(true).truncate(true).open(target)
+        .with_context(|| format!("failed to create {}", target.display()))?;
+    iso.set_len(u64::from(sectors) * SECTOR_SIZE).context("failed to resize the ISO")?;
+
+    let mut root = Vec::new();
+    root.extend(directory_record(&[0], ROOT_DIRECTORY as u32, SECTOR_SIZE as u32, true));
+    root.extend(directory_record(&[1], ROOT_DIRECTORY as u32, SECTOR_SIZE as u32, true));
+    root.extend(directory_record(ESP_NAME, ESP as u32, esp_size, false));
+    let structures = [
+        (PRIMARY_VOLUME_DESCRIPTOR, primary_volume_descriptor(label, sectors)),
+        (BOOT_RECORD, boot_record()),
+        (TERMINATOR, volume_descriptor(255)),
+        (L_PATH_TABLE, path_table(u32::to_le_bytes, u16::to_le_bytes)),
+        (M_PATH_TABLE, path_table(u32::to_be_bytes, u16::to_be_bytes)),
+        (ROOT_DIRECTORY, root),
+        (BOOT_CATALOG, boot_catalog(esp_sectors)),
+    ];
+    for (sector, data) in structures {
+        iso.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        iso.write_all(&data)?;
+    }
+    iso.flush().context("failed to write the ISO")?;
+    fat::write(Slice::new(&iso, ESP * SECTOR_SIZE, size)?, files)
+}
+
+/// Create the start of a volume descriptor.
+fn volume_descriptor(kind: u8) -> Vec<u8> {
+    let mut descriptor = vec![0; SECTOR_SIZE as usize];
+    descriptor[0] = kind;
+    descriptor[1..6].copy_from_slice(b"CD001");
+    descriptor[6] = 1;
+    descriptor
+}
+
+/// Create the primary volume descriptor.
+fn primary_volume_descriptor(label: &str, sectors: u32) -> Vec<u8> {
+    let mut descriptor = volume_descriptor(1);
+    // the identifiers are padded with spaces, an empty one consists of spaces
+    descriptor[8..72].fill(b' ');
+    descriptor[40..40 + label.len()].copy_from_slice(label.as_bytes());
+    descriptor[80..88].copy_from_slice(&both_u32(sectors));
+    descriptor[120..124].copy_from_slice(&both_u16(1));
+    descriptor[124..128].copy_from_slice(&both_u16(1));
+    descriptor[128..132].copy_from_slice(&both_u16(SECTOR_SIZE as u16));
+    descriptor[132..140].copy_from_slice(&both_u32(PATH_TABLE_SIZE));
+    descriptor[140..144].copy_from_slice(&(L_PATH_TABLE as u32).to_le_bytes());
+    descriptor[148..152].copy_from_slice(&(M_PATH_TABLE as u32).to_be_bytes());
+    descriptor[156..190].copy_from_slice(&directory_record(
+        &[0], ROOT_DIRECTORY as u32, SECTOR_SIZE as u32, true,
+    ));
+    descriptor[190..813].fill(b' ');
+    descriptor[574..581].copy_from_slice(b"TOWBOOT");
+    // the dates are unspecified
+    for date in descriptor[813..881].chunks_exact_mut(17) {
+        date[..16].fill(b'0');
+    }
+    descriptor[881] = 1;
+    descriptor
+}
+
+/// Create the boot record pointing to the boot catalog.
+fn boot_record() -> Vec<u8> {
+    let mut record = volume_descriptor(0);
+    let system = b"EL TORITO SPECIFICATION";
+    record[7..7 + system.len()].copy_from_slice(system);
+    record[71..75].copy_from_slice(&(BOOT_CATALOG as u32).to_le_bytes());
+    record
+}
+
+/// Create the boot catalog with a single entry for the ESP image.
+///
+/// The size of the image is stored in 16 bits (as 512 byte sectors). If the
+/// image is larger than that, the size is 0 and the firmware uses everything
+/// after the start of the image (which is at the end of the disc).
+fn boot_catalog(esp_sectors: u64) -> Vec<u8> {
+    let mut catalog = vec![0; SECTOR_SIZE as usize];
+    // the validation entry
+    catalog[0] = 1;
+    catalog[1] = PLATFORM_EFI;
+    catalog[4..11].copy_from_slice(b"towboot");
+    catalog[30..32].copy_from_slice(&[0x55, 0xaa]);
+    // the words of the validation entry have to add up to 0
+    let sum = catalog[..32].chunks_exact(2)
+        .map(|w| u16::from_le_bytes([w[0], w[1]]))
+        .fold(0u16, u16::wrapping_add);
+    catalog[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+    // the default entry: bootable, no emulation
+    catalog[32] = 0x88;
+    let virtual_sectors = esp_sectors * (SECTOR_SIZE / VIRTUAL_SECTOR_SIZE);
+    let count = u16::try_from(virtual_sectors).unwrap_or_else(|_| {
+        warn!("the ESP image is too large for the boot catalog, the firmware has to guess");
+        0
+    });
+    catalog[38..40].copy_from_slice(&count.to_le_bytes());
+    catalog[40..44].copy_from_slice(&(ESP as u32).to_le_bytes());
+    catalog
+}
+
+/// Create a path table containing only the root directory.
+fn path_table(u32_bytes: fn(u32) -> [u8; 4], u16_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+    let mut table = vec![0; PATH_TABLE_SIZE as usize];
+    table[0] = 1;
+    table[2..6].copy_from_slice(&u32_bytes(ROOT_DIRECTORY as u32));
+    table[6..8].copy_from_slice(&u16_bytes(1));
+    table
+}
+
+/// Create a directory record.
+fn directory_record(name: &[u8], sector: u32, length: u32, directory: bool) -> Vec<u8> {
+    // records always have an even length
+    let size = (33 + name.len()).next_multiple_of(2);
+    let mut record = vec![0; size];
+    record[0] = size as u8;
+    record[2..10].copy_from_slice(&both_u32(sector));
+    record[10..18].copy_from_slice(&both_u32(length));
+    // the recording date is unspecified
+    record[25] = if directory { 2 } else { 0 };
+    record[28..32].copy_from_slice(&both_u16(1));
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+    record
+}
+
+/// Store a number as little and big endian (as ISO 9660 likes to).
+fn both_u32(value: u32) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&value.to_le_bytes());
+    bytes[4..].copy_from_slice(&value.to_be_bytes());
+    bytes
+}
+
+fn both_u16(value: u16) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    bytes[..2].copy_from_slice(&value.to_le_bytes());
+    bytes[2..].copy_from_slice(&value.to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(both_u32(0x0102_0304), [4, 3, 2, 1, 1, 2, 3, 4]);
+        assert_eq!(both_u16(0x0102), [2, 1, 1, 2]);
+    }
+
+    #[test]
+    fn directory_records() {
+        assert_eq!(directory_record(ESP_NAME, 23, 1024 * 1024, false), [
+            46, 0, 23, 0, 0, 0, 0, 0, 0, 23, 0, 0, 16, 0, 0, 16, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 13,
+            b'E', b'F', b'I', b'B', b'O', b'O', b'T', b'.', b'I', b'M', b'G', b';', b'1',
+        ]);
+        // the root directory has a name of one byte (and is padded)
+        let root = directory_record(&[0], 21, 2048, true);
+        assert_eq!(root.len(), 34);
+        assert_eq!(root[..2], [34, 0]);
+        assert_eq!(root[10..18], [0, 8, 0, 0, 0, 0, 8, 0]);
+        assert_eq!(root[25], 2);
+        assert_eq!(root[32..], [1, 0]);
+    }
+
+    #[test]
+    fn path_tables() {
+        assert_eq!(
+            path_table(u32::to_le_bytes, u16::to_le_bytes),
+            [1, 0, 21, 0, 0, 0, 1, 0, 0, 0],
+        );
+        assert_eq!(
+            path_table(u32::to_be_bytes, u16::to_be_bytes),
+            [1, 0, 0, 0, 0, 21, 0, 1, 0, 0],
+        );
+    }
+
+    #[test]
+    fn descriptors() {
+        let descriptor = primary_volume_descriptor("TOWBOOT_TEST", 535);
+        assert_eq!(descriptor.len(), 2048);
+        assert_eq!(descriptor[..8], [1, b'C', b'D', b'0', b'0', b'1', 1, 0]);
+        assert_eq!(&descriptor[40..72], b"TOWBOOT_TEST                    ");
+        assert_eq!(descriptor[80..88], [0x17, 0x02, 0, 0, 0, 0, 0x02, 0x17]);
+        assert_eq!(descriptor[128..132], [0, 8, 8, 0]);
+        assert_eq!(descriptor[140..144], [19, 0, 0, 0]);
+        assert_eq!(descriptor[148..152], [0, 0, 0, 20]);
+        assert_eq!(descriptor[156..190], directory_record(&[0], 21, 2048, true));
+        assert_eq!(descriptor[881], 1);
+
+        let record = boot_record();
+        assert_eq!(record[..7], [0, b'C', b'D', b'0', b'0', b'1', 1]);
+        assert_eq!(&record[7..39], b"EL TORITO SPECIFICATION\0\0\0\0\0\0\0\0\0");
+        assert_eq!(record[71..75], [22, 0, 0, 0]);
+
+        assert_eq!(volume_descriptor(255)[..7], [255, b'C', b'D', b'0', b'0', b'1', 1]);
+    }
+
+    #[test]
+    fn catalog() {
+        let catalog = boot_catalog(512);
+        assert_eq!(catalog[..32], [
+            1, 0xef, 0, 0, b't', b'o', b'w', b'b', b'o', b'o', b't', 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xdc, 0x24, 0x55, 0xaa,
+        ]);
+        assert_eq!(catalog[32..44], [0x88, 0, 0, 0, 0, 0, 0, 8, 23, 0, 0, 0]);
+        assert!(catalog[44..].iter().all(|b| *b == 0));
+        // too large for 16 bits
+        assert_eq!(boot_catalog(0x4000)[38..40], [0, 0]);
+    }
+
+    #[test]
+    fn disc() {
+        let path = std::env::temp_dir().join(format!("towbootctl-iso-{}", std::process::id()));
+        assert!(create_iso(&path, "lowercase", 1024 * 1024, &Files::new()).is_err());
+        create_iso(&path, "TOWBOOT", 1024 * 1024, &Files::new()).unwrap();
+        let iso = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(iso.len(), (23 + 512) * 2048);
+        let sector = |number: usize| &iso[number * 2048..(number + 1) * 2048];
+        assert_eq!(sector(16), primary_volume_descriptor("TOWBOOT", 535));
+        assert_eq!(sector(17), boot_record());
+        assert_eq!(sector(18), volume_descriptor(255));
+        assert_eq!(sector(22), boot_catalog(512));
+        let root = sector(21);
+        assert_eq!(root[..34], directory_record(&[0], 21, 2048, true));
+        assert_eq!(root[34..68], directory_record(&[1], 21, 2048, true));
+        assert_eq!(root[68..114], directory_record(ESP_NAME, 23, 1024 * 1024, false));
+        // the ESP image is a FAT filesystem
+        assert_eq!(sector(23)[510..512], [0x55, 0xaa]);
+    }
+}
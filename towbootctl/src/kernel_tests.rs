@@ -0,0 +1,243 @@
+//! Booting the test kernels
+//!
+//! `tests/kernels` contains a tiny Multiboot kernel (in assembly) that prints
+//! what it has been given to the serial console and then exits QEMU. It's
+//! built as an ELF kernel and as a flat binary using the a.out kludge.
+//! `tests.toml` in the same folder lists the tests: which kernel to boot with
+//! which entry and which lines it has to print. Each test gets its own disk
+//! image, which is booted in QEMU (see `qemu`).
+//!
+//! The kernels are built with the host's C compiler and linker, which have to
+//! support 32-bit x86 (GCC and binutils usually do).
+//! There's no Multiboot 2 kernel, because towboot doesn't support Multiboot 2.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use argh::FromArgs;
+use log::{debug, error, info};
+use toml::Value;
+use toml::value::Table;
+
+use super::config::{self, Files};
+use super::fat;
+use super::image;
+use super::qemu::{Arch, BootImageCommand, Outcome};
+
+/// what the kernel prints in front of every line
+const MARKER: &str = "towboot-test: ";
+
+/// Build the test kernels and boot each of them with towboot in QEMU.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "test-kernels")]
+pub(crate) struct TestKernelsCommand {
+    /// towboot's executable
+    #[argh(option)]
+    efi: PathBuf,
+    /// the folder containing the kernels and tests.toml (default: tests/kernels)
+    #[argh(
+        option,
+        default = "PathBuf::from(concat!(env!(\"CARGO_MANIFEST_DIR\"), \"/../tests/kernels\"))"
+    )]
+    kernels: PathBuf,
+    /// where to put the kernels, images and logs (default: target/test-kernels)
+    #[argh(option, default = "PathBuf::from(\"target/test-kernels\")")]
+    output: PathBuf,
+    /// only run the tests whose names contain this
+    #[argh(option)]
+    filter: Option<String>,
+    /// the architecture: i686 or x86_64 (default: i686)
+    #[argh(option, default = "Arch::I686")]
+    arch: Arch,
+    /// the firmware (by default, this is searched in the usual places)
+    #[argh(option)]
+    firmware: Option<PathBuf>,
+    /// fail a test after this many seconds (default: 60)
+    #[argh(option, default = "60")]
+    timeout: u64,
+    /// use KVM
+    #[argh(switch)]
+    kvm: bool,
+    /// the C compiler that builds the kernels (default: cc)
+    #[argh(option, default = "String::from(\"cc\")")]
+    cc: String,
+    /// the linker that links the kernels (default: ld)
+    #[argh(option, default = "String::from(\"ld\")")]
+    ld: String,
+}
+
+impl TestKernelsCommand {
+    pub(crate) fn run(self) -> Result<()> {
+        if !self.arch.is_x86() {
+            bail!("the test kernels only run on x86");
+        }
+        let tests_path = self.kernels.join("tests.toml");
+        let tests: Value = fs::read_to_string(&tests_path)
+            .with_context(|| format!("failed to read {}", tests_path.display()))?
+            .parse()
+            .with_context(|| format!("failed to parse {}", tests_path.display()))?;
+        fs::create_dir_all(&self.output)
+            .with_context(|| format!("failed to create {}", self.output.display()))?;
+        let mut kernels = BTreeMap::new();
+        for (name, kernel) in tests.get("kernels").and_then(Value::as_table)
+            .ok_or_else(|| anyhow!("{} doesn't contain any kernels", tests_path.display()))? {
+            let binary = self.build_kernel(name, kernel)
+                .with_context(|| format!("failed to build kernel {name}"))?;
+            kernels.insert(name.as_str(), binary);
+        }
+        let tests = tests.get("tests").and_then(Value::as_table)
+            .ok_or_else(|| anyhow!("{} doesn't contain any tests", tests_path.display()))?
+            .iter()
+            .filter(|(name, _)| self.filter.as_ref().is_none_or(|f| name.contains(f.as_str())))
+            .collect::<Vec<_>>();
+        let mut failed = Vec::new();
+        for (name, test) in &tests {
+            info!("running {name}");
+            match self.run_test(name, test, &kernels) {
+                Ok(()) => info!("{name}: ok"),
+                Err(e) => {
+                    error!("{name}: {e:#}");
+                    failed.push(name.as_str());
+                },
+            }
+        }
+        if !failed.is_empty() {
+            bail!("{} of {} tests failed: {}", failed.len(), tests.len(), failed.join(", "));
+        }
+        info!("all {} tests passed", tests.len());
+        Ok(())
+    }
+
+    /// Build a kernel, returning the path to it.
+    fn build_kernel(&self, name: &str, kernel: &Value) -> Result<PathBuf> {
+        let flat = kernel.get("flat").and_then(Value::as_bool).unwrap_or(false);
+        let object = self.output.join(format!("{name}.o"));
+        let binary = self.output.join(format!("{name}.{}", if flat { "bin" } else { "elf" }));
+        let mut cc = Command::new(&self.cc);
+        cc.args(["-m32", "-c", "-o"]).arg(&object);
+        let defines = kernel.get("defines").and_then(Value::as_array)
+            .map(Vec::as_slice).unwrap_or_default();
+        for define in defines {
+            let define = define.as_str().ok_or_else(|| anyhow!("defines have to be strings"))?;
+            cc.arg(format!("-D{define}"));
+        }
+        cc.arg(self.kernels.join("kernel.S"));
+        run(cc)?;
+        let mut ld = Command::new(&self.ld);
+        ld.args(["-m", "elf_i386", "-T"]).arg(self.kernels.join("linker.ld"));
+        if flat {
+            ld.args(["--oformat", "binary"]);
+        }
+        ld.arg("-o").arg(&binary).arg(&object);
+        run(ld)?;
+        Ok(binary)
+    }
+
+    /// Create an image for a test, boot it and check the kernel's output.
+    fn run_test(&self, name: &str, test: &Value, kernels: &BTreeMap<&str, PathBuf>) -> Result<()> {
+        let kernel = test.get("kernel").and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("the test doesn't have a kernel"))?;
+        let binary = kernels.get(kernel)
+            .ok_or_else(|| anyhow!("there's no kernel called {kernel}"))?;
+        let expect = test.get("expect").and_then(Value::as_array)
+            .map(Vec::as_slice).unwrap_or_default()
+            .iter()
+            .map(|e| e.as_str().ok_or_else(|| anyhow!("expected lines have to be strings")))
+            .collect::<Result<Vec<_>>>()?;
+        let file_name = binary.file_name().unwrap().to_string_lossy().into_owned();
+        let directory = self.output.join(name);
+        fs::create_dir_all(&directory)
+            .with_context(|| format!("failed to create {}", directory.display()))?;
+
+        let config = test_config(test, &file_name)?;
+        let config_path = directory.join("towboot.toml");
+        fs::write(&config_path, toml::to_string(&config)?)
+            .with_context(|| format!("failed to write {}", config_path.display()))?;
+        let mut files = Files::new();
+        files.insert(file_name, binary.clone());
+        config::add_referenced_files(&mut files, &config, &self.kernels)?;
+        files.insert(String::from("towboot.toml"), config_path);
+        files.insert(
+            format!("EFI/BOOT/{}", image::removable_media_path(&self.efi)?), self.efi.clone(),
+        );
+        let disk = directory.join("image.img");
+        image::create_image(&disk, fat::size_for(&files, image::MINIMUM_SIZE)?, &files)?;
+
+        let serial_log = directory.join("serial.log");
+        let qemu = BootImageCommand::headless(
+            disk, self.arch, self.firmware.clone(), serial_log.clone(), self.timeout, self.kvm,
+        );
+        match qemu.boot()? {
+            Outcome::Exited(0) => (),
+            Outcome::Exited(status) => bail!(
+                "the kernel exited with {status} (see {})", serial_log.display(),
+            ),
+            outcome => bail!("the kernel didn't exit ({outcome:?})"),
+        }
+        check_output(&serial_log, &expect)
+    }
+}
+
+/// Create the configuration for a test.
+///
+/// It boots the test's entry immediately and only logs to the debug console,
+/// unless the test's `config` says otherwise.
+fn test_config(test: &Value, file_name: &str) -> Result<Value> {
+    let mut entry = match test.get("entry") {
+        Some(Value::Table(entry)) => entry.clone(),
+        Some(_) => bail!("the test's entry has to be a table"),
+        None => Table::new(),
+    };
+    entry.insert(String::from("image"), Value::String(format!("\\{file_name}")));
+    let mut config = Table::new();
+    config.insert(String::from("config_version"), Value::Integer(2));
+    config.insert(String::from("default"), Value::String(String::from("test")));
+    config.insert(String::from("timeout"), Value::Integer(0));
+    config.insert(String::from("log_level"), Value::String(String::from("debug")));
+    config.insert(
+        String::from("log_targets"), Value::Array(vec![Value::String(String::from("debugcon"))]),
+    );
+    match test.get("config") {
+        Some(Value::Table(overrides)) => config.extend(overrides.clone()),
+        Some(_) => bail!("the test's config has to be a table"),
+        None => (),
+    }
+    let mut entries = Table::new();
+    entries.insert(String::from("test"), Value::Table(entry));
+    config.insert(String::from("entries"), Value::Table(entries));
+    Ok(Value::Table(config))
+}
+
+/// Check that the kernel has printed a line starting with each of the expected ones.
+fn check_output(serial_log: &Path, expect: &[&str]) -> Result<()> {
+    let output = fs::read(serial_log)
+        .with_context(|| format!("failed to read {}", serial_log.display()))?;
+    let output = String::from_utf8_lossy(&output);
+    // The firmware may write to the serial console, too.
+    let lines: Vec<&str> = output.lines()
+        .filter_map(|line| line.find(MARKER).map(|start| &line[start + MARKER.len()..]))
+        .map(|line| line.trim_end_matches('\r'))
+        .collect();
+    debug!("the kernel printed {lines:?}");
+    let missing: Vec<&str> = expect.iter().copied()
+        .filter(|e| !lines.iter().any(|line| line.starts_with(e)))
+        .collect();
+    if !missing.is_empty() {
+        bail!("the kernel didn't print {missing:?} (see {})", serial_log.display());
+    }
+    Ok(())
+}
+
+/// Run a command, failing if it fails.
+fn run(mut command: Command) -> Result<()> {
+    debug!("running {command:?}");
+    let status = command.status()
+        .with_context(|| format!("failed to run {}", command.get_program().to_string_lossy()))?;
+    if !status.success() {
+        bail!("{command:?} failed ({status})");
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! towbootctl is a companion utility for towboot that runs on the host.
+//!
+//! It can create bootable disk images and ISOs containing towboot, its
+//! configuration and everything the configuration refers to (so there's no
+//! need for mtools, parted, mkgpt or xorriso) or install all of that to an ESP.
+//! It can also generate a configuration from the kernels in `/boot` and boot
+//! images in QEMU (to check whether they work), which is also used to boot the
+//! test kernels. Or, everything can be embedded into towboot's executable, so
+//! that a single binary is enough.
+//!
+//! This is a separate crate because towboot itself is always built for UEFI.
+
+use anyhow::Result;
+use argh::FromArgs;
+use log::LevelFilter;
+
+mod config;
+#[cfg(target_os = "linux")]
+mod efivars;
+mod embed;
+mod fat;
+mod generate;
+mod gpt;
+mod image;
+mod install;
+mod iso;
+mod kernel_tests;
+mod qemu;
+
+/// A companion utility for towboot.
+#[derive(Debug, FromArgs)]
+struct Args {
+    /// log more details
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Image(image::ImageCommand),
+    Iso(iso::IsoCommand),
+    Install(install::InstallCommand),
+    GenerateConfig(generate::GenerateConfigCommand),
+    BootImage(qemu::BootImageCommand),
+    Embed(embed::EmbedCommand),
+    TestKernels(kernel_tests::TestKernelsCommand),
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    env_logger::Builder::new()
+        .filter_level(if args.verbose { LevelFilter::Debug } else { LevelFilter::Info })
+        .parse_default_env()
+        .init();
+    match args.command {
+        Command::Image(command) => command.run(),
+        Command::Iso(command) => command.run(),
+        Command::Install(command) => command.run(),
+        Command::GenerateConfig(command) => command.run(),
+        Command::BootImage(command) => command.run(),
+        Command::Embed(command) => command.run(),
+        Command::TestKernels(command) => command.run(),
+    }
+}
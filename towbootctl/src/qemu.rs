@@ -0,0 +1,408 @@
+//! Booting images in QEMU
+//!
+//! The image (a disk image or an ISO) is booted with OVMF (or the AArch64
+//! equivalent). The serial console is shown and written to a file, QEMU's
+//! debug console (port 0xE9, x86 only) is written to another file.
+//!
+//! On x86, the guest can end the test by writing its status to port 0xF4
+//! (QEMU's `isa-debug-exit` device). QEMU then exits with `(status << 1) | 1`,
+//! which is translated back, so towbootctl exits with the guest's status.
+//! Alternatively, a text can be given that ends the test successfully as soon
+//! as it appears on the serial console. Without either, this exits with 0 when
+//! the machine is powered off.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use argh::FromArgs;
+use log::{debug, error, info};
+
+/// the port of the `isa-debug-exit` device
+const EXIT_PORT: &str = "0xf4";
+/// how often to check whether QEMU has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Boot an image in QEMU.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "boot-image")]
+pub(crate) struct BootImageCommand {
+    /// the disk image or ISO to boot
+    #[argh(positional)]
+    image: PathBuf,
+    /// the architecture: i686, x86_64 or aarch64 (default: i686)
+    #[argh(option, default = "Arch::I686")]
+    arch: Arch,
+    /// the firmware (by default, this is searched in the usual places)
+    #[argh(option)]
+    firmware: Option<PathBuf>,
+    /// where to write the serial console to (default: serial.log)
+    #[argh(option, default = "PathBuf::from(\"serial.log\")")]
+    serial_log: PathBuf,
+    /// where to write the debug console to (default: debugcon.log, x86 only)
+    #[argh(option, default = "PathBuf::from(\"debugcon.log\")")]
+    debugcon_log: PathBuf,
+    /// succeed as soon as this appears on the serial console
+    #[argh(option)]
+    expect: Option<String>,
+    /// fail after this many seconds
+    #[argh(option)]
+    timeout: Option<u64>,
+    /// the memory of the machine in MiB (default: 256)
+    #[argh(option, default = "256")]
+    memory: u32,
+    /// use KVM
+    #[argh(switch)]
+    kvm: bool,
+    /// wait for a GDB to attach to localhost:1234
+    #[argh(switch)]
+    gdb: bool,
+    /// don't open a window
+    #[argh(switch)]
+    headless: bool,
+    /// don't show the serial console (it's still written to the log)
+    #[argh(switch)]
+    quiet: bool,
+}
+
+/// How a boot has ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// the expected text has appeared on the serial console
+    Found,
+    /// the guest has written its status to the exit port
+    Exited(i32),
+    /// the machine has been powered off
+    PoweredOff,
+    /// QEMU itself has failed (with this exit code)
+    Failed(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Arch {
+    I686,
+    X86_64,
+    Aarch64,
+}
+
+impl FromStr for Arch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "i686" => Ok(Self::I686),
+            "x86_64" => Ok(Self::X86_64),
+            "aarch64" => Ok(Self::Aarch64),
+            _ => Err(format!("unknown architecture {s}, try i686, x86_64 or aarch64")),
+        }
+    }
+}
+
+impl Arch {
+    fn qemu(self) -> &'static str {
+        match self {
+            Self::I686 => "qemu-system-i386",
+            Self::X86_64 => "qemu-system-x86_64",
+            Self::Aarch64 => "qemu-system-aarch64",
+        }
+    }
+
+    /// Where the firmware usually is (the first one is where build.sh puts it).
+    fn firmware_paths(self) -> &'static [&'static str] {
+        match self {
+            Self::I686 => &[
+                "OVMF.fd",
+                "/usr/share/OVMF/OVMF32_CODE_4M.fd",
+                "/usr/share/edk2/ovmf-ia32/OVMF_CODE.fd",
+            ],
+            Self::X86_64 => &[
+                "/usr/share/ovmf/OVMF.fd",
+                "/usr/share/OVMF/OVMF.fd",
+                "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+                "/usr/share/edk2-ovmf/x64/OVMF.fd",
+            ],
+            Self::Aarch64 => &[
+                "/usr/share/qemu-efi-aarch64/QEMU_EFI.fd",
+                "/usr/share/AAVMF/AAVMF_CODE.fd",
+                "/usr/share/edk2/aarch64/QEMU_EFI.fd",
+            ],
+        }
+    }
+
+    pub(crate) fn is_x86(self) -> bool {
+        matches!(self, Self::I686 | Self::X86_64)
+    }
+}
+
+impl BootImageCommand {
+    /// Boot an image without showing anything (for automated tests).
+    ///
+    /// The debug console is written to `debugcon.log` next to the serial console's log.
+    pub(crate) fn headless(
+        image: PathBuf, arch: Arch, firmware: Option<PathBuf>, serial_log: PathBuf,
+        timeout: u64, kvm: bool,
+    ) -> Self {
+        Self {
+            image, arch, firmware,
+            debugcon_log: serial_log.with_file_name("debugcon.log"),
+            serial_log,
+            expect: None,
+            timeout: Some(timeout),
+            memory: 256,
+            kvm,
+            gdb: false,
+            headless: true,
+            quiet: true,
+        }
+    }
+
+    pub(crate) fn run(self) -> Result<()> {
+        match self.boot()? {
+            Outcome::Found => Ok(()),
+            Outcome::PoweredOff if self.expect.is_some() => {
+                bail!("the guest didn't print the expected output")
+            },
+            Outcome::PoweredOff | Outcome::Exited(0) => Ok(()),
+            Outcome::Exited(status) => std::process::exit(status),
+            Outcome::Failed(code) => {
+                error!("QEMU failed with {code}");
+                std::process::exit(code);
+            },
+        }
+    }
+
+    /// Boot the image and wait until the guest is done.
+    pub(crate) fn boot(&self) -> Result<Outcome> {
+        let firmware = match &self.firmware {
+            Some(firmware) => firmware.clone(),
+            None => self.arch.firmware_paths().iter().map(PathBuf::from).find(|p| p.exists())
+                .ok_or_else(|| anyhow!("failed to find the firmware, please pass --firmware"))?,
+        };
+        let mut qemu = self.command(&firmware);
+        debug!("running {qemu:?}");
+        let mut child = qemu.spawn()
+            .with_context(|| format!("failed to run {}", self.arch.qemu()))?;
+        let found = Arc::new(AtomicBool::new(false));
+        let serial = {
+            let stdout = child.stdout.take().unwrap();
+            let log = File::create(&self.serial_log)
+                .with_context(|| format!("failed to create {}", self.serial_log.display()))?;
+            let expect = self.expect.clone().filter(|e| !e.is_empty());
+            let found = found.clone();
+            let show = !self.quiet;
+            thread::spawn(move || capture_serial(stdout, log, show, expect, &found))
+        };
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status)
+            }
+            if found.load(Ordering::Relaxed) {
+                info!("found the expected output");
+                break None
+            }
+            if self.timeout.is_some_and(|t| start.elapsed() > Duration::from_secs(t)) {
+                child.kill()?;
+                child.wait()?;
+                bail!("the guest didn't finish in time");
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        let Some(status) = status else {
+            child.kill()?;
+            child.wait()?;
+            return Ok(Outcome::Found)
+        };
+        // This makes sure that everything has been written.
+        let _ = serial.join();
+        if found.load(Ordering::Relaxed) {
+            info!("found the expected output");
+            return Ok(Outcome::Found)
+        }
+        let code = status.code().ok_or_else(|| anyhow!("QEMU has been killed"))?;
+        Ok(match (code, self.arch.is_x86()) {
+            (0, _) => Outcome::PoweredOff,
+            (code, true) if code & 1 == 1 => {
+                let guest = code >> 1;
+                info!("the guest exited with {guest}");
+                Outcome::Exited(guest)
+            },
+            (code, _) => Outcome::Failed(code),
+        })
+    }
+
+    /// Build QEMU's command line.
+    fn command(&self, firmware: &Path) -> Command {
+        let mut qemu = Command::new(self.arch.qemu());
+        qemu.arg("-bios").arg(firmware);
+        qemu.args(["-m", &self.memory.to_string()]);
+        qemu.args(["-serial", "stdio"]);
+        match (self.arch, self.kvm) {
+            (Arch::Aarch64, false) => { qemu.args(["-machine", "virt", "-cpu", "cortex-a57"]); },
+            (Arch::Aarch64, true) => { qemu.args(["-machine", "virt,accel=kvm", "-cpu", "host"]); },
+            (_, true) => { qemu.args(["-machine", "pc,accel=kvm,kernel-irqchip=off"]); },
+            (_, false) => (),
+        }
+        if self.arch.is_x86() {
+            qemu.arg("-debugcon").arg(format!("file:{}", self.debugcon_log.display()));
+            qemu.args(["-device", &format!("isa-debug-exit,iobase={EXIT_PORT},iosize=0x04")]);
+        }
+        let iso = self.image.extension().is_some_and(|e| e.eq_ignore_ascii_case("iso"));
+        let file = format!("file={}", self.image.display());
+        match (iso, self.arch) {
+            (true, Arch::Aarch64) => {
+                qemu.args(["-device", "virtio-scsi-pci", "-device", "scsi-cd,drive=image"]);
+                qemu.args(["-drive", &format!("if=none,id=image,media=cdrom,format=raw,{file}")]);
+            },
+            (true, _) => { qemu.args(["-drive", &format!("media=cdrom,format=raw,{file}")]); },
+            (false, Arch::Aarch64) => {
+                qemu.args(["-drive", &format!("if=virtio,format=raw,{file}")]);
+            },
+            (false, _) => { qemu.args(["-drive", &format!("format=raw,{file}")]); },
+        }
+        if self.gdb {
+            info!("the machine starts paused, waiting for GDB to attach to localhost:1234");
+            qemu.args(["-S", "-s"]);
+        }
+        if self.headless {
+            qemu.args(["-display", "none"]);
+        }
+        qemu.stdin(Stdio::inherit()).stdout(Stdio::piped());
+        qemu
+    }
+}
+
+/// Show the serial console, write it to the log and look for the expected text.
+fn capture_serial(
+    mut serial: impl Read, mut log: File, show: bool, expect: Option<String>, found: &AtomicBool,
+) {
+    let mut buffer = [0; 4096];
+    let mut seen = Vec::new();
+    let stdout = io::stdout();
+    while let Ok(length @ 1..) = serial.read(&mut buffer) {
+        let data = &buffer[..length];
+        if show {
+            let _ = stdout.lock().write_all(data);
+            let _ = stdout.lock().flush();
+        }
+        let _ = log.write_all(data);
+        if let Some(expect) = &expect {
+            seen.extend_from_slice(data);
+            if seen.windows(expect.len()).any(|w| w == expect.as_bytes()) {
+                found.store(true, Ordering::Relaxed);
+            }
+            // only the end can still be part of a match
+            let keep = seen.len().saturating_sub(expect.len());
+            seen.drain(..keep);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::fs;
+
+    use super::*;
+
+    /// A reader that returns the chunks one by one.
+    struct Chunks(Vec<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0)
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    /// Capture the chunks and return whether the text has been found and the log.
+    fn capture(name: &str, chunks: Vec<&'static [u8]>, expect: Option<&str>) -> (bool, Vec<u8>) {
+        let path = std::env::temp_dir()
+            .join(format!("towbootctl-serial-{}-{name}", std::process::id()));
+        let found = AtomicBool::new(false);
+        capture_serial(
+            Chunks(chunks), File::create(&path).unwrap(), false, expect.map(String::from), &found,
+        );
+        let log = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        (found.load(Ordering::Relaxed), log)
+    }
+
+    #[test]
+    fn serial() {
+        let chunks = vec![&b"towboot: loading"[..], b" the kernel\nHel", b"lo, world!\n"];
+        let (found, log) = capture("split", chunks, Some("Hello, world"));
+        assert!(found);
+        assert_eq!(log, b"towboot: loading the kernel\nHello, world!\n");
+        let (found, _) = capture("missing", vec![b"Hello, ", b"there"], Some("Hello, world"));
+        assert!(!found);
+        let (found, log) = capture("none", vec![b"Hello, world"], None);
+        assert!(!found);
+        assert_eq!(log, b"Hello, world");
+    }
+
+    #[test]
+    fn architectures() {
+        assert_eq!("i686".parse(), Ok(Arch::I686));
+        assert_eq!("x86_64".parse(), Ok(Arch::X86_64));
+        assert_eq!("aarch64".parse(), Ok(Arch::Aarch64));
+        assert!("riscv64".parse::<Arch>().is_err());
+        assert!(Arch::X86_64.is_x86());
+        assert!(!Arch::Aarch64.is_x86());
+    }
+
+    /// Get the arguments QEMU would be run with.
+    fn arguments(command: &BootImageCommand) -> Vec<String> {
+        command.command(Path::new("OVMF.fd")).get_args()
+            .map(OsStr::to_string_lossy).map(|a| a.into_owned()).collect()
+    }
+
+    #[test]
+    fn command_lines() {
+        let mut command = BootImageCommand::headless(
+            PathBuf::from("disk.img"), Arch::I686, None, PathBuf::from("out/serial.log"), 10, false,
+        );
+        assert_eq!(command.command(Path::new("OVMF.fd")).get_program(), "qemu-system-i386");
+        assert_eq!(arguments(&command), [
+            "-bios", "OVMF.fd", "-m", "256", "-serial", "stdio",
+            "-debugcon", "file:out/debugcon.log",
+            "-device", "isa-debug-exit,iobase=0xf4,iosize=0x04",
+            "-drive", "format=raw,file=disk.img",
+            "-display", "none",
+        ]);
+
+        command.image = PathBuf::from("towboot.ISO");
+        command.kvm = true;
+        command.headless = false;
+        command.gdb = true;
+        assert_eq!(arguments(&command), [
+            "-bios", "OVMF.fd", "-m", "256", "-serial", "stdio",
+            "-machine", "pc,accel=kvm,kernel-irqchip=off",
+            "-debugcon", "file:out/debugcon.log",
+            "-device", "isa-debug-exit,iobase=0xf4,iosize=0x04",
+            "-drive", "media=cdrom,format=raw,file=towboot.ISO",
+            "-S", "-s",
+        ]);
+
+        command.arch = Arch::Aarch64;
+        command.kvm = false;
+        command.gdb = false;
+        assert_eq!(arguments(&command), [
+            "-bios", "OVMF.fd", "-m", "256", "-serial", "stdio",
+            "-machine", "virt", "-cpu", "cortex-a57",
+            "-device", "virtio-scsi-pci", "-device", "scsi-cd,drive=image",
+            "-drive", "if=none,id=image,media=cdrom,format=raw,file=towboot.ISO",
+        ]);
+    }
+}